@@ -100,6 +100,9 @@ pub trait IsServer<'a> {
                 debug!("{:?}", configure);
                 self.set_version_rolling_mask(configure.version_rolling_mask());
                 self.set_version_rolling_min_bit(configure.version_rolling_min_bit_count());
+                if configure.subscribe_extranonce() {
+                    self.handle_extranonce_subscribe();
+                }
                 let (version_rolling, min_diff) = self.handle_configure(&configure);
                 Ok(Some(configure.respond(version_rolling, min_diff)))
             }
@@ -119,15 +122,26 @@ pub trait IsServer<'a> {
                     None => self.version_rolling_mask().is_none(),
                 };
 
-                let is_valid_submission = self.is_authorized(&submit.user_name)
-                    && self.extranonce2_size() == submit.extra_nonce2.len()
-                    && has_valid_version_bits;
+                if self.extranonce2_size() != submit.extra_nonce2.len() {
+                    return Err(Error::InvalidSubmission);
+                }
+                if !self.is_authorized(&submit.user_name) {
+                    return Ok(Some(submit.respond_with_error(
+                        server_to_client::SubmitError::UnauthorizedWorker,
+                    )));
+                }
+                if !has_valid_version_bits {
+                    return Ok(Some(submit.respond_with_error(
+                        server_to_client::SubmitError::InvalidVersionBits,
+                    )));
+                }
 
-                if is_valid_submission {
-                    let accepted = self.handle_submit(&submit);
-                    Ok(Some(submit.respond(accepted)))
-                } else {
-                    Err(Error::InvalidSubmission)
+                match self.validate_submit(&submit) {
+                    Ok(()) => {
+                        let accepted = self.handle_submit(&submit);
+                        Ok(Some(submit.respond(accepted)))
+                    }
+                    Err(e) => Ok(Some(submit.respond_with_error(e))),
                 }
             }
             methods::Client2Server::Subscribe(subscribe) => {
@@ -184,6 +198,17 @@ pub trait IsServer<'a> {
     ///
     fn handle_submit(&self, request: &client_to_server::Submit<'a>) -> bool;
 
+    /// Called before [handle_submit](IsServer::handle_submit) for every well formed share sent
+    /// by an authorized user. If an error is returned the share is not handled and the client
+    /// receives a response with the matching stratum error code.
+    ///
+    fn validate_submit(
+        &self,
+        _request: &client_to_server::Submit<'a>,
+    ) -> Result<(), server_to_client::SubmitError> {
+        Ok(())
+    }
+
     /// Indicates to the server that the client supports the mining.set_extranonce method.
     fn handle_extranonce_subscribe(&self);
 
@@ -221,8 +246,16 @@ pub trait IsServer<'a> {
         }
         .into())
     }
+    /// Changes the version rolling mask of a client that negotiated version rolling with
+    /// `mining.configure`, returns the `mining.set_version_mask` to be sent to the client.
     // {"params":["00003000"], "id":null, "method": "mining.set_version_mask"}
-    // fn update_version_rolling_mask
+    fn update_version_rolling_mask(
+        &mut self,
+        version_mask: HexU32Be,
+    ) -> Result<json_rpc::Message, Error<'a>> {
+        self.set_version_rolling_mask(Some(version_mask.clone()));
+        Ok(server_to_client::SetVersionMask { version_mask }.into())
+    }
 
     fn notify(&mut self) -> Result<json_rpc::Message, Error>;
 
@@ -511,3 +544,180 @@ pub enum ClientStatus {
     Configured,
     Subscribed,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use server_to_client::SubmitError;
+
+    struct Server {
+        authorized: Vec<String>,
+        version_rolling_mask: Option<HexU32Be>,
+        validation: Result<(), SubmitError>,
+    }
+
+    impl<'a> IsServer<'a> for Server {
+        fn handle_configure(
+            &mut self,
+            _request: &client_to_server::Configure,
+        ) -> (Option<server_to_client::VersionRollingParams>, Option<bool>) {
+            (None, None)
+        }
+
+        fn handle_subscribe(
+            &self,
+            _request: &client_to_server::Subscribe,
+        ) -> Vec<(String, String)> {
+            vec![]
+        }
+
+        fn handle_authorize(&self, _request: &client_to_server::Authorize) -> bool {
+            true
+        }
+
+        fn handle_submit(&self, _request: &client_to_server::Submit<'a>) -> bool {
+            true
+        }
+
+        fn validate_submit(
+            &self,
+            _request: &client_to_server::Submit<'a>,
+        ) -> Result<(), SubmitError> {
+            self.validation.clone()
+        }
+
+        fn handle_extranonce_subscribe(&self) {}
+
+        fn is_authorized(&self, name: &str) -> bool {
+            self.authorized.iter().any(|authorized| authorized == name)
+        }
+
+        fn authorize(&mut self, name: &str) {
+            self.authorized.push(name.to_string());
+        }
+
+        fn set_extranonce1(&mut self, _extranonce1: Option<Extranonce<'a>>) -> Extranonce<'a> {
+            self.extranonce1()
+        }
+
+        fn extranonce1(&self) -> Extranonce<'a> {
+            Extranonce::try_from(vec![0; 4]).unwrap()
+        }
+
+        fn set_extranonce2_size(&mut self, _extra_nonce2_size: Option<usize>) -> usize {
+            self.extranonce2_size()
+        }
+
+        fn extranonce2_size(&self) -> usize {
+            4
+        }
+
+        fn version_rolling_mask(&self) -> Option<HexU32Be> {
+            self.version_rolling_mask.clone()
+        }
+
+        fn set_version_rolling_mask(&mut self, mask: Option<HexU32Be>) {
+            self.version_rolling_mask = mask;
+        }
+
+        fn set_version_rolling_min_bit(&mut self, _mask: Option<HexU32Be>) {}
+
+        fn notify(&mut self) -> Result<json_rpc::Message, Error> {
+            unimplemented!()
+        }
+    }
+
+    fn server(validation: Result<(), SubmitError>) -> Server {
+        Server {
+            authorized: vec!["worker".to_string()],
+            version_rolling_mask: Some(HexU32Be(0x1fffe000)),
+            validation,
+        }
+    }
+
+    fn submit(user_name: &str, extranonce2_size: usize, version_bits: u32) -> json_rpc::Message {
+        client_to_server::Submit {
+            user_name: user_name.to_string(),
+            job_id: "1".to_string(),
+            extra_nonce2: Extranonce::try_from(vec![0; extranonce2_size]).unwrap(),
+            time: HexU32Be(0x6436eddf),
+            nonce: HexU32Be(0x41d5deb0),
+            version_bits: Some(HexU32Be(version_bits)),
+            id: 7,
+        }
+        .into()
+    }
+
+    // code of the error of the response, None when the share is accepted
+    fn error_code(server: &mut Server, message: json_rpc::Message) -> Option<i32> {
+        let response = server.handle_message(message).unwrap().unwrap();
+        assert_eq!(response.id, 7);
+        match response.error {
+            Some(error) => {
+                assert_eq!(response.result, serde_json::Value::Bool(false));
+                Some(error.code)
+            }
+            None => {
+                assert_eq!(response.result, serde_json::Value::Bool(true));
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn valid_share_is_accepted() {
+        assert_eq!(
+            error_code(&mut server(Ok(())), submit("worker", 4, 0x2000)),
+            None
+        );
+    }
+
+    #[test]
+    fn wrong_extranonce2_size_is_an_error() {
+        assert!(matches!(
+            server(Ok(())).handle_message(submit("worker", 8, 0x2000)),
+            Err(Error::InvalidSubmission)
+        ));
+    }
+
+    #[test]
+    fn unauthorized_worker_is_rejected() {
+        assert_eq!(
+            error_code(&mut server(Ok(())), submit("other", 4, 0x2000)),
+            Some(24)
+        );
+    }
+
+    #[test]
+    fn version_bits_outside_the_mask_are_rejected() {
+        assert_eq!(
+            error_code(&mut server(Ok(())), submit("worker", 4, 0x2000_0000)),
+            Some(20)
+        );
+        // no version rolling was negotiated
+        let mut server = server(Ok(()));
+        server.version_rolling_mask = None;
+        assert_eq!(
+            error_code(&mut server, submit("worker", 4, 0x2000)),
+            Some(20)
+        );
+    }
+
+    #[test]
+    fn validation_errors_are_rejected_with_their_code() {
+        let errors = [
+            (SubmitError::Other("other".to_string()), 20),
+            (SubmitError::InvalidNTime, 20),
+            (SubmitError::JobNotFound, 21),
+            (SubmitError::DuplicateShare, 22),
+            (SubmitError::LowDifficulty, 23),
+            (SubmitError::NotSubscribed, 25),
+        ];
+        for (error, code) in errors {
+            assert_eq!(
+                error_code(&mut server(Err(error)), submit("worker", 4, 0x2000)),
+                Some(code)
+            );
+        }
+    }
+}
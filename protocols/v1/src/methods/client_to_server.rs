@@ -8,7 +8,7 @@ use std::convert::{TryFrom, TryInto};
 use crate::{
     error::Error,
     json_rpc::{Message, Response, StandardRequest},
-    methods::ParsingMethodError,
+    methods::{server_to_client::SubmitError, ParsingMethodError},
     utils::{Extranonce, HexU32Be},
 };
 
@@ -129,7 +129,7 @@ pub struct Submit<'a> {
 
 impl<'a> Submit<'a> {
     pub fn respond(self, is_ok: bool) -> Response {
-        // infallibel
+        // infallible
         let result = serde_json::to_value(is_ok).unwrap();
         Response {
             id: self.id,
@@ -137,6 +137,17 @@ impl<'a> Submit<'a> {
             error: None,
         }
     }
+
+    /// Rejects the share giving the client the reason of the rejection.
+    pub fn respond_with_error(self, error: SubmitError) -> Response {
+        // infallible
+        let result = serde_json::to_value(false).unwrap();
+        Response {
+            id: self.id,
+            result,
+            error: Some(error.into()),
+        }
+    }
 }
 
 impl<'a> From<Submit<'a>> for Message {
@@ -369,10 +380,16 @@ impl Configure {
         version_rolling: Option<crate::server_to_client::VersionRollingParams>,
         minimum_difficulty: Option<bool>,
     ) -> Response {
+        let subscribe_extranonce = if self.subscribe_extranonce() {
+            Some(true)
+        } else {
+            None
+        };
         let response = crate::server_to_client::Configure {
             id: self.id,
             version_rolling,
             minimum_difficulty,
+            subscribe_extranonce,
         };
         match Message::from(response) {
             Message::OkResponse(r) => r,
@@ -400,6 +417,25 @@ impl Configure {
         }
         res
     }
+
+    /// Minimum difficulty requested by the miner with the `minimum-difficulty` extension.
+    pub fn minimum_difficulty(&self) -> Option<u64> {
+        let mut res = None;
+        for ext in &self.extensions {
+            if let ConfigureExtension::MinimumDifficulty(min_diff) = ext {
+                res = Some(*min_diff);
+            };
+        }
+        res
+    }
+
+    /// True if the miner asked for `mining.set_extranonce` with the `subscribe-extranonce`
+    /// extension.
+    pub fn subscribe_extranonce(&self) -> bool {
+        self.extensions
+            .iter()
+            .any(|ext| matches!(ext, ConfigureExtension::SubcribeExtraNonce))
+    }
 }
 
 impl From<Configure> for Message {
@@ -716,3 +752,23 @@ fn test_version_extension_with_no_bit_count() {
         _ => panic!(),
     };
 }
+
+#[test]
+fn test_configure_minimum_difficulty_and_subscribe_extranonce() {
+    let client_message = r#"{"id":0,
+            "method": "mining.configure",
+            "params":[
+                ["version-rolling", "minimum-difficulty", "subscribe-extranonce"],
+                {"version-rolling.mask":"1fffe000",
+                "minimum-difficulty.value":2048}
+            ]
+        }"#;
+    let client_message: StandardRequest = serde_json::from_str(&client_message).unwrap();
+    let server_configure = Configure::try_from(client_message).unwrap();
+    assert_eq!(server_configure.minimum_difficulty(), Some(2048));
+    assert!(server_configure.subscribe_extranonce());
+
+    let response = server_configure.respond(None, Some(true));
+    assert_eq!(response.result["minimum-difficulty"], Value::Bool(true));
+    assert_eq!(response.result["subscribe-extranonce"], Value::Bool(true));
+}
@@ -6,7 +6,7 @@ use std::convert::{TryFrom, TryInto};
 
 use crate::{
     error::Error,
-    json_rpc::{Message, Notification, Response},
+    json_rpc::{JsonRpcError, Message, Notification, Response},
     methods::ParsingMethodError,
     utils::{Extranonce, HexBytes, HexU32Be, MerkleNode, PrevHash},
 };
@@ -238,14 +238,14 @@ impl<'a> TryFrom<Notification> for SetExtranonce<'a> {
 #[derive(Debug, Clone)]
 /// Server may arbitrarily adjust version mask
 pub struct SetVersionMask {
-    version_mask: HexU32Be,
+    pub version_mask: HexU32Be,
 }
 
 impl From<SetVersionMask> for Message {
     fn from(sv: SetVersionMask) -> Self {
         let version_mask: Value = sv.version_mask.into();
         Message::Notification(Notification {
-            method: "mining.set_version".to_string(),
+            method: "mining.set_version_mask".to_string(),
             params: (&[version_mask][..]).into(),
         })
     }
@@ -333,6 +333,67 @@ impl Submit {
     }
 }
 
+/// Reasons for which a server can reject a `mining.submit`, sent to the client in the `error`
+/// field of the response together with the matching stratum error code:
+///
+/// * 20 - Other/Unknown
+/// * 21 - Job not found (=stale)
+/// * 22 - Duplicate share
+/// * 23 - Low difficulty share
+/// * 24 - Unauthorized worker
+/// * 25 - Not subscribed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubmitError {
+    Other(String),
+    JobNotFound,
+    DuplicateShare,
+    LowDifficulty,
+    UnauthorizedWorker,
+    NotSubscribed,
+    /// The submitted version bits are not allowed by the negotiated version rolling mask
+    InvalidVersionBits,
+    /// The submitted ntime is out of the range allowed by the job
+    InvalidNTime,
+}
+
+impl SubmitError {
+    pub fn code(&self) -> i32 {
+        match self {
+            SubmitError::Other(_) | SubmitError::InvalidVersionBits | SubmitError::InvalidNTime => {
+                20
+            }
+            SubmitError::JobNotFound => 21,
+            SubmitError::DuplicateShare => 22,
+            SubmitError::LowDifficulty => 23,
+            SubmitError::UnauthorizedWorker => 24,
+            SubmitError::NotSubscribed => 25,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            SubmitError::Other(message) => message.clone(),
+            SubmitError::JobNotFound => "Job not found".to_string(),
+            SubmitError::DuplicateShare => "Duplicate share".to_string(),
+            SubmitError::LowDifficulty => "Low difficulty share".to_string(),
+            SubmitError::UnauthorizedWorker => "Unauthorized worker".to_string(),
+            SubmitError::NotSubscribed => "Not subscribed".to_string(),
+            SubmitError::InvalidVersionBits => "Invalid version bits".to_string(),
+            SubmitError::InvalidNTime => "Invalid ntime".to_string(),
+        }
+    }
+}
+
+impl From<SubmitError> for JsonRpcError {
+    fn from(e: SubmitError) -> Self {
+        JsonRpcError {
+            code: e.code(),
+            message: e.message(),
+            data: None,
+        }
+    }
+}
+
 /// mining.subscribe
 /// mining.subscribe("user agent/version", "extranonce1")
 /// The optional second parameter specifies a mining.notify subscription id the client wishes to resume
@@ -428,6 +489,7 @@ pub struct Configure {
     pub id: u64,
     pub version_rolling: Option<VersionRollingParams>,
     pub minimum_difficulty: Option<bool>,
+    pub subscribe_extranonce: Option<bool>,
 }
 
 impl Configure {
@@ -468,6 +530,10 @@ impl From<Configure> for Message {
             let minimum_difficulty: Value = min_diff.into();
             params.insert("minimum-difficulty".to_string(), minimum_difficulty);
         };
+        if let Some(subscribe_extranonce) = co.subscribe_extranonce {
+            let subscribe_extranonce: Value = subscribe_extranonce.into();
+            params.insert("subscribe-extranonce".to_string(), subscribe_extranonce);
+        };
         Message::OkResponse(Response {
             id: co.id,
             error: None,
@@ -489,6 +555,7 @@ impl TryFrom<&Response> for Configure {
         let version_rolling_mask = params.get("version-rolling.mask");
         let version_rolling_min_bit_count = params.get("version-rolling.min-bit-count");
         let minimum_difficulty = params.get("minimum-difficulty");
+        let subscribe_extranonce = params.get("subscribe-extranonce");
 
         // Deserialize version-rolling response.
         // Composed by 3 fields:
@@ -540,10 +607,19 @@ impl TryFrom<&Response> for Configure {
             None => None,
         };
 
+        let subscribe_extranonce = match subscribe_extranonce {
+            Some(a) => Some(
+                a.as_bool()
+                    .ok_or_else(|| ParsingMethodError::UnexpectedObjectParams(params.clone()))?,
+            ),
+            None => None,
+        };
+
         Ok(Configure {
             id,
             version_rolling,
             minimum_difficulty,
+            subscribe_extranonce,
         })
     }
 }
@@ -562,7 +638,8 @@ fn configure_response_parsing_all_fields() {
                 "version-rolling":true,
                 "version-rolling.mask":"1fffe000",
                 "version-rolling.min-bit-count":"00000005",
-                "minimum-difficulty":false
+                "minimum-difficulty":false,
+                "subscribe-extranonce":true
             }
         }"#;
     let client_response = serde_json::from_str(&client_response_str).unwrap();
@@ -575,6 +652,7 @@ fn configure_response_parsing_all_fields() {
     assert_eq!(version_rolling.version_rolling_min_bit_count, HexU32Be(5));
 
     assert_eq!(server_configure.minimum_difficulty, Some(false));
+    assert_eq!(server_configure.subscribe_extranonce, Some(true));
 }
 
 #[test]
@@ -583,7 +661,8 @@ fn configure_response_parsing_no_vr_min_bit_count() {
             "result":{
                 "version-rolling":true,
                 "version-rolling.mask":"1fffe000",
-                "minimum-difficulty":false
+                "minimum-difficulty":false,
+                "subscribe-extranonce":true
             }
         }"#;
     let client_response = serde_json::from_str(&client_response_str).unwrap();
@@ -1,7 +1,7 @@
 use super::extended_to_standard_job;
 use crate::{
     common_properties::StandardChannel,
-    job_creator::{self, JobsCreators},
+    job_creator::{self, CoinbaseCommitment, JobsCreators},
     parsers::Mining,
     utils::{GroupId, Id, Mutex},
     Error,
@@ -258,19 +258,17 @@ impl ChannelFactory {
                 .safe_lock(|ids| ids.new_channel_id(extended_channels_group))
                 .unwrap();
             self.channel_to_group_id.insert(channel_id, 0);
-            let target = match crate::utils::hash_rate_to_target(
-                hash_rate.into(),
-                self.share_per_min.into(),
-            ) {
-                Ok(target) => target,
-                Err(e) => {
-                    error!(
-                        "Impossible to get target: {:?}. Request id: {:?}",
-                        e, request_id
-                    );
-                    return Err(e);
-                }
-            };
+            let target: binary_sv2::U256<'static> =
+                match Target::from_hashrate(hash_rate.into(), self.share_per_min.into()) {
+                    Ok(target) => target.into(),
+                    Err(e) => {
+                        error!(
+                            "Impossible to get target: {:?}. Request id: {:?}",
+                            e, request_id
+                        );
+                        return Err(Error::TargetError(e));
+                    }
+                };
             let extranonce = self
                 .extranonces
                 .next_extended(max_extranonce_size as usize)
@@ -346,19 +344,17 @@ impl ChannelFactory {
         let hom_group_id = 0;
         let mut result = vec![];
         let channel_id = id;
-        let target = match crate::utils::hash_rate_to_target(
-            downstream_hash_rate.into(),
-            self.share_per_min.into(),
-        ) {
-            Ok(target) => target,
-            Err(e) => {
-                error!(
-                    "Impossible to get target: {:?}. Request id: {:?}",
-                    e, request_id
-                );
-                return Err(e);
-            }
-        };
+        let target: binary_sv2::U256<'static> =
+            match Target::from_hashrate(downstream_hash_rate.into(), self.share_per_min.into()) {
+                Ok(target) => target.into(),
+                Err(e) => {
+                    error!(
+                        "Impossible to get target: {:?}. Request id: {:?}",
+                        e, request_id
+                    );
+                    return Err(Error::TargetError(e));
+                }
+            };
         let extranonce = self
             .extranonces
             .next_standard()
@@ -401,19 +397,17 @@ impl ChannelFactory {
             .safe_lock(|ids| ids.new_channel_id(group_id))
             .unwrap();
         let complete_id = GroupId::into_complete_id(group_id, channel_id);
-        let target = match crate::utils::hash_rate_to_target(
-            downstream_hash_rate.into(),
-            self.share_per_min.into(),
-        ) {
-            Ok(target_) => target_,
-            Err(e) => {
-                info!(
-                    "Impossible to get target: {:?}. Request id: {:?}",
-                    e, request_id
-                );
-                return Err(e);
-            }
-        };
+        let target: binary_sv2::U256<'static> =
+            match Target::from_hashrate(downstream_hash_rate.into(), self.share_per_min.into()) {
+                Ok(target_) => target_.into(),
+                Err(e) => {
+                    info!(
+                        "Impossible to get target: {:?}. Request id: {:?}",
+                        e, request_id
+                    );
+                    return Err(Error::TargetError(e));
+                }
+            };
         let extranonce = self
             .extranonces
             .next_standard()
@@ -958,6 +952,20 @@ impl ChannelFactory {
         channel.target = new_target.into();
         Some(true)
     }
+    /// updates the extranonce prefix for the given channel_id, the new prefix must have the same
+    /// len of the upstream part of the extranonce (range_0)
+    fn update_extranonce_prefix_for_channel(
+        &mut self,
+        channel_id: u32,
+        new_prefix: binary_sv2::B032<'static>,
+    ) -> Option<bool> {
+        if new_prefix.to_vec().len() != self.extranonces.get_range0_len() {
+            return Some(false);
+        }
+        let channel = self.extended_channels.get_mut(&channel_id)?;
+        channel.extranonce_prefix = new_prefix;
+        Some(true)
+    }
 }
 
 /// Used by a pool to in order to manage all downstream channel. It add job creation capabilities
@@ -1264,6 +1272,11 @@ impl PoolChannelFactory {
         self.pool_coinbase_outputs = outs;
     }
 
+    /// calls [`JobsCreators::add_coinbase_commitment`]
+    pub fn add_coinbase_commitment(&mut self, commitment: Arc<dyn CoinbaseCommitment>) {
+        self.job_creator.add_coinbase_commitment(commitment);
+    }
+
     /// calls [`ChannelFactory::update_target_for_channel`]
     /// Set a partucular downstream channel target.
     pub fn update_target_for_channel(
@@ -1273,6 +1286,17 @@ impl PoolChannelFactory {
     ) -> Option<bool> {
         self.inner.update_target_for_channel(channel_id, new_target)
     }
+    /// calls [`ChannelFactory::update_extranonce_prefix_for_channel`]
+    /// Set the extranonce prefix of a channel, used when upstream send a `SetExtranoncePrefix`.
+    /// Return `Some(false)` if the prefix len do not match the upstream extranonce len.
+    pub fn update_extranonce_prefix_for_channel(
+        &mut self,
+        channel_id: u32,
+        new_prefix: binary_sv2::B032<'static>,
+    ) -> Option<bool> {
+        self.inner
+            .update_extranonce_prefix_for_channel(channel_id, new_prefix)
+    }
     // Set the target for this channel. This is the upstream target.
     pub fn set_target(&mut self, new_target: &mut Target) {
         self.inner.kind.set_target(new_target);
@@ -1756,7 +1780,7 @@ impl ExtendedChannelKind {
 #[cfg(test)]
 mod test {
     use super::*;
-    use binary_sv2::{Seq0255, B064K, U256};
+    use binary_sv2::{Seq0255, U256};
     use bitcoin::{hash_types::WPubkeyHash, PublicKey, TxOut};
     use mining_sv2::OpenStandardMiningChannel;
 
@@ -1818,10 +1842,6 @@ mod test {
         (prefix, extranonce, suffix)
     }
 
-    fn get_coinbase_outputs() -> B064K<'static> {
-        decode_hex(COINBASE_OUTPUT).unwrap().try_into().unwrap()
-    }
-
     fn get_merkle_path() -> Seq0255<'static, U256<'static>> {
         let mut m_path = decode_hex(MERKLE_PATH).unwrap();
         m_path.reverse();
@@ -1830,11 +1850,7 @@ mod test {
     }
 
     fn nbit_to_target(nbit: u32) -> U256<'static> {
-        let mut target = bitcoin::blockdata::block::BlockHeader::u256_from_compact_target(nbit)
-            .to_be_bytes()
-            .to_vec();
-        target.reverse();
-        target.try_into().unwrap()
+        Target::from_compact(nbit).unwrap().into()
     }
 
     fn decode_hex(s: &str) -> Result<Vec<u8>, std::num::ParseIntError> {
@@ -1849,7 +1865,10 @@ mod test {
         let (prefix, coinbase_extranonce, _) = get_coinbase();
 
         // Initialize a Channel of type Pool
-        let out = TxOut {value: BLOCK_REWARD, script_pubkey: decode_hex("4104c6d0969c2d98a5c19ba7c36c7937c5edbd60ff2a01397c4afe54f16cd641667ea0049ba6f9e1796ba3c8e49e1b504c532ebbaaa1010c3f7d9b83a8ea7fd800e2ac").unwrap().into()};
+        let out = TxOut {
+            value: BLOCK_REWARD,
+            script_pubkey: decode_hex(COINBASE_OUTPUT).unwrap().into(),
+        };
         let pool_signature = "".to_string();
         let creator = JobsCreators::new(7);
         let share_per_min = 1.0;
@@ -1883,8 +1902,9 @@ mod test {
             coinbase_prefix: prefix.try_into().unwrap(),
             coinbase_tx_input_sequence: u32::MAX,
             coinbase_tx_value_remaining: 5_000_000_000,
+            // the only output of the coinbase is the pool one
             coinbase_tx_outputs_count: 0,
-            coinbase_tx_outputs: get_coinbase_outputs(),
+            coinbase_tx_outputs: vec![].try_into().unwrap(),
             coinbase_tx_locktime: 0,
             merkle_path: get_merkle_path(),
         };
@@ -1979,4 +1999,48 @@ mod test {
             OnNewShare::ShareMeetDownstreamTarget => panic!(),
         };
     }
+
+    #[test]
+    fn test_update_extranonce_prefix_for_channel() {
+        let extranonces = ExtendedExtranonce::new(0..4, 4..4, 4..16);
+        let mut channel = PoolChannelFactory::new(
+            Arc::new(Mutex::new(GroupId::new())),
+            extranonces,
+            JobsCreators::new(16),
+            1.0,
+            ExtendedChannelKind::ProxyJd {
+                upstream_target: [255_u8; 32].into(),
+            },
+            vec![],
+            "".to_string(),
+        );
+        channel
+            .replicate_upstream_extended_channel_only_jd(
+                [255_u8; 32].try_into().unwrap(),
+                vec![1, 2, 3, 4].try_into().unwrap(),
+                7,
+                12,
+            )
+            .unwrap();
+
+        let new_prefix: binary_sv2::B032 = vec![5, 6, 7, 8].try_into().unwrap();
+        assert_eq!(
+            channel.update_extranonce_prefix_for_channel(7, new_prefix.clone()),
+            Some(true)
+        );
+        assert_eq!(
+            channel.inner.extended_channels[&7].extranonce_prefix,
+            new_prefix
+        );
+        // unknown channel
+        assert_eq!(
+            channel.update_extranonce_prefix_for_channel(8, new_prefix),
+            None
+        );
+        // the upstream part of the extranonce can not change size
+        assert_eq!(
+            channel.update_extranonce_prefix_for_channel(7, vec![5, 6].try_into().unwrap()),
+            Some(false)
+        );
+    }
 }
@@ -1,10 +1,8 @@
 //! Errors specific to this crate
 
-use crate::{
-    common_properties::CommonDownstreamData, parsers::PoolMessages as AllMessages,
-    utils::InputError,
-};
+use crate::{common_properties::CommonDownstreamData, parsers::PoolMessages as AllMessages};
 use binary_sv2::Error as BinarySv2Error;
+use mining_sv2::TargetError;
 use std::fmt::{self, Display, Formatter};
 
 #[derive(Debug)]
@@ -55,12 +53,21 @@ pub enum Error {
     InvalidExtranonceSize(u16, u16),
     PoisonLock(String),
     InvalidBip34Bytes(Vec<u8>),
+    // template_id
+    InvalidCoinbaseOutputs(u64),
+    ScriptSigTooBig(usize),
+    CoinbaseCommitmentTooBig,
+    // value of the commitment outputs
+    CoinbaseCommitmentWithValue(u64),
     // (downstream_job_id, upstream_job_id)
     JobNotUpdated(u32, u32),
-    TargetError(InputError),
-    HashrateError(InputError),
+    TargetError(TargetError),
+    HashrateError(TargetError),
     LogicErrorMessage(std::boxed::Box<AllMessages<'static>>),
     JDSMissingTransactions,
+    InvalidExtendedPubKey(String),
+    InvalidDerivationPath(String),
+    DerivationIndexError(String),
 }
 
 impl From<BinarySv2Error> for Error {
@@ -148,11 +155,18 @@ impl Display for Error {
             NoValidTemplate(e) => write!(f, "Impossible to retrieve a template for the required template id: {}", e),
             PoisonLock(e) => write!(f, "Poison lock: {}", e),
             InvalidBip34Bytes(e) => write!(f, "Invalid Bip34 bytes {:?}", e),
+            ScriptSigTooBig(size) => write!(f, "Coinbase scriptSig of {} bytes, the max is 100", size),
+            CoinbaseCommitmentTooBig => write!(f, "A coinbase commitment is bigger than its declared max size"),
+            CoinbaseCommitmentWithValue(value) => write!(f, "A coinbase commitment has outputs of {} sats, only 0 value outputs are allowed", value),
+            InvalidCoinbaseOutputs(template_id) => write!(f, "Coinbase outputs of template {} do not match coinbase_tx_outputs_count", template_id),
             JobNotUpdated(ds_job_id, us_job_id) => write!(f, "Channel Factory did not update job: Downstream job id = {}, Upstream job id = {}", ds_job_id, us_job_id),
             TargetError(e) => write!(f, "Impossible to get Target: {:?}", e),
             HashrateError(e) => write!(f, "Impossible to get Hashrate: {:?}", e),
             LogicErrorMessage(e) => write!(f, "Message is well formatted but can not be handled: {:?}", e),
             JDSMissingTransactions => write!(f, "JD server cannot propagate the block: missing transactions"),
+            InvalidExtendedPubKey(e) => write!(f, "Invalid extended public key: {}", e),
+            InvalidDerivationPath(e) => write!(f, "Invalid derivation path: {}", e),
+            DerivationIndexError(e) => write!(f, "Impossible to read or persist the derivation index: {}", e),
         }
     }
 }
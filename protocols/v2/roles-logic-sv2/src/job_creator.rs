@@ -4,7 +4,7 @@ use crate::{errors, utils::Id, Error};
 use binary_sv2::B064K;
 use mining_sv2::NewExtendedMiningJob;
 use nohash_hasher::BuildNoHashHasher;
-use std::{collections::HashMap, convert::TryInto};
+use std::{collections::HashMap, convert::TryInto, fmt::Debug, sync::Arc};
 use template_distribution_sv2::{NewTemplate, SetNewPrevHash};
 use tracing::debug;
 
@@ -15,11 +15,85 @@ use stratum_common::{
             transaction::{OutPoint, Transaction, TxIn, TxOut},
             witness::Witness,
         },
-        consensus::Decodable,
+        consensus::{encode, encode::VarInt, Decodable},
         util::psbt::serialize::{Deserialize, Serialize},
+        Script,
     },
 };
 
+/// Max size of the coinbase scriptSig allowed by the consensus rules
+pub const MAX_COINBASE_SCRIPT_SIG_SIZE: usize = 100;
+
+/// Max size of the data of an OP_RETURN output relayed by the nodes
+const MAX_OP_RETURN_DATA_SIZE: usize = 80;
+
+/// Provider of extra data committed in the coinbase of the jobs created from a template, like
+/// merged mining commitments or pool tags. For a given template it must always return the same
+/// data, and never more than its declared max sizes. The outputs must have a 0 value, the
+/// template value is all paid to the pool outputs.
+pub trait CoinbaseCommitment: Debug + Send + Sync {
+    /// Outputs of 0 value placed after the pool outputs and before the outputs provided by the
+    /// node
+    fn outputs(&self, _template: &NewTemplate) -> Vec<TxOut> {
+        vec![]
+    }
+
+    /// Max serialized size of the outputs, it is accounted in CoinbaseOutputDataSize
+    fn max_outputs_size(&self) -> u32 {
+        0
+    }
+
+    /// Bytes placed in the scriptSig after the pool signature and before the extranonce
+    fn script_sig_bytes(&self, _template: &NewTemplate) -> Vec<u8> {
+        vec![]
+    }
+
+    /// Max size of the scriptSig bytes
+    fn max_script_sig_size(&self) -> usize {
+        0
+    }
+}
+
+/// Commits a tag (pool name, miner tag ecc ecc) in an OP_RETURN output of 0 value
+#[derive(Debug, Clone)]
+pub struct OpReturnTag {
+    output: TxOut,
+}
+
+impl OpReturnTag {
+    pub fn new(tag: &[u8]) -> Result<Self, Error> {
+        if tag.len() > MAX_OP_RETURN_DATA_SIZE {
+            return Err(Error::CoinbaseCommitmentTooBig);
+        }
+        let output = TxOut {
+            value: 0,
+            script_pubkey: Script::new_op_return(tag),
+        };
+        Ok(Self { output })
+    }
+}
+
+impl CoinbaseCommitment for OpReturnTag {
+    fn outputs(&self, _template: &NewTemplate) -> Vec<TxOut> {
+        vec![self.output.clone()]
+    }
+
+    fn max_outputs_size(&self) -> u32 {
+        self.output.serialize().len() as u32
+    }
+}
+
+/// Max number of bytes that the pool adds to the coinbase outputs: its own outputs plus the ones
+/// of the commitments. It is the size that must be sent to the TP with CoinbaseOutputDataSize.
+pub fn coinbase_output_max_additional_size(
+    pool_outputs: &[TxOut],
+    commitments: &[Arc<dyn CoinbaseCommitment>],
+) -> u32 {
+    let pool_outputs_size: usize = pool_outputs.iter().map(|o| o.serialize().len()).sum();
+    let commitments_size: u32 = commitments.iter().map(|c| c.max_outputs_size()).sum();
+    pool_outputs_size as u32 + commitments_size
+}
+
 #[derive(Debug)]
 pub struct JobsCreators {
     lasts_new_template: Vec<NewTemplate<'static>>,
@@ -28,26 +102,42 @@ pub struct JobsCreators {
     ids: Id,
     last_target: mining_sv2::Target,
     extranonce_len: u8,
+    coinbase_commitments: Vec<Arc<dyn CoinbaseCommitment>>,
 }
 
 /// Transform the byte array `coinbase_outputs` in a vector of TxOut
-/// It assumes the data to be valid data and does not do any kind of check
+/// It decodes outputs until all the bytes are consumed and stops at the first invalid one, use
+/// [`template_coinbase_outputs`] when the number of outputs is known
 pub fn tx_outputs_to_costum_scripts(tx_outputs: &[u8]) -> Vec<TxOut> {
     let mut txs = vec![];
-    let mut cursor = 0;
-    let mut txouts = &tx_outputs[cursor..];
-    while let Ok(out) = TxOut::consensus_decode(&mut txouts) {
-        let len = match out.script_pubkey.len() {
-            a @ 0..=252 => 8 + 1 + a,
-            a @ 253..=10000 => 8 + 3 + a,
-            _ => break,
-        };
-        cursor += len;
-        txs.push(out)
+    let mut txouts = tx_outputs;
+    while !txouts.is_empty() {
+        match TxOut::consensus_decode(&mut txouts) {
+            Ok(out) => txs.push(out),
+            Err(_) => break,
+        }
     }
     txs
 }
 
+/// Node provided coinbase outputs of a template (witness commitment, other commitments ecc ecc),
+/// they keep their value and are placed after the pool outputs. Fails if
+/// `coinbase_tx_outputs` is not made of exactly `coinbase_tx_outputs_count` outputs.
+pub fn template_coinbase_outputs(template: &NewTemplate) -> Result<Vec<TxOut>, Error> {
+    let encoded = template.coinbase_tx_outputs.to_vec();
+    let mut cursor = &encoded[..];
+    let mut outputs = Vec::with_capacity(template.coinbase_tx_outputs_count as usize);
+    for _ in 0..template.coinbase_tx_outputs_count {
+        let output = TxOut::consensus_decode(&mut cursor)
+            .map_err(|_| Error::InvalidCoinbaseOutputs(template.template_id))?;
+        outputs.push(output);
+    }
+    if !cursor.is_empty() {
+        return Err(Error::InvalidCoinbaseOutputs(template.template_id));
+    }
+    Ok(outputs)
+}
+
 impl JobsCreators {
     pub fn new(extranonce_len: u8) -> Self {
         Self {
@@ -57,9 +147,19 @@ impl JobsCreators {
             ids: Id::new(),
             last_target: mining_sv2::Target::new(0, 0),
             extranonce_len,
+            coinbase_commitments: vec![],
         }
     }
 
+    /// Registers a provider of extra coinbase data, used for the jobs created from now on
+    pub fn add_coinbase_commitment(&mut self, commitment: Arc<dyn CoinbaseCommitment>) {
+        self.coinbase_commitments.push(commitment);
+    }
+
+    pub fn coinbase_commitments(&self) -> &[Arc<dyn CoinbaseCommitment>] {
+        &self.coinbase_commitments[..]
+    }
+
     pub fn get_template_id_from_job(&self, job_id: u32) -> Option<u64> {
         self.job_to_template_id.get(&job_id).map(|x| x - 1)
     }
@@ -72,8 +172,25 @@ impl JobsCreators {
         mut pool_coinbase_outputs: Vec<TxOut>,
         pool_signature: String,
     ) -> Result<NewExtendedMiningJob<'static>, Error> {
-        let server_tx_outputs = template.coinbase_tx_outputs.to_vec();
-        let mut outputs = tx_outputs_to_costum_scripts(&server_tx_outputs);
+        let mut script_sig_bytes = pool_signature.into_bytes();
+        for commitment in &self.coinbase_commitments {
+            let mut outputs = commitment.outputs(template);
+            let outputs_size: usize = outputs.iter().map(|o| o.serialize().len()).sum();
+            let mut bytes = commitment.script_sig_bytes(template);
+            if outputs_size > commitment.max_outputs_size() as usize
+                || bytes.len() > commitment.max_script_sig_size()
+            {
+                return Err(Error::CoinbaseCommitmentTooBig);
+            }
+            // the value of the template is given to the first pool output
+            let value: u64 = outputs.iter().map(|o| o.value).sum();
+            if value != 0 {
+                return Err(Error::CoinbaseCommitmentWithValue(value));
+            }
+            pool_coinbase_outputs.append(&mut outputs);
+            script_sig_bytes.append(&mut bytes);
+        }
+        let mut outputs = template_coinbase_outputs(template)?;
         pool_coinbase_outputs.append(&mut outputs);
 
         // This is to make sure that 0 is never used, so we can use 0 for
@@ -87,7 +204,7 @@ impl JobsCreators {
         new_extended_job(
             template,
             &mut pool_coinbase_outputs,
-            pool_signature,
+            script_sig_bytes,
             next_job_id,
             version_rolling_allowed,
             self.extranonce_len,
@@ -158,7 +275,7 @@ pub fn extended_job_from_custom_job(
     new_extended_job(
         &mut template,
         &mut outputs,
-        pool_signature,
+        pool_signature.into_bytes(),
         0,
         true,
         extranonce_len,
@@ -171,13 +288,15 @@ pub fn extended_job_from_custom_job(
 /// Pool related arguments:
 ///
 /// * `coinbase_outputs`: coinbase output transactions specified by the pool.
+/// * `script_sig_bytes`: pool signature and commitments placed in the scriptSig before the
+///   extranonce.
 /// * `job_id`: incremented job identifier specified by the pool.
 /// * `version_rolling_allowed`: boolean specified by the channel.
 /// * `extranonce_len`: extranonce length specified by the channel.
 fn new_extended_job(
     new_template: &mut NewTemplate,
     coinbase_outputs: &mut [TxOut],
-    pool_signature: String,
+    script_sig_bytes: Vec<u8>,
     job_id: u32,
     version_rolling_allowed: bool,
     extranonce_len: u8,
@@ -193,7 +312,11 @@ fn new_extended_job(
         .map_err(|_| Error::TxVersionTooBig)?;
 
     let bip34_bytes = get_bip_34_bytes(new_template, tx_version)?;
-    let script_prefix_len = bip34_bytes.len() + pool_signature.as_bytes().len();
+    let script_prefix_len = bip34_bytes.len() + script_sig_bytes.len();
+    let script_sig_len = script_prefix_len + extranonce_len as usize;
+    if script_sig_len > MAX_COINBASE_SCRIPT_SIG_SIZE {
+        return Err(Error::ScriptSigTooBig(script_sig_len));
+    }
 
     let coinbase = coinbase(
         bip34_bytes,
@@ -201,7 +324,7 @@ fn new_extended_job(
         new_template.coinbase_tx_locktime,
         new_template.coinbase_tx_input_sequence,
         coinbase_outputs,
-        pool_signature,
+        &script_sig_bytes,
         extranonce_len,
     );
 
@@ -234,6 +357,23 @@ fn new_extended_job(
     Ok(new_extended_mining_job)
 }
 
+/// position of the extranonce in the serialized coinbase
+fn extranonce_index(coinbase: &Transaction, script_prefix_len: usize) -> usize {
+    // The coinbase is serialized with the segwit marker and flag only when it has a witness
+    let segwit_bytes = match coinbase.input.iter().all(|input| input.witness.is_empty()) {
+        true => 0,
+        false => 2,
+    };
+    let script_len = coinbase.input[0].script_sig.len() as u64;
+    4    // tx version
+        + segwit_bytes
+        + VarInt(coinbase.input.len() as u64).len() // number of inputs
+        + 32 // prev OutPoint
+        + 4  // index
+        + VarInt(script_len).len() // bytes in script
+        + script_prefix_len // bip34_bytes
+}
+
 /// used to extract the coinbase transaction prefix for extended jobs
 /// so the extranonce search space can be introduced
 fn coinbase_tx_prefix(
@@ -241,19 +381,7 @@ fn coinbase_tx_prefix(
     script_prefix_len: usize,
 ) -> Result<B064K<'static>, Error> {
     let encoded = coinbase.serialize();
-    // If script_prefix_len is not 0 we are not in a test environment and the coinbase will have the 0
-    // witness
-    let segwit_bytes = match script_prefix_len {
-        0 => 0,
-        _ => 2,
-    };
-    let index = 4    // tx version
-        + segwit_bytes
-        + 1  // number of inputs TODO can be also 3
-        + 32 // prev OutPoint
-        + 4  // index
-        + 1  // bytes in script TODO can be also 3
-        + script_prefix_len; // bip34_bytes
+    let index = extranonce_index(coinbase, script_prefix_len);
     let r = encoded[0..index].to_vec();
     r.try_into().map_err(Error::BinarySv2Error)
 }
@@ -266,29 +394,17 @@ fn coinbase_tx_suffix(
     script_prefix_len: usize,
 ) -> Result<B064K<'static>, Error> {
     let encoded = coinbase.serialize();
-    // If script_prefix_len is not 0 we are not in a test enviornment and the coinbase have the 0
-    // witness
-    let segwit_bytes = match script_prefix_len {
-        0 => 0,
-        _ => 2,
-    };
-    let r = encoded[4    // tx version
-        + segwit_bytes
-        + 1  // number of inputs TODO can be also 3
-        + 32 // prev OutPoint
-        + 4  // index
-        + 1  // bytes in script TODO can be also 3
-        + script_prefix_len  // bip34_bytes
-        + (extranonce_len as usize)..]
-        .to_vec();
+    let index = extranonce_index(coinbase, script_prefix_len) + extranonce_len as usize;
+    let r = encoded[index..].to_vec();
     r.try_into().map_err(Error::BinarySv2Error)
 }
 
 // Just double check if received coinbase_prefix is the right one can be removed or used only for
 // tests
 fn get_bip_34_bytes(new_template: &NewTemplate, tx_version: i32) -> Result<Vec<u8>, Error> {
+    // Old blocks used in the tests have no bip34 bytes
     #[cfg(test)]
-    if tx_version == 1 {
+    if tx_version == 1 && new_template.coinbase_prefix.inner_as_ref().is_empty() {
         return Ok(vec![]);
     };
 
@@ -320,14 +436,15 @@ fn get_bip_34_bytes(new_template: &NewTemplate, tx_version: i32) -> Result<Vec<u
 }
 
 /// coinbase_tx_input_script_prefix: extranonce prefix (script lenght + bip34 block height) provided by the node
-/// It assume that NewTemplate.coinbase_tx_outputs == 0
+/// coinbase_outputs: the pool outputs, the commitments outputs and then the outputs provided by the
+/// node
 fn coinbase(
     mut bip34_bytes: Vec<u8>,
     version: i32,
     lock_time: u32,
     sequence: u32,
     coinbase_outputs: &[TxOut],
-    pool_signature: String,
+    script_sig_bytes: &[u8],
     extranonce_len: u8,
 ) -> Transaction {
     // If script_prefix_len is not 0 we are not in a test enviornment and the coinbase have the 0
@@ -336,7 +453,7 @@ fn coinbase(
         0 => Witness::from_vec(vec![]),
         _ => Witness::from_vec(vec![vec![0; 32]]),
     };
-    bip34_bytes.extend_from_slice(pool_signature.as_bytes());
+    bip34_bytes.extend_from_slice(script_sig_bytes);
     bip34_bytes.extend_from_slice(&vec![0; extranonce_len as usize]);
     let tx_in = TxIn {
         previous_output: OutPoint::null(),
@@ -385,20 +502,22 @@ struct StrippedCoinbaseTx {
     inputs: Vec<Vec<u8>>,
     outputs: Vec<Vec<u8>>,
     lock_time: u32,
-    // helper field
+    // helper fields
     bip141_bytes_len: usize,
+    // size of the varint encoding the length of the last input's script
+    script_len_bytes: usize,
 }
 
 impl StrippedCoinbaseTx {
     /// create
     fn from_coinbase(tx: Transaction, full_extranonce_len: usize) -> Result<Self, Error> {
-        let bip141_bytes_len = tx
+        let last_script_len = tx
             .input
             .last()
             .ok_or(Error::BadPayloadSize)?
             .script_sig
-            .len()
-            - full_extranonce_len;
+            .len();
+        let bip141_bytes_len = last_script_len - full_extranonce_len;
         Ok(Self {
             version: tx.version as u32,
             inputs: tx
@@ -408,7 +527,9 @@ impl StrippedCoinbaseTx {
                     let mut ser: Vec<u8> = vec![];
                     ser.extend_from_slice(&txin.previous_output.txid);
                     ser.extend_from_slice(&txin.previous_output.vout.to_le_bytes());
-                    ser.push(txin.script_sig.len() as u8);
+                    ser.extend_from_slice(&encode::serialize(
+                        &VarInt(txin.script_sig.len() as u64),
+                    ));
                     ser.extend_from_slice(txin.script_sig.as_bytes());
                     ser.extend_from_slice(&txin.sequence.0.to_le_bytes());
                     ser
@@ -417,6 +538,7 @@ impl StrippedCoinbaseTx {
             outputs: tx.output.iter().map(|o| o.serialize()).collect(),
             lock_time: tx.lock_time.into(),
             bip141_bytes_len,
+            script_len_bytes: VarInt(last_script_len as u64).len(),
         })
     }
 
@@ -434,13 +556,13 @@ impl StrippedCoinbaseTx {
         let new_last_input_len =
         32 // outpoint
         + 4 // vout
-        + 1 // script length byte -> TODO can be also 3 (based on TODO in `coinbase_tx_prefix()`)
+        + self.script_len_bytes // script length
         + self.bip141_bytes_len // space for bip34 bytes
         ;
         last_input.truncate(new_last_input_len);
         let mut prefix: Vec<u8> = vec![];
         prefix.extend_from_slice(&self.version.to_le_bytes());
-        prefix.push(self.inputs.len() as u8);
+        prefix.extend_from_slice(&encode::serialize(&VarInt(self.inputs.len() as u64)));
         prefix.extend_from_slice(&inputs.concat());
         prefix.try_into().map_err(Error::BinarySv2Error)
     }
@@ -457,7 +579,7 @@ impl StrippedCoinbaseTx {
         // only take the last intput's sequence u32 (bytes after the extranonce space)
         let last_input_sequence = &last_input[last_input.len() - 4..];
         suffix.extend_from_slice(last_input_sequence);
-        suffix.push(self.outputs.len() as u8);
+        suffix.extend_from_slice(&encode::serialize(&VarInt(self.outputs.len() as u64)));
         suffix.extend_from_slice(&self.outputs.concat());
         suffix.extend_from_slice(&self.lock_time.to_le_bytes());
         suffix.try_into().map_err(Error::BinarySv2Error)
@@ -497,9 +619,18 @@ pub mod tests {
         });
         let coinbase_prefix: binary_sv2::B0255 = coinbase_prefix.try_into().unwrap();
 
+        // the node provides the witness commitment
         let mut coinbase_tx_outputs_gen = Gen::new(32);
+        let mut witness_commitment: vec::Vec<u8> = vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+        witness_commitment.extend((0..32).map(|_| u8::arbitrary(&mut coinbase_tx_outputs_gen)));
+        let witness_commitment = TxOut {
+            value: 0,
+            script_pubkey: witness_commitment.into(),
+        };
         let mut coinbase_tx_outputs_inner: vec::Vec<u8> = vec::Vec::new();
-        coinbase_tx_outputs_inner.resize_with(32, || u8::arbitrary(&mut coinbase_tx_outputs_gen));
+        witness_commitment
+            .consensus_encode(&mut coinbase_tx_outputs_inner)
+            .unwrap();
         let coinbase_tx_outputs: binary_sv2::B064K = coinbase_tx_outputs_inner.try_into().unwrap();
 
         let mut merkle_path_inner_gen = Gen::new(32);
@@ -516,7 +647,7 @@ pub mod tests {
             coinbase_prefix,
             coinbase_tx_input_sequence: u32::arbitrary(g),
             coinbase_tx_value_remaining: u64::arbitrary(g),
-            coinbase_tx_outputs_count: 0,
+            coinbase_tx_outputs_count: 1,
             coinbase_tx_outputs,
             coinbase_tx_locktime: u32::arbitrary(g),
             merkle_path,
@@ -536,9 +667,6 @@ pub mod tests {
         pub_k
     }
 
-    #[cfg(feature = "prop_test")]
-    use stratum_common::bitcoin::Script;
-
     // Test job_id_from_template
     #[cfg(feature = "prop_test")]
     #[quickcheck_macros::quickcheck]
@@ -712,39 +840,289 @@ pub mod tests {
             "stripped tx hash is not the same as bitcoin crate"
         );
     }
+    // Coinbase of the mainnet block 781232 mined by braiins, with a 15 bytes extranonce
+    const BRAIINS_COINBASE_PREFIX: &[u8] = &[
+        1_u8, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 75, 3, 176, 235, 11, 250, 190, 109, 109, 50,
+        247, 22, 140, 225, 176, 1, 231, 78, 225, 50, 226, 181, 165, 55, 145, 137, 154, 46, 9, 44,
+        65, 72, 231, 173, 111, 131, 26, 81, 223, 179, 225, 1, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    const BRAIINS_COINBASE_SUFFIX: &[u8] = &[
+        245_u8, 192, 42, 69, 19, 47, 115, 108, 117, 115, 104, 47, 0, 0, 0, 0, 3, 78, 213, 148, 39,
+        0, 0, 0, 0, 25, 118, 169, 20, 124, 21, 78, 209, 220, 89, 96, 158, 61, 38, 171, 178, 223,
+        46, 163, 213, 135, 205, 140, 65, 136, 172, 0, 0, 0, 0, 0, 0, 0, 0, 44, 106, 76, 41, 82, 83,
+        75, 66, 76, 79, 67, 75, 58, 214, 9, 239, 96, 221, 25, 108, 87, 155, 50, 55, 47, 91, 115,
+        172, 168, 0, 12, 86, 195, 26, 241, 10, 22, 190, 151, 254, 24, 0, 78, 106, 26, 0, 0, 0, 0,
+        0, 0, 0, 0, 38, 106, 36, 170, 33, 169, 237, 103, 66, 68, 105, 2, 55, 65, 241, 216, 46, 82,
+        223, 150, 0, 97, 103, 2, 82, 186, 233, 145, 90, 210, 231, 35, 100, 107, 52, 171, 233, 50,
+        200, 0, 0, 0, 0,
+    ];
+
     #[test]
     fn stripped_tx_id_braiins_example() {
         let mut encoded = vec![];
-        let coinbase_prefix = &[
-            1_u8, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 75, 3, 176, 235, 11, 250, 190, 109, 109,
-            50, 247, 22, 140, 225, 176, 1, 231, 78, 225, 50, 226, 181, 165, 55, 145, 137, 154, 46,
-            9, 44, 65, 72, 231, 173, 111, 131, 26, 81, 223, 179, 225, 1, 0, 0, 0, 0, 0, 0, 0,
-        ];
-        let coinbase_suffix = &[
-            245_u8, 192, 42, 69, 19, 47, 115, 108, 117, 115, 104, 47, 0, 0, 0, 0, 3, 78, 213, 148,
-            39, 0, 0, 0, 0, 25, 118, 169, 20, 124, 21, 78, 209, 220, 89, 96, 158, 61, 38, 171, 178,
-            223, 46, 163, 213, 135, 205, 140, 65, 136, 172, 0, 0, 0, 0, 0, 0, 0, 0, 44, 106, 76,
-            41, 82, 83, 75, 66, 76, 79, 67, 75, 58, 214, 9, 239, 96, 221, 25, 108, 87, 155, 50, 55,
-            47, 91, 115, 172, 168, 0, 12, 86, 195, 26, 241, 10, 22, 190, 151, 254, 24, 0, 78, 106,
-            26, 0, 0, 0, 0, 0, 0, 0, 0, 38, 106, 36, 170, 33, 169, 237, 103, 66, 68, 105, 2, 55,
-            65, 241, 216, 46, 82, 223, 150, 0, 97, 103, 2, 82, 186, 233, 145, 90, 210, 231, 35,
-            100, 107, 52, 171, 233, 50, 200, 0, 0, 0, 0,
-        ];
         let extranonce = vec![0_u8; 15]; // braiins pool requires 15 bytes for extranonce
-        encoded.extend_from_slice(coinbase_prefix);
-        let mut encoded_clone = encoded.clone();
-        encoded_clone.extend_from_slice(&extranonce[..]);
-        encoded_clone.extend_from_slice(coinbase_suffix);
-        // let mut i = 1;
-        // while let Err(_) = Transaction::deserialize(&encoded_clone) {
-        //     encoded_clone = encoded.clone();
-        //     extranonce.push(0);
-        //     encoded_clone.extend_from_slice(&extranonce[..]);
-        //     encoded_clone.extend_from_slice(coinbase_suffix);
-        //     i+=1;
-        // }
-        // println!("SIZE: {:?}", i);
-        Transaction::deserialize(&encoded_clone).unwrap();
+        encoded.extend_from_slice(BRAIINS_COINBASE_PREFIX);
+        encoded.extend_from_slice(&extranonce[..]);
+        encoded.extend_from_slice(BRAIINS_COINBASE_SUFFIX);
+        Transaction::deserialize(&encoded).unwrap();
+    }
+
+    // The outputs provided by the node (RSK commitment and witness commitment) are placed after the
+    // pool output, the resulting job must produce the same coinbase that has been mined
+    #[test]
+    fn job_from_mainnet_template_with_node_outputs() {
+        let mut encoded = BRAIINS_COINBASE_PREFIX.to_vec();
+        encoded.extend_from_slice(&[0_u8; 15]);
+        encoded.extend_from_slice(BRAIINS_COINBASE_SUFFIX);
+        let mainnet_coinbase = Transaction::deserialize(&encoded).unwrap();
+        let script_sig = mainnet_coinbase.input[0].script_sig.as_bytes();
+        // height push plus one byte, the rest of the script is used as extranonce
+        let coinbase_prefix = script_sig[..5].to_vec();
+        let extranonce = script_sig[5..].to_vec();
+
+        let mut node_outputs = vec![];
+        for output in &mainnet_coinbase.output[1..] {
+            output.consensus_encode(&mut node_outputs).unwrap();
+        }
+        let mut template = NewTemplate {
+            template_id: 1,
+            future_template: false,
+            version: 0x2000_0000,
+            coinbase_tx_version: 1,
+            coinbase_prefix: coinbase_prefix.try_into().unwrap(),
+            coinbase_tx_input_sequence: mainnet_coinbase.input[0].sequence.0,
+            coinbase_tx_value_remaining: mainnet_coinbase.output[0].value,
+            coinbase_tx_outputs_count: 2,
+            coinbase_tx_outputs: node_outputs.try_into().unwrap(),
+            coinbase_tx_locktime: 0,
+            merkle_path: vec![].into(),
+        };
+        let pool_output = TxOut {
+            value: 0,
+            script_pubkey: mainnet_coinbase.output[0].script_pubkey.clone(),
+        };
+        let mut jobs_creators = JobsCreators::new(extranonce.len() as u8);
+        let job = jobs_creators
+            .on_new_template(&mut template, false, vec![pool_output], "".to_string())
+            .unwrap();
+
+        let mut coinbase = job.coinbase_tx_prefix.to_vec();
+        coinbase.extend_from_slice(&extranonce);
+        coinbase.extend_from_slice(job.coinbase_tx_suffix.inner_as_ref());
+        let coinbase = Transaction::deserialize(&coinbase).unwrap();
+        assert_eq!(coinbase.output, mainnet_coinbase.output);
+        assert_eq!(coinbase.txid(), mainnet_coinbase.txid());
+
+        let stripped_job = extended_job_to_non_segwit(job, extranonce.len()).unwrap();
+        let mut stripped = stripped_job.coinbase_tx_prefix.to_vec();
+        stripped.extend_from_slice(&extranonce);
+        stripped.extend_from_slice(stripped_job.coinbase_tx_suffix.inner_as_ref());
+        assert_eq!(stripped, encoded);
+    }
+
+    #[test]
+    fn node_outputs_must_match_their_count() {
+        let output = TxOut {
+            value: 0,
+            script_pubkey: vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed].into(),
+        };
+        let mut encoded = vec![];
+        output.consensus_encode(&mut encoded).unwrap();
+        let mut template = template_from_gen(&mut Gen::new(255));
+        template.coinbase_tx_outputs = encoded.clone().try_into().unwrap();
+
+        template.coinbase_tx_outputs_count = 1;
+        assert_eq!(template_coinbase_outputs(&template).unwrap(), vec![output]);
+        template.coinbase_tx_outputs_count = 2;
+        assert!(template_coinbase_outputs(&template).is_err());
+        template.coinbase_tx_outputs_count = 0;
+        assert!(template_coinbase_outputs(&template).is_err());
+        encoded.push(0);
+        template.coinbase_tx_outputs = encoded.try_into().unwrap();
+        template.coinbase_tx_outputs_count = 1;
+        assert!(template_coinbase_outputs(&template).is_err());
+    }
+
+    // Lengths over 252 are encoded on 3 bytes, the scriptSig can not be that long so only the
+    // number of outputs is
+    #[test]
+    fn job_with_three_bytes_varints() {
+        let mut node_outputs = vec![];
+        for index in 0..300_u16 {
+            let output = TxOut {
+                value: index as u64,
+                script_pubkey: [vec![0x6a, 0x02], index.to_le_bytes().to_vec()]
+                    .concat()
+                    .into(),
+            };
+            output.consensus_encode(&mut node_outputs).unwrap();
+        }
+        let mut template = template_from_gen(&mut Gen::new(255));
+        template.coinbase_tx_outputs_count = 300;
+        template.coinbase_tx_outputs = node_outputs.try_into().unwrap();
+        let pool_output = TxOut {
+            value: 0,
+            script_pubkey: Script::new_p2pk(&new_pub_key()),
+        };
+        let extranonce = [7_u8; 32];
+        let mut jobs_creators = JobsCreators::new(extranonce.len() as u8);
+        let too_long_signature = "a".repeat(MAX_COINBASE_SCRIPT_SIG_SIZE);
+        assert!(matches!(
+            jobs_creators.on_new_template(
+                &mut template,
+                false,
+                vec![pool_output.clone()],
+                too_long_signature
+            ),
+            Err(Error::ScriptSigTooBig(_))
+        ));
+        let job = jobs_creators
+            .on_new_template(&mut template, false, vec![pool_output], "SRI".to_string())
+            .unwrap();
+
+        let mut encoded = job.coinbase_tx_prefix.to_vec();
+        encoded.extend_from_slice(&extranonce);
+        encoded.extend_from_slice(job.coinbase_tx_suffix.inner_as_ref());
+        let coinbase = Transaction::deserialize(&encoded).unwrap();
+        assert_eq!(coinbase.output.len(), 301);
+        assert_eq!(coinbase.output[300].value, 299);
+        assert!(coinbase.input[0]
+            .script_sig
+            .as_bytes()
+            .ends_with(&extranonce));
+
+        let stripped_job = extended_job_to_non_segwit(job, extranonce.len()).unwrap();
+        let path: &[binary_sv2::U256] = &[];
+        let stripped_merkle_root = merkle_root_from_path(
+            stripped_job.coinbase_tx_prefix.inner_as_ref(),
+            stripped_job.coinbase_tx_suffix.inner_as_ref(),
+            &extranonce,
+            path,
+        )
+        .unwrap();
+        assert_eq!(stripped_merkle_root, coinbase.txid().to_vec());
+    }
+
+    // Merged mining commitment in the scriptSig
+    #[derive(Debug)]
+    struct MergedMining;
+
+    impl CoinbaseCommitment for MergedMining {
+        fn script_sig_bytes(&self, template: &NewTemplate) -> Vec<u8> {
+            let mut bytes = vec![0xfa, 0xbe, 0x6d, 0x6d];
+            bytes.extend_from_slice(&template.template_id.to_le_bytes());
+            bytes
+        }
+
+        fn max_script_sig_size(&self) -> usize {
+            12
+        }
+    }
+
+    // Declares less than what it commits
+    #[derive(Debug)]
+    struct TooBig;
+
+    impl CoinbaseCommitment for TooBig {
+        fn outputs(&self, _template: &NewTemplate) -> Vec<TxOut> {
+            vec![TxOut {
+                value: 0,
+                script_pubkey: Script::new_op_return(&[1; 10]),
+            }]
+        }
+    }
+
+    // Pays itself from the coinbase
+    #[derive(Debug)]
+    struct WithValue;
+
+    impl CoinbaseCommitment for WithValue {
+        fn outputs(&self, _template: &NewTemplate) -> Vec<TxOut> {
+            vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new_op_return(&[1; 10]),
+            }]
+        }
+
+        fn max_outputs_size(&self) -> u32 {
+            100
+        }
+    }
+
+    #[test]
+    fn coinbase_commitments_are_added_to_the_jobs() {
+        let pool_output = TxOut {
+            value: 0,
+            script_pubkey: Script::new_p2pk(&new_pub_key()),
+        };
+        let tag = OpReturnTag::new(b"SRI Pool").unwrap();
+        let commitments: Vec<Arc<dyn CoinbaseCommitment>> =
+            vec![Arc::new(tag), Arc::new(MergedMining)];
+        let mut jobs_creators = JobsCreators::new(32);
+        for commitment in &commitments {
+            jobs_creators.add_coinbase_commitment(commitment.clone());
+        }
+        let mut template = template_from_gen(&mut Gen::new(255));
+        let job = jobs_creators
+            .on_new_template(
+                &mut template,
+                false,
+                vec![pool_output.clone()],
+                "SRI".to_string(),
+            )
+            .unwrap();
+
+        let extranonce = [0_u8; 32];
+        let mut encoded = job.coinbase_tx_prefix.to_vec();
+        encoded.extend_from_slice(&extranonce);
+        encoded.extend_from_slice(job.coinbase_tx_suffix.inner_as_ref());
+        let coinbase = Transaction::deserialize(&encoded).unwrap();
+        // pool output, tag, witness commitment provided by the node
+        assert_eq!(coinbase.output.len(), 3);
+        assert_eq!(coinbase.output[0].script_pubkey, pool_output.script_pubkey);
+        assert_eq!(
+            coinbase.output[1].script_pubkey,
+            Script::new_op_return(b"SRI Pool")
+        );
+        assert_eq!(
+            coinbase.output[2],
+            template_coinbase_outputs(&template).unwrap()[0]
+        );
+        let mut script_sig_end = b"SRI".to_vec();
+        script_sig_end.extend(MergedMining.script_sig_bytes(&template));
+        script_sig_end.extend_from_slice(&extranonce);
+        assert!(coinbase.input[0]
+            .script_sig
+            .as_bytes()
+            .ends_with(&script_sig_end));
+
+        // 8 bytes of value, 1 of script length, OP_RETURN, push and tag
+        assert_eq!(
+            coinbase_output_max_additional_size(std::slice::from_ref(&pool_output), &commitments),
+            pool_output.serialize().len() as u32 + 8 + 1 + 2 + 8
+        );
+
+        jobs_creators.add_coinbase_commitment(Arc::new(TooBig));
+        assert!(matches!(
+            jobs_creators.on_new_template(&mut template, false, vec![pool_output], "".to_string()),
+            Err(Error::CoinbaseCommitmentTooBig)
+        ));
+        assert!(OpReturnTag::new(&[0; 81]).is_err());
+    }
+
+    #[test]
+    fn coinbase_commitments_can_not_have_value() {
+        let pool_output = TxOut {
+            value: 0,
+            script_pubkey: Script::new_p2pk(&new_pub_key()),
+        };
+        let mut jobs_creators = JobsCreators::new(32);
+        jobs_creators.add_coinbase_commitment(Arc::new(WithValue));
+        let mut template = template_from_gen(&mut Gen::new(255));
+        assert!(matches!(
+            jobs_creators.on_new_template(&mut template, false, vec![pool_output], "".to_string()),
+            Err(Error::CoinbaseCommitmentWithValue(1000))
+        ));
     }
 }
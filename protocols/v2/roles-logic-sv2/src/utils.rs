@@ -1,12 +1,14 @@
 use std::{
     convert::{TryFrom, TryInto},
-    ops::{Div, Mul},
+    fs,
+    path::PathBuf,
     str::FromStr,
     sync::{Mutex as Mutex_, MutexGuard, PoisonError},
 };
 
 use binary_sv2::{Seq064K, ShortTxId, U256};
 use job_declaration_sv2::{DeclareMiningJob, SubmitSolutionJd};
+use mining_sv2::{Target, TargetError};
 use siphasher::sip::SipHasher24;
 //compact_target_from_u256
 use bitcoin::Block;
@@ -14,15 +16,17 @@ use stratum_common::{
     bitcoin,
     bitcoin::{
         blockdata::block::BlockHeader,
-        hash_types::{BlockHash, TxMerkleNode},
+        hash_types::{BlockHash, TxMerkleNode, WitnessMerkleNode, Wtxid},
         hashes::{sha256, sha256d::Hash as DHash, Hash},
         secp256k1::{All, Secp256k1},
         util::{
+            base58,
+            bip32::{DerivationPath, ExtendedPubKey},
+            hash::bitcoin_merkle_root,
             psbt::serialize::Deserialize,
-            uint::{Uint128, Uint256},
-            BitArray,
+            uint::Uint256,
         },
-        PublicKey, Script, Transaction, XOnlyPublicKey,
+        PublicKey, Script, Transaction, TxOut, XOnlyPublicKey,
     },
 };
 use tracing::error;
@@ -183,6 +187,44 @@ fn reduce_path<T: AsRef<[u8]>>(coinbase_id: [u8; 32], path: &[T]) -> [u8; 32] {
     root
 }
 
+/// Returns the merkle path of the coinbase of a block made by the coinbase followed by `txids`,
+/// that is the list of nodes that [`merkle_root_from_path_`] needs to compute the merkle root
+/// from the coinbase txid.
+pub fn merkle_path_from_txids(txids: &[bitcoin::Txid]) -> Vec<[u8; 32]> {
+    let mut path = Vec::new();
+    // the coinbase position is always the first one, its value do not affect the path so the
+    // level do not contain it
+    let mut level: Vec<[u8; 32]> = txids.iter().map(|txid| txid.into_inner()).collect();
+    while !level.is_empty() {
+        path.push(level[0]);
+        // the first pair of the next level is the one that contain the coinbase
+        let mut next_level = Vec::with_capacity(level.len() / 2);
+        for pair in level[1..].chunks(2) {
+            let right = pair.get(1).unwrap_or(&pair[0]);
+            let to_hash = [&pair[0][..], &right[..]].concat();
+            next_level.push(DHash::hash(&to_hash).into_inner());
+        }
+        level = next_level;
+    }
+    path
+}
+
+/// OP_RETURN, push 36 bytes, witness commitment header
+pub const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+/// Returns the output script of the witness commitment of a block made by a coinbase, with an all
+/// zeros witness reserved value, followed by `txs`.
+pub fn witness_commitment_script(txs: &[Transaction]) -> Script {
+    let hashes = std::iter::once(Wtxid::all_zeros().as_hash())
+        .chain(txs.iter().map(|tx| tx.wtxid().as_hash()));
+    // there is always at least the coinbase
+    let witness_root: WitnessMerkleNode = bitcoin_merkle_root(hashes).unwrap().into();
+    let commitment = Block::compute_witness_commitment(&witness_root, &[0; 32]);
+    let mut script = WITNESS_COMMITMENT_HEADER.to_vec();
+    script.extend_from_slice(&commitment[..]);
+    script.into()
+}
+
 //
 // Coinbase output construction utils
 //
@@ -252,145 +294,201 @@ impl TryFrom<CoinbaseOutput> for Script {
     }
 }
 
-#[derive(Debug)]
-pub enum InputError {
-    NegativeInput,
-    DivisionByZero,
+// BIP32 version bytes of the extended public keys, the SLIP-132 ones are mapped to them
+const XPUB_VERSION: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const TPUB_VERSION: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+// ypub, zpub, Ypub, Zpub
+const SLIP132_MAINNET_VERSIONS: [[u8; 4]; 4] = [
+    [0x04, 0x9d, 0x7c, 0xb2],
+    [0x04, 0xb2, 0x47, 0x46],
+    [0x02, 0x95, 0xb4, 0x3f],
+    [0x02, 0xaa, 0x7e, 0xd3],
+];
+// upub, vpub, Upub, Vpub
+const SLIP132_TESTNET_VERSIONS: [[u8; 4]; 4] = [
+    [0x04, 0x4a, 0x52, 0x62],
+    [0x04, 0x5f, 0x1c, 0xf6],
+    [0x02, 0x42, 0x89, 0xef],
+    [0x02, 0x57, 0x54, 0x83],
+];
+
+/// Parses a BIP32 extended public key (xpub, tpub) or one of its SLIP-132 variants (ypub, zpub,
+/// upub, vpub, ..), as exported by most wallets. The script type encoded by SLIP-132 is ignored,
+/// it is the `output_script_type` of the [`CoinbaseOutput`] that matters.
+pub fn parse_extended_pub_key(key: &str) -> Result<ExtendedPubKey, Error> {
+    let mut data =
+        base58::from_check(key).map_err(|e| Error::InvalidExtendedPubKey(e.to_string()))?;
+    if data.len() < 4 {
+        return Err(Error::InvalidExtendedPubKey(key.to_string()));
+    }
+    let mut version = [0; 4];
+    version.copy_from_slice(&data[0..4]);
+    if SLIP132_MAINNET_VERSIONS.contains(&version) {
+        data[0..4].copy_from_slice(&XPUB_VERSION);
+    } else if SLIP132_TESTNET_VERSIONS.contains(&version) {
+        data[0..4].copy_from_slice(&TPUB_VERSION);
+    }
+    ExtendedPubKey::decode(&data).map_err(|e| Error::InvalidExtendedPubKey(e.to_string()))
 }
 
-/// The pool set a target for each miner. Each target is calibrated on the hashrate of the miner.
-/// The following function takes as input a miner hashrate and the shares per minute requested by
-/// the pool. The output t is the target (in big endian) for the miner with that hashrate. The
-/// miner that mines with target t produces the requested number of shares per minute.
-///
-///
-/// If we want a speficic number of shares per minute from a miner of known hashrate,
-/// how do we set the adequate target?
-///
-/// According to [1] and [2],  it is possible to model the probability of finding a block with
-/// a random variable X whose distribution is negtive hypergeometric [3].
-/// Such a variable is characterized as follows. Say that there are n (2^256) elements (possible
-/// hash values), of which t (values <= target) are defined as success and the remaining as
-/// failures. The variable X has codomain the positive integers, and X=k is the event where element
-/// are drawn one after the other, without replacement, and only the k-th element is successful.
-/// The expected value of this variable is (n-t)/(t+1).
-/// So, on average, a miner has to perform (2^256-t)/(t+1) hashes before finding hash whose value
-/// is below the target t. If the pool wants, on average, a share every s seconds, then, on
-/// average, the miner has to perform h*s hashes before finding one that is smaller than the
-/// target, where h is the miner's hashrate. Therefore, s*h= (2^256-t)/(t+1). If we consider h the
-/// global bitcoin's hashrate, s = 600 seconds and t the bicoin global target, then, for all the
-/// blocks we tried, the two members of the equations have the same order of magnitude and, most
-/// of the cases, they coincide with the first two digits. We take this as evidence of the
-/// correctness of our calculations. Thus, if the pool wants on average a share every s
-/// seconds from a miner with hashrate h, then the target t for the miner is t = (2^256-sh)/(sh+1).
-///
-/// [1] https://papers.ssrn.com/sol3/papers.cfm?abstract_id=3399742
-/// [2] https://www.zora.uzh.ch/id/eprint/173483/1/SSRN-id3399742-2.pdf
-/// [3] https://en.wikipedia.org/wiki/Negative_hypergeometric_distribution
-/// bdiff: 0x00000000ffff0000000000000000000000000000000000000000000000000000
-/// https://en.bitcoin.it/wiki/Difficulty#How_soon_might_I_expect_to_generate_a_block.3F
-pub fn hash_rate_to_target(
-    hashrate: f64,
-    share_per_min: f64,
-) -> Result<U256<'static>, crate::Error> {
-    // checks that we are not dividing by zero
-    if share_per_min == 0.0 {
-        return Err(Error::TargetError(InputError::DivisionByZero));
+impl CoinbaseOutput {
+    /// When `output_script_value` is an extended public key, returns the output paying to the
+    /// child key at `path_template` (eg `m/0/*`), where `*` is replaced by `index`. Only the
+    /// output script types that take a public key can be derived.
+    pub fn derive(&self, path_template: &str, index: u32) -> Result<CoinbaseOutput, Error> {
+        if !path_template.contains('*') {
+            return Err(Error::InvalidDerivationPath(path_template.to_string()));
+        }
+        let path = path_template.replace('*', &index.to_string());
+        let path = DerivationPath::from_str(&path)
+            .map_err(|e| Error::InvalidDerivationPath(e.to_string()))?;
+        let xpub = parse_extended_pub_key(&self.output_script_value)?;
+        let child = xpub
+            .derive_pub(&Secp256k1::verification_only(), &path)
+            .map_err(|e| Error::InvalidDerivationPath(e.to_string()))?;
+        let output_script_value = match self.output_script_type.as_str() {
+            "TEST" | "P2PK" | "P2PKH" | "P2WPKH" => child.public_key.to_string(),
+            "P2TR" => XOnlyPublicKey::from(child.public_key).to_string(),
+            "P2SH" | "P2WSH" => return Err(Error::InvalidOutputScript),
+            _ => return Err(Error::UnknownOutputScriptType),
+        };
+        Ok(CoinbaseOutput {
+            output_script_type: self.output_script_type.clone(),
+            output_script_value,
+        })
     }
-    if share_per_min.is_sign_negative() {
-        return Err(Error::TargetError(InputError::NegativeInput));
-    };
-    if hashrate.is_sign_negative() {
-        return Err(Error::TargetError(InputError::NegativeInput));
-    };
-
-    // if we want 5 shares per minute, this means that s=60/5=12 seconds interval between shares
-    // this quantity will be at the numerator, so we multiply the result by 100 again later
-    let shares_occurrency_frequence = 60_f64 / share_per_min;
+}
 
-    let h_times_s = hashrate * shares_occurrency_frequence;
-    let h_times_s = h_times_s as u128;
+/// Coinbase outputs paying to keys derived from extended public keys, a fresh key is used for
+/// each found block. The derivation index in use is persisted in `index_file`, so that after a
+/// restart an address that already received a block reward is never reused.
+///
+/// Outputs without a derivation path template are always the same.
+#[derive(Debug)]
+pub struct CoinbaseOutputsRotation {
+    // (output, derivation path template)
+    outputs: Vec<(CoinbaseOutput, Option<String>)>,
+    index_file: PathBuf,
+    index: u32,
+}
 
-    // We calculate the denominator: h*s+1
-    // the denominator is h*s+1, where h*s is an u128, so always positive.
-    // this means that the denominator can never be zero
-    // we add 100 in place of 1 because h*s is actually h*s*100, we in order to simplify later we
-    // must calculate (h*s+1)*100
-    let h_times_s_plus_one = h_times_s + 1;
+impl CoinbaseOutputsRotation {
+    /// Loads the derivation index from `index_file`, a missing file means that no key has been
+    /// used yet.
+    pub fn new(
+        outputs: Vec<(CoinbaseOutput, Option<String>)>,
+        index_file: PathBuf,
+    ) -> Result<Self, Error> {
+        let index = match fs::read_to_string(&index_file) {
+            Ok(content) => content
+                .trim()
+                .parse()
+                .map_err(|_| Error::DerivationIndexError(format!("{:?}", index_file)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(Error::DerivationIndexError(e.to_string())),
+        };
+        let rotation = Self {
+            outputs,
+            index_file,
+            index,
+        };
+        // fail early on an invalid key or path
+        rotation.outputs()?;
+        Ok(rotation)
+    }
 
-    let h_times_s_plus_one: Uint256 = from_u128_to_uint256(h_times_s_plus_one);
-    let denominator = h_times_s_plus_one;
+    /// Rotation of the coinbase outputs of a role config, None when no output has a derivation
+    /// path template. `index_file` is required otherwise.
+    pub fn from_config(
+        outputs: Vec<(CoinbaseOutput, Option<String>)>,
+        index_file: Option<PathBuf>,
+    ) -> Result<Option<Self>, Error> {
+        if outputs
+            .iter()
+            .all(|(_, path_template)| path_template.is_none())
+        {
+            return Ok(None);
+        }
+        let index_file = index_file.ok_or_else(|| {
+            Error::DerivationIndexError(
+                "coinbase_derivation_index_file is required with a derivation_path".to_string(),
+            )
+        })?;
+        Self::new(outputs, index_file).map(Some)
+    }
 
-    // We calculate the numerator: 2^256-sh
-    let two_to_256_minus_one = [255_u8; 32];
-    let two_to_256_minus_one = bitcoin::util::uint::Uint256::from_be_bytes(two_to_256_minus_one);
+    pub fn index(&self) -> u32 {
+        self.index
+    }
 
-    let mut h_times_s_array = [0u8; 32];
-    h_times_s_array[16..].copy_from_slice(&h_times_s.to_be_bytes());
-    let numerator =
-        two_to_256_minus_one - bitcoin::util::uint::Uint256::from_be_bytes(h_times_s_array);
+    /// Coinbase outputs for the current derivation index
+    pub fn outputs(&self) -> Result<Vec<TxOut>, Error> {
+        let mut result = Vec::with_capacity(self.outputs.len());
+        for (output, path_template) in &self.outputs {
+            let output = match path_template {
+                Some(path_template) => output.derive(path_template, self.index)?,
+                None => output.clone(),
+            };
+            result.push(TxOut {
+                value: 0,
+                script_pubkey: output.try_into()?,
+            });
+        }
+        Ok(result)
+    }
 
-    let mut target = numerator.div(denominator).to_be_bytes();
-    target.reverse();
-    Ok(U256::<'static>::from(target))
+    /// To be called when a block paying to the current outputs has been found. The next index is
+    /// persisted before being used and the new outputs are returned.
+    pub fn rotate(&mut self) -> Result<Vec<TxOut>, Error> {
+        let next = self
+            .index
+            .checked_add(1)
+            .ok_or_else(|| Error::DerivationIndexError("index overflow".to_string()))?;
+        // write and rename so that a crash never leaves a truncated file
+        let tmp_file = self.index_file.with_extension("tmp");
+        fs::write(&tmp_file, next.to_string())
+            .and_then(|_| fs::rename(&tmp_file, &self.index_file))
+            .map_err(|e| Error::DerivationIndexError(e.to_string()))?;
+        self.index = next;
+        self.outputs()
+    }
 }
 
-/// this function utilizes the equation used in [`hash_rate_to_target`], but
-/// translated to solve for hash_rate given a target: h = (2^256-t)/s(t+1)
-/// where s is seconds_between_two_consecutive_shares and t is target
-pub fn hash_rate_from_target(target: U256<'static>, share_per_min: f64) -> Result<f64, Error> {
-    // checks that we are not dividing by zero
-    if share_per_min == 0.0 {
-        return Err(Error::HashrateError(InputError::DivisionByZero));
-    }
-    if share_per_min.is_sign_negative() {
-        return Err(Error::HashrateError(InputError::NegativeInput));
-    }
+/// Errors of the hashrate and target conversions, the ones of [`Target`]
+#[deprecated(note = "use mining_sv2::TargetError")]
+pub type InputError = TargetError;
 
-    let mut target_arr: [u8; 32] = [0; 32];
-    target_arr.as_mut().copy_from_slice(target.inner_as_ref());
-    target_arr.reverse();
-    let target = Uint256::from_be_bytes(target_arr);
-
-    // we calculate the numerator 2^256-t
-    // note that [255_u8,;32] actually is 2^256 -1, but 2^256 -t = (2^256-1) - (t-1)
-    let max_target = [255_u8; 32];
-    let max_target = Uint256::from_be_bytes(max_target);
-    let numerator = max_target - (target - Uint256::one());
-
-    // now we calcualte the denominator s(t+1)
-    // *100 here to move the fractional bit up so we can make this an int later
-    let shares_occurrency_frequence = 60_f64 / (share_per_min) * 100.0;
-    // note that t+1 cannot be zero because t unsigned. Therefore the denominator is zero if and
-    // only if s is zero.
-    let shares_occurrency_frequence = shares_occurrency_frequence as u128;
-    if shares_occurrency_frequence == 0_u128 {
-        return Err(Error::HashrateError(InputError::DivisionByZero));
-    }
-    let shares_occurrency_frequence = u128_as_u256(shares_occurrency_frequence);
-    let mut target_plus_one = Uint256::from_be_bytes(target_arr);
-    target_plus_one.increment();
-    let denominator = shares_occurrency_frequence
-        .mul(target_plus_one)
-        .div(Uint256::from_u64(100).unwrap());
-
-    let result = from_uint128_to_u128(numerator.div(denominator).low_128());
-    // we multiply back by 100 so that it cancels with the same factor at the denominator
-    Ok(result as f64)
+/// Target (in little endian) of a miner with `hashrate` that produces `share_per_min` shares
+#[deprecated(note = "use mining_sv2::Target::from_hashrate")]
+pub fn hash_rate_to_target(hashrate: f64, share_per_min: f64) -> Result<U256<'static>, Error> {
+    Target::from_hashrate(hashrate, share_per_min)
+        .map(Into::into)
+        .map_err(Error::TargetError)
 }
 
-fn from_uint128_to_u128(input: Uint128) -> u128 {
-    let input = input.to_be_bytes();
-    u128::from_be_bytes(input)
+/// Hashrate of a miner that produces `share_per_min` shares with `target`
+#[deprecated(note = "use mining_sv2::Target::hashrate")]
+pub fn hash_rate_from_target(target: U256<'static>, share_per_min: f64) -> Result<f64, Error> {
+    Target::from(target)
+        .hashrate(share_per_min)
+        .map_err(Error::HashrateError)
 }
 
+#[deprecated(note = "use mining_sv2::Target::from")]
 pub fn from_u128_to_uint256(input: u128) -> Uint256 {
-    let input: [u8; 16] = input.to_be_bytes();
-    let mut be_bytes = [0_u8; 32];
-    for (i, b) in input.iter().enumerate() {
-        be_bytes[16 + i] = *b;
-    }
-    Uint256::from_be_bytes(be_bytes)
+    let mut be_bytes = U256::from(Target::from(input)).to_vec();
+    be_bytes.reverse();
+    // 32 bytes
+    Uint256::from_be_slice(&be_bytes).unwrap()
+}
+
+/// Target (in little endian) of a miner with `hash_per_second` that produces `share_per_min`
+/// shares, panics on invalid inputs
+#[deprecated(note = "use mining_sv2::Target::from_hashrate")]
+pub fn target_from_hash_rate(hash_per_second: f32, share_per_min: f32) -> U256<'static> {
+    Target::from_hashrate(hash_per_second as f64, share_per_min as f64)
+        .expect("Invalid hashrate or shares per minute")
+        .into()
 }
 
 /// Used to package multiple SV2 channels into a single group.
@@ -638,27 +736,6 @@ pub(crate) fn new_header_hash<'decoder>(header: BlockHeader) -> U256<'decoder> {
     hash.try_into().unwrap()
 }
 
-fn u128_as_u256(v: u128) -> Uint256 {
-    let u128_min = [0_u8; 16];
-    let u128_b = v.to_be_bytes();
-    let u256 = [&u128_min[..], &u128_b[..]].concat();
-    // below never panic
-    Uint256::from_be_slice(&u256).unwrap()
-}
-
-/// target = u256_max * (shar_per_min / 60) * (2^32 / hash_per_second)
-/// target = u128_max * ((shar_per_min / 60) * (2^32 / hash_per_second) * u128_max)
-pub fn target_from_hash_rate(hash_per_second: f32, share_per_min: f32) -> U256<'static> {
-    assert!(hash_per_second >= 1000000000.0);
-    let operand = (share_per_min as f64 / 60.0) * (u32::MAX as f64 / hash_per_second as f64);
-    assert!(operand <= 1.0);
-    let operand = operand * (u128::MAX as f64);
-    let target = u128_as_u256(u128::MAX) * u128_as_u256(operand as u128);
-    let mut target: [u8; 32] = target.to_be_bytes();
-    target.reverse();
-    target.into()
-}
-
 #[cfg_attr(feature = "cargo-clippy", allow(clippy::too_many_arguments))]
 pub fn get_target(
     nonce: u32,
@@ -729,7 +806,8 @@ pub fn get_short_hash(txid: bitcoin::Txid, tx_short_hash_nonce: u64) -> ShortTxI
     short_tx_id
 }
 
-fn tx_hash_list_hash_builder(txid_list: Vec<bitcoin::Txid>) -> U256<'static> {
+/// Returns the `tx_hash_list_hash` of a `DeclareMiningJob`, the sha256 of the concatenated txids
+pub fn tx_hash_list_hash_builder(txid_list: Vec<bitcoin::Txid>) -> U256<'static> {
     // TODO: understand if this field is redunant and to be deleted since
     // the full coinbase is known
     let mut vec_u8 = vec![];
@@ -811,9 +889,9 @@ impl<'a> From<BlockCreator<'a>> for bitcoin::Block {
 mod tests {
     #[cfg(feature = "serde")]
     use super::*;
-    use super::{hash_rate_from_target, hash_rate_to_target};
     #[cfg(feature = "serde")]
     use binary_sv2::{Seq0255, B064K, U256};
+    use mining_sv2::Target;
     use rand::Rng;
     #[cfg(feature = "serde")]
     use serde::Deserialize;
@@ -952,6 +1030,80 @@ mod tests {
         assert_eq!(expect, actual);
     }
 
+    #[test]
+    fn test_merkle_path_from_txids() {
+        use super::{merkle_path_from_txids, merkle_root_from_path_};
+        use bitcoin::{hashes::Hash, util::hash::bitcoin_merkle_root, Txid};
+
+        let mut rng = rand::thread_rng();
+        for txs_number in 0..20 {
+            let coinbase_id = Txid::from_inner(rng.gen());
+            let txids: Vec<Txid> = (0..txs_number)
+                .map(|_| Txid::from_inner(rng.gen()))
+                .collect();
+            let expected = bitcoin_merkle_root(std::iter::once(coinbase_id).chain(txids.clone()))
+                .unwrap()
+                .into_inner();
+            let path = merkle_path_from_txids(&txids);
+            assert_eq!(
+                merkle_root_from_path_(coinbase_id.into_inner(), &path),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_witness_commitment_script() {
+        use super::witness_commitment_script;
+        use bitcoin::{
+            blockdata::block::BlockHeader, hash_types::TxMerkleNode, hashes::Hash, OutPoint,
+            PackedLockTime, Sequence, Transaction, TxIn, TxOut, Witness,
+        };
+
+        let mut rng = rand::thread_rng();
+        let segwit_tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: vec![].into(),
+                sequence: Sequence(0),
+                witness: Witness::from_vec(vec![(0..72).map(|_| rng.gen()).collect()]),
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: vec![0; 22].into(),
+            }],
+        };
+        let txs = vec![segwit_tx];
+        let coinbase = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: vec![3, 1, 0, 0].into(),
+                sequence: Sequence(0),
+                witness: Witness::from_vec(vec![vec![0; 32]]),
+            }],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: witness_commitment_script(&txs),
+            }],
+        };
+        let block = bitcoin::Block {
+            header: BlockHeader {
+                version: 2,
+                prev_blockhash: Hash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata: [vec![coinbase], txs].concat(),
+        };
+        assert!(block.check_witness_commitment());
+    }
+
     #[test]
     #[cfg(feature = "serde")]
     fn gets_new_header() -> Result<(), Error> {
@@ -1024,9 +1176,7 @@ mod tests {
 
         let hr = 10.0; // 10 h/s
         let hrs = hr * 60.0; // number of hashes in 1 minute
-        let mut target = hash_rate_to_target(hr, 1.0).unwrap().to_vec();
-        target.reverse();
-        let target = bitcoin::util::uint::Uint256::from_be_slice(&target[..]).unwrap();
+        let target = Target::from_hashrate(hr, 1.0).unwrap();
 
         let mut i: i64 = 0;
         let mut results = vec![];
@@ -1034,11 +1184,8 @@ mod tests {
         while successes < attempts {
             let a: u128 = rng.gen();
             let b: u128 = rng.gen();
-            let a = a.to_be_bytes();
-            let b = b.to_be_bytes();
-            let concat = [&a[..], &b[..]].concat().to_vec();
             i += 1;
-            if bitcoin::util::uint::Uint256::from_be_slice(&concat[..]).unwrap() <= target {
+            if Target::new(a, b) <= target {
                 results.push(i);
                 i = 0;
                 successes += 1;
@@ -1055,17 +1202,84 @@ mod tests {
 
     #[test]
     fn test_hash_rate_from_target() {
+        // the hashes per share 60*hr/spm are an integer, the target is then the exact inverse
+        for hr in [1.0, 10.0, 202470.0, 1e12, 1e15] {
+            let expected_share_per_min = 1.0;
+            let target = Target::from_hashrate(hr, expected_share_per_min).unwrap();
+            let realized_share_per_min = expected_share_per_min * 10.0; // increase SPM by 10x
+            let hash_rate = target.hashrate(realized_share_per_min).unwrap();
+            assert_eq!(
+                hash_rate,
+                hr * 10.0,
+                "Target::hashrate is not the inverse of Target::from_hashrate"
+            );
+        }
+
+        // otherwise the hashes per share are floored, here from 12148249.68 to 12148249, and the
+        // hashrate is lower by less than one hash per share
         let hr = 202470.828;
-        let expected_share_per_min = 1.0;
-        let target = hash_rate_to_target(hr, expected_share_per_min).unwrap();
-        let realized_share_per_min = expected_share_per_min * 10.0; // increase SPM by 10x
-        let hash_rate = hash_rate_from_target(target.clone(), realized_share_per_min).unwrap();
-        let new_hr = (hr * 10.0).trunc();
-
-        assert!(
-            hash_rate == new_hr,
-            "hash_rate_from_target equation was not properly transformed"
-        )
+        let target = Target::from_hashrate(hr, 1.0).unwrap();
+        let hash_rate = target.hashrate(10.0).unwrap();
+        assert_eq!(hash_rate, 12148249.0 * 10.0 / 60.0);
+        assert!(hr * 10.0 - hash_rate < 10.0 / 60.0);
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn test_target_from_compact_matches_bitcoin(bits: u32) -> bool {
+        // bitcoin reads the sign bit as part of the mantissa of the small targets
+        if bits & 0x0080_0000 != 0 {
+            return true;
+        }
+        match Target::from_compact(bits) {
+            Ok(target) => {
+                let mut expected =
+                    bitcoin::blockdata::block::BlockHeader::u256_from_compact_target(bits)
+                        .to_be_bytes();
+                expected.reverse();
+                target == Target::from(expected)
+            }
+            Err(_) => true,
+        }
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn test_target_to_compact_matches_bitcoin(input: (u128, u128, u8)) -> bool {
+        let mut be_bytes = [0_u8; 32];
+        be_bytes[..16].copy_from_slice(&input.1.to_be_bytes());
+        be_bytes[16..].copy_from_slice(&input.0.to_be_bytes());
+        let value = bitcoin::util::uint::Uint256::from_be_bytes(be_bytes) >> input.2 as usize;
+        let mut le_bytes = value.to_be_bytes();
+        le_bytes.reverse();
+        Target::from(le_bytes).to_compact()
+            == bitcoin::blockdata::block::BlockHeader::compact_target_from_u256(&value)
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_conversions_use_target() {
+        use super::{
+            from_u128_to_uint256, hash_rate_from_target, hash_rate_to_target,
+            target_from_hash_rate, InputError,
+        };
+        let expected = Target::from_hashrate(1e12, 6.0).unwrap();
+        let target = hash_rate_to_target(1e12, 6.0).unwrap();
+        assert_eq!(Target::from(target.clone()), expected);
+        assert_eq!(
+            hash_rate_from_target(target, 6.0).unwrap(),
+            expected.hashrate(6.0).unwrap()
+        );
+        assert_eq!(
+            Target::from(target_from_hash_rate(2f32.powi(40), 6.0)),
+            Target::from_hashrate(2f64.powi(40), 6.0).unwrap()
+        );
+        assert!(matches!(
+            hash_rate_to_target(1e12, 0.0),
+            Err(crate::Error::TargetError(InputError::DivisionByZero))
+        ));
+        assert_eq!(
+            from_u128_to_uint256(u128::MAX),
+            bitcoin::util::uint::Uint256([u64::MAX, u64::MAX, 0, 0])
+        );
     }
 
     #[test]
@@ -1075,4 +1289,97 @@ mod tests {
         // m.super_safe_lock(|i| *i = (*i).checked_add(1).unwrap()); // will not compile
         m.super_safe_lock(|i| *i = (*i).checked_add(1).unwrap_or_default()); // compiles
     }
+
+    // BIP32 test vector 2
+    const MASTER_XPUB: &str = "xpub661MyMwAqRbcFW31YEwpkMuc5THy2PSt5bDMsktWQcFF8syAmRUapSCGu8ED9W6oDMSgv6Zz8idoc4a6mr8BDzTJY47LJhkJ8UB7WEGuduB";
+    const CHILD_0_XPUB: &str = "xpub69H7F5d8KSRgmmdJg2KhpAK8SR3DjMwAdkxj3ZuxV27CprR9LgpeyGmXUbC6wb7ERfvrnKZjXoUmmDznezpbZb7ap6r1D3tgFxHmwMkQTPH";
+
+    fn xpub_output(output_script_type: &str, xpub: &str) -> super::CoinbaseOutput {
+        super::CoinbaseOutput {
+            output_script_type: output_script_type.to_string(),
+            output_script_value: xpub.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_derive_coinbase_output() {
+        use bitcoin::util::{base58, bip32::ExtendedPubKey};
+        use std::str::FromStr;
+
+        let child = ExtendedPubKey::from_str(CHILD_0_XPUB).unwrap();
+        let derived = xpub_output("P2WPKH", MASTER_XPUB).derive("m/*", 0).unwrap();
+        assert_eq!(derived.output_script_type, "P2WPKH");
+        assert_eq!(derived.output_script_value, child.public_key.to_string());
+
+        // the same key exported as zpub
+        let mut data = base58::from_check(MASTER_XPUB).unwrap();
+        data[0..4].copy_from_slice(&[0x04, 0xb2, 0x47, 0x46]);
+        let zpub = base58::check_encode_slice(&data);
+        assert!(zpub.starts_with("zpub"));
+        let derived_from_zpub = xpub_output("P2WPKH", &zpub).derive("m/*", 0).unwrap();
+        assert_eq!(
+            derived_from_zpub.output_script_value,
+            derived.output_script_value
+        );
+
+        let taproot = xpub_output("P2TR", MASTER_XPUB).derive("m/*", 0).unwrap();
+        let _: bitcoin::Script = std::convert::TryInto::try_into(taproot).unwrap();
+        assert!(xpub_output("P2WSH", MASTER_XPUB).derive("m/*", 0).is_err());
+        assert!(xpub_output("P2WPKH", MASTER_XPUB).derive("m/0", 0).is_err());
+        assert!(xpub_output("P2WPKH", MASTER_XPUB)
+            .derive("m/*'", 0)
+            .is_err());
+        assert!(xpub_output("P2WPKH", "not a key").derive("m/*", 0).is_err());
+    }
+
+    #[test]
+    fn test_coinbase_outputs_rotation() {
+        let index_file = std::env::temp_dir().join(format!(
+            "coinbase_derivation_index_{}",
+            rand::thread_rng().gen::<u64>()
+        ));
+        let outputs = vec![(
+            xpub_output("P2WPKH", MASTER_XPUB),
+            Some("m/0/*".to_string()),
+        )];
+
+        let mut rotation =
+            super::CoinbaseOutputsRotation::new(outputs.clone(), index_file.clone()).unwrap();
+        assert_eq!(rotation.index(), 0);
+        let first = rotation.outputs().unwrap();
+        let second = rotation.rotate().unwrap();
+        assert_eq!(rotation.index(), 1);
+        assert_ne!(first, second);
+        assert_eq!(std::fs::read_to_string(&index_file).unwrap(), "1");
+
+        // after a restart the last index is used again, never a previous one
+        let rotation = super::CoinbaseOutputsRotation::new(outputs, index_file.clone()).unwrap();
+        assert_eq!(rotation.index(), 1);
+        assert_eq!(rotation.outputs().unwrap(), second);
+        std::fs::remove_file(index_file).unwrap();
+    }
+
+    #[test]
+    fn test_coinbase_outputs_rotation_from_config() {
+        let fixed = vec![(xpub_output("P2WPKH", MASTER_XPUB), None)];
+        assert!(super::CoinbaseOutputsRotation::from_config(fixed, None)
+            .unwrap()
+            .is_none());
+        let derived = vec![(
+            xpub_output("P2WPKH", MASTER_XPUB),
+            Some("m/0/*".to_string()),
+        )];
+        assert!(matches!(
+            super::CoinbaseOutputsRotation::from_config(derived.clone(), None),
+            Err(crate::Error::DerivationIndexError(_))
+        ));
+        let index_file = std::env::temp_dir().join(format!(
+            "coinbase_derivation_index_{}",
+            rand::thread_rng().gen::<u64>()
+        ));
+        let rotation = super::CoinbaseOutputsRotation::from_config(derived, Some(index_file))
+            .unwrap()
+            .unwrap();
+        assert_eq!(rotation.index(), 0);
+    }
 }
@@ -0,0 +1,697 @@
+use binary_sv2::U256;
+use core::{
+    cmp::{Ord, PartialOrd},
+    convert::TryInto,
+};
+
+// 2^128 as a float, u128::MAX rounds up to it
+const TWO_POW_128: f64 = u128::MAX as f64;
+
+/// Target of pool difficulty 1: 0x00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff
+const POOL_DIFFICULTY_1: Target = Target {
+    head: u128::MAX,
+    tail: u128::MAX >> 32,
+};
+
+/// Error of the conversions from and to a [`Target`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetError {
+    NegativeInput,
+    DivisionByZero,
+    /// The input is NaN or infinite
+    NotFinite,
+    /// The result does not fit in 256 bits
+    Overflow,
+}
+
+/// Target is a 256-bit unsigned integer in little-endian
+///
+/// All the arithmetic is done on integers, floats are only used at the boundaries: the hashrate
+/// and the difficulty inputs are decomposed in their exact mantissa and exponent, and the
+/// outputs are converted to float as the very last step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    head: u128, // least significant bits
+    tail: u128, // most significant bits
+}
+
+impl Target {
+    pub const ZERO: Target = Target { head: 0, tail: 0 };
+    pub const MAX: Target = Target {
+        head: u128::MAX,
+        tail: u128::MAX,
+    };
+
+    pub fn new(head: u128, tail: u128) -> Self {
+        Self { head, tail }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.head == 0 && self.tail == 0
+    }
+
+    /// Returns None when the product does not fit in 256 bits
+    pub fn checked_mul(&self, factor: u64) -> Option<Self> {
+        let mut limbs = self.to_limbs();
+        let mut carry: u128 = 0;
+        for limb in limbs.iter_mut() {
+            let product = (*limb as u128) * (factor as u128) + carry;
+            *limb = product as u64;
+            carry = product >> 64;
+        }
+        match carry {
+            0 => Some(Self::from_limbs(limbs)),
+            _ => None,
+        }
+    }
+
+    pub fn saturating_mul(&self, factor: u64) -> Self {
+        self.checked_mul(factor).unwrap_or(Self::MAX)
+    }
+
+    /// Returns None when the divisor is 0
+    pub fn checked_div(&self, divisor: u64) -> Option<Self> {
+        match divisor {
+            0 => None,
+            // the quotient is not bigger than self so it never overflows
+            _ => self.shl_div(0, divisor),
+        }
+    }
+
+    /// Target of a miner that produces `shares_per_minute` shares with `hashrate` (h/s).
+    ///
+    /// Each hash is below the target t with probability (t+1)/2^256, so on average a miner
+    /// performs 2^256/(t+1) hashes per share. If the pool wants a share every s seconds from a
+    /// miner with hashrate h then s*h = 2^256/(t+1), so the target is t = 2^256/(s*h) - 1. s*h is
+    /// floored to an integer, then the division is exact.
+    pub fn from_hashrate(hashrate: f64, shares_per_minute: f64) -> Result<Self, TargetError> {
+        let (hashrate_mantissa, hashrate_exp) = decompose(hashrate)?;
+        let (spm_mantissa, spm_exp) = decompose(shares_per_minute)?;
+        if spm_mantissa == 0 {
+            return Err(TargetError::DivisionByZero);
+        }
+        // s*h = 60*h/spm
+        let hashes = Self::from(60 * hashrate_mantissa as u128)
+            .shl_div(hashrate_exp - spm_exp, spm_mantissa);
+        match hashes {
+            Some(hashes) => Ok(Self::from_hashes_per_share(&hashes)),
+            // 2^256 or more hashes per share, even the hash 0 would be too often
+            None => Ok(Self::ZERO),
+        }
+    }
+
+    /// Hashrate (h/s) of a miner that produces `shares_per_minute` shares with this target, it
+    /// is the inverse of [`Target::from_hashrate`]: h = 2^256/(s*(t+1)).
+    pub fn hashrate(&self, shares_per_minute: f64) -> Result<f64, TargetError> {
+        let (spm_mantissa, _) = decompose(shares_per_minute)?;
+        if spm_mantissa == 0 {
+            return Err(TargetError::DivisionByZero);
+        }
+        Ok(self.expected_hashes() * shares_per_minute / 60.0)
+    }
+
+    /// Target that is met on average once every `hashes` hashes: 2^256/hashes - 1, MAX when
+    /// less than 2 hashes are asked
+    pub fn from_hashes_per_share(hashes: &Target) -> Self {
+        match Self::two_pow_256_div_rem(hashes) {
+            // 2^256 / hashes >= 1 as hashes < 2^256
+            Some((quotient, _)) => quotient.wrapping_sub(&Self::from(1)),
+            None => Self::MAX,
+        }
+    }
+
+    /// Number of hashes (floored) needed on average to find one below the target: 2^256/(t+1)
+    pub fn hashes_per_share(&self) -> Self {
+        match self.checked_add(&Self::from(1)) {
+            // saturated when the target is 0
+            Some(denominator) => Self::two_pow_256_div_rem(&denominator)
+                .map(|(quotient, _)| quotient)
+                .unwrap_or(Self::MAX),
+            None => Self::from(1),
+        }
+    }
+
+    /// Target of a pool difficulty, difficulty 1 is
+    /// 0x00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff
+    pub fn from_pool_difficulty(difficulty: f64) -> Result<Self, TargetError> {
+        let (mantissa, exp) = decompose(difficulty)?;
+        if mantissa == 0 {
+            return Err(TargetError::DivisionByZero);
+        }
+        // difficulties smaller than 1 / 2^32 have targets too big for 256 bits
+        Ok(POOL_DIFFICULTY_1
+            .shl_div(-exp, mantissa)
+            .unwrap_or(Self::MAX))
+    }
+
+    /// Pool difficulty of the target, infinite when the target is 0
+    pub fn pool_difficulty(&self) -> f64 {
+        if self.is_zero() {
+            return f64::INFINITY;
+        }
+        let (quotient, remainder) = POOL_DIFFICULTY_1.div_rem(self);
+        quotient.to_f64() + remainder.to_f64() / self.to_f64()
+    }
+
+    /// Target of the compact representation used in the block header nBits, as decoded by
+    /// bitcoind
+    pub fn from_compact(bits: u32) -> Result<Self, TargetError> {
+        let size = bits >> 24;
+        let mut word = bits & 0x007f_ffff;
+        if word != 0 && bits & 0x0080_0000 != 0 {
+            return Err(TargetError::NegativeInput);
+        }
+        if word != 0 && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32)) {
+            return Err(TargetError::Overflow);
+        }
+        if size <= 3 {
+            word >>= 8 * (3 - size);
+            Ok(Self::from(word as u128))
+        } else {
+            // above checks ensure that no bit of word is shifted out
+            Ok(Self::from(word as u128).shl(8 * (size - 3)))
+        }
+    }
+
+    /// Compact representation of the target, the mantissa keeps the 23 most significant bits
+    pub fn to_compact(&self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut compact = if size <= 3 {
+            (self.head as u32) << (8 * (3 - size))
+        } else {
+            self.shr(8 * (size - 3)).head as u32
+        };
+        // the sign bit is set, move the mantissa one byte down
+        if compact & 0x0080_0000 != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+        compact | (size << 24)
+    }
+
+    /// (floor(2^256 / divisor), 2^256 % divisor), None when the quotient does not fit in 256
+    /// bits, that is when the divisor is 0 or 1
+    fn two_pow_256_div_rem(divisor: &Target) -> Option<(Self, Self)> {
+        if *divisor <= Self::from(1) {
+            return None;
+        }
+        // 2^256 = MAX + 1, the remainder of MAX is smaller than the divisor so it can be
+        // incremented
+        let (quotient, remainder) = Self::MAX.div_rem(divisor);
+        let remainder = remainder.checked_add(&Self::from(1))?;
+        match remainder == *divisor {
+            // divisor >= 2 so the quotient is at most 2^255
+            true => Some((quotient.checked_add(&Self::from(1))?, Self::ZERO)),
+            false => Some((quotient, remainder)),
+        }
+    }
+
+    /// Exact 2^256/(t+1) as float
+    fn expected_hashes(&self) -> f64 {
+        let denominator = match self.checked_add(&Self::from(1)) {
+            Some(denominator) => denominator,
+            // every hash is a share
+            None => return 1.0,
+        };
+        match Self::two_pow_256_div_rem(&denominator) {
+            Some((quotient, remainder)) => {
+                quotient.to_f64() + remainder.to_f64() / denominator.to_f64()
+            }
+            // the target is 0
+            None => TWO_POW_128 * TWO_POW_128,
+        }
+    }
+
+    /// floor(self * 2^shift / divisor), None when the result does not fit in 256 bits. The
+    /// divisor must not be 0.
+    fn shl_div(&self, shift: i32, divisor: u64) -> Option<Self> {
+        debug_assert!(divisor != 0);
+        if self.is_zero() {
+            return Some(Self::ZERO);
+        }
+        // floor(floor(a / 2^k) / d) == floor(a / (2^k * d))
+        let (dividend, shift) = match shift {
+            s if s < 0 => (self.shr(s.unsigned_abs().min(256)), 0),
+            s => (self.clone(), s as u32),
+        };
+        // a >= 1 and d < 2^64 so the result is at least 2^(shift - 64)
+        if shift >= 320 {
+            return None;
+        }
+        let divisor = divisor as u128;
+        let mut quotient = Self::ZERO;
+        let mut remainder: u128 = 0;
+        for i in (0..256 + shift).rev() {
+            let bit = i >= shift && dividend.bit(i - shift);
+            // remainder < divisor < 2^64 so this never overflows
+            remainder = (remainder << 1) | bit as u128;
+            if quotient.shl1() {
+                return None;
+            }
+            if remainder >= divisor {
+                remainder -= divisor;
+                quotient.head |= 1;
+            }
+        }
+        Some(quotient)
+    }
+
+    /// Long division, the divisor must not be 0
+    fn div_rem(&self, divisor: &Target) -> (Self, Self) {
+        debug_assert!(!divisor.is_zero());
+        let mut quotient = Self::ZERO;
+        let mut remainder = Self::ZERO;
+        for i in (0..256).rev() {
+            // when the remainder overflows it is bigger than any divisor, the wrapping sub then
+            // gives the right value
+            let carry = remainder.shl1();
+            remainder.head |= self.bit(i) as u128;
+            if carry || remainder >= *divisor {
+                remainder = remainder.wrapping_sub(divisor);
+                quotient.set_bit(i);
+            }
+        }
+        (quotient, remainder)
+    }
+
+    fn checked_add(&self, other: &Target) -> Option<Self> {
+        let (head, carry) = self.head.overflowing_add(other.head);
+        let tail = self.tail.checked_add(other.tail)?;
+        let tail = tail.checked_add(carry as u128)?;
+        Some(Self { head, tail })
+    }
+
+    fn wrapping_sub(&self, other: &Target) -> Self {
+        let (head, borrow) = self.head.overflowing_sub(other.head);
+        let tail = self
+            .tail
+            .wrapping_sub(other.tail)
+            .wrapping_sub(borrow as u128);
+        Self { head, tail }
+    }
+
+    /// Shifts left by one bit, returns the bit shifted out
+    fn shl1(&mut self) -> bool {
+        let carry = self.tail >> 127 == 1;
+        self.tail = (self.tail << 1) | (self.head >> 127);
+        self.head <<= 1;
+        carry
+    }
+
+    fn shl(&self, shift: u32) -> Self {
+        match shift {
+            0 => self.clone(),
+            s if s < 128 => Self {
+                head: self.head << s,
+                tail: (self.tail << s) | (self.head >> (128 - s)),
+            },
+            s if s < 256 => Self {
+                head: 0,
+                tail: self.head << (s - 128),
+            },
+            _ => Self::ZERO,
+        }
+    }
+
+    fn shr(&self, shift: u32) -> Self {
+        match shift {
+            0 => self.clone(),
+            s if s < 128 => Self {
+                head: (self.head >> s) | (self.tail << (128 - s)),
+                tail: self.tail >> s,
+            },
+            s if s < 256 => Self {
+                head: self.tail >> (s - 128),
+                tail: 0,
+            },
+            _ => Self::ZERO,
+        }
+    }
+
+    fn bit(&self, i: u32) -> bool {
+        match i {
+            i if i < 128 => (self.head >> i) & 1 == 1,
+            i => (self.tail >> (i - 128)) & 1 == 1,
+        }
+    }
+
+    fn set_bit(&mut self, i: u32) {
+        match i {
+            i if i < 128 => self.head |= 1 << i,
+            i => self.tail |= 1 << (i - 128),
+        }
+    }
+
+    /// Number of significant bits
+    fn bits(&self) -> u32 {
+        match self.tail {
+            0 => 128 - self.head.leading_zeros(),
+            tail => 256 - tail.leading_zeros(),
+        }
+    }
+
+    fn to_f64(&self) -> f64 {
+        self.tail as f64 * TWO_POW_128 + self.head as f64
+    }
+
+    fn to_limbs(&self) -> [u64; 4] {
+        [
+            self.head as u64,
+            (self.head >> 64) as u64,
+            self.tail as u64,
+            (self.tail >> 64) as u64,
+        ]
+    }
+
+    fn from_limbs(limbs: [u64; 4]) -> Self {
+        Self {
+            head: limbs[0] as u128 | ((limbs[1] as u128) << 64),
+            tail: limbs[2] as u128 | ((limbs[3] as u128) << 64),
+        }
+    }
+}
+
+/// Exact decomposition of a finite non negative float in mantissa * 2^exp
+fn decompose(value: f64) -> Result<(u64, i32), TargetError> {
+    if !value.is_finite() {
+        return Err(TargetError::NotFinite);
+    }
+    if value.is_sign_negative() && value != 0.0 {
+        return Err(TargetError::NegativeInput);
+    }
+    let bits = value.to_bits();
+    let exp = ((bits >> 52) & 0x7ff) as i32;
+    let fraction = bits & ((1 << 52) - 1);
+    match exp {
+        // subnormal
+        0 => Ok((fraction, -1074)),
+        _ => Ok((fraction | (1 << 52), exp - 1075)),
+    }
+}
+
+impl From<u128> for Target {
+    fn from(v: u128) -> Self {
+        Self { head: v, tail: 0 }
+    }
+}
+
+impl From<[u8; 32]> for Target {
+    fn from(v: [u8; 32]) -> Self {
+        // below unwraps never panics
+        let head = u128::from_le_bytes(v[0..16].try_into().unwrap());
+        let tail = u128::from_le_bytes(v[16..32].try_into().unwrap());
+        Self { head, tail }
+    }
+}
+
+impl<'a> From<U256<'a>> for Target {
+    fn from(v: U256<'a>) -> Self {
+        let inner = v.inner_as_ref();
+        // below unwraps never panics
+        let head = u128::from_le_bytes(inner[0..16].try_into().unwrap());
+        let tail = u128::from_le_bytes(inner[16..32].try_into().unwrap());
+        Self { head, tail }
+    }
+}
+
+impl From<Target> for U256<'static> {
+    fn from(v: Target) -> Self {
+        let mut inner = v.head.to_le_bytes().to_vec();
+        inner.extend_from_slice(&v.tail.to_le_bytes());
+        // below unwraps never panics
+        inner.try_into().unwrap()
+    }
+}
+
+impl PartialOrd for Target {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Target {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        if self.tail == other.tail && self.head == other.head {
+            core::cmp::Ordering::Equal
+        } else if self.tail != other.tail {
+            self.tail.cmp(&other.tail)
+        } else {
+            self.head.cmp(&other.head)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[quickcheck_macros::quickcheck]
+    fn test_target_from_u256(input: (u128, u128)) -> bool {
+        let target_expected = Target {
+            head: input.0,
+            tail: input.1,
+        };
+
+        let bytes = [&input.0.to_ne_bytes()[..], &input.1.to_ne_bytes()[..]].concat();
+        let u256: U256 = bytes.try_into().unwrap();
+        let target_final: Target = u256.clone().into();
+
+        let u256_final: U256 = target_final.clone().into();
+
+        target_expected == target_final && u256_final == u256
+    }
+    #[quickcheck_macros::quickcheck]
+    fn test_target_to_u256(input: (u128, u128)) -> bool {
+        let target_start = Target {
+            head: input.0,
+            tail: input.1,
+        };
+        let u256 = U256::<'static>::from(target_start.clone());
+        let target_final = Target::from(u256);
+        target_final == target_final
+    }
+
+    #[test]
+    fn test_ord_with_equal_head_tail() {
+        let target_1 = Target { head: 1, tail: 1 };
+        let target_2 = Target { head: 1, tail: 2 };
+        assert!(target_1 < target_2);
+
+        //also test with equal tails
+        let target_3 = Target { head: 2, tail: 2 };
+        assert!(target_2 < target_3);
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn test_ord_for_target_positive_increment(input: (u128, u128, u128, u128)) -> bool {
+        let max = u128::MAX;
+        // we want input.0 and input.1 >= 0 and < u128::MAX
+        let input = (input.0 % max, input.1 % max, input.2, input.3);
+        let target_start = Target {
+            head: input.0,
+            tail: input.1,
+        };
+        let positive_increment = (
+            input.2 % (max - target_start.head) + 1,
+            input.3 % (max - target_start.tail) + 1,
+        );
+        let target_final = Target {
+            head: target_start.head + positive_increment.0,
+            tail: target_start.tail + positive_increment.1,
+        };
+        target_final > target_start
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn test_ord_for_target_negative_increment(input: (u128, u128, u128, u128)) -> bool {
+        let max = u128::MAX;
+        let input = (input.0 % max + 1, input.1 % max + 1, input.2, input.3);
+        let target_start = Target {
+            head: input.0,
+            tail: input.1,
+        };
+        let negative_increment = (
+            input.2 % target_start.head + 1,
+            input.3 % target_start.tail + 1,
+        );
+        let target_final = Target {
+            head: target_start.head - negative_increment.0,
+            tail: target_start.tail - negative_increment.1,
+        };
+        target_final < target_start
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn test_ord_for_target_zero_increment(input: (u128, u128)) -> bool {
+        let target_start = Target {
+            head: input.0,
+            tail: input.1,
+        };
+        let target_final = target_start.clone();
+        target_start == target_final
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn test_mul_then_div(input: (u128, u128, u64)) -> bool {
+        let target = Target::new(input.0, input.1);
+        let factor = input.2.max(1);
+        match target.checked_mul(factor) {
+            Some(product) => product.checked_div(factor) == Some(target),
+            // the product overflows only when target > MAX / factor
+            None => target > Target::MAX.checked_div(factor).unwrap(),
+        }
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn test_div_rem(input: (u128, u128, u64)) -> bool {
+        let target = Target::new(input.0, input.1);
+        let divisor = input.2.max(1);
+        let quotient = target.checked_div(divisor).unwrap();
+        let remainder = target.wrapping_sub(&quotient.checked_mul(divisor).unwrap());
+        remainder < Target::from(divisor as u128)
+            && target.div_rem(&Target::from(divisor as u128)) == (quotient, remainder)
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn test_div_rem_by_target(input: (u128, u128, u128, u128)) -> bool {
+        let dividend = Target::new(input.0, input.1);
+        let divisor = Target::new(input.2.max(1), input.3 >> (input.0 % 128));
+        let (quotient, remainder) = dividend.div_rem(&divisor);
+        // quotient * divisor + remainder == dividend, checked on the limbs of the quotient
+        let limbs = quotient.to_limbs();
+        let mut product = Target::ZERO;
+        for (i, limb) in limbs.iter().enumerate() {
+            let partial = match divisor.checked_mul(*limb) {
+                Some(partial) => partial.shl(64 * i as u32),
+                None => return false,
+            };
+            product = match product.checked_add(&partial) {
+                Some(product) => product,
+                None => return false,
+            };
+        }
+        remainder < divisor && product.checked_add(&remainder) == Some(dividend)
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn test_hashes_per_share_round_trip(hashes: u128) -> bool {
+        // exact as long as hashes^2 < 2^256
+        let hashes = Target::from(hashes.max(1));
+        Target::from_hashes_per_share(&hashes).hashes_per_share() == hashes
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn test_target_from_hashes(hashes: u64) -> bool {
+        // (t+1)*h <= 2^256 < (t+2)*h, that is t*h + h-1 <= MAX < t*h + 2h-1
+        let hashes = hashes.max(1);
+        let target = Target::from_hashes_per_share(&Target::from(hashes as u128));
+        let product = target.checked_mul(hashes).unwrap();
+        let lower = product.checked_add(&Target::from(hashes as u128 - 1));
+        let upper = product.checked_add(&Target::from(2 * hashes as u128 - 1));
+        lower.is_some() && upper.is_none()
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn test_hashrate_round_trip(hashrate: u64, shares_per_minute: u8) -> bool {
+        let hashrate = hashrate as f64;
+        let shares_per_minute = shares_per_minute.max(1) as f64;
+        let target = Target::from_hashrate(hashrate, shares_per_minute).unwrap();
+        let estimated = target.hashrate(shares_per_minute).unwrap();
+        // s*h is floored to an integer of hashes
+        (estimated - hashrate).abs() <= shares_per_minute / 60.0 + hashrate * 1e-12
+    }
+
+    #[test]
+    fn test_hashrate_edge_cases() {
+        assert_eq!(Target::from_hashrate(0.0, 1.0), Ok(Target::MAX));
+        assert_eq!(Target::from_hashrate(1e300, 1.0), Ok(Target::ZERO));
+        assert_eq!(
+            Target::from_hashrate(1.0, 0.0),
+            Err(TargetError::DivisionByZero)
+        );
+        assert_eq!(
+            Target::from_hashrate(-1.0, 1.0),
+            Err(TargetError::NegativeInput)
+        );
+        assert_eq!(
+            Target::from_hashrate(f64::NAN, 1.0),
+            Err(TargetError::NotFinite)
+        );
+        assert_eq!(Target::MAX.hashrate(0.0), Err(TargetError::DivisionByZero));
+        // every hash is a share
+        assert_eq!(Target::MAX.hashes_per_share(), Target::from(1));
+        assert_eq!(Target::MAX.hashrate(60.0), Ok(1.0));
+        assert_eq!(Target::from_hashrate(0.5, 60.0), Ok(Target::MAX));
+        assert_eq!(Target::ZERO.hashes_per_share(), Target::MAX);
+        // half of the hashes are shares
+        let half = Target::new(u128::MAX, u128::MAX >> 1);
+        assert_eq!(Target::from_hashrate(2.0, 60.0), Ok(half.clone()));
+        assert_eq!(half.hashrate(60.0), Ok(2.0));
+        // 60 hashes per share at 1 share per minute
+        let target = Target::from_hashrate(1.0, 1.0).unwrap();
+        assert_eq!(target.hashes_per_share(), Target::from(60));
+        assert_eq!(target.hashrate(1.0), Ok(1.0));
+        assert_eq!(target.hashrate(6.0), Ok(6.0));
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn test_pool_difficulty_round_trip(difficulty: u64) -> bool {
+        let difficulty = difficulty.max(1) as f64;
+        let target = Target::from_pool_difficulty(difficulty).unwrap();
+        (target.pool_difficulty() - difficulty).abs() <= difficulty * 1e-12
+    }
+
+    #[test]
+    fn test_pool_difficulty() {
+        assert_eq!(Target::from_pool_difficulty(1.0), Ok(POOL_DIFFICULTY_1));
+        assert_eq!(POOL_DIFFICULTY_1.pool_difficulty(), 1.0);
+        assert_eq!(
+            Target::from_pool_difficulty(2.0),
+            Ok(POOL_DIFFICULTY_1.shr(1))
+        );
+        assert_eq!(
+            Target::from_pool_difficulty(0.5),
+            Ok(POOL_DIFFICULTY_1.shl(1))
+        );
+        assert_eq!(Target::from_pool_difficulty(1e-20), Ok(Target::MAX));
+        assert_eq!(
+            Target::from_pool_difficulty(0.0),
+            Err(TargetError::DivisionByZero)
+        );
+        assert_eq!(Target::ZERO.pool_difficulty(), f64::INFINITY);
+    }
+
+    #[test]
+    fn test_compact() {
+        // genesis block
+        let target = Target::from_compact(0x1d00ffff).unwrap();
+        assert_eq!(target, Target::new(0, 0xffff << 80));
+        assert_eq!(target.to_compact(), 0x1d00ffff);
+        // regtest
+        let target = Target::from_compact(0x207fffff).unwrap();
+        assert_eq!(target, Target::new(0, 0x7fffff << 104));
+        assert_eq!(target.to_compact(), 0x207fffff);
+        // the mantissa has a sign bit
+        assert_eq!(Target::from(0x80).to_compact(), 0x02008000);
+        assert_eq!(
+            Target::from_compact(0x04923456),
+            Err(TargetError::NegativeInput)
+        );
+        assert_eq!(Target::from_compact(0x23000001), Err(TargetError::Overflow));
+        assert_eq!(Target::from_compact(0x01003456), Ok(Target::ZERO));
+        assert_eq!(Target::from_compact(0x01123456), Ok(Target::from(0x12)));
+        assert_eq!(Target::ZERO.to_compact(), 0);
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn test_compact_round_trip(input: (u128, u128, u8)) -> bool {
+        let target = Target::new(input.0, input.1).shr(input.2 as u32);
+        let compact = target.to_compact();
+        let truncated = Target::from_compact(compact).unwrap();
+        // only the bits below the mantissa are lost
+        let lost = target.wrapping_sub(&truncated);
+        truncated <= target
+            && lost.bits() <= target.bits().saturating_sub(16)
+            && truncated.to_compact() == compact
+    }
+}
@@ -184,14 +184,23 @@ impl Arbitrary for NewTemplate<'static> {
         }
         let coinbase_prefix: binary_sv2::B0255 = coinbase_prefix.try_into().unwrap();
 
-        // TODO uncomment when node provided outputs are supported
-        //let mut coinbase_tx_outputs = vec::Vec::new();
-        //let coinbase_tx_outputs_len = u16::arbitrary(g) as usize;
-        //for _ in 0..coinbase_tx_outputs_len {
-        //    coinbase_tx_outputs.push(u8::arbitrary(g))
-        //};
-        //coinbase_tx_outputs.resize(coinbase_tx_outputs.len() - coinbase_tx_outputs.len() % 36,0);
-        //let coinbase_tx_outputs: binary_sv2::B064K = coinbase_tx_outputs.try_into().unwrap();
+        // node provided outputs: value, script length as a varint (also on 3 bytes) and script
+        let mut coinbase_tx_outputs = vec::Vec::new();
+        let coinbase_tx_outputs_count = u8::arbitrary(g) % 4;
+        for _ in 0..coinbase_tx_outputs_count {
+            coinbase_tx_outputs.extend_from_slice(&u64::arbitrary(g).to_le_bytes());
+            let script_len = u16::arbitrary(g) % 300;
+            if script_len < 0xfd {
+                coinbase_tx_outputs.push(script_len as u8);
+            } else {
+                coinbase_tx_outputs.push(0xfd);
+                coinbase_tx_outputs.extend_from_slice(&script_len.to_le_bytes());
+            }
+            for _ in 0..script_len {
+                coinbase_tx_outputs.push(u8::arbitrary(g));
+            }
+        }
+        let coinbase_tx_outputs: binary_sv2::B064K = coinbase_tx_outputs.try_into().unwrap();
 
         let mut merkle_path = vec::Vec::new();
         let merkle_path_len = u8::arbitrary(g);
@@ -213,11 +222,8 @@ impl Arbitrary for NewTemplate<'static> {
             coinbase_prefix,
             coinbase_tx_input_sequence: u32::arbitrary(g),
             coinbase_tx_value_remaining: u64::arbitrary(g),
-            // the belows should be used when node provided outputs are enabled
-            //coinbase_tx_outputs_count: coinbase_tx_outputs.len().checked_div(36).unwrap_or(0) as u32,
-            //coinbase_tx_outputs,
-            coinbase_tx_outputs_count: 0,
-            coinbase_tx_outputs: Vec::new().try_into().unwrap(),
+            coinbase_tx_outputs_count: coinbase_tx_outputs_count as u32,
+            coinbase_tx_outputs,
             coinbase_tx_locktime: u32::arbitrary(g),
             merkle_path,
         }
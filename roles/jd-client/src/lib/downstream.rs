@@ -13,7 +13,7 @@ use roles_logic_sv2::{
         common::{ParseDownstreamCommonMessages, SendTo as SendToCommon},
         mining::{ParseDownstreamMiningMessages, SendTo, SupportedChannelTypes},
     },
-    job_creator::JobsCreators,
+    job_creator::{CoinbaseCommitment, JobsCreators},
     mining_sv2::*,
     parsers::{Mining, MiningDeviceMessages, PoolMessages},
     template_distribution_sv2::{NewTemplate, SubmitSolution},
@@ -48,8 +48,19 @@ pub struct DownstreamMiningNode {
     // used to retreive the job id of the share that we send upstream
     last_template_id: u64,
     jd: Option<Arc<Mutex<JobDeclarator>>>,
+    // OpenExtendedMiningChannel relayed upstream, used to reopen the channel when the pool ask to
+    // reconnect to another endpoint
+    pub open_channel_request: Option<OpenExtendedMiningChannel<'static>>,
+    // last templates sent downstream with the pool outputs used to build the job, used to go back
+    // to a job accepted by the pool when the pool refuse a custom job
+    last_templates: VecDeque<(NewTemplate<'static>, Vec<u8>)>,
+    // Providers of extra coinbase data, registered in the channel factory once created
+    coinbase_commitments: Vec<Arc<dyn CoinbaseCommitment>>,
 }
 
+/// How many templates are kept in `DownstreamMiningNode::last_templates`
+const LAST_TEMPLATES_CAPACITY: usize = 10;
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum DownstreamMiningNodeStatus {
@@ -118,7 +129,7 @@ impl DownstreamMiningNodeStatus {
             DownstreamMiningNodeStatus::SoloMinerChannelOpend((channel, _)) => channel,
         }
     }
-    fn have_channel(&self) -> bool {
+    pub fn have_channel(&self) -> bool {
         match self {
             DownstreamMiningNodeStatus::Initializing(_) => false,
             DownstreamMiningNodeStatus::Paired(_) => false,
@@ -148,7 +159,7 @@ impl DownstreamMiningNodeStatus {
 }
 
 use core::convert::TryInto;
-use std::sync::Arc;
+use std::{collections::VecDeque, sync::Arc};
 
 impl DownstreamMiningNode {
     #[allow(clippy::too_many_arguments)]
@@ -162,6 +173,7 @@ impl DownstreamMiningNode {
         tx_status: status::Sender,
         miner_coinbase_output: Vec<TxOut>,
         jd: Option<Arc<Mutex<JobDeclarator>>>,
+        coinbase_commitments: Vec<Arc<dyn CoinbaseCommitment>>,
     ) -> Self {
         Self {
             receiver,
@@ -178,6 +190,15 @@ impl DownstreamMiningNode {
             // Is upated in the message handler that si called earlier in the main loop.
             last_template_id: 0,
             jd,
+            open_channel_request: None,
+            last_templates: VecDeque::with_capacity(LAST_TEMPLATES_CAPACITY),
+            coinbase_commitments,
+        }
+    }
+
+    fn add_coinbase_commitments(&self, channel_factory: &mut PoolChannelFactory) {
+        for commitment in &self.coinbase_commitments {
+            channel_factory.add_coinbase_commitment(commitment.clone());
         }
     }
 
@@ -232,9 +253,10 @@ impl DownstreamMiningNode {
             let recv_factory = {
                 let self_mutex = self_mutex.clone();
                 tokio::task::spawn(async move {
-                    let factory = UpstreamMiningNode::take_channel_factory(upstream).await;
+                    let mut factory = UpstreamMiningNode::take_channel_factory(upstream).await;
                     self_mutex
                         .safe_lock(|s| {
+                            s.add_coinbase_commitments(&mut factory);
                             s.status.set_channel(factory);
                         })
                         .unwrap();
@@ -280,27 +302,26 @@ impl DownstreamMiningNode {
                     .await
                     .unwrap();
             }
-            Ok(SendTo::RelayNewMessage(Mining::SubmitSharesExtended(mut share))) => {
+            Ok(SendTo::RelayNewMessage(Mining::SubmitSharesExtended(share))) => {
                 // If we have a realy new message it means that we are in a pooled mining mods.
                 let upstream_mutex = self_mutex
                     .safe_lock(|s| s.status.get_upstream().unwrap())
                     .unwrap();
-                // When re receive SetupConnectionSuccess we link the last_template_id with the
-                // pool's job_id. The below return as soon as we have a pairable job id for the
-                // template_id associated with this share.
+                // When re receive SetCustomMiningJobSuccess we link the last_template_id with the
+                // pool's job_id. Until then the share is held by the upstream, that sends it as
+                // soon as the pool accepts the job.
                 let last_template_id = self_mutex.safe_lock(|s| s.last_template_id).unwrap();
-                let job_id_future =
-                    UpstreamMiningNode::get_job_id(&upstream_mutex, last_template_id);
-                let job_id = match timeout(Duration::from_secs(10), job_id_future).await {
-                    Ok(job_id) => job_id,
-                    Err(_) => {
-                        return;
-                    }
+                let share = match UpstreamMiningNode::on_share(
+                    &upstream_mutex,
+                    last_template_id,
+                    share.into_static(),
+                ) {
+                    Some(share) => share,
+                    None => return,
                 };
-                share.job_id = job_id;
                 debug!(
                     "Sending valid block solution upstream, with job_id {}",
-                    job_id
+                    share.job_id
                 );
                 let message = Mining::SubmitSharesExtended(share);
                 let message: PoolMessages = PoolMessages::Mining(message);
@@ -360,6 +381,17 @@ impl DownstreamMiningNode {
             super::IS_NEW_TEMPLATE_HANDLED.store(true, std::sync::atomic::Ordering::Release);
             return Ok(());
         }
+        self_mutex
+            .safe_lock(|s| {
+                s.last_templates
+                    .retain(|(t, _)| t.template_id != new_template.template_id);
+                if s.last_templates.len() == LAST_TEMPLATES_CAPACITY {
+                    s.last_templates.pop_front();
+                }
+                s.last_templates
+                    .push_back((new_template.clone(), pool_output.to_vec()));
+            })
+            .unwrap();
         let mut pool_out = &pool_output[0..];
         let pool_output =
             TxOut::consensus_decode(&mut pool_out).expect("Upstream sent an invalid coinbase");
@@ -396,6 +428,34 @@ impl DownstreamMiningNode {
         Ok(())
     }
 
+    /// Called when the pool refuse the custom job built on a template. The template with id
+    /// `template_id`, that must be a template accepted by the pool, is sent again downstream as
+    /// a new job so that the downstream go back mining on a job that the pool knows. Return false
+    /// if the template is no longer available.
+    pub async fn fall_back_to_template(
+        self_mutex: &Arc<Mutex<Self>>,
+        template_id: u64,
+    ) -> Result<bool, Error> {
+        let template = self_mutex
+            .safe_lock(|s| {
+                s.last_templates
+                    .iter()
+                    .find(|(t, _)| t.template_id == template_id)
+                    .cloned()
+            })
+            .unwrap();
+        match template {
+            Some((mut template, pool_output)) => {
+                info!("Falling back to the job built on template {}", template_id);
+                // the prev hash of the template is the current one
+                template.future_template = false;
+                Self::on_new_template(self_mutex, template, &pool_output[..]).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
     pub async fn on_set_new_prev_hash(
         self_mutex: &Arc<Mutex<Self>>,
         new_prev_hash: roles_logic_sv2::template_distribution_sv2::SetNewPrevHash<'static>,
@@ -462,6 +522,7 @@ impl
         m: OpenExtendedMiningChannel,
     ) -> Result<SendTo<UpstreamMiningNode>, Error> {
         if !self.status.is_solo_miner() {
+            self.open_channel_request = Some(m.into_static());
             // Safe unwrap alreay checked if it cointains upstream with is_solo_miner
             Ok(SendTo::RelaySameMessageToRemote(
                 self.status.get_upstream().unwrap(),
@@ -483,7 +544,7 @@ impl
             let creator = JobsCreators::new(extranonce_len as u8);
             let share_per_min = 1.0;
             let kind = roles_logic_sv2::channel_logic::channel_factory::ExtendedChannelKind::Pool;
-            let channel_factory = PoolChannelFactory::new(
+            let mut channel_factory = PoolChannelFactory::new(
                 ids,
                 extranonces,
                 creator,
@@ -492,6 +553,7 @@ impl
                 coinbase_outputs,
                 "SOLO".to_string(),
             );
+            self.add_coinbase_commitments(&mut channel_factory);
             self.status.set_channel(channel_factory);
 
             let request_id = m.request_id;
@@ -649,11 +711,7 @@ impl ParseDownstreamCommonMessages<roles_logic_sv2::routing_logic::NoRouting>
 
 use network_helpers_sv2::noise_connection_tokio::Connection;
 use std::net::SocketAddr;
-use tokio::{
-    net::TcpListener,
-    task::AbortHandle,
-    time::{timeout, Duration},
-};
+use tokio::{net::TcpListener, task::AbortHandle};
 
 /// Strat listen for downstream mining node. Return as soon as one downstream connect.
 #[allow(clippy::too_many_arguments)]
@@ -669,6 +727,7 @@ pub async fn listen_for_downstream_mining(
     tx_status: status::Sender,
     miner_coinbase_output: Vec<TxOut>,
     jd: Option<Arc<Mutex<JobDeclarator>>>,
+    coinbase_commitments: Vec<Arc<dyn CoinbaseCommitment>>,
 ) -> Result<Arc<Mutex<DownstreamMiningNode>>, Error> {
     info!("Listening for downstream mining connections on {}", address);
     let listner = TcpListener::bind(address).await.unwrap();
@@ -694,6 +753,7 @@ pub async fn listen_for_downstream_mining(
             tx_status,
             miner_coinbase_output,
             jd,
+            coinbase_commitments,
         );
 
         let mut incoming: StdFrame = node.receiver.recv().await.unwrap().try_into().unwrap();
@@ -0,0 +1,207 @@
+//! Fail-back to a higher priority upstream. When the JDC is not using the first upstream in the
+//! config (or is mining solo), the upstreams that come before the one in use are probed in the
+//! background with a noise handshake followed by a `SetupConnection`, both on the pool and on the
+//! JDS. When one of them stays healthy for `healthy_period_secs` it is set in the
+//! [`PoolChangerTrigger`] and the template receiver switches to it on the next `SetNewPrevHash`.
+
+use super::{
+    job_declarator::setup_connection::SetupConnectionHandler,
+    proxy_config::{FailBackConfig, Upstream},
+    upstream_sv2::Upstream as UpstreamMiningNode,
+    PoolChangerTrigger,
+};
+use codec_sv2::{Frame, HandshakeRole, Initiator, StandardSv2Frame};
+use key_utils::Secp256k1PublicKey;
+use network_helpers_sv2::noise_connection_tokio::Connection;
+use roles_logic_sv2::{
+    common_messages_sv2::SetupConnection,
+    parsers::{CommonMessages, PoolMessages},
+    utils::Mutex,
+};
+use std::{
+    convert::TryInto,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, task::AbortHandle, time::timeout};
+use tracing::{debug, info};
+
+type StdFrame = StandardSv2Frame<PoolMessages<'static>>;
+
+/// Seconds after which a probe that did not complete is considered failed.
+const PROBE_TIMEOUT_SECS: u64 = 10;
+
+/// Health of the probed upstreams, in the order of the config.
+#[derive(Debug)]
+struct HealthTracker {
+    healthy_period: Duration,
+    // when each upstream has been seen healthy without interruptions
+    healthy_since: Vec<Option<Instant>>,
+}
+
+impl HealthTracker {
+    fn new(upstreams: usize, healthy_period: Duration) -> Self {
+        Self {
+            healthy_period,
+            healthy_since: vec![None; upstreams],
+        }
+    }
+
+    fn on_probe(&mut self, index: usize, healthy: bool, now: Instant) {
+        self.healthy_since[index] = match (healthy, self.healthy_since[index]) {
+            (true, Some(since)) => Some(since),
+            (true, None) => Some(now),
+            (false, _) => None,
+        };
+    }
+
+    /// The first upstream in the config that is healthy for long enough
+    fn recovered(&self, now: Instant) -> Option<usize> {
+        self.healthy_since.iter().position(|since| {
+            matches!(since, Some(since) if now.duration_since(*since) >= self.healthy_period)
+        })
+    }
+}
+
+/// Starts probing `upstreams`, that are the upstreams with an higher priority than the one in
+/// use. Does nothing if fail-back is disabled or if there is nothing to probe.
+#[allow(clippy::too_many_arguments)]
+pub fn start_health_probing(
+    upstreams: Vec<Upstream>,
+    config: FailBackConfig,
+    min_version: u16,
+    max_version: u16,
+    proxy_address: SocketAddr,
+    pool_changer_trigger: Arc<Mutex<PoolChangerTrigger>>,
+    task_collector: Arc<Mutex<Vec<AbortHandle>>>,
+) {
+    if config.probe_interval_secs == 0 || upstreams.is_empty() {
+        return;
+    }
+    let probe_interval = Duration::from_secs(config.probe_interval_secs);
+    let healthy_period = Duration::from_secs(config.healthy_period_secs);
+    let task = tokio::task::spawn(async move {
+        let mut tracker = HealthTracker::new(upstreams.len(), healthy_period);
+        loop {
+            tokio::time::sleep(probe_interval).await;
+            for (index, upstream) in upstreams.iter().enumerate() {
+                let healthy =
+                    probe_upstream(upstream, min_version, max_version, proxy_address).await;
+                debug!("Upstream {} healthy: {}", upstream.pool_address, healthy);
+                tracker.on_probe(index, healthy, Instant::now());
+            }
+            if let Some(index) = tracker.recovered(Instant::now()) {
+                info!(
+                    "Upstream {} is healthy again, switching back to it at the next job boundary",
+                    upstreams[index].pool_address
+                );
+                pool_changer_trigger
+                    .safe_lock(|t| t.set_fail_back(index))
+                    .unwrap();
+                break;
+            }
+        }
+    });
+    task_collector
+        .safe_lock(|c| c.push(task.abort_handle()))
+        .unwrap();
+}
+
+/// Probes the pool and the JDS of `upstream`.
+async fn probe_upstream(
+    upstream: &Upstream,
+    min_version: u16,
+    max_version: u16,
+    proxy_address: SocketAddr,
+) -> bool {
+    let (pool_address, jd_address) = match (
+        upstream.pool_address.parse::<SocketAddr>(),
+        upstream.jd_address.parse::<SocketAddr>(),
+    ) {
+        (Ok(pool_address), Ok(jd_address)) => (pool_address, jd_address),
+        _ => return false,
+    };
+    let setup_pool =
+        match UpstreamMiningNode::get_setup_connection_message(min_version, max_version, true) {
+            Ok(setup_connection) => setup_connection,
+            Err(_) => return false,
+        };
+    let setup_jd = SetupConnectionHandler::get_setup_connection_message(proxy_address);
+    probe(pool_address, upstream.authority_pubkey, setup_pool).await
+        && probe(jd_address, upstream.authority_pubkey, setup_jd).await
+}
+
+/// Opens a noise connection with `address` and sends `setup_connection`, returns true if the
+/// remote answers with a `SetupConnectionSuccess`. The connection is closed right after.
+async fn probe(
+    address: SocketAddr,
+    authority_public_key: Secp256k1PublicKey,
+    setup_connection: SetupConnection<'static>,
+) -> bool {
+    let probe = async move {
+        let stream = TcpStream::connect(address).await.ok()?;
+        let initiator = Initiator::from_raw_k(authority_public_key.into_bytes()).ok()?;
+        let (receiver, sender, recv_task, send_task) =
+            Connection::new::<PoolMessages<'static>>(stream, HandshakeRole::Initiator(initiator))
+                .await
+                .ok()?;
+        let frame: StdFrame = PoolMessages::Common(setup_connection.into())
+            .try_into()
+            .ok()?;
+        let sent = sender.send(frame.into()).await;
+        let incoming = match sent {
+            Ok(_) => receiver.recv().await.ok(),
+            Err(_) => None,
+        };
+        recv_task.abort();
+        send_task.abort();
+        let mut incoming: StdFrame = incoming?.try_into().ok()?;
+        let message_type = incoming.get_header()?.msg_type();
+        let message: CommonMessages = (message_type, incoming.payload()).try_into().ok()?;
+        Some(matches!(message, CommonMessages::SetupConnectionSuccess(_)))
+    };
+    matches!(
+        timeout(Duration::from_secs(PROBE_TIMEOUT_SECS), probe).await,
+        Ok(Some(true))
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn higher_priority_upstream_is_chosen_after_the_healthy_period() {
+        let healthy_period = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut tracker = HealthTracker::new(2, healthy_period);
+        tracker.on_probe(0, false, start);
+        tracker.on_probe(1, true, start);
+        assert_eq!(tracker.recovered(start), None);
+        assert_eq!(tracker.recovered(start + healthy_period), Some(1));
+
+        // the first upstream becomes healthy later, it is chosen once healthy for long enough
+        let later = start + Duration::from_secs(30);
+        tracker.on_probe(0, true, later);
+        tracker.on_probe(1, true, later);
+        assert_eq!(tracker.recovered(later + Duration::from_secs(40)), Some(1));
+        assert_eq!(tracker.recovered(later + healthy_period), Some(0));
+    }
+
+    #[test]
+    fn failed_probe_restarts_the_healthy_period() {
+        let healthy_period = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut tracker = HealthTracker::new(1, healthy_period);
+        tracker.on_probe(0, true, start);
+        let failure = start + Duration::from_secs(50);
+        tracker.on_probe(0, false, failure);
+        assert_eq!(tracker.recovered(start + healthy_period), None);
+        let recovery = failure + Duration::from_secs(10);
+        tracker.on_probe(0, true, recovery);
+        // seen healthy again only 10s after the failure
+        assert_eq!(tracker.recovered(recovery + Duration::from_secs(40)), None);
+        assert_eq!(tracker.recovered(recovery + healthy_period), Some(0));
+    }
+}
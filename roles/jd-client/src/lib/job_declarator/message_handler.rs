@@ -9,7 +9,10 @@ use roles_logic_sv2::{
     parsers::JobDeclaration,
 };
 pub type SendTo = SendTo_<JobDeclaration<'static>, ()>;
+use binary_sv2::U256;
 use roles_logic_sv2::errors::Error;
+use stratum_common::bitcoin::{hashes::Hash, util::psbt::serialize::Deserialize, Transaction};
+use tracing::error;
 
 impl ParseServerJobDeclarationMessages for JobDeclarator {
     fn handle_allocate_mining_job_token_success(
@@ -40,9 +43,34 @@ impl ParseServerJobDeclarationMessages for JobDeclarator {
         &mut self,
         message: IdentifyTransactions,
     ) -> Result<SendTo, Error> {
+        // the JDS could not identify the transactions by short id, so the full txids of the
+        // declared job are sent
+        let last_declare = match self.last_declare_mining_jobs_sent.get(&message.request_id) {
+            Some(Some(last_declare)) => last_declare,
+            _ => {
+                error!(
+                    "Received IdentifyTransactions for unknown job {}",
+                    message.request_id
+                );
+                return Ok(SendTo::None(None));
+            }
+        };
+        let mut tx_data_hashes: Vec<U256<'static>> = Vec::new();
+        for tx in last_declare.tx_list.to_vec() {
+            match Transaction::deserialize(&tx) {
+                Ok(tx) => tx_data_hashes.push(tx.txid().into_inner().into()),
+                Err(e) => {
+                    error!(
+                        "Can not decode a transaction of job {}: {:?}",
+                        message.request_id, e
+                    );
+                    return Ok(SendTo::None(None));
+                }
+            }
+        }
         let message_identify_transactions = IdentifyTransactionsSuccess {
             request_id: message.request_id,
-            tx_data_hashes: Vec::new().into(),
+            tx_data_hashes: tx_data_hashes.into(),
         };
         let message_enum =
             JobDeclaration::IdentifyTransactionsSuccess(message_identify_transactions);
@@ -1,6 +1,6 @@
 pub mod message_handler;
 use async_channel::{Receiver, Sender};
-use binary_sv2::{Seq0255, Seq064K, B016M, B064K, U256};
+use binary_sv2::{Seq0255, Seq064K, ShortTxId, B016M, B0255, B064K, U256};
 use codec_sv2::{HandshakeRole, Initiator, StandardEitherFrame, StandardSv2Frame};
 use network_helpers_sv2::noise_connection_tokio::Connection;
 use roles_logic_sv2::{
@@ -9,10 +9,14 @@ use roles_logic_sv2::{
     mining_sv2::SubmitSharesExtended,
     parsers::{JobDeclaration, PoolMessages},
     template_distribution_sv2::SetNewPrevHash,
-    utils::{hash_lists_tuple, Mutex},
+    utils::{get_short_hash, tx_hash_list_hash_builder, Mutex},
 };
-use std::{collections::HashMap, convert::TryInto, str::FromStr};
-use stratum_common::bitcoin::{util::psbt::serialize::Deserialize, Transaction};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryInto,
+    str::FromStr,
+};
+use stratum_common::bitcoin::{util::psbt::serialize::Deserialize, Transaction, Txid};
 use tokio::task::AbortHandle;
 use tracing::{error, info};
 
@@ -34,7 +38,7 @@ pub type Message = PoolMessages<'static>;
 pub type SendTo = SendTo_<JobDeclaration<'static>, ()>;
 pub type StdFrame = StandardSv2Frame<Message>;
 
-mod setup_connection;
+pub mod setup_connection;
 use setup_connection::SetupConnectionHandler;
 
 use super::{error::Error, proxy_config::ProxyConfig, upstream_sv2::Upstream};
@@ -45,6 +49,8 @@ pub struct LastDeclareJob {
     template: NewTemplate<'static>,
     coinbase_pool_output: Vec<u8>,
     tx_list: Seq064K<'static, B016M<'static>>,
+    // The job is mined before DeclareMiningJobSuccess, using the token of the declaration
+    async_mining: bool,
 }
 
 #[derive(Debug)]
@@ -70,6 +76,8 @@ pub struct JobDeclarator {
         ),
         BuildNoHashHasher<u64>,
     >,
+    // Future templates refused by the JDS before their SetNewPrevHash
+    refused_templates: VecDeque<u64>,
     up: Arc<Mutex<Upstream>>,
     task_collector: Arc<Mutex<Vec<AbortHandle>>>,
     pub coinbase_tx_prefix: B064K<'static>,
@@ -118,6 +126,7 @@ impl JobDeclarator {
             last_declare_mining_jobs_sent: HashMap::with_capacity(10),
             last_set_new_prev_hash: None,
             future_jobs: HashMap::with_hasher(BuildNoHashHasher::default()),
+            refused_templates: VecDeque::with_capacity(10),
             up,
             task_collector,
             coinbase_tx_prefix: vec![].try_into().unwrap(),
@@ -130,16 +139,20 @@ impl JobDeclarator {
         Ok(self_)
     }
 
-    fn get_last_declare_job_sent(self_mutex: &Arc<Mutex<Self>>, request_id: u32) -> LastDeclareJob {
-        self_mutex
-            .safe_lock(|s| {
-                s.last_declare_mining_jobs_sent
-                    .get(&request_id)
-                    .expect("LastDeclareJob not found")
-                    .clone()
-                    .expect("unreachable code")
-            })
+    /// Returns None when the job has already been dropped from the last declared jobs, e.g. when
+    /// the JDS answers after more than 10 newer jobs have been declared
+    fn get_last_declare_job_sent(
+        self_mutex: &Arc<Mutex<Self>>,
+        request_id: u32,
+    ) -> Option<LastDeclareJob> {
+        let last_declare = self_mutex
+            .safe_lock(|s| s.last_declare_mining_jobs_sent.get(&request_id).cloned())
             .unwrap()
+            .flatten();
+        if last_declare.is_none() {
+            error!("Received an answer for unknown declared job {}", request_id);
+        }
+        last_declare
     }
 
     fn update_last_declare_job_sent(
@@ -222,10 +235,15 @@ impl JobDeclarator {
         }
     }
 
+    /// Declares the job built on `template`. When `async_mining_allowed` the job is set with the
+    /// pool right away (or on SetNewPrevHash for a future template), without waiting for
+    /// DeclareMiningJobSuccess.
+    #[allow(clippy::too_many_arguments)]
     pub async fn on_new_template(
         self_mutex: &Arc<Mutex<Self>>,
         template: NewTemplate<'static>,
         token: Vec<u8>,
+        async_mining_allowed: bool,
         tx_list_: Seq064K<'static, B016M<'static>>,
         excess_data: B064K<'static>,
         coinbase_pool_output: Vec<u8>,
@@ -233,14 +251,14 @@ impl JobDeclarator {
         let (id, _, sender) = self_mutex
             .safe_lock(|s| (s.req_ids.next(), s.min_extranonce_size, s.sender.clone()))
             .unwrap();
-        // TODO: create right nonce
-        let tx_short_hash_nonce = 0;
         let mut tx_list: Vec<Transaction> = Vec::new();
         for tx in tx_list_.to_vec() {
             //TODO remove unwrap
             let tx = Transaction::deserialize(&tx).unwrap();
             tx_list.push(tx);
         }
+        let txids: Vec<Txid> = tx_list.iter().map(|tx| tx.txid()).collect();
+        let (tx_short_hash_nonce, tx_short_hash_list) = Self::short_hash_list(&txids);
         let declare_job = DeclareMiningJob {
             request_id: id,
             mining_job_token: token.try_into().unwrap(),
@@ -252,22 +270,206 @@ impl JobDeclarator {
                 .safe_lock(|s| s.coinbase_tx_suffix.clone())
                 .unwrap(),
             tx_short_hash_nonce,
-            tx_short_hash_list: hash_lists_tuple(tx_list.clone(), tx_short_hash_nonce).0,
-            tx_hash_list_hash: hash_lists_tuple(tx_list.clone(), tx_short_hash_nonce).1,
+            tx_short_hash_list: tx_short_hash_list.into(),
+            tx_hash_list_hash: tx_hash_list_hash_builder(txids),
             excess_data, // request transaction data
         };
         let last_declare = LastDeclareJob {
             declare_job: declare_job.clone(),
-            template,
-            coinbase_pool_output,
+            template: template.clone(),
+            coinbase_pool_output: coinbase_pool_output.clone(),
             tx_list: tx_list_.clone(),
+            async_mining: async_mining_allowed,
         };
         Self::update_last_declare_job_sent(self_mutex, id, last_declare);
         let frame: StdFrame =
-            PoolMessages::JobDeclaration(JobDeclaration::DeclareMiningJob(declare_job))
+            PoolMessages::JobDeclaration(JobDeclaration::DeclareMiningJob(declare_job.clone()))
                 .try_into()
                 .unwrap();
         sender.send(frame.into()).await.unwrap();
+
+        if async_mining_allowed {
+            if template.future_template {
+                let merkle_path = template.merkle_path.clone();
+                self_mutex
+                    .safe_lock(|s| {
+                        s.future_jobs.insert(
+                            template.template_id,
+                            (declare_job, merkle_path, template, coinbase_pool_output),
+                        )
+                    })
+                    .unwrap();
+            } else {
+                let (set_new_prev_hash, up) = self_mutex
+                    .safe_lock(|s| (s.last_set_new_prev_hash.clone(), s.up.clone()))
+                    .unwrap();
+                match set_new_prev_hash {
+                    Some(p) => {
+                        let token = declare_job.mining_job_token.clone();
+                        Self::set_custom_job(
+                            &up,
+                            declare_job,
+                            p,
+                            template,
+                            coinbase_pool_output,
+                            token,
+                        )
+                        .await
+                    }
+                    None => error!("Non future template received before any SetNewPrevHash"),
+                }
+            }
+        }
+    }
+
+    /// Sends to the pool the SetCustomMiningJob of a declared job
+    async fn set_custom_job(
+        up: &Arc<Mutex<Upstream>>,
+        declare_job: DeclareMiningJob<'static>,
+        set_new_prev_hash: SetNewPrevHash<'static>,
+        template: NewTemplate<'static>,
+        mut pool_outs: Vec<u8>,
+        token: B0255<'static>,
+    ) {
+        let mut template_outs = template.coinbase_tx_outputs.to_vec();
+        pool_outs.append(&mut template_outs);
+        if let Err(e) = Upstream::set_custom_jobs(
+            up,
+            declare_job,
+            set_new_prev_hash,
+            template.merkle_path,
+            token,
+            template.coinbase_tx_version,
+            template.coinbase_prefix,
+            template.coinbase_tx_input_sequence,
+            template.coinbase_tx_value_remaining,
+            pool_outs,
+            template.coinbase_tx_locktime,
+            template.template_id,
+        )
+        .await
+        {
+            // the connection with the pool is lost, the upstream changes pool
+            error!("Failed to send SetCustomMiningJob: {}", e);
+        }
+    }
+
+    /// DeclareMiningJobSuccess of a job that the pool is already mining (or that is waiting for
+    /// its SetNewPrevHash). If the JDS sent a new token the job is set again with it, unless the
+    /// downstream already moved to another job.
+    async fn on_async_job_declared(
+        self_mutex: &Arc<Mutex<Self>>,
+        request_id: u32,
+        last_declare: LastDeclareJob,
+        new_token: B0255<'static>,
+    ) {
+        if last_declare.declare_job.mining_job_token == new_token {
+            return;
+        }
+        let template_id = last_declare.template.template_id;
+        let (waiting_for_prev_hash, set_new_prev_hash, is_last, up) = self_mutex
+            .safe_lock(|s| {
+                let waiting = match s.future_jobs.get_mut(&template_id) {
+                    Some(job) => {
+                        job.0.mining_job_token = new_token.clone();
+                        true
+                    }
+                    None => false,
+                };
+                (
+                    waiting,
+                    s.last_set_new_prev_hash.clone(),
+                    s.last_declare_mining_jobs_sent.keys().max() == Some(&request_id),
+                    s.up.clone(),
+                )
+            })
+            .unwrap();
+        if waiting_for_prev_hash {
+            return;
+        }
+        match set_new_prev_hash {
+            Some(p)
+                if is_last
+                    && (!last_declare.template.future_template || p.template_id == template_id) =>
+            {
+                let mut declare_job = last_declare.declare_job;
+                declare_job.mining_job_token = new_token.clone();
+                Self::set_custom_job(
+                    &up,
+                    declare_job,
+                    p,
+                    last_declare.template,
+                    last_declare.coinbase_pool_output,
+                    new_token,
+                )
+                .await
+            }
+            _ => info!(
+                "Job built on template {} is no longer mined, not updating its token",
+                template_id
+            ),
+        }
+    }
+
+    /// DeclareMiningJobError: the job can not be mined. If the downstream is already mining it
+    /// (last declared job on the current prev hash) it goes back to the last accepted job, a
+    /// future job is dropped before its SetNewPrevHash.
+    fn on_declare_mining_job_error(self_mutex: &Arc<Mutex<Self>>, request_id: u32) {
+        let last_declare = match Self::get_last_declare_job_sent(self_mutex, request_id) {
+            Some(last_declare) => last_declare,
+            None => return,
+        };
+        let template_id = last_declare.template.template_id;
+        let (active_prev_hash, up) = self_mutex
+            .safe_lock(|s| {
+                s.future_jobs.remove(&template_id);
+                let is_last = s.last_declare_mining_jobs_sent.keys().max() == Some(&request_id);
+                let active_prev_hash = match &s.last_set_new_prev_hash {
+                    Some(p)
+                        if is_last
+                            && (!last_declare.template.future_template
+                                || p.template_id == template_id) =>
+                    {
+                        Some(p.prev_hash.clone())
+                    }
+                    _ => None,
+                };
+                // on_set_new_prev_hash is waiting for the job, it falls back when it sees that
+                // the job has been refused
+                let waiting_for_job = s.set_new_prev_hash_counter > 0
+                    && active_prev_hash.is_some()
+                    && last_declare.template.future_template;
+                if last_declare.template.future_template
+                    && (active_prev_hash.is_none() || waiting_for_job)
+                {
+                    if s.refused_templates.len() == 10 {
+                        s.refused_templates.pop_front();
+                    }
+                    s.refused_templates.push_back(template_id);
+                }
+                match waiting_for_job {
+                    true => (None, s.up.clone()),
+                    false => (active_prev_hash, s.up.clone()),
+                }
+            })
+            .unwrap();
+        Upstream::on_declared_job_refused(&up, template_id, active_prev_hash);
+    }
+
+    /// Returns a fresh random nonce and the short ids of `txids` computed with it. The nonce is
+    /// drawn again until the short ids of the job are unique.
+    fn short_hash_list(txids: &[Txid]) -> (u64, Vec<ShortTxId<'static>>) {
+        loop {
+            let nonce: u64 = rand::random();
+            let short_hash_list: Vec<ShortTxId<'static>> = txids
+                .iter()
+                .map(|txid| get_short_hash(*txid, nonce))
+                .collect();
+            let unique: HashSet<Vec<u8>> = short_hash_list.iter().map(|s| s.to_vec()).collect();
+            if unique.len() == short_hash_list.len() {
+                return (nonce, short_hash_list);
+            }
+        }
     }
 
     pub fn on_upstream_message(self_mutex: Arc<Mutex<Self>>) {
@@ -290,7 +492,20 @@ impl JobDeclarator {
                         Ok(SendTo::None(Some(JobDeclaration::DeclareMiningJobSuccess(m)))) => {
                             let new_token = m.new_mining_job_token;
                             let last_declare =
-                                Self::get_last_declare_job_sent(&self_mutex, m.request_id);
+                                match Self::get_last_declare_job_sent(&self_mutex, m.request_id) {
+                                    Some(last_declare) => last_declare,
+                                    None => continue,
+                                };
+                            if last_declare.async_mining {
+                                Self::on_async_job_declared(
+                                    &self_mutex,
+                                    m.request_id,
+                                    last_declare,
+                                    new_token,
+                                )
+                                .await;
+                                continue;
+                            }
                             let mut last_declare_mining_job_sent = last_declare.declare_job;
                             let is_future = last_declare.template.future_template;
                             let id = last_declare.template.template_id;
@@ -319,30 +534,22 @@ impl JobDeclarator {
                                 let set_new_prev_hash = self_mutex
                                     .safe_lock(|s| s.last_set_new_prev_hash.clone())
                                     .unwrap();
-                                let mut template_outs = template.coinbase_tx_outputs.to_vec();
-                                let mut pool_outs = last_declare.coinbase_pool_output;
-                                pool_outs.append(&mut template_outs);
                                 match set_new_prev_hash {
-                                    Some(p) => Upstream::set_custom_jobs(
+                                    Some(p) => Self::set_custom_job(
                                         &up,
                                         last_declare_mining_job_sent,
                                         p,
-                                        merkle_path,
+                                        template,
+                                        last_declare.coinbase_pool_output,
                                         new_token,
-                                        template.coinbase_tx_version,
-                                        template.coinbase_prefix,
-                                        template.coinbase_tx_input_sequence,
-                                        template.coinbase_tx_value_remaining,
-                                        pool_outs,
-                                        template.coinbase_tx_locktime,
-                                        template.template_id
-                                        ).await.unwrap(),
+                                        ).await,
                                     None => panic!("Invalid state we received a NewTemplate not future, without having received a set new prev hash")
                                 }
                             }
                         }
                         Ok(SendTo::None(Some(JobDeclaration::DeclareMiningJobError(m)))) => {
                             error!("Job is not verified: {:?}", m);
+                            Self::on_declare_mining_job_error(&self_mutex, m.request_id);
                         }
                         Ok(SendTo::None(None)) => (),
                         Ok(SendTo::Respond(m)) => {
@@ -377,7 +584,8 @@ impl JobDeclarator {
                 s.last_set_new_prev_hash = Some(set_new_prev_hash.clone());
                 s.set_new_prev_hash_counter += 1;
             });
-            let (job, up, merkle_path, template, mut pool_outs) = loop {
+            let mut refused = false;
+            let (job, up, _merkle_path, template, pool_outs) = loop {
                 match self_mutex
                     .safe_lock(|s| {
                         if s.set_new_prev_hash_counter > 1
@@ -386,6 +594,11 @@ impl JobDeclarator {
                         {
                             s.set_new_prev_hash_counter -= 1;
                             Some(None)
+                        } else if s.refused_templates.contains(&id) {
+                            // the JDS refused the job that the downstream is now mining
+                            s.set_new_prev_hash_counter -= 1;
+                            refused = true;
+                            Some(None)
                         } else {
                             s.future_jobs.remove(&id).map(
                                 |(job, merkle_path, template, pool_outs)| {
@@ -400,30 +613,31 @@ impl JobDeclarator {
                     .unwrap()
                 {
                     Some(Some(future_job_tuple)) => break future_job_tuple,
-                    Some(None) => return,
+                    Some(None) => {
+                        if refused {
+                            let up = self_mutex.safe_lock(|s| s.up.clone()).unwrap();
+                            Upstream::on_declared_job_refused(
+                                &up,
+                                id,
+                                Some(set_new_prev_hash.prev_hash),
+                            );
+                        }
+                        return;
+                    }
                     None => {}
                 };
                 tokio::task::yield_now().await;
             };
             let signed_token = job.mining_job_token.clone();
-            let mut template_outs = template.coinbase_tx_outputs.to_vec();
-            pool_outs.append(&mut template_outs);
-            Upstream::set_custom_jobs(
+            Self::set_custom_job(
                 &up,
                 job,
                 set_new_prev_hash,
-                merkle_path,
-                signed_token,
-                template.coinbase_tx_version,
-                template.coinbase_prefix,
-                template.coinbase_tx_input_sequence,
-                template.coinbase_tx_value_remaining,
+                template,
                 pool_outs,
-                template.coinbase_tx_locktime,
-                template.template_id,
+                signed_token,
             )
-            .await
-            .unwrap();
+            .await;
         });
     }
 
@@ -464,3 +678,39 @@ impl JobDeclarator {
         sender.send(frame.into()).await.unwrap();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use stratum_common::bitcoin::hashes::Hash;
+
+    fn txids(n: u8) -> Vec<Txid> {
+        (0..n)
+            .map(|i| Txid::from_slice(&[i; 32]).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn short_hash_list_matches_the_returned_nonce() {
+        let txids = txids(50);
+        let (nonce, short_hash_list) = JobDeclarator::short_hash_list(&txids);
+        assert_eq!(short_hash_list.len(), txids.len());
+        for (txid, short_hash) in txids.iter().zip(short_hash_list.iter()) {
+            assert_eq!(get_short_hash(*txid, nonce).to_vec(), short_hash.to_vec());
+        }
+    }
+
+    #[test]
+    fn short_hash_list_is_unique() {
+        let txids = txids(200);
+        let (_, short_hash_list) = JobDeclarator::short_hash_list(&txids);
+        let unique: HashSet<Vec<u8>> = short_hash_list.iter().map(|s| s.to_vec()).collect();
+        assert_eq!(unique.len(), txids.len());
+    }
+
+    #[test]
+    fn short_hash_list_of_empty_job() {
+        let (_, short_hash_list) = JobDeclarator::short_hash_list(&[]);
+        assert!(short_hash_list.is_empty());
+    }
+}
@@ -14,7 +14,7 @@ pub type EitherFrame = StandardEitherFrame<Message>;
 pub struct SetupConnectionHandler {}
 
 impl SetupConnectionHandler {
-    fn get_setup_connection_message(proxy_address: SocketAddr) -> SetupConnection<'static> {
+    pub fn get_setup_connection_message(proxy_address: SocketAddr) -> SetupConnection<'static> {
         let endpoint_host = proxy_address
             .ip()
             .to_string()
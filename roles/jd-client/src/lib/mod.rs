@@ -1,9 +1,11 @@
 pub mod downstream;
 pub mod error;
+pub mod fail_back;
 pub mod job_declarator;
 pub mod proxy_config;
 pub mod status;
 pub mod template_receiver;
+pub mod tx_selection;
 pub mod upstream_sv2;
 
 use std::{sync::atomic::AtomicBool, time::Duration};
@@ -35,6 +37,8 @@ pub static IS_NEW_TEMPLATE_HANDLED: AtomicBool = AtomicBool::new(true);
 pub struct PoolChangerTrigger {
     timeout: Duration,
     task: Option<tokio::task::JoinHandle<()>>,
+    // index of an higher priority upstream that is healthy again, see `fail_back`
+    fail_back_to: Option<usize>,
 }
 
 impl PoolChangerTrigger {
@@ -42,9 +46,19 @@ impl PoolChangerTrigger {
         Self {
             timeout,
             task: None,
+            fail_back_to: None,
         }
     }
 
+    /// Ask to switch back to the upstream with index `upstream_index` at the next job boundary.
+    pub fn set_fail_back(&mut self, upstream_index: usize) {
+        self.fail_back_to = Some(upstream_index);
+    }
+
+    pub fn take_fail_back(&mut self) -> Option<usize> {
+        self.fail_back_to.take()
+    }
+
     pub fn start(&mut self, sender: status::Sender) {
         let timeout = self.timeout;
         let task = tokio::task::spawn(async move {
@@ -1,13 +1,20 @@
 use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
-use roles_logic_sv2::{errors::Error, utils::CoinbaseOutput as CoinbaseOutput_};
+use roles_logic_sv2::{
+    errors::Error,
+    job_creator::{CoinbaseCommitment, OpReturnTag},
+    utils::{CoinbaseOutput as CoinbaseOutput_, CoinbaseOutputsRotation},
+};
 use serde::Deserialize;
-use std::time::Duration;
+use std::{net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
 use stratum_common::bitcoin::TxOut;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct CoinbaseOutput {
     output_script_type: String,
     output_script_value: String,
+    /// When set `output_script_value` is an extended public key and a key is derived for each
+    /// block found in solo mining, eg `m/0/*`
+    derivation_path: Option<String>,
 }
 
 impl TryFrom<&CoinbaseOutput> for CoinbaseOutput_ {
@@ -37,12 +44,95 @@ pub struct ProxyConfig {
     pub cert_validity_sec: u64,
     pub tp_address: String,
     pub tp_authority_public_key: Option<Secp256k1PublicKey>,
+    /// Template Providers used, in order, when the one in use is not reachable
+    #[serde(default)]
+    pub backup_template_providers: Vec<TemplateProvider>,
     pub retry: u32,
     pub upstreams: Vec<Upstream>,
     #[serde(deserialize_with = "duration_from_toml")]
     pub timeout: Duration,
     pub coinbase_outputs: Vec<CoinbaseOutput>,
+    /// File where the derivation index of the coinbase outputs is persisted
+    pub coinbase_derivation_index_file: Option<String>,
+    /// Tag committed in an OP_RETURN output of the coinbase, at most 80 bytes
+    #[serde(default)]
+    pub coinbase_op_return_tag: Option<String>,
     pub test_only_do_not_send_solution_to_tp: Option<bool>,
+    #[serde(default)]
+    pub fail_back: FailBackConfig,
+    #[serde(default)]
+    pub tx_selection: TxSelectionConfig,
+    #[serde(default)]
+    pub block_submission: BlockSubmissionConfig,
+}
+
+/// Fail-back to an upstream with an higher priority than the one in use (or than solo mining).
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct FailBackConfig {
+    /// Seconds between two probes of the higher priority upstreams, 0 disables fail-back.
+    #[serde(default = "u64::default")]
+    pub probe_interval_secs: u64,
+    /// Seconds an upstream must be continuously healthy before switching back to it.
+    #[serde(default = "u64::default")]
+    pub healthy_period_secs: u64,
+}
+
+/// Policy applied to the template transactions before declaring a job, see `tx_selection`.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct TxSelectionConfig {
+    /// Txids of the transactions that are never declared.
+    #[serde(default)]
+    pub excluded_txids: Vec<String>,
+    /// Hex prefixes of output scripts, transactions with a matching output are never declared.
+    #[serde(default)]
+    pub excluded_script_patterns: Vec<String>,
+    /// Minimum fee rate in sat/vB, 0 disables the check.
+    #[serde(default)]
+    pub min_fee_rate: f64,
+    /// Maximum vsize of a transaction plus its ancestors in the template, 0 disables the check.
+    #[serde(default = "u64::default")]
+    pub max_ancestor_package_vsize: u64,
+    /// bitcoind RPC used to get the transaction fees, needed when any rule is set.
+    #[serde(default)]
+    pub core_rpc_url: String,
+    #[serde(default)]
+    pub core_rpc_port: u16,
+    #[serde(default)]
+    pub core_rpc_user: String,
+    #[serde(default)]
+    pub core_rpc_pass: String,
+    /// Timeout of the requests to the node in seconds, 0 keeps the default of the RPC client.
+    #[serde(default)]
+    pub rpc_timeout_secs: u64,
+    /// Retries of a request that failed to reach the node.
+    #[serde(default)]
+    pub rpc_retries: u32,
+}
+
+/// Miner's own bitcoind, found blocks are also sent to it with `submitblock`. An empty
+/// `core_rpc_url` disables it.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct BlockSubmissionConfig {
+    #[serde(default)]
+    pub core_rpc_url: String,
+    #[serde(default)]
+    pub core_rpc_port: u16,
+    #[serde(default)]
+    pub core_rpc_user: String,
+    #[serde(default)]
+    pub core_rpc_pass: String,
+    /// Timeout of the requests to the node in seconds, 0 keeps the default of the RPC client.
+    #[serde(default)]
+    pub rpc_timeout_secs: u64,
+    /// Retries of a request that failed to reach the node, a block is never resent after a timeout.
+    #[serde(default)]
+    pub rpc_retries: u32,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TemplateProvider {
+    pub address: String,
+    pub authority_public_key: Option<Secp256k1PublicKey>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -82,7 +172,27 @@ where
     }
 }
 
+/// Returns `tp_address` followed by the backup Template Providers, or the first invalid address
+pub fn get_template_providers(
+    config: &ProxyConfig,
+) -> Result<Vec<(SocketAddr, Option<Secp256k1PublicKey>)>, String> {
+    let main = TemplateProvider {
+        address: config.tp_address.clone(),
+        authority_public_key: config.tp_authority_public_key,
+    };
+    std::iter::once(&main)
+        .chain(config.backup_template_providers.iter())
+        .map(|tp| match SocketAddr::from_str(&tp.address) {
+            Ok(address) => Ok((address, tp.authority_public_key)),
+            Err(_) => Err(tp.address.clone()),
+        })
+        .collect()
+}
+
 pub fn get_coinbase_output(config: &ProxyConfig) -> Result<Vec<TxOut>, Error> {
+    if let Some(rotation) = get_coinbase_rotation(config)? {
+        return rotation.outputs();
+    }
     let mut result = Vec::new();
     for coinbase_output_pool in &config.coinbase_outputs {
         let coinbase_output: CoinbaseOutput_ = coinbase_output_pool.try_into()?;
@@ -97,3 +207,96 @@ pub fn get_coinbase_output(config: &ProxyConfig) -> Result<Vec<TxOut>, Error> {
         _ => Ok(result),
     }
 }
+
+/// Providers of the extra coinbase data of the jobs, their outputs are placed after the miner (or
+/// pool) outputs
+pub fn get_coinbase_commitments(
+    config: &ProxyConfig,
+) -> Result<Vec<Arc<dyn CoinbaseCommitment>>, Error> {
+    let mut commitments: Vec<Arc<dyn CoinbaseCommitment>> = vec![];
+    if let Some(tag) = &config.coinbase_op_return_tag {
+        commitments.push(Arc::new(OpReturnTag::new(tag.as_bytes())?));
+    }
+    Ok(commitments)
+}
+
+/// Returns None when no coinbase output is derived from an extended public key
+pub fn get_coinbase_rotation(
+    config: &ProxyConfig,
+) -> Result<Option<CoinbaseOutputsRotation>, Error> {
+    let mut outputs = Vec::with_capacity(config.coinbase_outputs.len());
+    for coinbase_output_pool in &config.coinbase_outputs {
+        let coinbase_output: CoinbaseOutput_ = coinbase_output_pool.try_into()?;
+        outputs.push((
+            coinbase_output,
+            coinbase_output_pool.derivation_path.clone(),
+        ));
+    }
+    CoinbaseOutputsRotation::from_config(
+        outputs,
+        config
+            .coinbase_derivation_index_file
+            .clone()
+            .map(Into::into),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> ProxyConfig {
+        toml::from_str(include_str!(
+            "../../config-examples/jdc-config-local-example.toml"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn template_providers_in_order_of_priority() {
+        let mut config = config();
+        config.backup_template_providers = vec![
+            TemplateProvider {
+                address: "127.0.0.1:18442".to_string(),
+                authority_public_key: None,
+            },
+            TemplateProvider {
+                address: "[::1]:28442".to_string(),
+                authority_public_key: config.tp_authority_public_key,
+            },
+        ];
+        let template_providers = get_template_providers(&config).unwrap();
+        let addresses: Vec<String> = template_providers
+            .iter()
+            .map(|(address, _)| address.to_string())
+            .collect();
+        assert_eq!(
+            addresses,
+            vec![
+                config.tp_address.clone(),
+                "127.0.0.1:18442".to_string(),
+                "[::1]:28442".to_string()
+            ]
+        );
+        assert!(template_providers[1].1.is_none());
+    }
+
+    #[test]
+    fn invalid_template_provider_address() {
+        let mut config = config();
+        config.backup_template_providers = vec![TemplateProvider {
+            address: "localhost:8442".to_string(),
+            authority_public_key: None,
+        }];
+        assert_eq!(
+            get_template_providers(&config).err(),
+            Some("localhost:8442".to_string())
+        );
+        config.backup_template_providers.clear();
+        config.tp_address = "127.0.0.1".to_string();
+        assert_eq!(
+            get_template_providers(&config).err(),
+            Some("127.0.0.1".to_string())
+        );
+    }
+}
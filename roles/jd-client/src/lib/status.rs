@@ -1,4 +1,5 @@
 use super::error::{self, Error};
+use std::{collections::VecDeque, time::SystemTime};
 
 #[derive(Debug)]
 pub enum Sender {
@@ -38,6 +39,8 @@ pub enum State<'a> {
     DownstreamShutdown(Error<'a>),
     UpstreamShutdown(Error<'a>),
     UpstreamRogue,
+    /// An upstream with an higher priority than the one in use is healthy again
+    UpstreamRecovered(usize),
     Healthy(String),
 }
 
@@ -46,6 +49,140 @@ pub struct Status<'a> {
     pub state: State<'a>,
 }
 
+/// Upstream the JDC is mining with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActiveUpstream {
+    /// Index of the upstream in the config
+    Pool(usize),
+    Solo,
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolChange {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub from: ActiveUpstream,
+    pub to: ActiveUpstream,
+    pub reason: String,
+}
+
+/// How many pool changes are kept in `PoolHistory`
+const POOL_HISTORY_CAPACITY: usize = 100;
+
+/// Upstream in use, pool changes and solo mining episodes of the JDC, the oldest entries are
+/// dropped.
+#[derive(Debug)]
+pub struct PoolHistory {
+    // number of upstreams in the config, the JDC mines solo after the last one
+    upstreams: usize,
+    upstream_index: usize,
+    changes: VecDeque<PoolChange>,
+    // (start, end) in seconds since the unix epoch, end is None for the ongoing episode
+    solo_episodes: VecDeque<(u64, Option<u64>)>,
+}
+
+impl PoolHistory {
+    pub fn new(upstreams: usize) -> Self {
+        let mut pool_history = Self {
+            upstreams,
+            upstream_index: 0,
+            changes: VecDeque::new(),
+            solo_episodes: VecDeque::new(),
+        };
+        if upstreams == 0 {
+            pool_history.solo_episodes.push_back((now(), None));
+        }
+        pool_history
+    }
+
+    /// Index in the config of the upstream in use, equal to the number of upstreams when solo
+    pub fn upstream_index(&self) -> usize {
+        self.upstream_index
+    }
+
+    pub fn active(&self) -> ActiveUpstream {
+        self.active_upstream(self.upstream_index)
+    }
+
+    fn active_upstream(&self, index: usize) -> ActiveUpstream {
+        match index < self.upstreams {
+            true => ActiveUpstream::Pool(index),
+            false => ActiveUpstream::Solo,
+        }
+    }
+
+    /// The upstream in use is rogue, moves to the next one or to solo mining
+    pub fn on_upstream_rogue(&mut self) {
+        let index = (self.upstream_index + 1).min(self.upstreams);
+        self.record(index, "upstream rogue", now());
+    }
+
+    /// An higher priority upstream is healthy again, moves back to it
+    pub fn on_upstream_recovered(&mut self, index: usize) {
+        self.record(index, "fail-back to an higher priority upstream", now());
+    }
+
+    fn record(&mut self, index: usize, reason: &str, timestamp: u64) {
+        let from = self.active();
+        let to = self.active_upstream(index);
+        self.upstream_index = index;
+        tracing::info!("Pool change from {:?} to {:?}: {}", from, to, reason);
+        if from == ActiveUpstream::Solo {
+            if let Some((start, end @ None)) = self.solo_episodes.back_mut() {
+                *end = Some(timestamp);
+                tracing::info!(
+                    "Solo mining episode ended after {}s",
+                    timestamp.saturating_sub(*start)
+                );
+            }
+        }
+        if to == ActiveUpstream::Solo && from != ActiveUpstream::Solo {
+            if self.solo_episodes.len() == POOL_HISTORY_CAPACITY {
+                self.solo_episodes.pop_front();
+            }
+            self.solo_episodes.push_back((timestamp, None));
+        }
+        if self.changes.len() == POOL_HISTORY_CAPACITY {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(PoolChange {
+            timestamp,
+            from,
+            to,
+            reason: reason.to_string(),
+        });
+        tracing::info!(
+            "{} pool changes so far, {}s spent mining solo in past episodes",
+            self.changes.len(),
+            self.solo_secs()
+        );
+    }
+
+    pub fn changes(&self) -> impl Iterator<Item = &PoolChange> {
+        self.changes.iter()
+    }
+
+    pub fn solo_episodes(&self) -> impl Iterator<Item = &(u64, Option<u64>)> {
+        self.solo_episodes.iter()
+    }
+
+    /// Seconds spent mining solo in the episodes that ended
+    pub fn solo_secs(&self) -> u64 {
+        self.solo_episodes
+            .iter()
+            .filter_map(|(start, end)| end.map(|end| end.saturating_sub(*start)))
+            .sum()
+    }
+}
+
+/// Seconds since the unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 async fn send_status(
     sender: &Sender,
     e: error::Error<'static>,
@@ -135,3 +272,58 @@ pub async fn handle_error(
         Error::Infallible(_) => send_status(sender, e, error_handling::ErrorBranch::Break).await,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pool_history_records_pool_changes_and_solo_episodes() {
+        let mut pool_history = PoolHistory::new(2);
+        assert_eq!(pool_history.active(), ActiveUpstream::Pool(0));
+        pool_history.record(1, "upstream rogue", 10);
+        pool_history.record(2, "upstream rogue", 20);
+        assert_eq!(pool_history.active(), ActiveUpstream::Solo);
+        assert_eq!(
+            pool_history.solo_episodes().collect::<Vec<_>>(),
+            [&(20, None)]
+        );
+        // the ongoing episode is not counted
+        assert_eq!(pool_history.solo_secs(), 0);
+
+        pool_history.record(0, "fail-back to an higher priority upstream", 50);
+        assert_eq!(pool_history.active(), ActiveUpstream::Pool(0));
+        assert_eq!(pool_history.upstream_index(), 0);
+        assert_eq!(
+            pool_history.solo_episodes().collect::<Vec<_>>(),
+            [&(20, Some(50))]
+        );
+        assert_eq!(pool_history.solo_secs(), 30);
+        let changes: Vec<(ActiveUpstream, ActiveUpstream, u64)> = pool_history
+            .changes()
+            .map(|change| (change.from, change.to, change.timestamp))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (ActiveUpstream::Pool(0), ActiveUpstream::Pool(1), 10),
+                (ActiveUpstream::Pool(1), ActiveUpstream::Solo, 20),
+                (ActiveUpstream::Solo, ActiveUpstream::Pool(0), 50),
+            ]
+        );
+    }
+
+    #[test]
+    fn pool_history_is_bounded() {
+        let mut pool_history = PoolHistory::new(1);
+        for i in 0..POOL_HISTORY_CAPACITY as u64 + 1 {
+            pool_history.record(1, "upstream rogue", 2 * i);
+            pool_history.record(0, "fail-back to an higher priority upstream", 2 * i + 1);
+        }
+        assert_eq!(pool_history.changes().count(), POOL_HISTORY_CAPACITY);
+        assert_eq!(pool_history.solo_episodes().count(), POOL_HISTORY_CAPACITY);
+        // the oldest episode is dropped
+        assert_eq!(pool_history.solo_episodes().next(), Some(&(2, Some(3))));
+        assert_eq!(pool_history.solo_secs(), POOL_HISTORY_CAPACITY as u64);
+    }
+}
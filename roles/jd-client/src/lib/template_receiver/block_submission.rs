@@ -0,0 +1,421 @@
+//! Redundant submission of the found blocks to the miner's own node. The block is assembled from
+//! the template transactions received with RequestTransactionDataSuccess and sent with
+//! `submitblock`, in parallel with the SubmitSolution sent to the TP and the JDS.
+//!
+//! The found blocks are tracked in `FoundBlocks` until one of the paths confirms them, their hash
+//! is computed from the merkle path of the job so that it is known also without a local node.
+use super::super::proxy_config::BlockSubmissionConfig;
+use roles_logic_sv2::{template_distribution_sv2::SubmitSolution, utils::merkle_root_from_path_};
+use rpc_sv2::mini_rpc_client::{Auth, MiniRpcClient, RpcError};
+use std::{collections::VecDeque, fmt, time::Instant};
+use stratum_common::bitcoin::{
+    blockdata::block::BlockHeader, consensus::Decodable, hash_types::TxMerkleNode, hashes::Hash,
+    Block, BlockHash, Transaction,
+};
+use tracing::{error, info, warn};
+
+// How many templates are remembered, a solution always refer to one of the last templates
+const TEMPLATES_CAPACITY: usize = 10;
+// How many found blocks are waiting for a confirmation, stale blocks are never confirmed
+const FOUND_BLOCKS_CAPACITY: usize = 10;
+
+/// Path through which a found block reached the network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmissionPath {
+    /// The TP moved to the block, the node behind the TP accepted it
+    TemplateProvider,
+    /// The pool moved to the block, the node behind the JDS accepted it
+    Jds,
+    /// `submitblock` succeeded on the local node
+    LocalNode,
+}
+
+impl fmt::Display for SubmissionPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubmissionPath::TemplateProvider => write!(f, "the TP"),
+            SubmissionPath::Jds => write!(f, "the JDS"),
+            SubmissionPath::LocalNode => write!(f, "the local node"),
+        }
+    }
+}
+
+/// Found blocks not yet confirmed by any path, shared by the template receiver and the upstream
+#[derive(Debug, Default)]
+pub struct FoundBlocks {
+    // (template id, merkle path) of the last templates sent downstream
+    merkle_paths: VecDeque<(u64, Vec<Vec<u8>>)>,
+    // (prev hash, nbits) of the last SetNewPrevHash
+    prev_hash: Option<(BlockHash, u32)>,
+    // (block hash, time it has been found)
+    pending: VecDeque<(BlockHash, Instant)>,
+}
+
+impl FoundBlocks {
+    /// Stores the merkle path of a template sent downstream, after the tx selection
+    pub fn on_template(&mut self, template_id: u64, merkle_path: Vec<Vec<u8>>) {
+        self.merkle_paths.retain(|(id, _)| *id != template_id);
+        if self.merkle_paths.len() == TEMPLATES_CAPACITY {
+            self.merkle_paths.pop_front();
+        }
+        self.merkle_paths.push_back((template_id, merkle_path));
+    }
+
+    pub fn on_set_new_prev_hash(&mut self, prev_hash: BlockHash, n_bits: u32) {
+        self.prev_hash = Some((prev_hash, n_bits));
+    }
+
+    /// Computes the hash of the block of a solution and waits for its confirmation. Returns None
+    /// if the template or the prev hash are not known.
+    pub fn on_solution(&mut self, solution: &SubmitSolution<'static>) -> Option<BlockHash> {
+        let merkle_path = match self
+            .merkle_paths
+            .iter()
+            .find(|(id, _)| *id == solution.template_id)
+        {
+            Some((_, merkle_path)) => merkle_path,
+            None => {
+                warn!(
+                    "Merkle path of template {} unknown, the block can not be tracked",
+                    solution.template_id
+                );
+                return None;
+            }
+        };
+        let (prev_blockhash, bits) = self.prev_hash?;
+        let coinbase_tx = solution.coinbase_tx.to_vec();
+        let coinbase = Transaction::consensus_decode(&mut &coinbase_tx[..]).ok()?;
+        let merkle_root = merkle_root_from_path_(coinbase.txid().into_inner(), merkle_path);
+        let header = BlockHeader {
+            version: solution.version as i32,
+            prev_blockhash,
+            merkle_root: TxMerkleNode::from_inner(merkle_root),
+            time: solution.header_timestamp,
+            bits,
+            nonce: solution.header_nonce,
+        };
+        let hash = header.block_hash();
+        self.on_found(hash);
+        Some(hash)
+    }
+
+    fn on_found(&mut self, hash: BlockHash) {
+        if self.pending.iter().any(|(pending, _)| *pending == hash) {
+            return;
+        }
+        if self.pending.len() == FOUND_BLOCKS_CAPACITY {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((hash, Instant::now()));
+    }
+
+    /// Logs the path that confirmed the block first. Returns false if `hash` is not a pending
+    /// found block, e.g. when another path already confirmed it.
+    pub fn on_confirmed(&mut self, hash: BlockHash, path: SubmissionPath) -> bool {
+        match self
+            .pending
+            .iter()
+            .position(|(pending, _)| *pending == hash)
+        {
+            Some(index) => {
+                let (_, found_at) = self.pending.remove(index).unwrap();
+                info!(
+                    "Block {} confirmed first by {}, {:?} after it has been found",
+                    hash,
+                    path,
+                    found_at.elapsed()
+                );
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LocalNode {
+    client: MiniRpcClient,
+    // (template id, transactions of the template)
+    templates: VecDeque<(u64, Vec<Transaction>)>,
+    // (prev hash, nbits) of the last SetNewPrevHash
+    prev_hash: Option<(BlockHash, u32)>,
+}
+
+impl LocalNode {
+    /// Returns None when no node is configured
+    pub fn new(config: &BlockSubmissionConfig) -> Option<Self> {
+        if config.core_rpc_url.is_empty() {
+            return None;
+        }
+        let url = config.core_rpc_url.clone() + ":" + &config.core_rpc_port.to_string();
+        let auth = Auth::new(config.core_rpc_user.clone(), config.core_rpc_pass.clone());
+        Some(Self {
+            client: MiniRpcClient::new(url, auth)
+                .with_options(config.rpc_timeout_secs, config.rpc_retries),
+            templates: VecDeque::with_capacity(TEMPLATES_CAPACITY),
+            prev_hash: None,
+        })
+    }
+
+    pub fn client(&self) -> MiniRpcClient {
+        self.client.clone()
+    }
+
+    /// Stores the transactions of a template, the ones that are declared
+    pub fn on_transactions(&mut self, template_id: u64, transactions: Vec<Transaction>) {
+        self.templates.retain(|(id, _)| *id != template_id);
+        if self.templates.len() == TEMPLATES_CAPACITY {
+            self.templates.pop_front();
+        }
+        self.templates.push_back((template_id, transactions));
+    }
+
+    pub fn on_set_new_prev_hash(&mut self, prev_hash: BlockHash, n_bits: u32) {
+        self.prev_hash = Some((prev_hash, n_bits));
+    }
+
+    /// Called with the result of `submitblock`, returns true if the node accepted the block
+    pub fn on_submit_block_result(&self, hash: BlockHash, result: Result<(), RpcError>) -> bool {
+        match result {
+            Ok(()) => {
+                info!("Block {} accepted by the local node", hash);
+                true
+            }
+            // the block has already been received by the node from another path
+            Err(RpcError::Other(reason)) if reason == "duplicate" => {
+                info!("Block {} already known by the local node", hash);
+                false
+            }
+            Err(e) => {
+                error!("Local node refused block {}: {:?}", hash, e);
+                false
+            }
+        }
+    }
+
+    /// Assembles the block of a solution, returns None if the transactions of the template or
+    /// the prev hash are not known
+    pub fn build_block(&self, solution: &SubmitSolution<'static>) -> Option<Block> {
+        let transactions = match self
+            .templates
+            .iter()
+            .find(|(id, _)| *id == solution.template_id)
+        {
+            Some((_, transactions)) => transactions.clone(),
+            None => {
+                warn!(
+                    "Transactions of template {} unknown, the block is not sent to the local node",
+                    solution.template_id
+                );
+                return None;
+            }
+        };
+        let (prev_blockhash, bits) = self.prev_hash?;
+        let coinbase_tx = solution.coinbase_tx.to_vec();
+        let coinbase = match Transaction::consensus_decode(&mut &coinbase_tx[..]) {
+            Ok(coinbase) => coinbase,
+            Err(e) => {
+                error!("Invalid coinbase in solution: {:?}", e);
+                return None;
+            }
+        };
+        let mut txdata = Vec::with_capacity(transactions.len() + 1);
+        txdata.push(coinbase);
+        txdata.extend(transactions);
+        let mut block = Block {
+            header: BlockHeader {
+                version: solution.version as i32,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: solution.header_timestamp,
+                bits,
+                nonce: solution.header_nonce,
+            },
+            txdata,
+        };
+        // txdata contains at least the coinbase
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use roles_logic_sv2::utils::merkle_path_from_txids;
+    use stratum_common::bitcoin::{
+        blockdata::{script::Script, witness::Witness},
+        consensus::serialize,
+        OutPoint, PackedLockTime, Sequence, TxIn, TxOut, Txid,
+    };
+
+    fn local_node() -> LocalNode {
+        LocalNode::new(&BlockSubmissionConfig {
+            core_rpc_url: "http://127.0.0.1".to_string(),
+            core_rpc_port: 18443,
+            core_rpc_user: "user".to_string(),
+            core_rpc_pass: "pass".to_string(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn tx(tag: u32) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::from(tag.to_le_bytes().to_vec()),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: 50,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    fn solution(template_id: u64, coinbase: &Transaction) -> SubmitSolution<'static> {
+        SubmitSolution {
+            template_id,
+            version: 0x2000_0000,
+            header_timestamp: 1_700_000_000,
+            header_nonce: 42,
+            coinbase_tx: serialize(coinbase).try_into().unwrap(),
+        }
+    }
+
+    fn block_hash(tag: u8) -> BlockHash {
+        BlockHash::from_slice(&[tag; 32]).unwrap()
+    }
+
+    #[test]
+    fn disabled_without_url() {
+        assert!(LocalNode::new(&BlockSubmissionConfig::default()).is_none());
+    }
+
+    #[test]
+    fn build_block() {
+        let mut local_node = local_node();
+        let transactions = vec![tx(1), tx(2), tx(3)];
+        local_node.on_transactions(7, transactions.clone());
+        local_node.on_set_new_prev_hash(block_hash(9), 0x1d00_ffff);
+        let coinbase = tx(0);
+
+        let block = local_node.build_block(&solution(7, &coinbase)).unwrap();
+        assert_eq!(block.txdata[0], coinbase);
+        assert_eq!(&block.txdata[1..], &transactions[..]);
+        assert!(block.check_merkle_root());
+        assert_eq!(block.header.version, 0x2000_0000);
+        assert_eq!(block.header.prev_blockhash, block_hash(9));
+        assert_eq!(block.header.time, 1_700_000_000);
+        assert_eq!(block.header.bits, 0x1d00_ffff);
+        assert_eq!(block.header.nonce, 42);
+    }
+
+    #[test]
+    fn build_block_with_unknown_template() {
+        let mut local_node = local_node();
+        local_node.on_set_new_prev_hash(block_hash(9), 0x1d00_ffff);
+        local_node.on_transactions(7, vec![tx(1)]);
+        assert!(local_node.build_block(&solution(8, &tx(0))).is_none());
+    }
+
+    #[test]
+    fn build_block_without_prev_hash() {
+        let mut local_node = local_node();
+        local_node.on_transactions(7, vec![tx(1)]);
+        assert!(local_node.build_block(&solution(7, &tx(0))).is_none());
+    }
+
+    #[test]
+    fn build_block_with_invalid_coinbase() {
+        let mut local_node = local_node();
+        local_node.on_transactions(7, vec![tx(1)]);
+        local_node.on_set_new_prev_hash(block_hash(9), 0x1d00_ffff);
+        let mut solution = solution(7, &tx(0));
+        solution.coinbase_tx = vec![1, 2, 3].try_into().unwrap();
+        assert!(local_node.build_block(&solution).is_none());
+    }
+
+    #[test]
+    fn only_the_last_templates_are_remembered() {
+        let mut local_node = local_node();
+        local_node.on_set_new_prev_hash(block_hash(9), 0x1d00_ffff);
+        for template_id in 0..=TEMPLATES_CAPACITY as u64 {
+            local_node.on_transactions(template_id, vec![tx(template_id as u32)]);
+        }
+        assert!(local_node.build_block(&solution(0, &tx(0))).is_none());
+        assert!(local_node.build_block(&solution(1, &tx(0))).is_some());
+    }
+
+    #[test]
+    fn submit_block_result() {
+        let local_node = local_node();
+        assert!(local_node.on_submit_block_result(block_hash(1), Ok(())));
+        assert!(!local_node
+            .on_submit_block_result(block_hash(1), Err(RpcError::Other("duplicate".to_string()))));
+        assert!(!local_node
+            .on_submit_block_result(block_hash(1), Err(RpcError::Other("high-hash".to_string()))));
+        assert!(!local_node.on_submit_block_result(block_hash(1), Err(RpcError::Timeout)));
+    }
+
+    #[test]
+    fn found_block_hash_is_the_one_of_the_local_node_block() {
+        let mut local_node = local_node();
+        let mut found_blocks = FoundBlocks::default();
+        let transactions = vec![tx(1), tx(2), tx(3)];
+        // the merkle path of the coinbase, as sent by the TP
+        let txids: Vec<Txid> = transactions.iter().map(|t| t.txid()).collect();
+        let merkle_path = merkle_path_from_txids(&txids)
+            .iter()
+            .map(|node| node.to_vec())
+            .collect();
+        local_node.on_transactions(7, transactions);
+        local_node.on_set_new_prev_hash(block_hash(9), 0x1d00_ffff);
+        found_blocks.on_template(7, merkle_path);
+        found_blocks.on_set_new_prev_hash(block_hash(9), 0x1d00_ffff);
+
+        let solution = solution(7, &tx(0));
+        let hash = found_blocks.on_solution(&solution).unwrap();
+        assert_eq!(
+            hash,
+            local_node.build_block(&solution).unwrap().block_hash()
+        );
+        assert!(found_blocks.on_confirmed(hash, SubmissionPath::TemplateProvider));
+    }
+
+    #[test]
+    fn solution_of_unknown_template_is_not_tracked() {
+        let mut found_blocks = FoundBlocks::default();
+        found_blocks.on_set_new_prev_hash(block_hash(9), 0x1d00_ffff);
+        found_blocks.on_template(7, vec![]);
+        assert!(found_blocks.on_solution(&solution(8, &tx(0))).is_none());
+        assert!(found_blocks.on_solution(&solution(7, &tx(0))).is_some());
+    }
+
+    #[test]
+    fn found_block_is_confirmed_once() {
+        let mut found_blocks = FoundBlocks::default();
+        found_blocks.on_found(block_hash(1));
+        assert!(!found_blocks.on_confirmed(block_hash(2), SubmissionPath::TemplateProvider));
+        assert!(found_blocks.on_confirmed(block_hash(1), SubmissionPath::Jds));
+        assert!(!found_blocks.on_confirmed(block_hash(1), SubmissionPath::TemplateProvider));
+        assert!(!found_blocks.on_confirmed(block_hash(1), SubmissionPath::LocalNode));
+    }
+
+    #[test]
+    fn stale_found_blocks_are_dropped() {
+        let mut found_blocks = FoundBlocks::default();
+        for tag in 0..=FOUND_BLOCKS_CAPACITY as u8 {
+            found_blocks.on_found(block_hash(tag));
+        }
+        assert!(!found_blocks.on_confirmed(block_hash(0), SubmissionPath::LocalNode));
+        assert!(found_blocks.on_confirmed(
+            block_hash(FOUND_BLOCKS_CAPACITY as u8),
+            SubmissionPath::LocalNode
+        ));
+    }
+}
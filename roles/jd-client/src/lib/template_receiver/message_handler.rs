@@ -8,14 +8,15 @@ use roles_logic_sv2::{
 
 impl ParseServerTemplateDistributionMessages for TemplateRx {
     fn handle_new_template(&mut self, m: NewTemplate) -> Result<SendTo, Error> {
-        let new_template = m.into_static();
+        let mut new_template = m.into_static();
+        new_template.template_id = self.template_ids.on_new_template(new_template.template_id);
         let new_template = TemplateDistribution::NewTemplate(new_template);
         Ok(SendTo::None(Some(new_template)))
     }
 
     fn handle_set_new_prev_hash(&mut self, m: SetNewPrevHash) -> Result<SendTo, Error> {
         let new_prev_hash = SetNewPrevHash {
-            template_id: m.template_id,
+            template_id: self.template_ids.shift(m.template_id),
             prev_hash: m.prev_hash.into_static(),
             header_timestamp: m.header_timestamp,
             n_bits: m.n_bits,
@@ -33,7 +34,7 @@ impl ParseServerTemplateDistributionMessages for TemplateRx {
         let m = RequestTransactionDataSuccess {
             transaction_list: m.transaction_list.into_static(),
             excess_data: m.excess_data.into_static(),
-            template_id: m.template_id,
+            template_id: self.template_ids.shift(m.template_id),
         };
         let tx_received = TemplateDistribution::RequestTransactionDataSuccess(m);
         Ok(SendTo::None(Some(tx_received)))
@@ -44,7 +45,7 @@ impl ParseServerTemplateDistributionMessages for TemplateRx {
         _m: RequestTransactionDataError,
     ) -> Result<SendTo, Error> {
         let m = RequestTransactionDataError {
-            template_id: _m.template_id,
+            template_id: self.template_ids.shift(_m.template_id),
             error_code: _m.error_code.into_static(),
         };
         let error_code_string =
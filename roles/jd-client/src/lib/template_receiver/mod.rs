@@ -1,32 +1,91 @@
-use super::{job_declarator::JobDeclarator, status, PoolChangerTrigger};
+use super::{
+    job_declarator::JobDeclarator, status, tx_selection::TxSelectionPolicy, PoolChangerTrigger,
+};
 use async_channel::{Receiver, Sender};
+use binary_sv2::{Seq0255, Seq064K, B016M, U256};
 use codec_sv2::{Frame, HandshakeRole, Initiator, StandardEitherFrame, StandardSv2Frame};
 use error_handling::handle_result;
 use key_utils::Secp256k1PublicKey;
 use network_helpers_sv2::noise_connection_tokio::Connection;
 use roles_logic_sv2::{
     handlers::{template_distribution::ParseServerTemplateDistributionMessages, SendTo_},
+    job_creator::{
+        coinbase_output_max_additional_size, template_coinbase_outputs, CoinbaseCommitment,
+    },
     job_declaration_sv2::AllocateMiningJobTokenSuccess,
     parsers::{PoolMessages, TemplateDistribution},
     template_distribution_sv2::{
-        CoinbaseOutputDataSize, NewTemplate, RequestTransactionData, SubmitSolution,
+        CoinbaseOutputDataSize, NewTemplate, RequestTransactionData, SetNewPrevHash, SubmitSolution,
+    },
+    utils::{
+        merkle_path_from_txids, u256_to_block_hash, witness_commitment_script,
+        CoinbaseOutputsRotation, Mutex, WITNESS_COMMITMENT_HEADER,
     },
-    utils::Mutex,
 };
 use setup_connection::SetupConnectionHandler;
-use std::{convert::TryInto, net::SocketAddr, sync::Arc};
-use stratum_common::bitcoin::{consensus::Encodable, TxOut};
-use tokio::task::AbortHandle;
+use std::{collections::VecDeque, convert::TryInto, net::SocketAddr, sync::Arc, time::Duration};
+use stratum_common::bitcoin::{
+    consensus::{encode::serialize_hex, Encodable},
+    util::psbt::serialize::Deserialize,
+    Transaction, TxOut, Txid,
+};
+use tokio::{net::TcpStream, task::AbortHandle, time::timeout};
 use tracing::{error, info, warn};
 
+pub mod block_submission;
 mod message_handler;
 mod setup_connection;
 
+use block_submission::{FoundBlocks, LocalNode, SubmissionPath};
+
 pub type SendTo = SendTo_<roles_logic_sv2::parsers::TemplateDistribution<'static>, ()>;
 pub type Message = PoolMessages<'static>;
 pub type StdFrame = StandardSv2Frame<Message>;
 pub type EitherFrame = StandardEitherFrame<Message>;
 
+// How many filtered template ids are remembered, see `TemplateRx::filtered_templates`
+const FILTERED_TEMPLATES_CAPACITY: usize = 10;
+// When the connection to the TP is lost each Template Provider is tried again every
+// TP_RECONNECT_INTERVAL for TP_RECONNECT_ROUNDS rounds, after that the JDC restarts
+const TP_RECONNECT_ROUNDS: usize = 12;
+const TP_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+// Each step of the connection to a TP (TCP connection, noise handshake, SetupConnection) must
+// complete in this time, so that a TP that accepts the connection and hangs is skipped
+const TP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Template ids of a TP are only unique for that connection, the ids received after a
+/// reconnection are shifted by an offset so that they never clash with the previous ones
+#[derive(Debug, Default)]
+struct TemplateIds {
+    offset: u64,
+    // Greatest (shifted) template id received
+    last: u64,
+}
+
+impl TemplateIds {
+    /// Shifted id of a NewTemplate
+    fn on_new_template(&mut self, tp_template_id: u64) -> u64 {
+        let template_id = tp_template_id + self.offset;
+        self.last = self.last.max(template_id);
+        template_id
+    }
+
+    /// Shifted id of the other messages of the TP, they refer to a template already received
+    fn shift(&self, tp_template_id: u64) -> u64 {
+        tp_template_id + self.offset
+    }
+
+    /// Id of the template for the TP in use, None if it has been received from a previous
+    /// connection
+    fn to_tp(&self, template_id: u64) -> Option<u64> {
+        template_id.checked_sub(self.offset)
+    }
+
+    fn on_reconnect(&mut self) {
+        self.offset = self.last + 1;
+    }
+}
+
 pub struct TemplateRx {
     receiver: Receiver<EitherFrame>,
     sender: Sender<EitherFrame>,
@@ -40,12 +99,29 @@ pub struct TemplateRx {
     pool_chaneger_trigger: Arc<Mutex<PoolChangerTrigger>>,
     miner_coinbase_output: Vec<u8>,
     test_only_do_not_send_solution_to_tp: bool,
+    tx_selection: Option<Arc<TxSelectionPolicy>>,
+    // Templates whose transactions have been filtered by the tx selection policy, the TP would
+    // build the block with all the template transactions so solutions for these templates are
+    // not sent to it
+    filtered_templates: VecDeque<u64>,
+    // Miner's own node, found blocks are also submitted to it
+    local_node: Option<LocalNode>,
+    found_blocks: Arc<Mutex<FoundBlocks>>,
+    // When set, `miner_coinbase_output` pays to a fresh key after each found block
+    coinbase_rotation: Option<CoinbaseOutputsRotation>,
+    // (address, authority public key) of the Template Providers, in order of priority
+    template_providers: Vec<(SocketAddr, Option<Secp256k1PublicKey>)>,
+    // Index in `template_providers` of the TP in use
+    current_tp: usize,
+    template_ids: TemplateIds,
+    // Providers of extra coinbase data, their outputs are declared after the pool ones
+    coinbase_commitments: Vec<Arc<dyn CoinbaseCommitment>>,
 }
 
 impl TemplateRx {
     #[allow(clippy::too_many_arguments)]
     pub async fn connect(
-        address: SocketAddr,
+        template_providers: Vec<(SocketAddr, Option<Secp256k1PublicKey>)>,
         solution_receiver: Receiver<SubmitSolution<'static>>,
         tx_status: status::Sender,
         jd: Option<Arc<Mutex<super::job_declarator::JobDeclarator>>>,
@@ -53,30 +129,20 @@ impl TemplateRx {
         task_collector: Arc<Mutex<Vec<AbortHandle>>>,
         pool_chaneger_trigger: Arc<Mutex<PoolChangerTrigger>>,
         miner_coinbase_outputs: Vec<TxOut>,
-        authority_public_key: Option<Secp256k1PublicKey>,
         test_only_do_not_send_solution_to_tp: bool,
+        tx_selection: Option<Arc<TxSelectionPolicy>>,
+        local_node: Option<LocalNode>,
+        found_blocks: Arc<Mutex<FoundBlocks>>,
+        coinbase_rotation: Option<CoinbaseOutputsRotation>,
+        coinbase_commitments: Vec<Arc<dyn CoinbaseCommitment>>,
     ) {
         let mut encoded_outputs = vec![];
         miner_coinbase_outputs
             .consensus_encode(&mut encoded_outputs)
             .expect("Invalid coinbase output in config");
-        let stream = tokio::net::TcpStream::connect(address).await.unwrap();
-
-        let initiator = match authority_public_key {
-            Some(pub_key) => Initiator::from_raw_k(pub_key.into_bytes()),
-            None => Initiator::without_pk(),
-        }
-        .unwrap();
-        let (mut receiver, mut sender, _, _) =
-            Connection::new(stream, HandshakeRole::Initiator(initiator))
-                .await
-                .unwrap();
-
-        info!("Template Receiver try to set up connection");
-        SetupConnectionHandler::setup(&mut receiver, &mut sender, address)
+        let (receiver, sender, current_tp) = Self::connect_to_any_tp(&template_providers, 0)
             .await
-            .unwrap();
-        info!("Template Receiver connection set up");
+            .expect("No Template Provider reachable");
 
         let self_mutex = Arc::new(Mutex::new(Self {
             receiver: receiver.clone(),
@@ -89,6 +155,15 @@ impl TemplateRx {
             pool_chaneger_trigger,
             miner_coinbase_output: encoded_outputs,
             test_only_do_not_send_solution_to_tp,
+            tx_selection,
+            filtered_templates: VecDeque::with_capacity(FILTERED_TEMPLATES_CAPACITY),
+            local_node,
+            found_blocks,
+            coinbase_rotation,
+            template_providers,
+            current_tp,
+            template_ids: TemplateIds::default(),
+            coinbase_commitments,
         }));
 
         let task = tokio::task::spawn(Self::on_new_solution(self_mutex.clone(), solution_receiver));
@@ -98,19 +173,128 @@ impl TemplateRx {
         Self::start_templates(self_mutex);
     }
 
+    async fn connect_to_tp(
+        address: SocketAddr,
+        authority_public_key: Option<Secp256k1PublicKey>,
+    ) -> Option<(Receiver<EitherFrame>, Sender<EitherFrame>)> {
+        let stream = match timeout(TP_CONNECT_TIMEOUT, TcpStream::connect(address)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                warn!("Failed to connect to the TP at {}: {}", address, e);
+                return None;
+            }
+            Err(_) => {
+                warn!("Connection to the TP at {} timed out", address);
+                return None;
+            }
+        };
+        let initiator = match authority_public_key {
+            Some(pub_key) => Initiator::from_raw_k(pub_key.into_bytes()),
+            None => Initiator::without_pk(),
+        }
+        .expect("Invalid TP authority public key");
+        let (mut receiver, mut sender, _, _) = match timeout(
+            TP_CONNECT_TIMEOUT,
+            Connection::new(stream, HandshakeRole::Initiator(initiator)),
+        )
+        .await
+        {
+            Ok(Ok(connection)) => connection,
+            Ok(Err(e)) => {
+                warn!("Noise handshake with the TP at {} failed: {:?}", address, e);
+                return None;
+            }
+            Err(_) => {
+                warn!("Noise handshake with the TP at {} timed out", address);
+                return None;
+            }
+        };
+
+        info!("Template Receiver try to set up connection");
+        match timeout(
+            TP_CONNECT_TIMEOUT,
+            SetupConnectionHandler::setup(&mut receiver, &mut sender, address),
+        )
+        .await
+        {
+            Ok(Ok(())) => (),
+            Ok(Err(_)) => {
+                warn!("Failed to set up the connection with the TP at {}", address);
+                return None;
+            }
+            Err(_) => {
+                warn!("Connection setup with the TP at {} timed out", address);
+                return None;
+            }
+        }
+        info!("Template Receiver connection set up with {}", address);
+        Some((receiver, sender))
+    }
+
+    /// Tries the Template Providers in order starting from `first`, returns the connection and
+    /// the index of the connected TP
+    async fn connect_to_any_tp(
+        template_providers: &[(SocketAddr, Option<Secp256k1PublicKey>)],
+        first: usize,
+    ) -> Option<(Receiver<EitherFrame>, Sender<EitherFrame>, usize)> {
+        for i in 0..template_providers.len() {
+            let index = (first + i) % template_providers.len();
+            let (address, authority_public_key) = template_providers[index];
+            if let Some((receiver, sender)) =
+                Self::connect_to_tp(address, authority_public_key).await
+            {
+                return Some((receiver, sender, index));
+            }
+        }
+        None
+    }
+
+    /// Called when the connection with the TP is lost, the TP in use is tried first since it
+    /// may just have restarted. Downstream and upstream sessions are kept, the jobs are updated
+    /// with the next NewTemplate and SetNewPrevHash. Returns None if no TP is reachable.
+    async fn reconnect(self_mutex: &Arc<Mutex<Self>>) -> Option<()> {
+        let (template_providers, current_tp) = self_mutex
+            .safe_lock(|s| (s.template_providers.clone(), s.current_tp))
+            .unwrap();
+        for round in 0..TP_RECONNECT_ROUNDS {
+            if round > 0 {
+                tokio::time::sleep(TP_RECONNECT_INTERVAL).await;
+            }
+            if let Some((receiver, sender, index)) =
+                Self::connect_to_any_tp(&template_providers, current_tp).await
+            {
+                info!("Reconnected to the TP at {}", template_providers[index].0);
+                self_mutex
+                    .safe_lock(|s| {
+                        s.receiver = receiver;
+                        s.sender = sender;
+                        s.current_tp = index;
+                        s.template_ids.on_reconnect();
+                    })
+                    .unwrap();
+                return Some(());
+            }
+        }
+        None
+    }
+
     pub async fn send(self_: &Arc<Mutex<Self>>, sv2_frame: StdFrame) {
         let either_frame = sv2_frame.into();
         let sender_to_tp = self_.safe_lock(|self_| self_.sender.clone()).unwrap();
-        match sender_to_tp.send(either_frame).await {
-            Ok(_) => (),
-            Err(e) => panic!("{:?}", e),
+        if let Err(e) = sender_to_tp.send(either_frame).await {
+            // The receiving loop reconnects to a TP
+            error!("Impossible to send a message to the TP: {:?}", e);
         }
     }
 
+    /// `size` is the one of the pool outputs, the one of the commitments is added
     pub async fn send_max_coinbase_size(self_mutex: &Arc<Mutex<Self>>, size: u32) {
+        let commitments_size = self_mutex
+            .safe_lock(|s| coinbase_output_max_additional_size(&[], &s.coinbase_commitments))
+            .unwrap();
         let coinbase_output_data_size = PoolMessages::TemplateDistribution(
             TemplateDistribution::CoinbaseOutputDataSize(CoinbaseOutputDataSize {
-                coinbase_output_max_additional_size: size,
+                coinbase_output_max_additional_size: size + commitments_size,
             }),
         );
         let frame: StdFrame = coinbase_output_data_size.try_into().unwrap();
@@ -121,10 +305,13 @@ impl TemplateRx {
         self_mutex: &Arc<Mutex<Self>>,
         new_template: NewTemplate<'static>,
     ) {
+        // the template has just been received from the TP in use
+        let template_id = self_mutex
+            .safe_lock(|s| s.template_ids.to_tp(new_template.template_id))
+            .unwrap()
+            .unwrap();
         let tx_data_request = PoolMessages::TemplateDistribution(
-            TemplateDistribution::RequestTransactionData(RequestTransactionData {
-                template_id: new_template.template_id,
-            }),
+            TemplateDistribution::RequestTransactionData(RequestTransactionData { template_id }),
         );
         let frame: StdFrame = tx_data_request.try_into().unwrap();
         Self::send(self_mutex, frame).await;
@@ -153,16 +340,22 @@ impl TemplateRx {
         let tx_status = self_mutex.safe_lock(|s| s.tx_status.clone()).unwrap();
         let mut coinbase_output_max_additional_size_sent = false;
         let mut last_token = None;
-        let miner_coinbase_output = self_mutex
-            .safe_lock(|s| s.miner_coinbase_output.clone())
-            .unwrap();
+        // With a tx selection policy the job of a template is sent downstream only once its
+        // transactions have been filtered, so that the downstream never mines a job that
+        // commits to transactions that are not declared. (template, pool output) of the job
+        // waiting for the transactions, and the SetNewPrevHash that activates it if received
+        // meanwhile.
+        let mut deferred_job: Option<(NewTemplate<'static>, Vec<u8>)> = None;
+        let mut pending_prev_hash: Option<SetNewPrevHash<'static>> = None;
         let main_task = {
             let self_mutex = self_mutex.clone();
             tokio::task::spawn(async move {
                 // Send CoinbaseOutputDataSize size to TP
                 loop {
                     if last_token.is_none() {
-                        let jd = self_mutex.safe_lock(|s| s.jd.clone()).unwrap();
+                        let (jd, miner_coinbase_output) = self_mutex
+                            .safe_lock(|s| (s.jd.clone(), s.miner_coinbase_output.clone()))
+                            .unwrap();
                         last_token =
                             Some(Self::get_last_token(jd, &miner_coinbase_output[..]).await);
                     }
@@ -183,7 +376,23 @@ impl TemplateRx {
                         .clone()
                         .safe_lock(|s| s.receiver.clone())
                         .unwrap();
-                    let received = handle_result!(tx_status.clone(), receiver.recv().await);
+                    let received = match receiver.recv().await {
+                        Ok(received) => received,
+                        Err(e) => {
+                            error!("Connection with the TP lost: {:?}", e);
+                            handle_result!(
+                                tx_status.clone(),
+                                Self::reconnect(&self_mutex).await.ok_or(e)
+                            );
+                            // The transactions of the old TP templates will never be received,
+                            // the new TP sends a new template and prev hash
+                            deferred_job = None;
+                            pending_prev_hash = None;
+                            // The new TP needs the CoinbaseOutputDataSize before sending templates
+                            coinbase_output_max_additional_size_sent = false;
+                            continue;
+                        }
+                    };
                     let mut frame: StdFrame =
                         handle_result!(tx_status.clone(), received.try_into());
                     let message_type = frame.get_header().unwrap().msg_type();
@@ -201,16 +410,44 @@ impl TemplateRx {
                                 // Send the new template along with the token to the JD so that JD can
                                 // declare the mining job
                                 Some(TemplateDistribution::NewTemplate(m)) => {
-                                    // See coment on the definition of the global for memory
-                                    // ordering
-                                    super::IS_NEW_TEMPLATE_HANDLED
-                                        .store(false, std::sync::atomic::Ordering::Release);
+                                    // In solo mining the miner outputs may have been rotated
+                                    // after the token has been taken
+                                    if jd.is_none() {
+                                        let miner_coinbase_output = self_mutex
+                                            .safe_lock(|s| s.miner_coinbase_output.clone())
+                                            .unwrap();
+                                        last_token = Some(
+                                            Self::get_last_token(None, &miner_coinbase_output[..])
+                                                .await,
+                                        );
+                                    }
                                     Self::send_tx_data_request(&self_mutex, m.clone()).await;
                                     self_mutex
                                         .safe_lock(|t| t.new_template_message = Some(m.clone()))
                                         .unwrap();
                                     let token = last_token.clone().unwrap();
                                     let pool_output = token.coinbase_output.to_vec();
+                                    if self_mutex.safe_lock(|s| s.tx_selection.is_some()).unwrap() {
+                                        // The transactions of the previous template have not been
+                                        // received, its job is sent with all of them. It is not
+                                        // filtered so the TP can propagate its blocks.
+                                        if let Some((template, pool_output)) = deferred_job.take() {
+                                            Self::send_deferred_job(
+                                                &self_mutex,
+                                                template,
+                                                &pool_output[..],
+                                                &mut pending_prev_hash,
+                                            )
+                                            .await;
+                                        }
+                                        deferred_job = Some((m, pool_output));
+                                        continue;
+                                    }
+                                    // See coment on the definition of the global for memory
+                                    // ordering
+                                    super::IS_NEW_TEMPLATE_HANDLED
+                                        .store(false, std::sync::atomic::Ordering::Release);
+                                    Self::track_template(&self_mutex, &m);
                                     super::downstream::DownstreamMiningNode::on_new_template(
                                         &down,
                                         m.clone(),
@@ -220,26 +457,34 @@ impl TemplateRx {
                                     .unwrap();
                                 }
                                 Some(TemplateDistribution::SetNewPrevHash(m)) => {
-                                    info!("Received SetNewPrevHash, waiting for IS_NEW_TEMPLATE_HANDLED");
-                                    // See coment on the definition of the global for memory
-                                    // ordering
-                                    while !super::IS_NEW_TEMPLATE_HANDLED
-                                        .load(std::sync::atomic::Ordering::Acquire)
-                                    {
-                                        tokio::task::yield_now().await;
+                                    // A new prev hash is the job boundary at which we switch back
+                                    // to an higher priority upstream, see `fail_back`
+                                    let fail_back = self_mutex
+                                        .safe_lock(|s| {
+                                            s.pool_chaneger_trigger
+                                                .safe_lock(|t| t.take_fail_back())
+                                                .unwrap()
+                                        })
+                                        .unwrap();
+                                    if let Some(upstream_index) = fail_back {
+                                        let _ = tx_status
+                                            .send(status::Status {
+                                                state: status::State::UpstreamRecovered(
+                                                    upstream_index,
+                                                ),
+                                            })
+                                            .await;
+                                        break;
                                     }
-                                    info!("IS_NEW_TEMPLATE_HANDLED ok");
-                                    if let Some(jd) = jd.as_ref() {
-                                        super::job_declarator::JobDeclarator::on_set_new_prev_hash(
-                                            jd.clone(),
-                                            m.clone(),
-                                        );
+                                    if deferred_job
+                                        .as_ref()
+                                        .map(|(t, _)| t.template_id == m.template_id)
+                                        .unwrap_or(false)
+                                    {
+                                        pending_prev_hash = Some(m);
+                                        continue;
                                     }
-                                    super::downstream::DownstreamMiningNode::on_set_new_prev_hash(
-                                        &down, m,
-                                    )
-                                    .await
-                                    .unwrap();
+                                    Self::on_set_new_prev_hash(&self_mutex, m).await;
                                 }
 
                                 Some(TemplateDistribution::RequestTransactionDataSuccess(m)) => {
@@ -247,19 +492,60 @@ impl TemplateRx {
                                     // template message
                                     let transactions_data = m.transaction_list;
                                     let excess_data = m.excess_data;
-                                    let m = self_mutex
+                                    let mut m = self_mutex
                                         .safe_lock(|t| t.new_template_message.clone())
                                         .unwrap()
                                         .unwrap();
                                     let token = last_token.unwrap();
                                     last_token = None;
                                     let mining_token = token.mining_job_token.to_vec();
+                                    let async_mining_allowed = token.async_mining_allowed;
                                     let pool_coinbase_out = token.coinbase_output.to_vec();
+                                    let tx_selection =
+                                        self_mutex.safe_lock(|s| s.tx_selection.clone()).unwrap();
+                                    let transactions_data = match tx_selection {
+                                        Some(policy) => {
+                                            Self::select_transactions(
+                                                &self_mutex,
+                                                &policy,
+                                                &mut m,
+                                                transactions_data,
+                                            )
+                                            .await
+                                        }
+                                        None => transactions_data,
+                                    };
+                                    if let Some((template, pool_output)) = deferred_job.take() {
+                                        // the job is sent with the filtered template
+                                        let template = if template.template_id == m.template_id {
+                                            m.clone()
+                                        } else {
+                                            template
+                                        };
+                                        Self::send_deferred_job(
+                                            &self_mutex,
+                                            template,
+                                            &pool_output[..],
+                                            &mut pending_prev_hash,
+                                        )
+                                        .await;
+                                    }
+                                    Self::store_transactions(
+                                        &self_mutex,
+                                        m.template_id,
+                                        &transactions_data,
+                                    );
                                     if let Some(jd) = jd.as_ref() {
+                                        let pool_coinbase_out = Self::add_commitments_outputs(
+                                            &self_mutex,
+                                            &m,
+                                            pool_coinbase_out,
+                                        );
                                         super::job_declarator::JobDeclarator::on_new_template(
                                             jd,
                                             m.clone(),
                                             mining_token,
+                                            async_mining_allowed,
                                             transactions_data,
                                             excess_data,
                                             pool_coinbase_out,
@@ -267,8 +553,17 @@ impl TemplateRx {
                                         .await;
                                     }
                                 }
-                                Some(TemplateDistribution::RequestTransactionDataError(_)) => {
-                                    warn!("The prev_hash of the template requested to Template Provider no longer points to the latest tip. Continuing work on the updated template.")
+                                Some(TemplateDistribution::RequestTransactionDataError(m)) => {
+                                    warn!("The prev_hash of the template requested to Template Provider no longer points to the latest tip. Continuing work on the updated template.");
+                                    // A newer template is coming, the stale one is not mined
+                                    if deferred_job
+                                        .as_ref()
+                                        .map(|(t, _)| t.template_id == m.template_id)
+                                        .unwrap_or(false)
+                                    {
+                                        deferred_job = None;
+                                        pending_prev_hash = None;
+                                    }
                                 }
                                 _ => {
                                     error!("{:?}", frame);
@@ -305,12 +600,336 @@ impl TemplateRx {
             .unwrap();
     }
 
+    /// Sends downstream the job of a template whose transactions were waited for by the tx
+    /// selection policy, then the SetNewPrevHash that activates it if it has been received
+    /// meanwhile.
+    async fn send_deferred_job(
+        self_mutex: &Arc<Mutex<Self>>,
+        template: NewTemplate<'static>,
+        pool_output: &[u8],
+        pending_prev_hash: &mut Option<SetNewPrevHash<'static>>,
+    ) {
+        let down = self_mutex.safe_lock(|s| s.down.clone()).unwrap();
+        let template_id = template.template_id;
+        Self::track_template(self_mutex, &template);
+        if let Err(e) =
+            super::downstream::DownstreamMiningNode::on_new_template(&down, template, pool_output)
+                .await
+        {
+            error!(
+                "Impossible to send the downstream job of template {}: {:?}",
+                template_id, e
+            );
+        }
+        if let Some(prev_hash) = pending_prev_hash.take() {
+            if prev_hash.template_id == template_id {
+                Self::on_set_new_prev_hash(self_mutex, prev_hash).await;
+            } else {
+                *pending_prev_hash = Some(prev_hash);
+            }
+        }
+    }
+
+    /// Remembers the merkle path of a template sent downstream, needed to compute the hash of the
+    /// blocks found with its job
+    fn track_template(self_mutex: &Arc<Mutex<Self>>, template: &NewTemplate<'static>) {
+        let found_blocks = self_mutex.safe_lock(|s| s.found_blocks.clone()).unwrap();
+        found_blocks
+            .safe_lock(|f| f.on_template(template.template_id, template.merkle_path.to_vec()))
+            .unwrap();
+    }
+
+    /// Activates the template of the SetNewPrevHash on the local node, the JD and the downstream
+    async fn on_set_new_prev_hash(self_mutex: &Arc<Mutex<Self>>, m: SetNewPrevHash<'static>) {
+        let (jd, down) = self_mutex
+            .safe_lock(|s| (s.jd.clone(), s.down.clone()))
+            .unwrap();
+        info!("Received SetNewPrevHash, waiting for IS_NEW_TEMPLATE_HANDLED");
+        // See coment on the definition of the global for memory
+        // ordering
+        while !super::IS_NEW_TEMPLATE_HANDLED.load(std::sync::atomic::Ordering::Acquire) {
+            tokio::task::yield_now().await;
+        }
+        info!("IS_NEW_TEMPLATE_HANDLED ok");
+        let prev_hash = u256_to_block_hash(m.prev_hash.clone());
+        let found_blocks = self_mutex
+            .safe_lock(|s| {
+                if let Some(local_node) = s.local_node.as_mut() {
+                    local_node.on_set_new_prev_hash(prev_hash, m.n_bits)
+                }
+                s.found_blocks.clone()
+            })
+            .unwrap();
+        let confirmed = found_blocks
+            .safe_lock(|f| {
+                f.on_set_new_prev_hash(prev_hash, m.n_bits);
+                f.on_confirmed(prev_hash, SubmissionPath::TemplateProvider)
+            })
+            .unwrap();
+        if confirmed {
+            Self::rotate_coinbase_outputs(self_mutex);
+        }
+        if let Some(jd) = jd.as_ref() {
+            super::job_declarator::JobDeclarator::on_set_new_prev_hash(jd.clone(), m.clone());
+        }
+        super::downstream::DownstreamMiningNode::on_set_new_prev_hash(&down, m)
+            .await
+            .unwrap();
+    }
+
+    /// Applies the tx selection policy to the transactions of `template`. When some transactions
+    /// are removed, the merkle path, the witness commitment and the coinbase value of the
+    /// template are updated and the template is remembered as filtered.
+    ///
+    /// If the policy can not be applied, the template is declared with all its transactions.
+    async fn select_transactions(
+        self_mutex: &Arc<Mutex<Self>>,
+        policy: &TxSelectionPolicy,
+        template: &mut NewTemplate<'static>,
+        transactions_data: Seq064K<'static, B016M<'static>>,
+    ) -> Seq064K<'static, B016M<'static>> {
+        let raw_txs = transactions_data.to_vec();
+        let mut txs: Vec<Transaction> = Vec::with_capacity(raw_txs.len());
+        for raw_tx in &raw_txs {
+            match Transaction::deserialize(raw_tx) {
+                Ok(tx) => txs.push(tx),
+                Err(e) => {
+                    error!(
+                        "Invalid transaction in template {}: {:?}",
+                        template.template_id, e
+                    );
+                    return transactions_data;
+                }
+            }
+        }
+        let selection = match policy.select(&txs).await {
+            Ok(Some(selection)) => selection,
+            Ok(None) => return transactions_data,
+            Err(e) => {
+                error!(
+                    "Impossible to apply the tx selection policy to template {}, declaring all its transactions: {}",
+                    template.template_id, e
+                );
+                return transactions_data;
+            }
+        };
+        info!(
+            "Tx selection policy removed {} of {} transactions ({} sats of fees) from template {}",
+            txs.len() - selection.kept.len(),
+            txs.len(),
+            selection.removed_fees,
+            template.template_id
+        );
+
+        let kept_txids: Vec<Txid> = selection.kept.iter().map(|i| txs[*i].txid()).collect();
+        let merkle_path: Vec<U256<'static>> = merkle_path_from_txids(&kept_txids)
+            .into_iter()
+            .map(|node| node.into())
+            .collect();
+        template.merkle_path = Seq0255::new(merkle_path).unwrap();
+        template.coinbase_tx_value_remaining = template
+            .coinbase_tx_value_remaining
+            .saturating_sub(selection.removed_fees);
+        let kept: Vec<Transaction> = selection.kept.iter().map(|i| txs[*i].clone()).collect();
+        Self::update_witness_commitment(template, &kept);
+
+        self_mutex
+            .safe_lock(|s| {
+                if s.filtered_templates.len() == FILTERED_TEMPLATES_CAPACITY {
+                    s.filtered_templates.pop_front();
+                }
+                s.filtered_templates.push_back(template.template_id);
+            })
+            .unwrap();
+
+        let kept_txs: Vec<B016M<'static>> = selection
+            .kept
+            .iter()
+            .map(|i| raw_txs[*i].clone().try_into().unwrap())
+            .collect();
+        Seq064K::new(kept_txs).unwrap()
+    }
+
+    /// Appends to the encoded pool outputs the ones of the commitments, in the same order of the
+    /// coinbase built by the job creator
+    fn add_commitments_outputs(
+        self_mutex: &Arc<Mutex<Self>>,
+        template: &NewTemplate<'static>,
+        mut pool_outputs: Vec<u8>,
+    ) -> Vec<u8> {
+        let commitments = self_mutex
+            .safe_lock(|s| s.coinbase_commitments.clone())
+            .unwrap();
+        for commitment in commitments {
+            for output in commitment.outputs(template) {
+                output
+                    .consensus_encode(&mut pool_outputs)
+                    .expect("Invalid coinbase output");
+            }
+        }
+        pool_outputs
+    }
+
+    /// The witness commitment in the template coinbase outputs commits to all the template
+    /// transactions, it is replaced with the one of `txs`.
+    fn update_witness_commitment(template: &mut NewTemplate<'static>, txs: &[Transaction]) {
+        let mut outputs = match template_coinbase_outputs(template) {
+            Ok(outputs) => outputs,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        if let Some(commitment) = outputs.iter_mut().rev().find(|o| {
+            o.script_pubkey
+                .as_bytes()
+                .starts_with(&WITNESS_COMMITMENT_HEADER)
+        }) {
+            commitment.script_pubkey = witness_commitment_script(txs);
+            let mut encoded_outputs = vec![];
+            for output in outputs {
+                output
+                    .consensus_encode(&mut encoded_outputs)
+                    .expect("Invalid coinbase output");
+            }
+            template.coinbase_tx_outputs = encoded_outputs.try_into().unwrap();
+        }
+    }
+
+    /// Keeps the transactions of the template, used to assemble the block sent to the local node
+    fn store_transactions(
+        self_mutex: &Arc<Mutex<Self>>,
+        template_id: u64,
+        transactions_data: &Seq064K<'static, B016M<'static>>,
+    ) {
+        if self_mutex.safe_lock(|s| s.local_node.is_none()).unwrap() {
+            return;
+        }
+        let mut transactions = Vec::new();
+        for raw_tx in transactions_data.to_vec() {
+            match Transaction::deserialize(&raw_tx) {
+                Ok(tx) => transactions.push(tx),
+                Err(e) => {
+                    error!("Invalid transaction in template {}: {:?}", template_id, e);
+                    return;
+                }
+            }
+        }
+        self_mutex
+            .safe_lock(|s| {
+                if let Some(local_node) = s.local_node.as_mut() {
+                    local_node.on_transactions(template_id, transactions)
+                }
+            })
+            .unwrap();
+    }
+
+    /// Assembles the block of the solution and sends it to the local node, if any
+    fn submit_to_local_node(self_: &Arc<Mutex<Self>>, solution: &SubmitSolution<'static>) {
+        let (to_submit, found_blocks) = self_
+            .safe_lock(|s| {
+                let to_submit = s.local_node.as_ref().and_then(|local_node| {
+                    local_node
+                        .build_block(solution)
+                        .map(|block| (block, local_node.client()))
+                });
+                (to_submit, s.found_blocks.clone())
+            })
+            .unwrap();
+        if let Some((block, client)) = to_submit {
+            let hash = block.block_hash();
+            info!("Submitting block {} to the local node", hash);
+            let task = {
+                let self_ = self_.clone();
+                tokio::task::spawn(async move {
+                    let result = client.submit_block(serialize_hex(&block)).await;
+                    let accepted = self_
+                        .safe_lock(|s| {
+                            s.local_node
+                                .as_ref()
+                                .map(|local_node| local_node.on_submit_block_result(hash, result))
+                        })
+                        .unwrap();
+                    if accepted == Some(true)
+                        && found_blocks
+                            .safe_lock(|f| f.on_confirmed(hash, SubmissionPath::LocalNode))
+                            .unwrap()
+                    {
+                        Self::rotate_coinbase_outputs(&self_);
+                    }
+                })
+            };
+            self_
+                .safe_lock(|s| {
+                    s.task_collector
+                        .safe_lock(|c| c.push(task.abort_handle()))
+                        .unwrap()
+                })
+                .unwrap();
+        }
+    }
+
+    /// Called when a found block is confirmed by a path, the templates received after that pay to
+    /// a fresh key. Stale solutions are never confirmed, so they do not consume a key.
+    fn rotate_coinbase_outputs(self_: &Arc<Mutex<Self>>) {
+        self_
+            .safe_lock(|s| {
+                if let Some(rotation) = s.coinbase_rotation.as_mut() {
+                    match rotation.rotate() {
+                        Ok(outputs) => {
+                            info!("New coinbase outputs: {:?}", outputs);
+                            let mut encoded_outputs = vec![];
+                            outputs
+                                .consensus_encode(&mut encoded_outputs)
+                                .expect("Invalid coinbase output in config");
+                            s.miner_coinbase_output = encoded_outputs;
+                        }
+                        // The next block would pay to the same key
+                        Err(e) => error!("Impossible to rotate the coinbase outputs: {}", e),
+                    }
+                }
+            })
+            .unwrap();
+    }
+
     async fn on_new_solution(self_: Arc<Mutex<Self>>, rx: Receiver<SubmitSolution<'static>>) {
-        while let Ok(solution) = rx.recv().await {
-            if !self_
+        while let Ok(mut solution) = rx.recv().await {
+            let found_blocks = self_.safe_lock(|s| s.found_blocks.clone()).unwrap();
+            if let Some(hash) = found_blocks
+                .safe_lock(|f| f.on_solution(&solution))
+                .unwrap()
+            {
+                info!("Found block {}", hash);
+            }
+            Self::submit_to_local_node(&self_, &solution);
+            let (filtered, tp_template_id) = self_
+                .safe_lock(|s| {
+                    (
+                        s.filtered_templates.contains(&solution.template_id),
+                        s.template_ids.to_tp(solution.template_id),
+                    )
+                })
+                .unwrap();
+            let tp_template_id = match tp_template_id {
+                Some(tp_template_id) => tp_template_id,
+                None => {
+                    info!(
+                        "Template {} has been received from a previous TP connection, the block is propagated by the JDS and the local node",
+                        solution.template_id
+                    );
+                    continue;
+                }
+            };
+            if filtered {
+                info!(
+                    "Template {} has been filtered by the tx selection policy, the block is propagated by the JDS and the local node",
+                    solution.template_id
+                );
+            } else if !self_
                 .safe_lock(|s| s.test_only_do_not_send_solution_to_tp)
                 .unwrap()
             {
+                solution.template_id = tp_template_id;
                 let sv2_frame: StdFrame = PoolMessages::TemplateDistribution(
                     TemplateDistribution::SubmitSolution(solution),
                 )
@@ -321,3 +940,172 @@ impl TemplateRx {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec_sv2::Responder;
+    use key_utils::Secp256k1SecretKey;
+    use roles_logic_sv2::common_messages_sv2::SetupConnectionSuccess;
+    use std::str::FromStr;
+    use tokio::net::TcpListener;
+
+    fn authority_public_key() -> Secp256k1PublicKey {
+        Secp256k1PublicKey::from_str("9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72").unwrap()
+    }
+
+    // TP that accepts the connections and answers the SetupConnection
+    async fn template_provider() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::task::spawn(async move {
+                    let secret_key = Secp256k1SecretKey::from_str(
+                        "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n",
+                    )
+                    .unwrap();
+                    let responder = Responder::from_authority_kp(
+                        &authority_public_key().into_bytes(),
+                        &secret_key.into_bytes(),
+                        Duration::from_secs(3600),
+                    )
+                    .unwrap();
+                    let (receiver, sender, _, _) =
+                        Connection::new::<Message>(stream, HandshakeRole::Responder(responder))
+                            .await
+                            .unwrap();
+                    // SetupConnection
+                    receiver.recv().await.unwrap();
+                    let success = SetupConnectionSuccess {
+                        used_version: 2,
+                        flags: 0,
+                    };
+                    let frame: StdFrame = PoolMessages::Common(success.into()).try_into().unwrap();
+                    sender.send(frame.into()).await.unwrap();
+                    // keep the connection open
+                    while receiver.recv().await.is_ok() {}
+                });
+            }
+        });
+        address
+    }
+
+    async fn unreachable_address() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    async fn connected_index(
+        template_providers: &[(SocketAddr, Option<Secp256k1PublicKey>)],
+        first: usize,
+    ) -> Option<usize> {
+        TemplateRx::connect_to_any_tp(template_providers, first)
+            .await
+            .map(|(_, _, index)| index)
+    }
+
+    #[tokio::test]
+    async fn connects_to_the_first_reachable_tp() {
+        let key = Some(authority_public_key());
+        let template_providers = vec![
+            (unreachable_address().await, key),
+            (template_provider().await, key),
+            (template_provider().await, key),
+        ];
+        assert_eq!(connected_index(&template_providers, 0).await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn tp_in_use_is_tried_first() {
+        let key = Some(authority_public_key());
+        let template_providers = vec![
+            (template_provider().await, key),
+            (template_provider().await, key),
+        ];
+        assert_eq!(connected_index(&template_providers, 1).await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn round_wraps_around_to_the_main_tp() {
+        let key = Some(authority_public_key());
+        let template_providers = vec![
+            (template_provider().await, key),
+            (unreachable_address().await, key),
+        ];
+        assert_eq!(connected_index(&template_providers, 1).await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn round_fails_when_no_tp_is_reachable() {
+        let key = Some(authority_public_key());
+        let template_providers = vec![
+            (unreachable_address().await, key),
+            (unreachable_address().await, key),
+        ];
+        assert_eq!(connected_index(&template_providers, 0).await, None);
+    }
+
+    #[tokio::test]
+    async fn tp_that_does_not_answer_is_skipped() {
+        // accepts the connections and never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent = listener.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            let mut streams = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+        let key = Some(authority_public_key());
+        let template_providers = vec![(silent, key), (template_provider().await, key)];
+        assert_eq!(connected_index(&template_providers, 0).await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn tp_with_another_authority_key_is_skipped() {
+        let other_key =
+            Secp256k1PublicKey::from_str("9azQdassggC7L3YMVcZyRJmK7qrFDj5MZNHb4LkaUrJRUhct92W")
+                .unwrap();
+        let template_providers = vec![
+            (template_provider().await, Some(other_key)),
+            (template_provider().await, Some(authority_public_key())),
+        ];
+        assert_eq!(connected_index(&template_providers, 0).await, Some(1));
+    }
+
+    #[test]
+    fn template_ids_are_not_shifted_before_a_reconnection() {
+        let mut template_ids = TemplateIds::default();
+        assert_eq!(template_ids.on_new_template(5), 5);
+        assert_eq!(template_ids.shift(5), 5);
+        assert_eq!(template_ids.to_tp(5), Some(5));
+    }
+
+    #[test]
+    fn template_ids_after_a_reconnection_do_not_clash() {
+        let mut template_ids = TemplateIds::default();
+        template_ids.on_new_template(7);
+        template_ids.on_new_template(3);
+        template_ids.on_reconnect();
+        // the new TP starts again from 0
+        assert_eq!(template_ids.on_new_template(0), 8);
+        assert_eq!(template_ids.shift(0), 8);
+        assert_eq!(template_ids.to_tp(8), Some(0));
+        // solutions of the templates of the previous TP are not sent to the new one
+        assert_eq!(template_ids.to_tp(7), None);
+
+        template_ids.on_new_template(2);
+        template_ids.on_reconnect();
+        assert_eq!(template_ids.on_new_template(0), 11);
+        assert_eq!(template_ids.to_tp(10), None);
+    }
+
+    #[test]
+    fn reconnection_without_templates() {
+        let mut template_ids = TemplateIds::default();
+        template_ids.on_reconnect();
+        template_ids.on_reconnect();
+        assert_eq!(template_ids.on_new_template(0), 1);
+    }
+}
@@ -50,10 +50,10 @@ impl SetupConnectionHandler {
         let mut incoming: StdFrame = receiver
             .recv()
             .await
-            .expect("Connection to TP closed!")
+            .map_err(|_| ())?
             .try_into()
-            .expect("Failed to parse incoming SetupConnectionResponse");
-        let message_type = incoming.get_header().unwrap().msg_type();
+            .map_err(|_| ())?;
+        let message_type = incoming.get_header().ok_or(())?.msg_type();
         let payload = incoming.payload();
         ParseUpstreamCommonMessages::handle_message_common(
             Arc::new(Mutex::new(SetupConnectionHandler {})),
@@ -61,7 +61,7 @@ impl SetupConnectionHandler {
             payload,
             CommonRoutingLogic::None,
         )
-        .unwrap();
+        .map_err(|_| ())?;
         Ok(())
     }
 }
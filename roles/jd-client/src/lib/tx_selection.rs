@@ -0,0 +1,632 @@
+//! Transaction selection policy applied to the transactions of a template before declaring the
+//! job built on it. The fees of the transactions are not part of the template, so they are taken
+//! from the mempool of a bitcoind node.
+use super::proxy_config::TxSelectionConfig;
+use rpc_sv2::mini_rpc_client::{Auth, MempoolEntry, MiniRpcClient, RpcError};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    str::FromStr,
+};
+use stratum_common::bitcoin::{hashes::hex::FromHex, Transaction, Txid};
+
+const SATS_PER_BTC: f64 = 100_000_000.0;
+
+fn to_sats(btc: f64) -> u64 {
+    (btc * SATS_PER_BTC).round() as u64
+}
+
+#[derive(Debug)]
+pub enum SelectionError {
+    /// The `tx_selection` section of the config is invalid
+    InvalidConfig(String),
+    Rpc(RpcError),
+    /// A transaction that must be removed is not in the node mempool, so the coinbase value can
+    /// not be updated
+    UnknownFee(Txid),
+}
+
+impl fmt::Display for SelectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectionError::InvalidConfig(ref e) => write!(f, "Invalid tx selection config: {}", e),
+            SelectionError::Rpc(ref e) => write!(f, "RPC error: `{:?}`", e),
+            SelectionError::UnknownFee(ref txid) => {
+                write!(f, "Transaction {} is not in the mempool", txid)
+            }
+        }
+    }
+}
+
+impl From<RpcError> for SelectionError {
+    fn from(e: RpcError) -> Self {
+        SelectionError::Rpc(e)
+    }
+}
+
+/// Transactions of a template that satisfy the policy
+#[derive(Debug)]
+pub struct Selection {
+    /// Indexes of the kept transactions, in the template order
+    pub kept: Vec<usize>,
+    /// Sum of the fees of the removed transactions in sats, to be subtracted from the coinbase
+    pub removed_fees: u64,
+}
+
+#[derive(Debug)]
+pub struct TxSelectionPolicy {
+    excluded_txids: HashSet<Txid>,
+    excluded_script_patterns: Vec<Vec<u8>>,
+    // sat/vB
+    min_fee_rate: f64,
+    max_ancestor_package_vsize: u64,
+    rpc_client: MiniRpcClient,
+}
+
+impl TxSelectionPolicy {
+    /// Returns None when the config do not contain any rule
+    pub fn new(config: &TxSelectionConfig) -> Result<Option<Self>, SelectionError> {
+        let excluded_txids = config
+            .excluded_txids
+            .iter()
+            .map(|txid| {
+                Txid::from_str(txid).map_err(|_| {
+                    SelectionError::InvalidConfig(format!("invalid excluded txid {}", txid))
+                })
+            })
+            .collect::<Result<HashSet<Txid>, SelectionError>>()?;
+        let excluded_script_patterns = config
+            .excluded_script_patterns
+            .iter()
+            .map(|pattern| {
+                Vec::<u8>::from_hex(pattern).map_err(|_| {
+                    SelectionError::InvalidConfig(format!(
+                        "invalid excluded script pattern {}",
+                        pattern
+                    ))
+                })
+            })
+            .collect::<Result<Vec<Vec<u8>>, SelectionError>>()?;
+        if !config.min_fee_rate.is_finite() {
+            return Err(SelectionError::InvalidConfig(format!(
+                "invalid min_fee_rate {}",
+                config.min_fee_rate
+            )));
+        }
+        if excluded_txids.is_empty()
+            && excluded_script_patterns.is_empty()
+            && config.min_fee_rate <= 0.0
+            && config.max_ancestor_package_vsize == 0
+        {
+            return Ok(None);
+        }
+        if config.core_rpc_url.is_empty() {
+            return Err(SelectionError::InvalidConfig(
+                "core_rpc_url is needed to get the transaction fees".to_string(),
+            ));
+        }
+        let url = config.core_rpc_url.clone() + ":" + &config.core_rpc_port.to_string();
+        let auth = Auth::new(config.core_rpc_user.clone(), config.core_rpc_pass.clone());
+        Ok(Some(Self {
+            excluded_txids,
+            excluded_script_patterns,
+            min_fee_rate: config.min_fee_rate,
+            max_ancestor_package_vsize: config.max_ancestor_package_vsize,
+            rpc_client: MiniRpcClient::new(url, auth)
+                .with_options(config.rpc_timeout_secs, config.rpc_retries),
+        }))
+    }
+
+    /// Applies the policy to the transactions of a template. Returns None if all of them are
+    /// kept.
+    pub async fn select(&self, txs: &[Transaction]) -> Result<Option<Selection>, SelectionError> {
+        let txids: Vec<Txid> = txs.iter().map(|tx| tx.txid()).collect();
+        // the min fee rate needs the fees of every transaction, the other rules only the fees of
+        // the removed ones, to update the coinbase value
+        let mempool = match self.min_fee_rate > 0.0 {
+            true => self.get_mempool_entries(&txids).await?,
+            false => {
+                let removed: Vec<Txid> = self
+                    .removed(txs, &txids, &BTreeMap::new())
+                    .iter()
+                    .zip(txids.iter())
+                    .filter(|(removed, _)| **removed)
+                    .map(|(_, txid)| *txid)
+                    .collect();
+                if removed.is_empty() {
+                    return Ok(None);
+                }
+                self.get_mempool_entries(&removed).await?
+            }
+        };
+        self.select_with_mempool(txs, &mempool)
+    }
+
+    async fn get_mempool_entries(
+        &self,
+        txids: &[Txid],
+    ) -> Result<BTreeMap<String, MempoolEntry>, SelectionError> {
+        let txids: Vec<String> = txids.iter().map(|txid| txid.to_string()).collect();
+        let entries = self.rpc_client.get_mempool_entries(&txids).await?;
+        Ok(txids
+            .into_iter()
+            .zip(entries)
+            .filter_map(|(txid, entry)| entry.map(|entry| (txid, entry)))
+            .collect())
+    }
+
+    fn select_with_mempool(
+        &self,
+        txs: &[Transaction],
+        mempool: &BTreeMap<String, MempoolEntry>,
+    ) -> Result<Option<Selection>, SelectionError> {
+        let txids: Vec<Txid> = txs.iter().map(|tx| tx.txid()).collect();
+        let removed = self.removed(txs, &txids, mempool);
+        if !removed.iter().any(|r| *r) {
+            return Ok(None);
+        }
+        let mut kept = Vec::with_capacity(txs.len());
+        let mut removed_fees = 0;
+        for (i, is_removed) in removed.iter().enumerate() {
+            if *is_removed {
+                let fee = mempool
+                    .get(&txids[i].to_string())
+                    .map(|entry| to_sats(entry.fees.base));
+                removed_fees += fee.ok_or(SelectionError::UnknownFee(txids[i]))?;
+            } else {
+                kept.push(i);
+            }
+        }
+        Ok(Some(Selection { kept, removed_fees }))
+    }
+
+    // Transactions of the template removed by the policy, in the template order. The fees in
+    // `mempool` are only used by the min fee rate.
+    fn removed(
+        &self,
+        txs: &[Transaction],
+        txids: &[Txid],
+        mempool: &BTreeMap<String, MempoolEntry>,
+    ) -> Vec<bool> {
+        let positions: HashMap<Txid, usize> = txids
+            .iter()
+            .enumerate()
+            .map(|(i, txid)| (*txid, i))
+            .collect();
+        let entries: Vec<Option<&MempoolEntry>> = txids
+            .iter()
+            .map(|txid| mempool.get(&txid.to_string()))
+            .collect();
+
+        // in-template ancestors of each transaction, a parent always come before its children
+        let mut ancestors: Vec<HashSet<usize>> = Vec::with_capacity(txs.len());
+        for tx in txs {
+            let mut tx_ancestors = HashSet::new();
+            for input in &tx.input {
+                if let Some(parent) = positions.get(&input.previous_output.txid) {
+                    tx_ancestors.insert(*parent);
+                    tx_ancestors.extend(ancestors[*parent].iter().copied());
+                }
+            }
+            ancestors.push(tx_ancestors);
+        }
+        let excluded: Vec<bool> = txs
+            .iter()
+            .enumerate()
+            .map(|(i, tx)| {
+                self.is_excluded(tx, &txids[i]) || self.exceeds_package_size(tx, txs, &ancestors[i])
+            })
+            .collect();
+        let mut below_min_fee_rate: Vec<bool> = entries
+            .iter()
+            .map(|entry| self.is_below_min_fee_rate(*entry))
+            .collect();
+        // A child that pays for its low fee ancestors (CPFP) is mined with them
+        for i in 0..txs.len() {
+            if !below_min_fee_rate[i] && !excluded[i] {
+                for ancestor in &ancestors[i] {
+                    below_min_fee_rate[*ancestor] = false;
+                }
+            }
+        }
+
+        let mut removed = vec![false; txs.len()];
+        for i in 0..txs.len() {
+            // a transaction that spend a removed one can not be included
+            removed[i] =
+                ancestors[i].iter().any(|a| removed[*a]) || excluded[i] || below_min_fee_rate[i];
+        }
+        removed
+    }
+
+    fn is_excluded(&self, tx: &Transaction, txid: &Txid) -> bool {
+        self.excluded_txids.contains(txid)
+            || tx.output.iter().any(|output| {
+                self.excluded_script_patterns
+                    .iter()
+                    .any(|pattern| output.script_pubkey.as_bytes().starts_with(pattern))
+            })
+    }
+
+    // The fee rate of a transaction is the one of the package made of it and its unconfirmed
+    // ancestors, or its own one if it is lower: the ancestors with an higher rate are mined
+    // without it. Transactions that are not in the mempool have an unknown fee and are not
+    // checked.
+    fn is_below_min_fee_rate(&self, entry: Option<&MempoolEntry>) -> bool {
+        match entry {
+            Some(entry) if self.min_fee_rate > 0.0 => {
+                let own_rate = to_sats(entry.fees.base) as f64 / entry.vsize as f64;
+                let package_rate = match (entry.fees.ancestor, entry.ancestor_size) {
+                    (Some(fees), Some(size)) if size > 0 => to_sats(fees) as f64 / size as f64,
+                    _ => own_rate,
+                };
+                own_rate.min(package_rate) < self.min_fee_rate
+            }
+            _ => false,
+        }
+    }
+
+    fn exceeds_package_size(
+        &self,
+        tx: &Transaction,
+        txs: &[Transaction],
+        ancestors: &HashSet<usize>,
+    ) -> bool {
+        if self.max_ancestor_package_vsize == 0 {
+            return false;
+        }
+        let package_vsize: usize =
+            tx.vsize() + ancestors.iter().map(|a| txs[*a].vsize()).sum::<usize>();
+        package_vsize as u64 > self.max_ancestor_package_vsize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rpc_sv2::stand_in::{error, result, stand_in};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+    use stratum_common::bitcoin::{
+        blockdata::{script::Script, witness::Witness},
+        OutPoint, PackedLockTime, Sequence, TxIn, TxOut,
+    };
+
+    // Transaction spending `parents`, `tag` makes its txid unique
+    fn tx(parents: &[&Transaction], script_pubkey: Vec<u8>, tag: u64) -> Transaction {
+        let input = match parents.is_empty() {
+            true => vec![TxIn {
+                previous_output: OutPoint::new(
+                    Txid::from_str(&"07".repeat(32)).unwrap(),
+                    tag as u32,
+                ),
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            false => parents
+                .iter()
+                .map(|parent| TxIn {
+                    previous_output: OutPoint::new(parent.txid(), 0),
+                    script_sig: Script::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                })
+                .collect(),
+        };
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input,
+            output: vec![TxOut {
+                value: tag,
+                script_pubkey: script_pubkey.into(),
+            }],
+        }
+    }
+
+    // Mempool entry of `tx` paying `fee` sats, with the ancestor package made of `ancestors`
+    fn entry(tx: &Transaction, fee: u64, ancestors: &[(&Transaction, u64)]) -> (String, Value) {
+        let ancestor_fees = fee + ancestors.iter().map(|(_, fee)| fee).sum::<u64>();
+        let ancestor_size = tx.vsize() + ancestors.iter().map(|(t, _)| t.vsize()).sum::<usize>();
+        (
+            tx.txid().to_string(),
+            json!({
+                "vsize": tx.vsize(),
+                "weight": tx.weight(),
+                "ancestorsize": ancestor_size,
+                "fees": {
+                    "base": fee as f64 / SATS_PER_BTC,
+                    "ancestor": ancestor_fees as f64 / SATS_PER_BTC,
+                },
+            }),
+        )
+    }
+
+    fn mempool(entries: Vec<(String, Value)>) -> BTreeMap<String, MempoolEntry> {
+        entries
+            .into_iter()
+            .map(|(txid, entry)| (txid, serde_json::from_value(entry).unwrap()))
+            .collect()
+    }
+
+    fn policy(mut config: TxSelectionConfig) -> TxSelectionPolicy {
+        if config.core_rpc_url.is_empty() {
+            config.core_rpc_url = "http://127.0.0.1".to_string();
+        }
+        TxSelectionPolicy::new(&config).unwrap().unwrap()
+    }
+
+    fn fee_rate_policy(min_fee_rate: f64) -> TxSelectionPolicy {
+        policy(TxSelectionConfig {
+            min_fee_rate,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn config_without_rules_has_no_policy() {
+        assert!(TxSelectionPolicy::new(&TxSelectionConfig::default())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn invalid_config_is_an_error() {
+        let invalid = [
+            TxSelectionConfig {
+                excluded_txids: vec!["not a txid".to_string()],
+                core_rpc_url: "http://127.0.0.1".to_string(),
+                ..Default::default()
+            },
+            TxSelectionConfig {
+                excluded_script_patterns: vec!["0g".to_string()],
+                core_rpc_url: "http://127.0.0.1".to_string(),
+                ..Default::default()
+            },
+            TxSelectionConfig {
+                min_fee_rate: f64::NAN,
+                core_rpc_url: "http://127.0.0.1".to_string(),
+                ..Default::default()
+            },
+            // no node to get the fees from
+            TxSelectionConfig {
+                min_fee_rate: 1.0,
+                ..Default::default()
+            },
+        ];
+        for config in invalid {
+            assert!(matches!(
+                TxSelectionPolicy::new(&config),
+                Err(SelectionError::InvalidConfig(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn nothing_removed() {
+        let a = tx(&[], vec![0x51], 1);
+        let b = tx(&[&a], vec![0x51], 2);
+        let mempool = mempool(vec![
+            entry(&a, 10_000, &[]),
+            entry(&b, 10_000, &[(&a, 10_000)]),
+        ]);
+        let selection = fee_rate_policy(1.0)
+            .select_with_mempool(&[a, b], &mempool)
+            .unwrap();
+        assert!(selection.is_none());
+    }
+
+    #[test]
+    fn excluded_transactions_and_their_descendants_are_removed() {
+        let a = tx(&[], vec![0x51], 1);
+        let b = tx(&[&a], vec![0x51], 2);
+        let c = tx(&[], vec![0x6a, 0x01, 0xff], 3);
+        let d = tx(&[], vec![0x51], 4);
+        let mempool = mempool(vec![
+            entry(&a, 1_000, &[]),
+            entry(&b, 2_000, &[(&a, 1_000)]),
+            entry(&c, 3_000, &[]),
+            entry(&d, 4_000, &[]),
+        ]);
+        let policy = policy(TxSelectionConfig {
+            excluded_txids: vec![a.txid().to_string()],
+            excluded_script_patterns: vec!["6a".to_string()],
+            ..Default::default()
+        });
+        let selection = policy
+            .select_with_mempool(&[a, b, c, d], &mempool)
+            .unwrap()
+            .unwrap();
+        assert_eq!(selection.kept, vec![3]);
+        assert_eq!(selection.removed_fees, 6_000);
+    }
+
+    #[test]
+    fn low_fee_rate_transactions_are_removed() {
+        let low = tx(&[], vec![0x51], 1);
+        let high = tx(&[], vec![0x51], 2);
+        let rate = |tx: &Transaction, sats_per_vbyte: u64| tx.vsize() as u64 * sats_per_vbyte;
+        let mempool = mempool(vec![
+            entry(&low, rate(&low, 1), &[]),
+            entry(&high, rate(&high, 10), &[]),
+        ]);
+        let selection = fee_rate_policy(5.0)
+            .select_with_mempool(&[low.clone(), high], &mempool)
+            .unwrap()
+            .unwrap();
+        assert_eq!(selection.kept, vec![1]);
+        assert_eq!(selection.removed_fees, rate(&low, 1));
+    }
+
+    #[test]
+    fn child_pays_for_parent() {
+        let parent = tx(&[], vec![0x51], 1);
+        let child = tx(&[&parent], vec![0x51], 2);
+        let rate = |tx: &Transaction, sats_per_vbyte: u64| tx.vsize() as u64 * sats_per_vbyte;
+        let parent_fee = rate(&parent, 1);
+        let mempool = mempool(vec![
+            entry(&parent, parent_fee, &[]),
+            entry(&child, rate(&child, 30), &[(&parent, parent_fee)]),
+        ]);
+        // the package pays about 15 sat/vB
+        let selection = fee_rate_policy(10.0)
+            .select_with_mempool(&[parent.clone(), child.clone()], &mempool)
+            .unwrap();
+        assert!(selection.is_none());
+
+        // the package does not pay enough, both are removed
+        let selection = fee_rate_policy(20.0)
+            .select_with_mempool(&[parent, child], &mempool)
+            .unwrap()
+            .unwrap();
+        assert!(selection.kept.is_empty());
+    }
+
+    #[test]
+    fn parent_does_not_pay_for_child() {
+        let parent = tx(&[], vec![0x51], 1);
+        let child = tx(&[&parent], vec![0x51], 2);
+        let rate = |tx: &Transaction, sats_per_vbyte: u64| tx.vsize() as u64 * sats_per_vbyte;
+        let parent_fee = rate(&parent, 100);
+        let mempool = mempool(vec![
+            entry(&parent, parent_fee, &[]),
+            entry(&child, rate(&child, 1), &[(&parent, parent_fee)]),
+        ]);
+        let selection = fee_rate_policy(10.0)
+            .select_with_mempool(&[parent, child.clone()], &mempool)
+            .unwrap()
+            .unwrap();
+        assert_eq!(selection.kept, vec![0]);
+        assert_eq!(selection.removed_fees, rate(&child, 1));
+    }
+
+    #[test]
+    fn large_packages_are_removed() {
+        let a = tx(&[], vec![0x51], 1);
+        let b = tx(&[&a], vec![0x51], 2);
+        let c = tx(&[&b], vec![0x51], 3);
+        let mempool = mempool(vec![
+            entry(&a, 1_000, &[]),
+            entry(&b, 1_000, &[(&a, 1_000)]),
+            entry(&c, 1_000, &[(&a, 1_000), (&b, 1_000)]),
+        ]);
+        let policy = policy(TxSelectionConfig {
+            max_ancestor_package_vsize: (a.vsize() + b.vsize()) as u64,
+            ..Default::default()
+        });
+        let selection = policy
+            .select_with_mempool(&[a, b, c], &mempool)
+            .unwrap()
+            .unwrap();
+        assert_eq!(selection.kept, vec![0, 1]);
+        assert_eq!(selection.removed_fees, 1_000);
+    }
+
+    #[test]
+    fn removed_transaction_not_in_mempool_is_an_error() {
+        let a = tx(&[], vec![0x51], 1);
+        let policy = policy(TxSelectionConfig {
+            excluded_txids: vec![a.txid().to_string()],
+            ..Default::default()
+        });
+        let txid = a.txid();
+        assert!(matches!(
+            policy.select_with_mempool(&[a], &BTreeMap::new()),
+            Err(SelectionError::UnknownFee(unknown)) if unknown == txid
+        ));
+    }
+
+    // Node answering getmempoolentry with `entries`, the txids asked are pushed to `asked`
+    async fn node(entries: Vec<(String, Value)>, asked: Arc<Mutex<Vec<String>>>) -> (String, u16) {
+        let entries: serde_json::Map<String, Value> = entries.into_iter().collect();
+        let url = stand_in(Arc::new(move |request| {
+            let responses: Vec<Value> = request
+                .body
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|request| {
+                    assert_eq!(request["method"], "getmempoolentry");
+                    let txid = request["params"][0].as_str().unwrap().to_string();
+                    asked.lock().unwrap().push(txid.clone());
+                    match entries.get(&txid) {
+                        Some(entry) => result(request, entry.clone()),
+                        None => error(request, -5, "Transaction not in mempool"),
+                    }
+                })
+                .collect();
+            (200, Value::Array(responses).to_string())
+        }))
+        .await;
+        let (host, port) = url.rsplit_once(':').unwrap();
+        (host.to_string(), port.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn select_uses_the_node_mempool() {
+        let low = tx(&[], vec![0x51], 1);
+        let high = tx(&[], vec![0x51], 2);
+        let unknown = tx(&[], vec![0x51], 3);
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let (core_rpc_url, core_rpc_port) = node(
+            vec![
+                entry(&low, low.vsize() as u64, &[]),
+                entry(&high, high.vsize() as u64 * 10, &[]),
+            ],
+            asked.clone(),
+        )
+        .await;
+        let policy = policy(TxSelectionConfig {
+            min_fee_rate: 5.0,
+            core_rpc_url,
+            core_rpc_port,
+            ..Default::default()
+        });
+        let selection = policy.select(&[low, high, unknown]).await.unwrap().unwrap();
+        assert_eq!(selection.kept, vec![1, 2]);
+        // only the transactions of the template are asked
+        assert_eq!(asked.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn exclusion_rules_ask_only_the_removed_fees() {
+        let a = tx(&[], vec![0x51], 1);
+        let b = tx(&[], vec![0x51], 2);
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let (core_rpc_url, core_rpc_port) = node(
+            vec![entry(&a, 1_000, &[]), entry(&b, 2_000, &[])],
+            asked.clone(),
+        )
+        .await;
+        let policy = policy(TxSelectionConfig {
+            excluded_txids: vec![a.txid().to_string()],
+            core_rpc_url,
+            core_rpc_port,
+            ..Default::default()
+        });
+        // nothing to remove, the node is not asked
+        assert!(policy.select(&[b.clone()]).await.unwrap().is_none());
+        assert!(asked.lock().unwrap().is_empty());
+
+        let selection = policy.select(&[a.clone(), b]).await.unwrap().unwrap();
+        assert_eq!(selection.kept, vec![1]);
+        assert_eq!(selection.removed_fees, 1_000);
+        assert_eq!(*asked.lock().unwrap(), vec![a.txid().to_string()]);
+    }
+
+    #[tokio::test]
+    async fn select_fails_without_node() {
+        let policy = policy(TxSelectionConfig {
+            min_fee_rate: 5.0,
+            core_rpc_url: "http://127.0.0.1".to_string(),
+            core_rpc_port: 1,
+            ..Default::default()
+        });
+        let a = tx(&[], vec![0x51], 1);
+        assert!(matches!(
+            policy.select(&[a]).await,
+            Err(SelectionError::Rpc(_))
+        ));
+    }
+}
@@ -6,6 +6,7 @@ use super::super::{
         ProxyResult,
     },
     status,
+    template_receiver::block_submission::{FoundBlocks, SubmissionPath},
     upstream_sv2::{EitherFrame, Message, StdFrame},
     PoolChangerTrigger,
 };
@@ -24,19 +25,25 @@ use roles_logic_sv2::{
         mining::{ParseUpstreamMiningMessages, SendTo},
     },
     job_declaration_sv2::DeclareMiningJob,
-    mining_sv2::{ExtendedExtranonce, Extranonce, SetCustomMiningJob},
+    mining_sv2::{
+        ExtendedExtranonce, Extranonce, OpenExtendedMiningChannelSuccess, SetCustomMiningJob,
+        SetExtranoncePrefix, SetTarget, SubmitSharesExtended,
+    },
     parsers::{Mining, MiningDeviceMessages, PoolMessages},
     routing_logic::{CommonRoutingLogic, MiningRoutingLogic, NoRouting},
     selectors::NullDownstreamMiningSelector,
-    utils::{Id, Mutex},
+    utils::{u256_to_block_hash, Id, Mutex},
     Error as RolesLogicError,
 };
 use std::{collections::HashMap, net::SocketAddr, sync::Arc, thread::sleep, time::Duration};
 use tokio::{net::TcpStream, task, task::AbortHandle};
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
 use std::collections::VecDeque;
 
+// Max number of shares held for a job not yet acknowledged by the pool
+const HELD_SHARES_PER_JOB: usize = 100;
+
 #[derive(Debug)]
 struct CircularBuffer {
     buffer: VecDeque<(u64, u32)>,
@@ -52,6 +59,8 @@ impl CircularBuffer {
     }
 
     fn insert(&mut self, key: u64, value: u32) {
+        // a job can be set again with a new token, the last job id is the one to use
+        self.buffer.retain(|(k, _)| *k != key);
         if self.buffer.len() == self.capacity {
             self.buffer.pop_front();
         }
@@ -74,27 +83,96 @@ impl std::default::Default for CircularBuffer {
 #[derive(Debug, Default)]
 struct TemplateToJobId {
     template_id_to_job_id: CircularBuffer,
-    request_id_to_template_id: HashMap<u32, u64>,
+    // request_id -> (template_id, prev_hash of the job)
+    request_id_to_template_id: HashMap<u32, (u64, U256<'static>)>,
+    // (template_id, prev_hash) of the jobs accepted by the pool, the last one at the back
+    accepted: VecDeque<(u64, U256<'static>)>,
+    // templates of the jobs refused by the pool or by the JDS
+    rejected_template_ids: VecDeque<u64>,
+    // Shares of the jobs that the pool did not acknowledge yet, they are sent as soon as the
+    // pool accepts the job and dropped if the job is refused
+    held_shares: VecDeque<(u64, Vec<SubmitSharesExtended<'static>>)>,
 }
 
 impl TemplateToJobId {
-    fn register_template_id(&mut self, template_id: u64, request_id: u32) {
+    fn register_template_id(
+        &mut self,
+        template_id: u64,
+        request_id: u32,
+        prev_hash: U256<'static>,
+    ) {
         self.request_id_to_template_id
-            .insert(request_id, template_id);
+            .insert(request_id, (template_id, prev_hash));
     }
 
-    fn register_job_id(&mut self, template_id: u64, job_id: u32) {
+    fn register_job_id(&mut self, template_id: u64, prev_hash: U256<'static>, job_id: u32) {
         self.template_id_to_job_id.insert(template_id, job_id);
+        self.accepted.retain(|(t, _)| *t != template_id);
+        if self.accepted.len() == self.template_id_to_job_id.capacity {
+            self.accepted.pop_front();
+        }
+        self.accepted.push_back((template_id, prev_hash));
+    }
+
+    fn register_rejected(&mut self, template_id: u64) {
+        if self.rejected_template_ids.len() == self.template_id_to_job_id.capacity {
+            self.rejected_template_ids.pop_front();
+        }
+        self.rejected_template_ids.push_back(template_id);
+        self.held_shares.retain(|(t, _)| *t != template_id);
+    }
+
+    fn hold_share(&mut self, template_id: u64, share: SubmitSharesExtended<'static>) {
+        match self.held_shares.iter_mut().find(|(t, _)| *t == template_id) {
+            Some((_, shares)) if shares.len() < HELD_SHARES_PER_JOB => shares.push(share),
+            Some(_) => warn!(
+                "Too many shares for job built on template {}, dropping share",
+                template_id
+            ),
+            None => {
+                if self.held_shares.len() == self.template_id_to_job_id.capacity {
+                    self.held_shares.pop_front();
+                }
+                self.held_shares.push_back((template_id, vec![share]));
+            }
+        }
+    }
+
+    fn take_held_shares(&mut self, template_id: u64) -> Vec<SubmitSharesExtended<'static>> {
+        match self.held_shares.iter().position(|(t, _)| *t == template_id) {
+            Some(index) => self
+                .held_shares
+                .remove(index)
+                .map(|(_, shares)| shares)
+                .unwrap_or_default(),
+            None => vec![],
+        }
     }
 
     fn get_job_id(&mut self, template_id: u64) -> Option<u32> {
         self.template_id_to_job_id.get(template_id)
     }
 
-    fn take_template_id(&mut self, request_id: u32) -> Option<u64> {
+    fn is_rejected(&self, template_id: u64) -> bool {
+        self.rejected_template_ids.contains(&template_id)
+    }
+
+    fn take_template_id(&mut self, request_id: u32) -> Option<(u64, U256<'static>)> {
         self.request_id_to_template_id.remove(&request_id)
     }
 
+    /// Template of the last accepted job built on `prev_hash` and not refused since, that means
+    /// that the pool still accepts shares for it.
+    fn fallback_template(&self, prev_hash: &U256<'static>) -> Option<u64> {
+        self.accepted
+            .iter()
+            .rev()
+            .find(|(template_id, accepted_prev_hash)| {
+                accepted_prev_hash == prev_hash && !self.is_rejected(*template_id)
+            })
+            .map(|(template_id, _)| *template_id)
+    }
+
     fn new() -> Self {
         Self::default()
     }
@@ -124,6 +202,23 @@ pub struct Upstream {
     channel_factory: Option<PoolChannelFactory>,
     template_to_job_id: TemplateToJobId,
     req_ids: Id,
+    /// Address of the pool endpoint we are connected to, updated on `Reconnect`.
+    address: SocketAddr,
+    authority_public_key: Secp256k1PublicKey,
+    /// Versions used in `SetupConnection`, needed to set up the connection again on `Reconnect`.
+    min_version: u16,
+    max_version: u16,
+    /// (channel id, extranonce size) of the first channel opened with the pool. The downstream
+    /// channel keeps this id also when the channel is opened again after a `Reconnect`.
+    downstream_channel: Option<(u32, u16)>,
+    /// Endpoint (host, port) received in a `Reconnect`, the connection is moved there as soon as
+    /// the message has been handled.
+    pending_reconnect: Option<(String, u16)>,
+    /// True while the channel is being opened again with a new endpoint.
+    reconnecting: bool,
+    /// Blocks found by the JDC, a `SetNewPrevHash` of the pool pointing to one of them means that
+    /// the block has been propagated by the JDS.
+    found_blocks: Arc<Mutex<FoundBlocks>>,
 }
 
 impl Upstream {
@@ -144,7 +239,7 @@ impl Upstream {
     /// `UpstreamConnection` with a channel to send and receive messages from the SV2 Upstream
     /// role and uses channels provided in the function arguments to send and receive messages
     /// from the `Downstream`.
-    #[cfg_attr(feature = "cargo-clippy", allow(clippy::too_many_arguments))]
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         address: SocketAddr,
         authority_public_key: Secp256k1PublicKey,
@@ -153,6 +248,7 @@ impl Upstream {
         tx_status: status::Sender,
         task_collector: Arc<Mutex<Vec<AbortHandle>>>,
         pool_chaneger_trigger: Arc<Mutex<PoolChangerTrigger>>,
+        found_blocks: Arc<Mutex<FoundBlocks>>,
     ) -> ProxyResult<'static, Arc<Mutex<Self>>> {
         // Connect to the SV2 Upstream role retry connection every 5 seconds.
         let socket = loop {
@@ -196,6 +292,14 @@ impl Upstream {
             channel_factory: None,
             template_to_job_id: TemplateToJobId::new(),
             req_ids: Id::new(),
+            address,
+            authority_public_key,
+            min_version: 0,
+            max_version: 0,
+            downstream_channel: None,
+            pending_reconnect: None,
+            reconnecting: false,
+            found_blocks,
         })))
     }
 
@@ -205,6 +309,12 @@ impl Upstream {
         min_version: u16,
         max_version: u16,
     ) -> ProxyResult<'static, ()> {
+        self_
+            .safe_lock(|s| {
+                s.min_version = min_version;
+                s.max_version = max_version;
+            })
+            .map_err(|_| PoisonLock)?;
         // Get the `SetupConnection` message with Mining Device information (currently hard coded)
         let setup_connection = Self::get_setup_connection_message(min_version, max_version, true)?;
 
@@ -279,12 +389,13 @@ impl Upstream {
             .unwrap()
             .as_secs() as u32;
 
+        let prev_hash = set_new_prev_hash.prev_hash;
         let to_send = SetCustomMiningJob {
             channel_id,
             request_id,
             token: signed_token,
             version: declare_mining_job.version,
-            prev_hash: set_new_prev_hash.prev_hash,
+            prev_hash: prev_hash.clone(),
             min_ntime: updated_timestamp,
             nbits: set_new_prev_hash.n_bits,
             coinbase_tx_version,
@@ -301,7 +412,7 @@ impl Upstream {
         self_
             .safe_lock(|s| {
                 s.template_to_job_id
-                    .register_template_id(template_id, request_id)
+                    .register_template_id(template_id, request_id, prev_hash)
             })
             .unwrap();
         Self::send(self_, frame).await
@@ -311,7 +422,7 @@ impl Upstream {
     /// appropriate handler.
     #[allow(clippy::result_large_err)]
     pub fn parse_incoming(self_: Arc<Mutex<Self>>) -> ProxyResult<'static, ()> {
-        let (recv, tx_status) = self_
+        let (mut recv, tx_status) = self_
             .safe_lock(|s| (s.receiver.clone(), s.tx_status.clone()))
             .map_err(|_| PoisonLock)?;
 
@@ -363,6 +474,18 @@ impl Upstream {
                                 .await
                                 .unwrap();
                         }
+                        Ok(SendTo::RelayNewMessageToRemote(downstream_mutex, message)) => {
+                            Self::send_to_downstream(&downstream_mutex, message).await;
+                        }
+                        Ok(SendTo::Multiple(messages)) => {
+                            for message in messages {
+                                if let SendTo::RelayNewMessageToRemote(downstream_mutex, message) =
+                                    message
+                                {
+                                    Self::send_to_downstream(&downstream_mutex, message).await;
+                                }
+                            }
+                        }
                         // No need to handle impossible state just panic cause are impossible and we
                         // will never panic ;-) Verified: handle_message_mining only either panics,
                         // returns Ok(SendTo::None(None)) or Ok(SendTo::None(Some(m))), or returns Err
@@ -384,6 +507,22 @@ impl Upstream {
                             break;
                         }
                     }
+
+                    // The pool asked to move to another endpoint, from now on we listen on the new
+                    // connection
+                    let pending_reconnect = self_
+                        .safe_lock(|s| s.pending_reconnect.take())
+                        .map_err(|_| PoisonLock);
+                    if let Some((host, port)) = handle_result!(tx_status, pending_reconnect) {
+                        match Self::reconnect(&self_, &host, port).await {
+                            Ok(receiver) => recv = receiver,
+                            Err(e) => {
+                                error!("Failed to reconnect to {}:{}: {}", host, port, e);
+                                Self::change_pool(tx_status.clone());
+                                break;
+                            }
+                        }
+                    }
                 }
             })
         };
@@ -396,11 +535,183 @@ impl Upstream {
             .unwrap();
         Ok(())
     }
+    /// Moves the connection to the pool endpoint received in a `Reconnect`. The Template Provider
+    /// and the Job Declarator connections are not touched: a new connection is set up with the
+    /// endpoint and the downstream channel is opened again, the pool extranonce prefix and target
+    /// are then updated in the downstream channel when the success is received. The next template
+    /// is declared to the new endpoint as usual. Returns the receiver of the new connection.
+    async fn reconnect(
+        self_: &Arc<Mutex<Self>>,
+        host: &str,
+        port: u16,
+    ) -> ProxyResult<'static, Receiver<EitherFrame>> {
+        info!("Pool asked to reconnect to {}:{}", host, port);
+        let address = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("can not resolve {}:{}", host, port),
+                )
+            })?;
+        let (authority_public_key, min_version, max_version, task_collector, downstream) = self_
+            .safe_lock(|s| {
+                (
+                    s.authority_public_key,
+                    s.min_version,
+                    s.max_version,
+                    s.task_collector.clone(),
+                    s.downstream.clone(),
+                )
+            })
+            .map_err(|_| PoisonLock)?;
+        let socket = TcpStream::connect(address).await?;
+        let initiator = Initiator::from_raw_k(authority_public_key.into_bytes())?;
+        let (receiver, sender, recv_task, send_task) =
+            Connection::new(socket, HandshakeRole::Initiator(initiator))
+                .await
+                .map_err(|e| {
+                    error!("Noise handshake with {} failed: {:?}", address, e);
+                    CodecNoise(codec_sv2::noise_sv2::Error::ExpectedIncomingHandshakeMessage)
+                })?;
+        task_collector
+            .safe_lock(|c| {
+                c.push(recv_task);
+                c.push(send_task);
+            })
+            .map_err(|_| PoisonLock)?;
+
+        // Dropping the old receiver and sender closes the old connection
+        self_
+            .safe_lock(|s| {
+                s.receiver = receiver.clone();
+                s.sender = sender;
+                s.address = address;
+                s.channel_id = None;
+                s.template_to_job_id = TemplateToJobId::new();
+            })
+            .map_err(|_| PoisonLock)?;
+        Self::setup_connection(self_.clone(), min_version, max_version).await?;
+
+        let open_channel = match downstream {
+            Some(downstream) => downstream
+                .safe_lock(|d| d.open_channel_request.clone())
+                .map_err(|_| PoisonLock)?,
+            None => None,
+        };
+        // If the downstream did not open a channel yet it is opened when the downstream ask for
+        // it
+        if let Some(open_channel) = open_channel {
+            self_
+                .safe_lock(|s| s.reconnecting = true)
+                .map_err(|_| PoisonLock)?;
+            let message = PoolMessages::Mining(Mining::OpenExtendedMiningChannel(open_channel));
+            let frame: StdFrame = message.try_into()?;
+            Self::send(self_, frame).await?;
+        }
+        Ok(receiver)
+    }
+
+    /// Called when the channel is opened again after a `Reconnect`. The downstream channel can
+    /// only be kept if the new channel has the same extranonce layout, in that case the downstream
+    /// channel is updated with the new extranonce prefix and target, otherwise we change pool.
+    fn on_channel_reopened(
+        &mut self,
+        m: OpenExtendedMiningChannelSuccess<'static>,
+    ) -> Result<SendTo<Downstream>, RolesLogicError> {
+        let (downstream, (downstream_channel_id, extranonce_size)) =
+            match (self.downstream.clone(), self.downstream_channel) {
+                (Some(downstream), Some(channel)) => (downstream, channel),
+                _ => {
+                    error!("Channel opened again without a downstream channel");
+                    Self::change_pool(self.tx_status.clone());
+                    return Ok(SendTo::None(None));
+                }
+            };
+        self.channel_id = Some(m.channel_id);
+        let updated = if m.extranonce_size == extranonce_size {
+            downstream
+                .safe_lock(|d| {
+                    if !d.status.have_channel() {
+                        return None;
+                    }
+                    let factory = d.status.get_channel();
+                    factory.set_target(&mut m.target.clone().into());
+                    factory
+                        .update_target_for_channel(downstream_channel_id, m.target.clone().into());
+                    factory.update_extranonce_prefix_for_channel(
+                        downstream_channel_id,
+                        m.extranonce_prefix.clone(),
+                    )
+                })
+                .map_err(|e| RolesLogicError::PoisonLock(e.to_string()))?
+        } else {
+            None
+        };
+        if updated != Some(true) {
+            error!(
+                "Channel opened on {} do not match the downstream channel, changing pool",
+                self.address
+            );
+            Self::change_pool(self.tx_status.clone());
+            return Ok(SendTo::None(None));
+        }
+        info!("Channel opened again on {}", self.address);
+        let set_extranonce_prefix = Mining::SetExtranoncePrefix(SetExtranoncePrefix {
+            channel_id: downstream_channel_id,
+            extranonce_prefix: m.extranonce_prefix,
+        });
+        let set_target = Mining::SetTarget(SetTarget {
+            channel_id: downstream_channel_id,
+            maximum_target: m.target,
+        });
+        Ok(SendTo::Multiple(vec![
+            SendTo::RelayNewMessageToRemote(downstream.clone(), set_extranonce_prefix),
+            SendTo::RelayNewMessageToRemote(downstream, set_target),
+        ]))
+    }
+
+    /// Channel id that the downstream uses for the channel opened with the pool.
+    fn downstream_channel_id(&self, upstream_channel_id: u32) -> u32 {
+        self.downstream_channel
+            .map(|(id, _)| id)
+            .unwrap_or(upstream_channel_id)
+    }
+
+    /// Asks the main loop to move to the next pool in the config.
+    fn change_pool(tx_status: status::Sender) {
+        task::spawn(async move {
+            let _ = tx_status
+                .send(status::Status {
+                    state: status::State::UpstreamRogue,
+                })
+                .await;
+        });
+    }
+
+    /// Sends a message to the downstream, errors are only logged: a closed downstream connection
+    /// is handled by the downstream itself.
+    async fn send_to_downstream(downstream: &Arc<Mutex<Downstream>>, message: Mining<'static>) {
+        let message = MiningDeviceMessages::Mining(message);
+        let sv2_frame: codec_sv2::Sv2Frame<MiningDeviceMessages, buffer_sv2::Slice> =
+            match message.try_into() {
+                Ok(frame) => frame,
+                Err(e) => {
+                    error!("Failed to encode a message for the downstream: {:?}", e);
+                    return;
+                }
+            };
+        if let Err(e) = Downstream::send(downstream, sv2_frame).await {
+            error!("Failed to send a message to the downstream: {:?}", e);
+        }
+    }
+
     /// Creates the `SetupConnection` message to setup the connection with the SV2 Upstream role.
     /// TODO: The Mining Device information is hard coded here, need to receive from Downstream
     /// instead.
     #[allow(clippy::result_large_err)]
-    fn get_setup_connection_message(
+    pub fn get_setup_connection_message(
         min_version: u16,
         max_version: u16,
         is_work_selection_enabled: bool,
@@ -441,17 +752,114 @@ impl Upstream {
             .unwrap()
     }
 
-    pub async fn get_job_id(self_: &Arc<Mutex<Self>>, template_id: u64) -> u32 {
-        loop {
-            if let Some(id) = self_
-                .safe_lock(|s| s.template_to_job_id.get_job_id(template_id))
-                .unwrap()
-            {
-                return id;
+    /// Returns the share with the pool job id of the custom job built on `template_id`, ready to
+    /// be sent to the pool. Shares of a job not yet acknowledged by the pool are held and sent
+    /// when the pool accepts the job, shares of a refused job are dropped.
+    pub fn on_share(
+        self_: &Arc<Mutex<Self>>,
+        template_id: u64,
+        mut share: SubmitSharesExtended<'static>,
+    ) -> Option<SubmitSharesExtended<'static>> {
+        self_
+            .safe_lock(|s| {
+                if s.template_to_job_id.is_rejected(template_id) {
+                    debug!(
+                        "Dropping share for template {}, the job has been refused",
+                        template_id
+                    );
+                    return None;
+                }
+                // After a reconnect the pool channel id can be different from the one of the
+                // downstream channel
+                match (s.template_to_job_id.get_job_id(template_id), s.channel_id) {
+                    (Some(job_id), Some(channel_id)) => {
+                        share.job_id = job_id;
+                        share.channel_id = channel_id;
+                        Some(share)
+                    }
+                    _ => {
+                        s.template_to_job_id.hold_share(template_id, share);
+                        None
+                    }
+                }
+            })
+            .unwrap()
+    }
+
+    /// Called when the JDS refuses the job built on `template_id`. Its shares are dropped and, if
+    /// the downstream is mining it (`active_prev_hash` is the prev hash of the job), the
+    /// downstream goes back to the last accepted job with the same prev hash, otherwise we change
+    /// pool.
+    pub fn on_declared_job_refused(
+        self_: &Arc<Mutex<Self>>,
+        template_id: u64,
+        active_prev_hash: Option<U256<'static>>,
+    ) {
+        self_
+            .safe_lock(|s| s.refuse_job(template_id, active_prev_hash.as_ref()))
+            .unwrap();
+    }
+
+    fn refuse_job(&mut self, template_id: u64, active_prev_hash: Option<&U256<'static>>) {
+        self.template_to_job_id.register_rejected(template_id);
+        let prev_hash = match active_prev_hash {
+            Some(prev_hash) => prev_hash,
+            None => return,
+        };
+        let tx_status = self.tx_status.clone();
+        match (
+            self.template_to_job_id.fallback_template(prev_hash),
+            self.downstream.clone(),
+        ) {
+            (Some(accepted_template_id), Some(downstream)) => {
+                task::spawn(async move {
+                    match Downstream::fall_back_to_template(&downstream, accepted_template_id).await
+                    {
+                        Ok(true) => (),
+                        Ok(false) => {
+                            error!("No accepted job to fall back to, changing pool");
+                            Self::change_pool(tx_status);
+                        }
+                        Err(e) => {
+                            error!("Failed to fall back to the accepted job: {:?}", e);
+                            Self::change_pool(tx_status);
+                        }
+                    }
+                });
+            }
+            _ => {
+                error!("No accepted job to fall back to, changing pool");
+                Self::change_pool(tx_status);
             }
-            tokio::task::yield_now().await;
         }
     }
+
+    /// Sends to the pool the shares held while the job built on `template_id` was not
+    /// acknowledged
+    fn release_held_shares(&mut self, template_id: u64, channel_id: u32, job_id: u32) {
+        let shares = self.template_to_job_id.take_held_shares(template_id);
+        if shares.is_empty() {
+            return;
+        }
+        debug!(
+            "Sending {} shares held for template {}",
+            shares.len(),
+            template_id
+        );
+        let sender = self.sender.clone();
+        task::spawn(async move {
+            for mut share in shares {
+                share.job_id = job_id;
+                share.channel_id = channel_id;
+                let message = PoolMessages::Mining(Mining::SubmitSharesExtended(share));
+                let frame: StdFrame = message.try_into().unwrap();
+                if sender.send(frame.into()).await.is_err() {
+                    error!("Impossible to send the held shares, pool connection closed");
+                    return;
+                }
+            }
+        });
+    }
 }
 
 impl IsUpstream<Downstream, NullDownstreamMiningSelector> for Upstream {
@@ -559,6 +967,10 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         m: roles_logic_sv2::mining_sv2::OpenExtendedMiningChannelSuccess,
     ) -> Result<SendTo<Downstream>, RolesLogicError> {
         info!("Receive open extended mining channel success");
+        if self.reconnecting {
+            self.reconnecting = false;
+            return self.on_channel_reopened(m.into_static());
+        }
         let ids = Arc::new(Mutex::new(roles_logic_sv2::utils::GroupId::new()));
         let pool_signature = self.pool_signature.clone();
         let prefix_len = m.extranonce_prefix.to_vec().len();
@@ -591,6 +1003,7 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
             .try_into()
             .unwrap();
         self.channel_id = Some(m.channel_id);
+        self.downstream_channel = Some((m.channel_id, m.extranonce_size));
         channel_factory
             .replicate_upstream_extended_channel_only_jd(
                 m.target.into_static(),
@@ -606,43 +1019,107 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         ))
     }
 
-    /// Handles the SV2 `OpenExtendedMiningChannelError` message (TODO).
+    /// Handles the SV2 `OpenExtendedMiningChannelError` message. Without a channel the JDC can
+    /// not mine with this pool so we move to the next one.
     fn handle_open_mining_channel_error(
         &mut self,
-        _: roles_logic_sv2::mining_sv2::OpenMiningChannelError,
+        m: roles_logic_sv2::mining_sv2::OpenMiningChannelError,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
-        Ok(SendTo::RelaySameMessageToRemote(
-            self.downstream.as_ref().unwrap().clone(),
-        ))
+        error!(
+            "Pool refused to open the channel: {}",
+            std::str::from_utf8(m.error_code.as_ref()).unwrap_or("unknown error code")
+        );
+        self.reconnecting = false;
+        Self::change_pool(self.tx_status.clone());
+        Ok(SendTo::None(None))
     }
 
-    /// Handles the SV2 `UpdateChannelError` message (TODO).
+    /// Handles the SV2 `UpdateChannelError` message. The error is relayed to the downstream that
+    /// sent the `UpdateChannel`, if the pool no longer knows the channel we change pool.
     fn handle_update_channel_error(
         &mut self,
-        _m: roles_logic_sv2::mining_sv2::UpdateChannelError,
+        m: roles_logic_sv2::mining_sv2::UpdateChannelError,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
-        Ok(SendTo::RelaySameMessageToRemote(
+        let error_code = std::str::from_utf8(m.error_code.as_ref()).unwrap_or("unknown error code");
+        warn!("Pool refused to update the channel: {}", error_code);
+        if error_code == "invalid-channel-id" {
+            Self::change_pool(self.tx_status.clone());
+            return Ok(SendTo::None(None));
+        }
+        let message = Mining::UpdateChannelError(roles_logic_sv2::mining_sv2::UpdateChannelError {
+            channel_id: self.downstream_channel_id(m.channel_id),
+            error_code: m.error_code.into_static(),
+        });
+        Ok(SendTo::RelayNewMessageToRemote(
             self.downstream.as_ref().unwrap().clone(),
+            message,
         ))
     }
 
-    /// Handles the SV2 `CloseChannel` message (TODO).
+    /// Handles the SV2 `CloseChannel` message. The JDC has only one channel with the pool, when it
+    /// is closed we change pool.
     fn handle_close_channel(
         &mut self,
-        _m: roles_logic_sv2::mining_sv2::CloseChannel,
+        m: roles_logic_sv2::mining_sv2::CloseChannel,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
-        Ok(SendTo::RelaySameMessageToRemote(
-            self.downstream.as_ref().unwrap().clone(),
-        ))
+        if self.channel_id != Some(m.channel_id) {
+            warn!("Pool closed unknown channel {}", m.channel_id);
+            return Ok(SendTo::None(None));
+        }
+        error!(
+            "Pool closed the channel: {}",
+            std::str::from_utf8(m.reason_code.as_ref()).unwrap_or("unknown reason code")
+        );
+        self.channel_id = None;
+        Self::change_pool(self.tx_status.clone());
+        Ok(SendTo::None(None))
     }
 
-    /// Handles the SV2 `SetExtranoncePrefix` message (TODO).
+    /// Handles the SV2 `SetExtranoncePrefix` message. The new prefix is set in the channel
+    /// factory used to check the downstream shares and then sent to the downstream.
     fn handle_set_extranonce_prefix(
         &mut self,
-        _: roles_logic_sv2::mining_sv2::SetExtranoncePrefix,
+        m: roles_logic_sv2::mining_sv2::SetExtranoncePrefix,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
-        Ok(SendTo::RelaySameMessageToRemote(
+        if self.channel_id != Some(m.channel_id) {
+            warn!("SetExtranoncePrefix for unknown channel {}", m.channel_id);
+            return Ok(SendTo::None(None));
+        }
+        let channel_id = self.downstream_channel_id(m.channel_id);
+        let extranonce_prefix = m.extranonce_prefix.into_static();
+        let updated = match self.channel_factory.as_mut() {
+            Some(factory) => {
+                factory.update_extranonce_prefix_for_channel(channel_id, extranonce_prefix.clone())
+            }
+            None => match &self.downstream {
+                Some(downstream) => downstream
+                    .safe_lock(|d| {
+                        if !d.status.have_channel() {
+                            return None;
+                        }
+                        d.status.get_channel().update_extranonce_prefix_for_channel(
+                            channel_id,
+                            extranonce_prefix.clone(),
+                        )
+                    })
+                    .map_err(|e| RolesLogicError::PoisonLock(e.to_string()))?,
+                None => None,
+            },
+        };
+        if updated != Some(true) {
+            // The extranonce layout of the downstream channel can not change
+            error!("Can not apply the extranonce prefix sent by the pool, changing pool");
+            Self::change_pool(self.tx_status.clone());
+            return Ok(SendTo::None(None));
+        }
+        debug!("Extranonce prefix updated for channel {}", channel_id);
+        let message = Mining::SetExtranoncePrefix(SetExtranoncePrefix {
+            channel_id,
+            extranonce_prefix,
+        });
+        Ok(SendTo::RelayNewMessageToRemote(
             self.downstream.as_ref().unwrap().clone(),
+            message,
         ))
     }
 
@@ -693,22 +1170,31 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
     /// role.
     fn handle_set_new_prev_hash(
         &mut self,
-        _: roles_logic_sv2::mining_sv2::SetNewPrevHash,
+        m: roles_logic_sv2::mining_sv2::SetNewPrevHash,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
         warn!("SNPH received from upstream, proxy ignore it, and use the one declared by JOB DECLARATOR");
+        let prev_hash = u256_to_block_hash(m.prev_hash.into_static());
+        self.found_blocks
+            .safe_lock(|f| f.on_confirmed(prev_hash, SubmissionPath::Jds))
+            .map_err(|e| RolesLogicError::PoisonLock(e.to_string()))?;
         Ok(SendTo::None(None))
     }
 
-    /// Handles the SV2 `SetCustomMiningJobSuccess` message (TODO).
+    /// Handles the SV2 `SetCustomMiningJobSuccess` message.
     fn handle_set_custom_mining_job_success(
         &mut self,
         m: roles_logic_sv2::mining_sv2::SetCustomMiningJobSuccess,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
-        // TODO
         info!("Set custom mining job success {}", m.job_id);
-        if let Some(template_id) = self.template_to_job_id.take_template_id(m.request_id) {
-            self.template_to_job_id
-                .register_job_id(template_id, m.job_id);
+        if let Some((template_id, prev_hash)) =
+            self.template_to_job_id.take_template_id(m.request_id)
+        {
+            // the JDS may have refused the job in the meantime
+            if !self.template_to_job_id.is_rejected(template_id) {
+                self.template_to_job_id
+                    .register_job_id(template_id, prev_hash, m.job_id);
+                self.release_held_shares(template_id, m.channel_id, m.job_id);
+            }
             Ok(SendTo::None(None))
         } else {
             error!("Attention received a SetupConnectionSuccess with unknown request_id");
@@ -716,12 +1202,28 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         }
     }
 
-    /// Handles the SV2 `SetCustomMiningJobError` message (TODO).
+    /// Handles the SV2 `SetCustomMiningJobError` message. If the pool accepted a job built on the
+    /// same prev hash, the downstream goes back to mining on that job, otherwise we change pool.
+    /// Shares for the refused job are not sent to the pool.
     fn handle_set_custom_mining_job_error(
         &mut self,
-        _m: roles_logic_sv2::mining_sv2::SetCustomMiningJobError,
+        m: roles_logic_sv2::mining_sv2::SetCustomMiningJobError,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
-        todo!()
+        error!(
+            "Pool refused custom job {}: {}",
+            m.request_id,
+            std::str::from_utf8(m.error_code.as_ref()).unwrap_or("unknown error code")
+        );
+        let (template_id, prev_hash) = match self.template_to_job_id.take_template_id(m.request_id)
+        {
+            Some(template) => template,
+            None => {
+                error!("Attention received a SetCustomMiningJobError with unknown request_id");
+                return Ok(SendTo::None(None));
+            }
+        };
+        self.refuse_job(template_id, Some(&prev_hash));
+        Ok(SendTo::None(None))
     }
 
     /// Handles the SV2 `SetTarget` message which updates the Downstream role(s) target
@@ -734,25 +1236,217 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
             factory.update_target_for_channel(m.channel_id, m.maximum_target.clone().into());
             factory.set_target(&mut m.maximum_target.clone().into());
         }
+        let channel_id = self.downstream_channel_id(m.channel_id);
         if let Some(downstream) = &self.downstream {
             let _ = downstream.safe_lock(|d| {
                 let factory = d.status.get_channel();
                 factory.set_target(&mut m.maximum_target.clone().into());
-                factory.update_target_for_channel(m.channel_id, m.maximum_target.into());
+                factory.update_target_for_channel(channel_id, m.maximum_target.clone().into());
             });
         }
-        Ok(SendTo::RelaySameMessageToRemote(
+        let message = Mining::SetTarget(SetTarget {
+            channel_id,
+            maximum_target: m.maximum_target.into_static(),
+        });
+        Ok(SendTo::RelayNewMessageToRemote(
             self.downstream.as_ref().unwrap().clone(),
+            message,
         ))
     }
 
-    /// Handles the SV2 `Reconnect` message (TODO).
+    /// Handles the SV2 `Reconnect` message. The connection is moved to the new endpoint by the
+    /// task that parses the pool messages right after this handler returns, see
+    /// [`Upstream::reconnect`].
     fn handle_reconnect(
         &mut self,
-        _m: roles_logic_sv2::mining_sv2::Reconnect,
+        m: roles_logic_sv2::mining_sv2::Reconnect,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
-        Ok(SendTo::RelaySameMessageToRemote(
-            self.downstream.as_ref().unwrap().clone(),
-        ))
+        // Empty host and port 0 mean the present ones
+        let host = match std::str::from_utf8(m.new_host.as_ref()) {
+            Ok("") => self.address.ip().to_string(),
+            Ok(host) => host.to_string(),
+            Err(_) => {
+                error!("Invalid host in Reconnect, changing pool");
+                Self::change_pool(self.tx_status.clone());
+                return Ok(SendTo::None(None));
+            }
+        };
+        let port = match m.new_port {
+            0 => self.address.port(),
+            port => port,
+        };
+        // resolved by the task that moves the connection, the lookup can take a while
+        self.pending_reconnect = Some((host, port));
+        Ok(SendTo::None(None))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_channel::unbounded;
+
+    fn prev_hash(tag: u8) -> U256<'static> {
+        vec![tag; 32].try_into().unwrap()
+    }
+
+    fn share(sequence_number: u32) -> SubmitSharesExtended<'static> {
+        SubmitSharesExtended {
+            channel_id: 1,
+            sequence_number,
+            job_id: 0,
+            nonce: 0,
+            ntime: 0,
+            version: 0,
+            extranonce: vec![].try_into().unwrap(),
+        }
+    }
+
+    fn upstream() -> (Upstream, Receiver<status::Status<'static>>) {
+        let (tx_status, rx_status) = unbounded();
+        let (sender, receiver) = unbounded();
+        let upstream = Upstream {
+            channel_id: Some(1),
+            tx_status: status::Sender::Upstream(tx_status),
+            min_extranonce_size: 0,
+            upstream_extranonce1_size: 16,
+            pool_signature: String::new(),
+            receiver,
+            sender,
+            downstream: None,
+            task_collector: Arc::new(Mutex::new(vec![])),
+            pool_chaneger_trigger: Arc::new(Mutex::new(PoolChangerTrigger::new(
+                Duration::from_secs(1),
+            ))),
+            channel_factory: None,
+            template_to_job_id: TemplateToJobId::new(),
+            req_ids: Id::new(),
+            address: "127.0.0.1:34254".parse().unwrap(),
+            authority_public_key: "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72"
+                .parse()
+                .unwrap(),
+            min_version: 2,
+            max_version: 2,
+            downstream_channel: None,
+            pending_reconnect: None,
+            reconnecting: false,
+            found_blocks: Arc::new(Mutex::new(FoundBlocks::default())),
+        };
+        (upstream, rx_status)
+    }
+
+    async fn changes_pool(rx_status: &Receiver<status::Status<'static>>) -> bool {
+        matches!(
+            tokio::time::timeout(Duration::from_millis(100), rx_status.recv()).await,
+            Ok(Ok(status::Status {
+                state: status::State::UpstreamRogue
+            }))
+        )
+    }
+
+    #[test]
+    fn held_shares_are_taken_once() {
+        let mut template_to_job_id = TemplateToJobId::new();
+        template_to_job_id.hold_share(1, share(0));
+        template_to_job_id.hold_share(1, share(1));
+        template_to_job_id.hold_share(2, share(2));
+        let shares = template_to_job_id.take_held_shares(1);
+        assert_eq!(
+            shares.iter().map(|s| s.sequence_number).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert!(template_to_job_id.take_held_shares(1).is_empty());
+        assert_eq!(template_to_job_id.take_held_shares(2).len(), 1);
+    }
+
+    #[test]
+    fn held_shares_are_bounded() {
+        let mut template_to_job_id = TemplateToJobId::new();
+        for sequence_number in 0..=HELD_SHARES_PER_JOB as u32 {
+            template_to_job_id.hold_share(1, share(sequence_number));
+        }
+        assert_eq!(
+            template_to_job_id.take_held_shares(1).len(),
+            HELD_SHARES_PER_JOB
+        );
+        // only the shares of the last jobs are held
+        let capacity = template_to_job_id.template_id_to_job_id.capacity as u64;
+        for template_id in 0..=capacity {
+            template_to_job_id.hold_share(template_id, share(0));
+        }
+        assert!(template_to_job_id.take_held_shares(0).is_empty());
+        assert_eq!(template_to_job_id.take_held_shares(capacity).len(), 1);
+    }
+
+    #[test]
+    fn held_shares_of_rejected_job_are_dropped() {
+        let mut template_to_job_id = TemplateToJobId::new();
+        template_to_job_id.hold_share(1, share(0));
+        template_to_job_id.register_rejected(1);
+        assert!(template_to_job_id.is_rejected(1));
+        assert!(template_to_job_id.take_held_shares(1).is_empty());
+    }
+
+    #[test]
+    fn fallback_template_is_the_last_accepted_on_the_prev_hash() {
+        let mut template_to_job_id = TemplateToJobId::new();
+        assert_eq!(template_to_job_id.fallback_template(&prev_hash(1)), None);
+        template_to_job_id.register_job_id(1, prev_hash(1), 10);
+        template_to_job_id.register_job_id(2, prev_hash(1), 11);
+        template_to_job_id.register_job_id(3, prev_hash(2), 12);
+        assert_eq!(template_to_job_id.fallback_template(&prev_hash(1)), Some(2));
+        assert_eq!(template_to_job_id.fallback_template(&prev_hash(2)), Some(3));
+        assert_eq!(template_to_job_id.fallback_template(&prev_hash(3)), None);
+        // a job refused after it has been accepted is skipped
+        template_to_job_id.register_rejected(2);
+        assert_eq!(template_to_job_id.fallback_template(&prev_hash(1)), Some(1));
+        template_to_job_id.register_rejected(1);
+        assert_eq!(template_to_job_id.fallback_template(&prev_hash(1)), None);
+    }
+
+    #[test]
+    fn job_id_of_job_set_again_is_the_last_one() {
+        let mut template_to_job_id = TemplateToJobId::new();
+        template_to_job_id.register_template_id(1, 7, prev_hash(1));
+        assert_eq!(
+            template_to_job_id.take_template_id(7),
+            Some((1, prev_hash(1)))
+        );
+        assert_eq!(template_to_job_id.take_template_id(7), None);
+        template_to_job_id.register_job_id(1, prev_hash(1), 10);
+        template_to_job_id.register_job_id(1, prev_hash(1), 11);
+        assert_eq!(template_to_job_id.get_job_id(1), Some(11));
+    }
+
+    #[tokio::test]
+    async fn refused_future_job_is_only_recorded() {
+        let (mut upstream, rx_status) = upstream();
+        upstream.template_to_job_id.hold_share(1, share(0));
+        upstream.refuse_job(1, None);
+        assert!(upstream.template_to_job_id.is_rejected(1));
+        assert!(upstream.template_to_job_id.take_held_shares(1).is_empty());
+        assert!(!changes_pool(&rx_status).await);
+    }
+
+    #[tokio::test]
+    async fn refused_job_without_fallback_changes_pool() {
+        let (mut upstream, rx_status) = upstream();
+        upstream
+            .template_to_job_id
+            .register_job_id(1, prev_hash(1), 10);
+        // the accepted job is on another prev hash
+        upstream.refuse_job(2, Some(&prev_hash(2)));
+        assert!(changes_pool(&rx_status).await);
+    }
+
+    #[tokio::test]
+    async fn refused_job_without_downstream_changes_pool() {
+        let (mut upstream, rx_status) = upstream();
+        upstream
+            .template_to_job_id
+            .register_job_id(1, prev_hash(1), 10);
+        upstream.refuse_job(2, Some(&prev_hash(1)));
+        assert!(upstream.template_to_job_id.is_rejected(2));
+        assert!(changes_pool(&rx_status).await);
     }
 }
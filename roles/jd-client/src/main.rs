@@ -8,7 +8,7 @@ use lib::{
     job_declarator::JobDeclarator,
     proxy_config::ProxyConfig,
     status,
-    template_receiver::TemplateRx,
+    template_receiver::{block_submission::FoundBlocks, TemplateRx},
     PoolChangerTrigger,
 };
 
@@ -40,13 +40,9 @@ fn process_cli_args<'a>() -> ProxyResult<'a, ProxyConfig> {
     Ok(toml::from_str::<ProxyConfig>(&config_file)?)
 }
 
-/// TODO on the setup phase JDC must send a random nonce to bitcoind and JDS used for the tx
-/// hashlist
-///
-/// TODO on setupconnection with bitcoind (TP) JDC must signal that want a tx short hash list with
-/// the templates
-///
-/// TODO JDC must handle TxShortHahhList message
+/// The short ids of the declared transactions are computed by the JDC, from the transactions
+/// received with RequestTransactionDataSuccess, using a random nonce for each declared job. If the
+/// JDS can not identify them it asks for the full txids with IdentifyTransactions.
 ///
 /// This will start:
 /// 1. An Upstream, this will connect with the mining Pool
@@ -97,7 +93,6 @@ fn process_cli_args<'a>() -> ProxyResult<'a, ProxyConfig> {
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    let mut upstream_index = 0;
     let mut interrupt_signal_future = Box::pin(tokio::signal::ctrl_c().fuse());
 
     // Channel used to manage failed tasks
@@ -109,11 +104,34 @@ async fn main() {
         Ok(p) => p,
         Err(_) => return,
     };
+    if let Err(e) = lib::proxy_config::get_coinbase_commitments(&proxy_config) {
+        error!("Invalid coinbase tag: {}", e);
+        return;
+    }
+    if let Err(e) = lib::tx_selection::TxSelectionPolicy::new(&proxy_config.tx_selection) {
+        error!("{}", e);
+        return;
+    }
+    if let Err(address) = lib::proxy_config::get_template_providers(&proxy_config) {
+        error!("Invalid Template Provider address {}", address);
+        return;
+    }
+    if let Err(e) = lib::proxy_config::get_coinbase_rotation(&proxy_config) {
+        error!("Invalid coinbase rotation: {}", e);
+        return;
+    }
+
+    // Pool changes and solo mining episodes
+    let mut pool_history = status::PoolHistory::new(proxy_config.upstreams.len());
 
     loop {
         {
             let task_collector = task_collector.clone();
+            let upstream_index = pool_history.upstream_index();
             let tx_status = tx_status.clone();
+            // Upstreams with an higher priority than the one in use, probed for fail-back
+            let upstreams_to_probe =
+                proxy_config.upstreams[..upstream_index.min(proxy_config.upstreams.len())].to_vec();
 
             if let Some(upstream) = proxy_config.upstreams.get(upstream_index) {
                 let initialize = initialize_jd(
@@ -121,6 +139,7 @@ async fn main() {
                     task_collector,
                     upstream.clone(),
                     proxy_config.timeout,
+                    upstreams_to_probe,
                 );
                 tokio::task::spawn(initialize);
             } else {
@@ -128,6 +147,7 @@ async fn main() {
                     tx_status.clone(),
                     task_collector,
                     proxy_config.timeout,
+                    upstreams_to_probe,
                 );
                 tokio::task::spawn(initialize);
             }
@@ -189,7 +209,20 @@ async fn main() {
                             }
                         })
                         .unwrap();
-                    upstream_index += 1;
+                    pool_history.on_upstream_rogue();
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    break;
+                }
+                status::State::UpstreamRecovered(index) => {
+                    info!("Switching back to upstream {}", index);
+                    task_collector
+                        .safe_lock(|s| {
+                            for handle in s {
+                                handle.abort();
+                            }
+                        })
+                        .unwrap();
+                    pool_history.on_upstream_recovered(index);
                     tokio::time::sleep(std::time::Duration::from_secs(1)).await;
                     break;
                 }
@@ -200,13 +233,16 @@ async fn main() {
         }
     }
 }
+
 async fn initialize_jd_as_solo_miner(
     tx_status: async_channel::Sender<status::Status<'static>>,
     task_collector: Arc<Mutex<Vec<AbortHandle>>>,
     timeout: Duration,
+    upstreams_to_probe: Vec<lib::proxy_config::Upstream>,
 ) {
     let proxy_config = process_cli_args().unwrap();
     let miner_tx_out = lib::proxy_config::get_coinbase_output(&proxy_config).unwrap();
+    let coinbase_commitments = lib::proxy_config::get_coinbase_commitments(&proxy_config).unwrap();
 
     // When Downstream receive a share that meets bitcoin target it transformit in a
     // SubmitSolution and send it to the TemplateReceiver
@@ -218,6 +254,17 @@ async fn initialize_jd_as_solo_miner(
         proxy_config.downstream_port,
     );
 
+    let pool_changer_trigger = Arc::new(Mutex::new(PoolChangerTrigger::new(timeout)));
+    lib::fail_back::start_health_probing(
+        upstreams_to_probe,
+        proxy_config.fail_back.clone(),
+        proxy_config.min_supported_version,
+        proxy_config.max_supported_version,
+        downstream_addr,
+        pool_changer_trigger.clone(),
+        task_collector.clone(),
+    );
+
     // Wait for downstream to connect
     let downstream = lib::downstream::listen_for_downstream_mining(
         downstream_addr,
@@ -231,26 +278,31 @@ async fn initialize_jd_as_solo_miner(
         status::Sender::Downstream(tx_status.clone()),
         miner_tx_out.clone(),
         None,
+        coinbase_commitments.clone(),
     )
     .await
     .unwrap();
 
     // Initialize JD part
-    let mut parts = proxy_config.tp_address.split(':');
-    let ip_tp = parts.next().unwrap().to_string();
-    let port_tp = parts.next().unwrap().parse::<u16>().unwrap();
+    // validated at startup
+    let template_providers = lib::proxy_config::get_template_providers(&proxy_config).unwrap();
 
     TemplateRx::connect(
-        SocketAddr::new(IpAddr::from_str(ip_tp.as_str()).unwrap(), port_tp),
+        template_providers,
         recv_solution,
         status::Sender::TemplateReceiver(tx_status.clone()),
         None,
         downstream,
         task_collector,
-        Arc::new(Mutex::new(PoolChangerTrigger::new(timeout))),
+        pool_changer_trigger,
         miner_tx_out.clone(),
-        proxy_config.tp_authority_public_key,
         false,
+        None,
+        lib::template_receiver::block_submission::LocalNode::new(&proxy_config.block_submission),
+        Arc::new(Mutex::new(FoundBlocks::default())),
+        // validated at startup
+        lib::proxy_config::get_coinbase_rotation(&proxy_config).unwrap(),
+        coinbase_commitments,
     )
     .await;
 }
@@ -260,8 +312,10 @@ async fn initialize_jd(
     task_collector: Arc<Mutex<Vec<AbortHandle>>>,
     upstream_config: lib::proxy_config::Upstream,
     timeout: Duration,
+    upstreams_to_probe: Vec<lib::proxy_config::Upstream>,
 ) {
     let proxy_config = process_cli_args().unwrap();
+    let coinbase_commitments = lib::proxy_config::get_coinbase_commitments(&proxy_config).unwrap();
     let test_only_do_not_send_solution_to_tp = proxy_config
         .test_only_do_not_send_solution_to_tp
         .unwrap_or(false);
@@ -285,6 +339,10 @@ async fn initialize_jd(
     // SubmitSolution and send it to the TemplateReceiver
     let (send_solution, recv_solution) = bounded(10);
 
+    // Blocks found by the downstream, the upstream and the TP receiver report the path that
+    // confirmed them first
+    let found_blocks = Arc::new(Mutex::new(FoundBlocks::default()));
+
     // Instantiate a new `Upstream` (SV2 Pool)
     let upstream = match lib::upstream_sv2::Upstream::new(
         upstream_addr,
@@ -294,6 +352,7 @@ async fn initialize_jd(
         status::Sender::Upstream(tx_status.clone()),
         task_collector.clone(),
         Arc::new(Mutex::new(PoolChangerTrigger::new(timeout))),
+        found_blocks.clone(),
     )
     .await
     {
@@ -331,9 +390,8 @@ async fn initialize_jd(
     );
 
     // Initialize JD part
-    let mut parts = proxy_config.tp_address.split(':');
-    let ip_tp = parts.next().unwrap().to_string();
-    let port_tp = parts.next().unwrap().parse::<u16>().unwrap();
+    // validated at startup
+    let template_providers = lib::proxy_config::get_template_providers(&proxy_config).unwrap();
 
     let mut parts = upstream_config.jd_address.split(':');
     let ip_jd = parts.next().unwrap().to_string();
@@ -358,6 +416,17 @@ async fn initialize_jd(
         }
     };
 
+    let pool_changer_trigger = Arc::new(Mutex::new(PoolChangerTrigger::new(timeout)));
+    lib::fail_back::start_health_probing(
+        upstreams_to_probe,
+        proxy_config.fail_back.clone(),
+        proxy_config.min_supported_version,
+        proxy_config.max_supported_version,
+        downstream_addr,
+        pool_changer_trigger.clone(),
+        task_collector.clone(),
+    );
+
     // Wait for downstream to connect
     let downstream = lib::downstream::listen_for_downstream_mining(
         downstream_addr,
@@ -371,21 +440,29 @@ async fn initialize_jd(
         status::Sender::Downstream(tx_status.clone()),
         vec![],
         Some(jd.clone()),
+        coinbase_commitments.clone(),
     )
     .await
     .unwrap();
 
     TemplateRx::connect(
-        SocketAddr::new(IpAddr::from_str(ip_tp.as_str()).unwrap(), port_tp),
+        template_providers,
         recv_solution,
         status::Sender::TemplateReceiver(tx_status.clone()),
         Some(jd.clone()),
         downstream,
         task_collector,
-        Arc::new(Mutex::new(PoolChangerTrigger::new(timeout))),
+        pool_changer_trigger,
         vec![],
-        proxy_config.tp_authority_public_key,
         test_only_do_not_send_solution_to_tp,
+        // validated at startup
+        lib::tx_selection::TxSelectionPolicy::new(&proxy_config.tx_selection)
+            .unwrap()
+            .map(Arc::new),
+        lib::template_receiver::block_submission::LocalNode::new(&proxy_config.block_submission),
+        found_blocks,
+        None,
+        coinbase_commitments,
     )
     .await;
 }
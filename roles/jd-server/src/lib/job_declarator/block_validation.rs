@@ -0,0 +1,601 @@
+//! Consensus limits of the block of a declared job, checked before issuing
+//! DeclareMiningJobSuccess. The block is assembled with `BlockCreator` from the declared coinbase
+//! and the job transactions, with an empty header and a zeroed extranonce since neither affects
+//! the checked limits.
+use binary_sv2::{B032, U256};
+use roles_logic_sv2::{job_declaration_sv2::DeclareMiningJob, utils::BlockCreator};
+use rpc_sv2::mini_rpc_client::{MiniRpcClient, RpcError};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    fmt,
+};
+use stratum_common::bitcoin::{
+    blockdata::{
+        opcodes::{self, all::*},
+        script::{Builder, Instruction},
+    },
+    consensus::deserialize,
+    Block, OutPoint, Script, Transaction, TxIn, TxOut, Witness,
+};
+
+pub const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+pub const MAX_BLOCK_SIGOPS_COST: usize = 80_000;
+const WITNESS_SCALE_FACTOR: usize = 4;
+const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+const MIN_COINBASE_SCRIPT_SIG_SIZE: usize = 2;
+const MAX_COINBASE_SCRIPT_SIG_SIZE: usize = 100;
+// Extranonce of SubmitSolutionJd
+const MAX_EXTRANONCE_SIZE: usize = 32;
+// The outputs spent by the declared jobs are cached up to this size, then the cache is cleared
+const PREVOUTS_CACHE_CAPACITY: usize = 100_000;
+
+#[derive(Debug)]
+pub enum BlockValidationError {
+    /// The coinbase prefix and suffix do not make a valid transaction
+    InvalidCoinbase(String),
+    /// Size of the coinbase scriptSig, outside of 2..=100 bytes
+    CoinbaseScriptSigSize(usize),
+    /// The coinbase scriptSig do not start with the expected height
+    Bip34Height(u64),
+    BlockWeight(usize),
+    SigopsCost(usize),
+    /// A transaction spends an output that is neither in the chain nor in the block
+    MissingInput(OutPoint),
+    /// The block can not be checked, eg the node is not reachable
+    Unavailable(String),
+}
+
+impl BlockValidationError {
+    /// Error code of the DeclareMiningJobError sent to the downstream
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            BlockValidationError::InvalidCoinbase(_) => "invalid-coinbase",
+            BlockValidationError::CoinbaseScriptSigSize(_) => "bad-cb-length",
+            BlockValidationError::Bip34Height(_) => "bad-cb-height",
+            BlockValidationError::BlockWeight(_) => "bad-blk-weight",
+            BlockValidationError::SigopsCost(_) => "bad-blk-sigops",
+            BlockValidationError::MissingInput(_) => "bad-txns-inputs-missingorspent",
+            BlockValidationError::Unavailable(_) => "validation-unavailable",
+        }
+    }
+}
+
+impl fmt::Display for BlockValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use BlockValidationError::*;
+        match self {
+            InvalidCoinbase(e) => write!(f, "Invalid coinbase: {}", e),
+            CoinbaseScriptSigSize(size) => write!(
+                f,
+                "Coinbase scriptSig of {} bytes, it must be between {} and {} bytes",
+                size, MIN_COINBASE_SCRIPT_SIG_SIZE, MAX_COINBASE_SCRIPT_SIG_SIZE
+            ),
+            Bip34Height(height) => write!(
+                f,
+                "Coinbase scriptSig do not start with the block height {}",
+                height
+            ),
+            BlockWeight(weight) => write!(
+                f,
+                "Block weight {} exceeds the limit of {}",
+                weight, MAX_BLOCK_WEIGHT
+            ),
+            SigopsCost(cost) => write!(
+                f,
+                "Block sigops cost {} exceeds the limit of {}",
+                cost, MAX_BLOCK_SIGOPS_COST
+            ),
+            MissingInput(outpoint) => write!(f, "Input {} missing or spent", outpoint),
+            Unavailable(e) => write!(f, "Impossible to validate the block: {}", e),
+        }
+    }
+}
+
+impl From<RpcError> for BlockValidationError {
+    fn from(e: RpcError) -> Self {
+        BlockValidationError::Unavailable(format!("{:?}", e))
+    }
+}
+
+/// Size of the extranonce that is added by the miner between the coinbase prefix and suffix: the
+/// prefix ends inside the scriptSig of the only coinbase input.
+fn extranonce_size(coinbase_prefix: &[u8]) -> Result<usize, BlockValidationError> {
+    let invalid = || BlockValidationError::InvalidCoinbase("Invalid coinbase prefix".to_string());
+    // version
+    let mut offset = 4;
+    // segwit marker and flag
+    if coinbase_prefix.get(offset) == Some(&0) && coinbase_prefix.get(offset + 1) == Some(&1) {
+        offset += 2;
+    }
+    // one input, the previous outpoint is 36 bytes
+    if coinbase_prefix.get(offset) != Some(&1) {
+        return Err(invalid());
+    }
+    offset += 1 + 36;
+    let script_sig_len = match coinbase_prefix.get(offset) {
+        Some(len @ 0..=0xfc) => *len as usize,
+        // scriptSigs longer than 252 bytes are invalid anyway
+        Some(_) => {
+            return Err(BlockValidationError::CoinbaseScriptSigSize(
+                MAX_COINBASE_SCRIPT_SIG_SIZE + 1,
+            ))
+        }
+        None => return Err(invalid()),
+    };
+    offset += 1;
+    let in_prefix = coinbase_prefix
+        .len()
+        .checked_sub(offset)
+        .ok_or_else(invalid)?;
+    match script_sig_len.checked_sub(in_prefix) {
+        Some(size) if size <= MAX_EXTRANONCE_SIZE => Ok(size),
+        _ => Err(invalid()),
+    }
+}
+
+/// Assembles the block of the declared job, `transactions` are the job transactions in order
+pub fn build_block(
+    declared_job: DeclareMiningJob<'static>,
+    transactions: Vec<Transaction>,
+) -> Result<Block, BlockValidationError> {
+    let coinbase_prefix = declared_job.coinbase_prefix.to_vec();
+    let extranonce = vec![0; extranonce_size(&coinbase_prefix)?];
+    let coinbase = [
+        &coinbase_prefix[..],
+        &extranonce[..],
+        &declared_job.coinbase_suffix.to_vec()[..],
+    ]
+    .concat();
+    // BlockCreator expects a valid coinbase
+    deserialize::<Transaction>(&coinbase)
+        .map_err(|e| BlockValidationError::InvalidCoinbase(e.to_string()))?;
+    let extranonce: B032<'static> = extranonce.try_into().unwrap();
+    let prev_hash: U256<'static> = [0_u8; 32].into();
+    let solution = roles_logic_sv2::job_declaration_sv2::SubmitSolutionJd {
+        extranonce,
+        prev_hash,
+        ntime: 0,
+        nonce: 0,
+        nbits: 0,
+        version: declared_job.version,
+    };
+    Ok(BlockCreator::new(declared_job, transactions, solution).into())
+}
+
+/// Outputs of the UTXO set spent by the declared jobs of a connection. They are only valid for
+/// the chain tip they have been retrieved at, a new block may spend them.
+#[derive(Debug, Default)]
+pub struct PrevoutsCache {
+    tip: Option<String>,
+    outputs: HashMap<OutPoint, TxOut>,
+}
+
+impl PrevoutsCache {
+    /// Clears the cache when the tip of the node changed or when it is full
+    pub fn on_tip(&mut self, tip: &str) {
+        if self.tip.as_deref() != Some(tip) || self.outputs.len() > PREVOUTS_CACHE_CAPACITY {
+            self.outputs.clear();
+            self.tip = Some(tip.to_string());
+        }
+    }
+}
+
+/// Outputs spent by the block transactions. The ones created in the block are taken from it, the
+/// others from the UTXO set of the node, `cache` keeps them between the jobs of a connection.
+pub async fn get_prevouts(
+    client: &MiniRpcClient,
+    block: &Block,
+    cache: &mut PrevoutsCache,
+) -> Result<HashMap<OutPoint, TxOut>, BlockValidationError> {
+    let cache = &mut cache.outputs;
+    // the outputs of a transaction can be spent by the following ones, the others are asked to
+    // the node in one batch
+    let mut created = HashSet::new();
+    let mut missing = Vec::new();
+    let mut requested = HashSet::new();
+    for tx in block.txdata.iter().skip(1) {
+        for input in &tx.input {
+            let outpoint = input.previous_output;
+            if !created.contains(&outpoint.txid)
+                && !cache.contains_key(&outpoint)
+                && requested.insert(outpoint)
+            {
+                missing.push(outpoint);
+            }
+        }
+        created.insert(tx.txid());
+    }
+    let requests: Vec<(String, u32)> = missing
+        .iter()
+        .map(|outpoint| (outpoint.txid.to_string(), outpoint.vout))
+        .collect();
+    let outputs = client.get_tx_outs(&requests).await?;
+    for (outpoint, prevout) in missing.into_iter().zip(outputs) {
+        let prevout = prevout.ok_or(BlockValidationError::MissingInput(outpoint))?;
+        cache.insert(outpoint, prevout);
+    }
+
+    let mut prevouts = HashMap::new();
+    for tx in block.txdata.iter().skip(1) {
+        for input in &tx.input {
+            let outpoint = input.previous_output;
+            if prevouts.contains_key(&outpoint) {
+                continue;
+            }
+            let prevout = cache
+                .get(&outpoint)
+                .cloned()
+                .ok_or(BlockValidationError::MissingInput(outpoint))?;
+            prevouts.insert(outpoint, prevout);
+        }
+        let txid = tx.txid();
+        for (vout, output) in tx.output.iter().enumerate() {
+            prevouts.insert(
+                OutPoint {
+                    txid,
+                    vout: vout as u32,
+                },
+                output.clone(),
+            );
+        }
+    }
+    Ok(prevouts)
+}
+
+/// Checks the block against the consensus limits. `height` is the height of the block,
+/// `prevouts` the outputs spent by its transactions. The BIP34 height and the sigops cost are not
+/// checked when they are not given.
+pub fn check_block(
+    block: &Block,
+    height: Option<u64>,
+    prevouts: Option<&HashMap<OutPoint, TxOut>>,
+) -> Result<(), BlockValidationError> {
+    let coinbase = &block.txdata[0];
+    let script_sig = coinbase.input[0].script_sig.as_bytes();
+    if !(MIN_COINBASE_SCRIPT_SIG_SIZE..=MAX_COINBASE_SCRIPT_SIG_SIZE).contains(&script_sig.len()) {
+        return Err(BlockValidationError::CoinbaseScriptSigSize(
+            script_sig.len(),
+        ));
+    }
+    // BIP34, the scriptSig starts with the serialized height
+    if let Some(height) = height {
+        let expected = Builder::new().push_int(height as i64).into_script();
+        if !script_sig.starts_with(expected.as_bytes()) {
+            return Err(BlockValidationError::Bip34Height(height));
+        }
+    }
+    let weight = block.weight();
+    if weight > MAX_BLOCK_WEIGHT {
+        return Err(BlockValidationError::BlockWeight(weight));
+    }
+    if let Some(prevouts) = prevouts {
+        let mut sigops_cost = 0;
+        for tx in &block.txdata {
+            sigops_cost += transaction_sigops_cost(tx, prevouts)?;
+        }
+        if sigops_cost > MAX_BLOCK_SIGOPS_COST {
+            return Err(BlockValidationError::SigopsCost(sigops_cost));
+        }
+    }
+    Ok(())
+}
+
+// Same as GetTransactionSigOpCost of bitcoind with the P2SH and witness rules active
+fn transaction_sigops_cost(
+    tx: &Transaction,
+    prevouts: &HashMap<OutPoint, TxOut>,
+) -> Result<usize, BlockValidationError> {
+    let legacy: usize = tx
+        .input
+        .iter()
+        .map(|input| count_sigops(&input.script_sig, false))
+        .chain(
+            tx.output
+                .iter()
+                .map(|output| count_sigops(&output.script_pubkey, false)),
+        )
+        .sum();
+    let mut cost = legacy * WITNESS_SCALE_FACTOR;
+    if tx.is_coin_base() {
+        return Ok(cost);
+    }
+    for input in &tx.input {
+        let prevout = prevouts
+            .get(&input.previous_output)
+            .ok_or(BlockValidationError::MissingInput(input.previous_output))?;
+        if prevout.script_pubkey.is_p2sh() {
+            cost += p2sh_sigops(&input.script_sig) * WITNESS_SCALE_FACTOR;
+        }
+        cost += witness_sigops(input, &prevout.script_pubkey);
+    }
+    Ok(cost)
+}
+
+fn count_sigops(script: &Script, accurate: bool) -> usize {
+    let mut count = 0;
+    let mut last_opcode = None;
+    for instruction in script.instructions() {
+        match instruction {
+            Ok(Instruction::Op(op)) => {
+                match op {
+                    OP_CHECKSIG | OP_CHECKSIGVERIFY => count += 1,
+                    OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                        count += match last_opcode {
+                            Some(n) if accurate && is_small_int(n) => small_int_value(n),
+                            _ => MAX_PUBKEYS_PER_MULTISIG,
+                        }
+                    }
+                    _ => (),
+                }
+                last_opcode = Some(op);
+            }
+            Ok(Instruction::PushBytes(_)) => last_opcode = None,
+            Err(_) => break,
+        }
+    }
+    count
+}
+
+fn is_small_int(op: opcodes::All) -> bool {
+    (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&op.to_u8())
+}
+
+fn small_int_value(op: opcodes::All) -> usize {
+    (op.to_u8() - OP_PUSHNUM_1.to_u8() + 1) as usize
+}
+
+/// Last data pushed by a push only script, None if the script is not push only
+fn last_push(script: &Script) -> Option<Vec<u8>> {
+    let mut last = Vec::new();
+    for instruction in script.instructions() {
+        match instruction {
+            Ok(Instruction::PushBytes(data)) => last = data.to_vec(),
+            Ok(Instruction::Op(op)) if op.to_u8() <= OP_PUSHNUM_16.to_u8() => last = Vec::new(),
+            _ => return None,
+        }
+    }
+    Some(last)
+}
+
+// The redeem script is the last push of the scriptSig
+fn p2sh_sigops(script_sig: &Script) -> usize {
+    match last_push(script_sig) {
+        Some(redeem_script) => count_sigops(&Script::from(redeem_script), true),
+        None => 0,
+    }
+}
+
+fn witness_sigops(input: &TxIn, script_pubkey: &Script) -> usize {
+    if script_pubkey.is_witness_program() {
+        return witness_program_sigops(script_pubkey, &input.witness);
+    }
+    if script_pubkey.is_p2sh() {
+        if let Some(redeem_script) = last_push(&input.script_sig) {
+            let redeem_script = Script::from(redeem_script);
+            if redeem_script.is_witness_program() {
+                return witness_program_sigops(&redeem_script, &input.witness);
+            }
+        }
+    }
+    0
+}
+
+// Only version 0 programs have sigops, the taproot ones are limited per input
+fn witness_program_sigops(program: &Script, witness: &Witness) -> usize {
+    let program = program.as_bytes();
+    if program[0] != OP_PUSHBYTES_0.to_u8() {
+        return 0;
+    }
+    match (program.len() - 2, witness.last()) {
+        (20, _) => 1,
+        (32, Some(witness_script)) => count_sigops(&Script::from(witness_script.to_vec()), true),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use stratum_common::bitcoin::{
+        blockdata::constants::genesis_block, consensus::serialize, Network, PackedLockTime,
+        Sequence,
+    };
+
+    fn multisig_2_of_3() -> Script {
+        Builder::new()
+            .push_opcode(OP_PUSHNUM_2)
+            .push_slice(&[2; 33])
+            .push_slice(&[3; 33])
+            .push_slice(&[4; 33])
+            .push_opcode(OP_PUSHNUM_3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script()
+    }
+
+    fn witness_program(version: opcodes::All, program: &[u8]) -> Script {
+        Builder::new()
+            .push_opcode(version)
+            .push_slice(program)
+            .into_script()
+    }
+
+    // Sigops cost of a transaction spending a single output locked by `script_pubkey`
+    fn spend_cost(script_pubkey: Script, script_sig: Script, witness: Vec<Vec<u8>>) -> usize {
+        let previous_output: OutPoint =
+            "0000000000000000000000000000000000000000000000000000000000000001:0"
+                .parse()
+                .unwrap();
+        let tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output,
+                script_sig,
+                sequence: Sequence::MAX,
+                witness: Witness::from_vec(witness),
+            }],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: Builder::new().push_opcode(OP_PUSHNUM_1).into_script(),
+            }],
+        };
+        let prevouts = HashMap::from([(
+            previous_output,
+            TxOut {
+                value: 0,
+                script_pubkey,
+            },
+        )]);
+        transaction_sigops_cost(&tx, &prevouts).unwrap()
+    }
+
+    fn block_with_script_sig(script_sig: Script) -> Block {
+        let mut block = genesis_block(Network::Bitcoin);
+        block.txdata[0].input[0].script_sig = script_sig;
+        block
+    }
+
+    #[test]
+    fn genesis_block_is_within_limits() {
+        let block = genesis_block(Network::Bitcoin);
+        assert_eq!(block.weight(), 1140);
+        // the output of the genesis coinbase is a pay to pubkey
+        assert_eq!(
+            transaction_sigops_cost(&block.txdata[0], &HashMap::new()).unwrap(),
+            4
+        );
+        assert!(check_block(&block, None, Some(&HashMap::new())).is_ok());
+    }
+
+    #[test]
+    fn counts_legacy_sigops() {
+        let p2pkh = Builder::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_slice(&[1; 20])
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        assert_eq!(count_sigops(&p2pkh, false), 1);
+        assert_eq!(count_sigops(&multisig_2_of_3(), false), 20);
+        assert_eq!(count_sigops(&multisig_2_of_3(), true), 3);
+    }
+
+    #[test]
+    fn counts_p2sh_sigops() {
+        let redeem_script = multisig_2_of_3();
+        let script_sig = Builder::new()
+            .push_opcode(OP_PUSHBYTES_0)
+            .push_slice(&[1; 71])
+            .push_slice(&[2; 71])
+            .push_slice(redeem_script.as_bytes())
+            .into_script();
+        let cost = spend_cost(
+            Script::new_p2sh(&redeem_script.script_hash()),
+            script_sig,
+            vec![],
+        );
+        assert_eq!(cost, 3 * WITNESS_SCALE_FACTOR);
+    }
+
+    #[test]
+    fn counts_witness_sigops() {
+        let p2wpkh = witness_program(OP_PUSHBYTES_0, &[1; 20]);
+        let witness = vec![vec![1; 71], vec![2; 33]];
+        assert_eq!(
+            spend_cost(p2wpkh.clone(), Script::new(), witness.clone()),
+            1
+        );
+
+        let p2wsh = witness_program(OP_PUSHBYTES_0, &[1; 32]);
+        let witness_script = vec![
+            vec![],
+            vec![1; 71],
+            vec![2; 71],
+            multisig_2_of_3().to_bytes(),
+        ];
+        assert_eq!(spend_cost(p2wsh, Script::new(), witness_script), 3);
+
+        let script_sig = Builder::new().push_slice(p2wpkh.as_bytes()).into_script();
+        let p2sh_p2wpkh = Script::new_p2sh(&p2wpkh.script_hash());
+        assert_eq!(spend_cost(p2sh_p2wpkh, script_sig, witness.clone()), 1);
+
+        let taproot = witness_program(OP_PUSHNUM_1, &[1; 32]);
+        assert_eq!(spend_cost(taproot, Script::new(), vec![vec![1; 64]]), 0);
+    }
+
+    #[test]
+    fn checks_bip34_height() {
+        let script_sig = Script::from(vec![0x03, 0x40, 0xd1, 0x0c, 0x00, 0x00]);
+        let block = block_with_script_sig(script_sig);
+        assert!(check_block(&block, Some(840_000), None).is_ok());
+        assert!(matches!(
+            check_block(&block, Some(839_999), None),
+            Err(BlockValidationError::Bip34Height(839_999))
+        ));
+        assert!(matches!(
+            check_block(&block, Some(840_001), None),
+            Err(BlockValidationError::Bip34Height(840_001))
+        ));
+    }
+
+    #[test]
+    fn checks_coinbase_script_sig_size() {
+        let block = block_with_script_sig(Script::from(vec![0x51]));
+        assert!(matches!(
+            check_block(&block, None, None),
+            Err(BlockValidationError::CoinbaseScriptSigSize(1))
+        ));
+        let block = block_with_script_sig(Script::from(vec![0x51; 101]));
+        assert!(matches!(
+            check_block(&block, None, None),
+            Err(BlockValidationError::CoinbaseScriptSigSize(101))
+        ));
+    }
+
+    #[test]
+    fn extranonce_size_from_coinbase_prefix() {
+        let coinbase = serialize(&genesis_block(Network::Bitcoin).txdata[0]);
+        // version, input count, previous outpoint and scriptSig length
+        let script_sig_start = 4 + 1 + 36 + 1;
+        let script_sig_len = coinbase[script_sig_start - 1] as usize;
+        let script_sig_end = script_sig_start + script_sig_len;
+
+        let prefix = &coinbase[..script_sig_end - 8];
+        assert_eq!(extranonce_size(prefix).unwrap(), 8);
+        let segwit_prefix = [&prefix[..4], &[0, 1], &prefix[4..]].concat();
+        assert_eq!(extranonce_size(&segwit_prefix).unwrap(), 8);
+        assert_eq!(extranonce_size(&coinbase[..script_sig_end]).unwrap(), 0);
+
+        // the extranonce does not fit in SubmitSolutionJd
+        assert!(extranonce_size(&coinbase[..script_sig_end - 33]).is_err());
+        // the prefix ends before the scriptSig
+        assert!(extranonce_size(&coinbase[..script_sig_start - 2]).is_err());
+        let mut two_inputs = prefix.to_vec();
+        two_inputs[4] = 2;
+        assert!(extranonce_size(&two_inputs).is_err());
+        let mut long_script_sig = prefix.to_vec();
+        long_script_sig[script_sig_start - 1] = 0xfd;
+        assert!(matches!(
+            extranonce_size(&long_script_sig),
+            Err(BlockValidationError::CoinbaseScriptSigSize(_))
+        ));
+    }
+
+    #[test]
+    fn prevouts_cache_is_cleared_on_new_tip() {
+        let outpoint = OutPoint::null();
+        let output = TxOut {
+            value: 0,
+            script_pubkey: Script::new(),
+        };
+        let mut cache = PrevoutsCache::default();
+        cache.on_tip("tip");
+        cache.outputs.insert(outpoint, output);
+        cache.on_tip("tip");
+        assert!(cache.outputs.contains_key(&outpoint));
+        cache.on_tip("new tip");
+        assert!(cache.outputs.is_empty());
+    }
+}
@@ -0,0 +1,473 @@
+//! Per connection quotas and policy of the transactions provided by a JDC, so that a public JDS
+//! can not be flooded with declarations or with transactions unknown to its node.
+use super::super::{JobPolicyConfig, QuotasConfig};
+use rpc_sv2::mini_rpc_client::{MiniRpcClient, RpcError};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    time::{Duration, Instant},
+};
+use stratum_common::bitcoin::{consensus::encode::serialize_hex, Transaction, Txid};
+
+const SATS_PER_BTC: f64 = 100_000_000.0;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+// Maximum number of transactions of a testmempoolaccept package
+const MAX_PACKAGE_COUNT: usize = 25;
+// testmempoolaccept rejections that are not a policy violation: the transaction spends one that
+// is not in the same package, eg a package split at MAX_PACKAGE_COUNT (the inputs are checked
+// with the consensus limits), or the node got it in the meantime
+const IGNORED_REJECT_REASONS: [&str; 3] = [
+    "missing-inputs",
+    "txn-already-in-mempool",
+    "txn-already-known",
+];
+
+#[derive(Debug)]
+pub enum JobPolicyError {
+    DeclarationRateExceeded(u32),
+    MissingTransactionsTooLarge(u64),
+    TooManyUnknownTransactions(usize),
+    /// (txid, reason)
+    NonStandardTransaction(String, String),
+    /// Fee rate in sat/vB
+    PackageFeeRateTooLow(f64),
+    /// The policy can not be applied, eg the node is not reachable
+    Unavailable(String),
+}
+
+impl JobPolicyError {
+    /// Error code of the DeclareMiningJobError sent to the downstream
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            JobPolicyError::DeclarationRateExceeded(_) => "declaration-rate-exceeded",
+            JobPolicyError::MissingTransactionsTooLarge(_) => "missing-transactions-too-large",
+            JobPolicyError::TooManyUnknownTransactions(_) => "too-many-unknown-transactions",
+            JobPolicyError::NonStandardTransaction(_, _) => "non-standard-transaction",
+            JobPolicyError::PackageFeeRateTooLow(_) => "package-fee-rate-too-low",
+            JobPolicyError::Unavailable(_) => "policy-unavailable",
+        }
+    }
+}
+
+impl fmt::Display for JobPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use JobPolicyError::*;
+        match self {
+            DeclarationRateExceeded(max) => {
+                write!(f, "More than {} declarations in a minute", max)
+            }
+            MissingTransactionsTooLarge(size) => {
+                write!(f, "Provided transactions of {} bytes", size)
+            }
+            TooManyUnknownTransactions(count) => {
+                write!(
+                    f,
+                    "{} unknown transactions cached for the connection",
+                    count
+                )
+            }
+            NonStandardTransaction(txid, reason) => {
+                write!(f, "Transaction {} refused by the node: {}", txid, reason)
+            }
+            PackageFeeRateTooLow(fee_rate) => {
+                write!(f, "Provided transactions pay {:.2} sat/vB", fee_rate)
+            }
+            Unavailable(e) => write!(f, "Impossible to apply the job policy: {}", e),
+        }
+    }
+}
+
+impl From<RpcError> for JobPolicyError {
+    fn from(e: RpcError) -> Self {
+        JobPolicyError::Unavailable(format!("{:?}", e))
+    }
+}
+
+#[derive(Debug)]
+pub struct JobPolicy {
+    quotas: QuotasConfig,
+    config: JobPolicyConfig,
+    // Time of the declarations accepted in the last RATE_WINDOW
+    declarations: VecDeque<Instant>,
+    // Transactions provided by the connection that may still be in the JDS mempool
+    provided: HashSet<Txid>,
+}
+
+impl JobPolicy {
+    pub fn new(quotas: QuotasConfig, config: JobPolicyConfig) -> Self {
+        Self {
+            quotas,
+            config,
+            declarations: VecDeque::new(),
+            provided: HashSet::new(),
+        }
+    }
+
+    pub fn config(&self) -> JobPolicyConfig {
+        self.config.clone()
+    }
+
+    /// Called on each DeclareMiningJob
+    pub fn on_declare_mining_job(&mut self) -> Result<(), JobPolicyError> {
+        let max = self.quotas.max_declarations_per_minute;
+        if max == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        while let Some(declared_at) = self.declarations.front() {
+            if now.duration_since(*declared_at) < RATE_WINDOW {
+                break;
+            }
+            self.declarations.pop_front();
+        }
+        if self.declarations.len() >= max as usize {
+            return Err(JobPolicyError::DeclarationRateExceeded(max));
+        }
+        self.declarations.push_back(now);
+        Ok(())
+    }
+
+    /// Called on ProvideMissingTransactionsSuccess, `size` is the size of the provided
+    /// transactions and `is_cached` tells if a transaction is still in the JDS mempool
+    pub fn on_missing_transactions(
+        &mut self,
+        transactions: &[Transaction],
+        size: u64,
+        is_cached: impl Fn(&Txid) -> bool,
+    ) -> Result<(), JobPolicyError> {
+        let max_size = self.quotas.max_missing_transactions_bytes_per_job;
+        if max_size != 0 && size > max_size {
+            return Err(JobPolicyError::MissingTransactionsTooLarge(size));
+        }
+        self.provided.retain(|txid| is_cached(txid));
+        let max_cached = self.quotas.max_unknown_transactions_cached as usize;
+        let txids: HashSet<Txid> = transactions.iter().map(|tx| tx.txid()).collect();
+        let cached = self.provided.union(&txids).count();
+        if max_cached != 0 && cached > max_cached {
+            return Err(JobPolicyError::TooManyUnknownTransactions(cached));
+        }
+        self.provided.extend(txids);
+        Ok(())
+    }
+}
+
+/// Applies the policy to the transactions provided by the downstream, in the job order
+pub async fn check_provided_transactions(
+    config: &JobPolicyConfig,
+    client: Option<MiniRpcClient>,
+    transactions: &[Transaction],
+) -> Result<(), JobPolicyError> {
+    if transactions.is_empty()
+        || (!config.reject_non_standard && config.min_package_fee_rate <= 0.0)
+    {
+        return Ok(());
+    }
+    let client = client.ok_or_else(|| JobPolicyError::Unavailable("No RPC client".to_string()))?;
+    for package in packages(transactions) {
+        let raw_txs = package.into_iter().map(serialize_hex).collect();
+        // fees in sats and vsize of the transactions of the package accepted by the node
+        let mut fees = 0;
+        let mut vsize = 0;
+        for result in client.test_mempool_accept(raw_txs).await? {
+            if result.allowed == Some(true) {
+                if let (Some(tx_fees), Some(tx_vsize)) = (result.fees, result.vsize) {
+                    fees += (tx_fees.base * SATS_PER_BTC).round() as u64;
+                    vsize += tx_vsize;
+                }
+                continue;
+            }
+            let reason = result
+                .reject_reason
+                .or(result.package_error)
+                .unwrap_or_else(|| "unknown".to_string());
+            if config.reject_non_standard && !IGNORED_REJECT_REASONS.contains(&reason.as_str()) {
+                return Err(JobPolicyError::NonStandardTransaction(result.txid, reason));
+            }
+        }
+        if config.min_package_fee_rate > 0.0 && vsize > 0 {
+            let fee_rate = fees as f64 / vsize as f64;
+            if fee_rate < config.min_package_fee_rate {
+                return Err(JobPolicyError::PackageFeeRateTooLow(fee_rate));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Groups the transactions that spend each other in the same testmempoolaccept package, so that
+/// a child is tested with its parents. The job order is kept, parents come before their children.
+/// Packages larger than MAX_PACKAGE_COUNT are split.
+fn packages(transactions: &[Transaction]) -> Vec<Vec<&Transaction>> {
+    fn find(root: &mut [usize], mut i: usize) -> usize {
+        while root[i] != i {
+            root[i] = root[root[i]];
+            i = root[i];
+        }
+        i
+    }
+    let index: HashMap<Txid, usize> = transactions
+        .iter()
+        .enumerate()
+        .map(|(i, tx)| (tx.txid(), i))
+        .collect();
+    // each transaction points towards the first transaction of its package
+    let mut root: Vec<usize> = (0..transactions.len()).collect();
+    for (i, tx) in transactions.iter().enumerate() {
+        for input in &tx.input {
+            if let Some(parent) = index.get(&input.previous_output.txid) {
+                let (a, b) = (find(&mut root, i), find(&mut root, *parent));
+                root[a.max(b)] = a.min(b);
+            }
+        }
+    }
+    let mut packages: Vec<Vec<&Transaction>> = Vec::new();
+    let mut package_of_root: HashMap<usize, usize> = HashMap::new();
+    for (i, tx) in transactions.iter().enumerate() {
+        let package = *package_of_root
+            .entry(find(&mut root, i))
+            .or_insert_with(|| {
+                packages.push(Vec::new());
+                packages.len() - 1
+            });
+        packages[package].push(tx);
+    }
+    packages
+        .into_iter()
+        .flat_map(|package| {
+            package
+                .chunks(MAX_PACKAGE_COUNT)
+                .map(|chunk| chunk.to_vec())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rpc_sv2::{
+        mini_rpc_client::Auth,
+        stand_in::{result, stand_in},
+    };
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use stratum_common::bitcoin::{
+        OutPoint, PackedLockTime, Script, Sequence, TxIn, TxOut, Witness,
+    };
+
+    // `value` makes the transaction unique
+    fn transaction(spends: &[&Transaction], value: u64) -> Transaction {
+        let input = match spends {
+            [] => vec![OutPoint::new(OutPoint::null().txid, value as u32)],
+            _ => spends
+                .iter()
+                .map(|tx| OutPoint::new(tx.txid(), 0))
+                .collect(),
+        };
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: input
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    script_sig: Script::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    fn txids(packages: Vec<Vec<&Transaction>>) -> Vec<Vec<Txid>> {
+        packages
+            .into_iter()
+            .map(|package| package.into_iter().map(|tx| tx.txid()).collect())
+            .collect()
+    }
+
+    fn job_policy(quotas: QuotasConfig) -> JobPolicy {
+        JobPolicy::new(quotas, JobPolicyConfig::default())
+    }
+
+    #[test]
+    fn children_are_in_the_package_of_their_parents() {
+        let parent = transaction(&[], 1);
+        let other = transaction(&[], 2);
+        let child = transaction(&[&parent], 3);
+        let other_parent = transaction(&[], 4);
+        // joins the two packages
+        let grandchild = transaction(&[&child, &other_parent], 5);
+        let transactions = vec![
+            parent.clone(),
+            other.clone(),
+            child.clone(),
+            other_parent.clone(),
+            grandchild.clone(),
+        ];
+        assert_eq!(
+            txids(packages(&transactions)),
+            vec![
+                vec![
+                    parent.txid(),
+                    child.txid(),
+                    other_parent.txid(),
+                    grandchild.txid()
+                ],
+                vec![other.txid()],
+            ]
+        );
+    }
+
+    #[test]
+    fn large_packages_are_split() {
+        let mut transactions = vec![transaction(&[], 0)];
+        for value in 1..=MAX_PACKAGE_COUNT as u64 {
+            let child = transaction(&[transactions.last().unwrap()], value);
+            transactions.push(child);
+        }
+        let packages = packages(&transactions);
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].len(), MAX_PACKAGE_COUNT);
+        assert_eq!(packages[1].len(), 1);
+        assert!(super::packages(&[]).is_empty());
+    }
+
+    #[test]
+    fn declarations_are_rate_limited() {
+        let mut policy = job_policy(QuotasConfig {
+            max_declarations_per_minute: 2,
+            ..Default::default()
+        });
+        assert!(policy.on_declare_mining_job().is_ok());
+        assert!(policy.on_declare_mining_job().is_ok());
+        assert!(matches!(
+            policy.on_declare_mining_job(),
+            Err(JobPolicyError::DeclarationRateExceeded(2))
+        ));
+        // declarations older than the window are forgotten
+        policy.declarations.iter_mut().for_each(|declared_at| {
+            *declared_at = Instant::now() - RATE_WINDOW;
+        });
+        assert!(policy.on_declare_mining_job().is_ok());
+
+        let mut unlimited = job_policy(QuotasConfig::default());
+        for _ in 0..100 {
+            assert!(unlimited.on_declare_mining_job().is_ok());
+        }
+    }
+
+    #[test]
+    fn provided_transactions_size_is_limited() {
+        let mut policy = job_policy(QuotasConfig {
+            max_missing_transactions_bytes_per_job: 1000,
+            ..Default::default()
+        });
+        let transactions = [transaction(&[], 1)];
+        assert!(policy
+            .on_missing_transactions(&transactions, 1000, |_| true)
+            .is_ok());
+        assert!(matches!(
+            policy.on_missing_transactions(&transactions, 1001, |_| true),
+            Err(JobPolicyError::MissingTransactionsTooLarge(1001))
+        ));
+    }
+
+    #[test]
+    fn cached_unknown_transactions_are_limited() {
+        let mut policy = job_policy(QuotasConfig {
+            max_unknown_transactions_cached: 2,
+            ..Default::default()
+        });
+        let first = transaction(&[], 1);
+        let second = transaction(&[], 2);
+        let third = transaction(&[], 3);
+        assert!(policy
+            .on_missing_transactions(&[first.clone(), second.clone()], 0, |_| true)
+            .is_ok());
+        // the same transactions provided again are counted once
+        assert!(policy
+            .on_missing_transactions(std::slice::from_ref(&first), 0, |_| true)
+            .is_ok());
+        assert!(matches!(
+            policy.on_missing_transactions(std::slice::from_ref(&third), 0, |_| true),
+            Err(JobPolicyError::TooManyUnknownTransactions(3))
+        ));
+        // the transactions dropped from the JDS mempool free the quota
+        let first_txid = first.txid();
+        assert!(policy
+            .on_missing_transactions(&[third], 0, |txid| *txid != first_txid)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn provided_transactions_without_node() {
+        let transactions = [transaction(&[], 1)];
+        let disabled = JobPolicyConfig::default();
+        assert!(check_provided_transactions(&disabled, None, &transactions)
+            .await
+            .is_ok());
+        let config = JobPolicyConfig {
+            reject_non_standard: true,
+            ..Default::default()
+        };
+        assert!(check_provided_transactions(&config, None, &[])
+            .await
+            .is_ok());
+        assert!(matches!(
+            check_provided_transactions(&config, None, &transactions).await,
+            Err(JobPolicyError::Unavailable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn each_package_must_pay_the_fee_rate() {
+        let paying = transaction(&[], 1);
+        let cheap_parent = transaction(&[], 2);
+        let cheap_child = transaction(&[&cheap_parent], 3);
+        // fees in sats of each transaction, all of 100 vB
+        let fees: HashMap<String, (String, u64)> =
+            [(&paying, 10_000), (&cheap_parent, 100), (&cheap_child, 100)]
+                .iter()
+                .map(|(tx, fee)| (serialize_hex(*tx), (tx.txid().to_string(), *fee)))
+                .collect();
+        let url = stand_in(Arc::new(move |request| {
+            let results: Vec<Value> = request.body["params"][0]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|raw_tx| {
+                    let (txid, fee) = &fees[raw_tx.as_str().unwrap()];
+                    json!({
+                        "txid": txid,
+                        "allowed": true,
+                        "vsize": 100,
+                        "fees": { "base": *fee as f64 / SATS_PER_BTC },
+                    })
+                })
+                .collect();
+            (200, result(&request.body, json!(results)).to_string())
+        }))
+        .await;
+        let client = MiniRpcClient::new(url, Auth::new("user".to_string(), "pass".to_string()));
+        let config = JobPolicyConfig {
+            min_package_fee_rate: 5.0,
+            ..Default::default()
+        };
+        assert!(
+            check_provided_transactions(&config, Some(client.clone()), &[paying.clone()])
+                .await
+                .is_ok()
+        );
+        // all the transactions together pay 34 sat/vB, the package of the cheap ones 1 sat/vB
+        let transactions = [paying, cheap_parent, cheap_child];
+        match check_provided_transactions(&config, Some(client), &transactions).await {
+            Err(JobPolicyError::PackageFeeRateTooLow(fee_rate)) => assert_eq!(fee_rate, 1.0),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
@@ -3,18 +3,20 @@ use roles_logic_sv2::{
     handlers::{job_declaration::ParseClientJobDeclarationMessages, SendTo_},
     job_declaration_sv2::{
         AllocateMiningJobToken, AllocateMiningJobTokenSuccess, DeclareMiningJob,
-        DeclareMiningJobError, DeclareMiningJobSuccess, IdentifyTransactionsSuccess,
-        ProvideMissingTransactions, ProvideMissingTransactionsSuccess, SubmitSolutionJd,
+        DeclareMiningJobError, DeclareMiningJobSuccess, IdentifyTransactions,
+        IdentifyTransactionsSuccess, ProvideMissingTransactions, ProvideMissingTransactionsSuccess,
+        SubmitSolutionJd,
     },
     parsers::JobDeclaration,
+    utils::tx_hash_list_hash_builder,
 };
 use std::{convert::TryInto, io::Cursor};
-use stratum_common::bitcoin::{Transaction, Txid};
+use stratum_common::bitcoin::{hashes::Hash, Transaction, Txid};
 pub type SendTo = SendTo_<JobDeclaration<'static>, ()>;
-use super::{signed_token, TransactionState};
+use super::{declare_mining_job_error, signed_token, TransactionState};
 use roles_logic_sv2::{errors::Error, parsers::PoolMessages as AllMessages};
 use stratum_common::bitcoin::consensus::Decodable;
-use tracing::info;
+use tracing::{info, warn};
 
 use super::JobDeclaratorDownstream;
 
@@ -36,6 +38,46 @@ impl JobDeclaratorDownstream {
         // 4. right nbits
         self.token_to_job_map.contains_key(&(token_u32))
     }
+
+    /// Called once every declared transaction is either found in the mempool or marked as
+    /// missing: the job is accepted if nothing is missing, otherwise the missing transactions are
+    /// requested to the downstream.
+    fn on_transactions_identified(
+        &mut self,
+        message: DeclareMiningJob<'static>,
+        transactions_with_state: Vec<TransactionState>,
+        missing_txs: Vec<u16>,
+        mut known_transactions: Vec<Txid>,
+    ) -> SendTo {
+        let request_id = message.request_id;
+        let tx_hash_list_hash = message.tx_hash_list_hash.clone();
+        self.declared_mining_job = (Some(message), transactions_with_state, missing_txs.clone());
+        // here we send the transactions that we want to be stored in jds mempool with full data
+        self.add_txs_to_mempool
+            .add_txs_to_mempool_inner
+            .known_transactions
+            .append(&mut known_transactions);
+
+        if missing_txs.is_empty() {
+            let message_success = DeclareMiningJobSuccess {
+                request_id,
+                new_mining_job_token: signed_token(
+                    tx_hash_list_hash,
+                    &self.public_key.clone(),
+                    &self.private_key.clone(),
+                ),
+            };
+            SendTo::Respond(JobDeclaration::DeclareMiningJobSuccess(message_success))
+        } else {
+            let message_provide_missing_transactions = ProvideMissingTransactions {
+                request_id,
+                unknown_tx_position_list: missing_txs.into(),
+            };
+            SendTo::Respond(JobDeclaration::ProvideMissingTransactions(
+                message_provide_missing_transactions,
+            ))
+        }
+    }
 }
 
 impl ParseClientJobDeclarationMessages for JobDeclaratorDownstream {
@@ -66,6 +108,12 @@ impl ParseClientJobDeclarationMessages for JobDeclaratorDownstream {
         // The unknown transactions is a vector that contains the transactions that are not in the
         // jds mempool, and will be non-empty in the ProvideMissingTransactionsSuccess message
         let mut known_transactions: Vec<Txid> = vec![];
+        if let Err(e) = self.job_policy.on_declare_mining_job() {
+            warn!("Declared job {} rejected: {}", message.request_id, e);
+            return Ok(SendTo::Respond(JobDeclaration::DeclareMiningJobError(
+                declare_mining_job_error(message.request_id, e.error_code(), e.to_string()),
+            )));
+        }
         self.tx_hash_list_hash = Some(message.tx_hash_list_hash.clone().into_static());
         if self.verify_job(&message) {
             let short_hash_list: Vec<ShortTxId> = message
@@ -75,12 +123,29 @@ impl ParseClientJobDeclarationMessages for JobDeclaratorDownstream {
                 .map(|x| x.to_vec().try_into().unwrap())
                 .collect();
             let nonce = message.tx_short_hash_nonce;
-            // TODO return None when we have a collision handle that case as weel
-            let short_id_mempool = self
-                .mempool
-                .safe_lock(|x| x.to_short_ids(nonce))
-                .unwrap()
-                .unwrap();
+            let short_id_mempool = match self.mempool.safe_lock(|x| x.to_short_ids(nonce)).unwrap()
+            {
+                Some(short_id_mempool) => short_id_mempool,
+                None => {
+                    // two transactions of the mempool have the same short id, the downstream is
+                    // asked for the full list of txids
+                    warn!(
+                        "Short id collision in the mempool for nonce {}, sending IdentifyTransactions",
+                        nonce
+                    );
+                    self.declared_mining_job = (
+                        Some(message.clone().into_static()),
+                        vec![TransactionState::Missing; short_hash_list.len()],
+                        Vec::new(),
+                    );
+                    let message_identify_transactions = IdentifyTransactions {
+                        request_id: message.request_id,
+                    };
+                    return Ok(SendTo::Respond(JobDeclaration::IdentifyTransactions(
+                        message_identify_transactions,
+                    )));
+                }
+            };
             let mut transactions_with_state =
                 vec![TransactionState::Missing; short_hash_list.len()];
             let mut missing_txs: Vec<u16> = Vec::new();
@@ -98,40 +163,12 @@ impl ParseClientJobDeclarationMessages for JobDeclaratorDownstream {
                     }
                 }
             }
-            self.declared_mining_job = (
-                Some(message.clone().into_static()),
+            Ok(self.on_transactions_identified(
+                message.into_static(),
                 transactions_with_state,
-                missing_txs.clone(),
-            );
-            // here we send the transactions that we want to be stored in jds mempool with full data
-
-            self.add_txs_to_mempool
-                .add_txs_to_mempool_inner
-                .known_transactions
-                .append(&mut known_transactions);
-
-            if missing_txs.is_empty() {
-                let message_success = DeclareMiningJobSuccess {
-                    request_id: message.request_id,
-                    new_mining_job_token: signed_token(
-                        message.tx_hash_list_hash.clone(),
-                        &self.public_key.clone(),
-                        &self.private_key.clone(),
-                    ),
-                };
-                let message_enum_success = JobDeclaration::DeclareMiningJobSuccess(message_success);
-                Ok(SendTo::Respond(message_enum_success))
-            } else {
-                let message_provide_missing_transactions = ProvideMissingTransactions {
-                    request_id: message.request_id,
-                    unknown_tx_position_list: missing_txs.into(),
-                };
-                let message_enum_provide_missing_transactions =
-                    JobDeclaration::ProvideMissingTransactions(
-                        message_provide_missing_transactions,
-                    );
-                Ok(SendTo::Respond(message_enum_provide_missing_transactions))
-            }
+                missing_txs,
+                known_transactions,
+            ))
         } else {
             let message_error = DeclareMiningJobError {
                 request_id: message.request_id,
@@ -145,72 +182,131 @@ impl ParseClientJobDeclarationMessages for JobDeclaratorDownstream {
 
     fn handle_identify_transactions_success(
         &mut self,
-        _message: IdentifyTransactionsSuccess,
+        message: IdentifyTransactionsSuccess,
     ) -> Result<SendTo, Error> {
-        Ok(SendTo::None(None))
+        let declared_job = match self.declared_mining_job.0.clone() {
+            Some(declared_job) => declared_job,
+            None => return Err(Error::NoValidJob),
+        };
+        // ignore old IdentifyTransactionsSuccess as for ProvideMissingTransactionsSuccess
+        if declared_job.request_id != message.request_id {
+            return Ok(SendTo::None(None));
+        }
+        let txids: Vec<Txid> = message
+            .tx_data_hashes
+            .to_vec()
+            .iter()
+            .map(|hash| Txid::from_slice(hash).unwrap())
+            .collect();
+        // the txids must be the ones the declared job commits to
+        if txids.len() != declared_job.tx_short_hash_list.to_vec().len()
+            || tx_hash_list_hash_builder(txids.clone()) != declared_job.tx_hash_list_hash
+        {
+            let message_error = DeclareMiningJobError {
+                request_id: declared_job.request_id,
+                error_code: "invalid-tx-hash-list"
+                    .to_string()
+                    .into_bytes()
+                    .try_into()
+                    .unwrap(),
+                error_details: Vec::new().try_into().unwrap(),
+            };
+            self.declared_mining_job = (None, Vec::new(), Vec::new());
+            return Ok(SendTo::Respond(JobDeclaration::DeclareMiningJobError(
+                message_error,
+            )));
+        }
+        let mut known_transactions: Vec<Txid> = vec![];
+        let mut transactions_with_state = vec![TransactionState::Missing; txids.len()];
+        let mut missing_txs: Vec<u16> = Vec::new();
+        let mempool = self.mempool.clone();
+        for (i, txid) in txids.iter().enumerate() {
+            if mempool.safe_lock(|x| x.mempool.contains_key(txid)).unwrap() {
+                transactions_with_state[i] = TransactionState::PresentInMempool(*txid);
+                known_transactions.push(*txid);
+            } else {
+                missing_txs.push(i as u16);
+            }
+        }
+        Ok(self.on_transactions_identified(
+            declared_job,
+            transactions_with_state,
+            missing_txs,
+            known_transactions,
+        ))
     }
 
     fn handle_provide_missing_transactions_success(
         &mut self,
         message: ProvideMissingTransactionsSuccess,
     ) -> Result<SendTo, Error> {
-        let (declared_mining_job, ref mut transactions_with_state, missing_indexes) =
-            &mut self.declared_mining_job;
+        let request_id = match &self.declared_mining_job.0 {
+            Some(declared_job) => declared_job.request_id,
+            None => return Err(Error::NoValidJob),
+        };
+        // check request_id in order to ignore old ProvideMissingTransactionsSuccess (see issue #860)
+        if request_id != message.request_id {
+            return Ok(SendTo::None(None));
+        }
         let mut unknown_transactions: Vec<Transaction> = vec![];
-        match declared_mining_job {
-            Some(declared_job) => {
-                let id = declared_job.request_id;
-                // check request_id in order to ignore old ProvideMissingTransactionsSuccess (see issue #860)
-                if id == message.request_id {
-                    for (i, tx) in message.transaction_list.inner_as_ref().iter().enumerate() {
-                        let mut cursor = Cursor::new(tx);
-                        let transaction =
-                            Transaction::consensus_decode_from_finite_reader(&mut cursor)
-                                .map_err(|e| Error::TxDecodingError(e.to_string()))?;
-                        Vec::push(&mut unknown_transactions, transaction.clone());
-                        let index =
-                            *missing_indexes
-                                .get(i)
-                                .ok_or(Error::LogicErrorMessage(Box::new(
-                                    AllMessages::JobDeclaration(
-                                        JobDeclaration::ProvideMissingTransactionsSuccess(
-                                            message.clone().into_static(),
-                                        ),
-                                    ),
-                                )))? as usize;
-                        // insert the missing transactions in the mempool
-                        transactions_with_state[index] =
-                            TransactionState::PresentInMempool(transaction.txid());
-                    }
-                    self.add_txs_to_mempool
-                        .add_txs_to_mempool_inner
-                        .unknown_transactions
-                        .append(&mut unknown_transactions);
-                    // if there still a missing transaction return an error
-                    for tx_with_state in transactions_with_state {
-                        match tx_with_state {
-                            TransactionState::PresentInMempool(_) => continue,
-                            TransactionState::Missing => return Err(Error::JDSMissingTransactions),
-                        }
-                    }
-                    // TODO check it
-                    let tx_hash_list_hash = self.tx_hash_list_hash.clone().unwrap().into_static();
-                    let message_success = DeclareMiningJobSuccess {
-                        request_id: message.request_id,
-                        new_mining_job_token: signed_token(
-                            tx_hash_list_hash,
-                            &self.public_key.clone(),
-                            &self.private_key.clone(),
-                        ),
-                    };
-                    let message_enum_success =
-                        JobDeclaration::DeclareMiningJobSuccess(message_success);
-                    return Ok(SendTo::Respond(message_enum_success));
-                }
+        let mut size = 0;
+        for tx in message.transaction_list.inner_as_ref() {
+            let mut cursor = Cursor::new(tx);
+            let transaction = Transaction::consensus_decode_from_finite_reader(&mut cursor)
+                .map_err(|e| Error::TxDecodingError(e.to_string()))?;
+            size += tx.len() as u64;
+            unknown_transactions.push(transaction);
+        }
+        let mempool = self.mempool.clone();
+        if let Err(e) =
+            self.job_policy
+                .on_missing_transactions(&unknown_transactions, size, |txid| {
+                    mempool
+                        .safe_lock(|x| x.mempool.contains_key(txid))
+                        .unwrap_or(false)
+                })
+        {
+            warn!("Declared job {} rejected: {}", request_id, e);
+            self.declared_mining_job = (None, Vec::new(), Vec::new());
+            return Ok(SendTo::Respond(JobDeclaration::DeclareMiningJobError(
+                declare_mining_job_error(request_id, e.error_code(), e.to_string()),
+            )));
+        }
+        let (_, transactions_with_state, missing_indexes) = &mut self.declared_mining_job;
+        for (i, transaction) in unknown_transactions.iter().enumerate() {
+            let index = *missing_indexes
+                .get(i)
+                .ok_or(Error::LogicErrorMessage(Box::new(
+                    AllMessages::JobDeclaration(JobDeclaration::ProvideMissingTransactionsSuccess(
+                        message.clone().into_static(),
+                    )),
+                )))? as usize;
+            // insert the missing transactions in the mempool
+            transactions_with_state[index] = TransactionState::PresentInMempool(transaction.txid());
+        }
+        // if there still a missing transaction return an error
+        for tx_with_state in transactions_with_state.iter() {
+            match tx_with_state {
+                TransactionState::PresentInMempool(_) => continue,
+                TransactionState::Missing => return Err(Error::JDSMissingTransactions),
             }
-            None => return Err(Error::NoValidJob),
         }
-        Ok(SendTo::None(None))
+        self.add_txs_to_mempool
+            .add_txs_to_mempool_inner
+            .unknown_transactions
+            .append(&mut unknown_transactions);
+        // TODO check it
+        let tx_hash_list_hash = self.tx_hash_list_hash.clone().unwrap().into_static();
+        let message_success = DeclareMiningJobSuccess {
+            request_id: message.request_id,
+            new_mining_job_token: signed_token(
+                tx_hash_list_hash,
+                &self.public_key.clone(),
+                &self.private_key.clone(),
+            ),
+        };
+        let message_enum_success = JobDeclaration::DeclareMiningJobSuccess(message_success);
+        Ok(SendTo::Respond(message_enum_success))
     }
 
     fn handle_submit_solution(&mut self, message: SubmitSolutionJd<'_>) -> Result<SendTo, Error> {
@@ -219,3 +315,134 @@ impl ParseClientJobDeclarationMessages for JobDeclaratorDownstream {
         Ok(SendTo::None(Some(m)))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use binary_sv2::U256;
+
+    fn downstream() -> JobDeclaratorDownstream {
+        super::super::test::downstream().0
+    }
+
+    fn txids(n: u8) -> Vec<Txid> {
+        (1..=n)
+            .map(|i| Txid::from_slice(&[i; 32]).unwrap())
+            .collect()
+    }
+
+    fn declare(downstream: &mut JobDeclaratorDownstream, request_id: u32, txids: &[Txid]) {
+        let short_ids: Vec<ShortTxId<'static>> = txids
+            .iter()
+            .map(|_| vec![0_u8; 6].try_into().unwrap())
+            .collect();
+        let job = DeclareMiningJob {
+            request_id,
+            mining_job_token: vec![0_u8; 4].try_into().unwrap(),
+            version: 0,
+            coinbase_prefix: Vec::new().try_into().unwrap(),
+            coinbase_suffix: Vec::new().try_into().unwrap(),
+            tx_short_hash_nonce: 0,
+            tx_short_hash_list: short_ids.into(),
+            tx_hash_list_hash: tx_hash_list_hash_builder(txids.to_vec()),
+            excess_data: Vec::new().try_into().unwrap(),
+        };
+        downstream.declared_mining_job = (Some(job), Vec::new(), Vec::new());
+    }
+
+    fn success(request_id: u32, txids: &[Txid]) -> IdentifyTransactionsSuccess<'static> {
+        let tx_data_hashes: Vec<U256<'static>> =
+            txids.iter().map(|txid| txid.into_inner().into()).collect();
+        IdentifyTransactionsSuccess {
+            request_id,
+            tx_data_hashes: tx_data_hashes.into(),
+        }
+    }
+
+    fn add_to_mempool(downstream: &JobDeclaratorDownstream, txids: &[Txid]) {
+        downstream
+            .mempool
+            .safe_lock(|m| {
+                for txid in txids {
+                    m.mempool.insert(*txid, None);
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn identify_transactions_success_without_declared_job() {
+        let mut downstream = downstream();
+        assert!(matches!(
+            downstream.handle_identify_transactions_success(success(1, &txids(2))),
+            Err(Error::NoValidJob)
+        ));
+    }
+
+    #[test]
+    fn identify_transactions_success_for_old_job_is_ignored() {
+        let mut downstream = downstream();
+        let txids = txids(2);
+        declare(&mut downstream, 2, &txids);
+        assert!(matches!(
+            downstream.handle_identify_transactions_success(success(1, &txids)),
+            Ok(SendTo::None(None))
+        ));
+        assert!(downstream.declared_mining_job.0.is_some());
+    }
+
+    #[test]
+    fn identify_transactions_success_with_wrong_txids() {
+        let mut downstream = downstream();
+        let declared = txids(3);
+        declare(&mut downstream, 1, &declared);
+        let mut wrong = declared.clone();
+        wrong.swap(0, 1);
+        for txids in [wrong, declared[..2].to_vec()] {
+            declare(&mut downstream, 1, &declared);
+            match downstream.handle_identify_transactions_success(success(1, &txids)) {
+                Ok(SendTo::Respond(JobDeclaration::DeclareMiningJobError(e))) => {
+                    assert_eq!(e.request_id, 1);
+                    assert_eq!(e.error_code.to_vec(), b"invalid-tx-hash-list".to_vec());
+                }
+                _ => panic!("expected DeclareMiningJobError"),
+            }
+            assert!(downstream.declared_mining_job.0.is_none());
+        }
+    }
+
+    #[test]
+    fn identify_transactions_success_with_known_transactions() {
+        let mut downstream = downstream();
+        let txids = txids(3);
+        declare(&mut downstream, 1, &txids);
+        add_to_mempool(&downstream, &txids);
+        assert!(matches!(
+            downstream.handle_identify_transactions_success(success(1, &txids)),
+            Ok(SendTo::Respond(JobDeclaration::DeclareMiningJobSuccess(m))) if m.request_id == 1
+        ));
+        assert_eq!(
+            downstream
+                .add_txs_to_mempool
+                .add_txs_to_mempool_inner
+                .known_transactions,
+            txids
+        );
+    }
+
+    #[test]
+    fn identify_transactions_success_with_missing_transactions() {
+        let mut downstream = downstream();
+        let txids = txids(3);
+        declare(&mut downstream, 1, &txids);
+        add_to_mempool(&downstream, &txids[1..2]);
+        match downstream.handle_identify_transactions_success(success(1, &txids)) {
+            Ok(SendTo::Respond(JobDeclaration::ProvideMissingTransactions(m))) => {
+                assert_eq!(m.request_id, 1);
+                assert_eq!(m.unknown_tx_position_list.into_inner(), vec![0, 2]);
+            }
+            _ => panic!("expected ProvideMissingTransactions"),
+        }
+        assert_eq!(downstream.declared_mining_job.2, vec![0, 2]);
+    }
+}
@@ -1,28 +1,37 @@
+pub mod block_validation;
+pub mod job_policy;
 pub mod message_handler;
 use super::{error::JdsError, mempool::JDsMempool, status, Configuration, EitherFrame, StdFrame};
 use async_channel::{Receiver, Sender};
 use binary_sv2::{B0255, U256};
+use block_validation::{BlockValidationError, PrevoutsCache};
 use codec_sv2::{Frame, HandshakeRole, Responder};
 use error_handling::handle_result;
+use job_policy::JobPolicy;
 use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey, SignatureService};
 use network_helpers_sv2::noise_connection_tokio::Connection;
 use nohash_hasher::BuildNoHashHasher;
 use roles_logic_sv2::{
     common_messages_sv2::SetupConnectionSuccess,
     handlers::job_declaration::{ParseClientJobDeclarationMessages, SendTo},
-    job_declaration_sv2::{DeclareMiningJob, SubmitSolutionJd},
+    job_declaration_sv2::{
+        DeclareMiningJob, DeclareMiningJobError, DeclareMiningJobSuccess, SubmitSolutionJd,
+    },
     parsers::{JobDeclaration, PoolMessages as JdsMessages},
     utils::{Id, Mutex},
 };
 use std::{collections::HashMap, convert::TryInto, sync::Arc};
 use tokio::{net::TcpListener, time::Duration};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 use stratum_common::bitcoin::{
     consensus::{encode::serialize, Encodable},
     Block, Transaction, Txid,
 };
 
+// Time given to the checks of a declared job, after that the job is refused
+const JOB_VALIDATION_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Clone, Debug)]
 pub enum TransactionState {
     PresentInMempool(Txid),
@@ -42,6 +51,15 @@ pub struct AddTrasactionsToMempool {
     pub sender_add_txs_to_mempool: Sender<AddTrasactionsToMempoolInner>,
 }
 
+/// A declared job as it was when the JDS accepted its transactions. It is checked off the receive
+/// loop, meanwhile the downstream can declare other jobs.
+#[derive(Clone, Debug)]
+pub struct DeclaredJob {
+    job: DeclareMiningJob<'static>,
+    transactions_with_state: Vec<TransactionState>,
+    add_txs_to_mempool_inner: AddTrasactionsToMempoolInner,
+}
+
 #[derive(Debug)]
 pub struct JobDeclaratorDownstream {
     sender: Sender<EitherFrame>,
@@ -63,6 +81,9 @@ pub struct JobDeclaratorDownstream {
     ),
     tx_hash_list_hash: Option<U256<'static>>,
     add_txs_to_mempool: AddTrasactionsToMempool,
+    // Outputs spent by the transactions of the declared jobs, see `validate_declared_job`
+    prevouts_cache: PrevoutsCache,
+    job_policy: JobPolicy,
 }
 
 impl JobDeclaratorDownstream {
@@ -100,9 +121,189 @@ impl JobDeclaratorDownstream {
                 add_txs_to_mempool_inner,
                 sender_add_txs_to_mempool,
             },
+            prevouts_cache: PrevoutsCache::default(),
+            job_policy: JobPolicy::new(config.quotas.clone(), config.job_policy.clone()),
         }
     }
 
+    /// Takes the declared job `request_id` together with the transactions that have to be added to
+    /// the JDS mempool for it, `None` if the last declared job is another one
+    fn take_declared_job(&mut self, request_id: u32) -> Option<DeclaredJob> {
+        let job = self.declared_mining_job.0.clone()?;
+        if job.request_id != request_id {
+            return None;
+        }
+        let add_txs_to_mempool_inner = std::mem::replace(
+            &mut self.add_txs_to_mempool.add_txs_to_mempool_inner,
+            AddTrasactionsToMempoolInner {
+                known_transactions: vec![],
+                unknown_transactions: vec![],
+            },
+        );
+        Some(DeclaredJob {
+            job,
+            transactions_with_state: self.declared_mining_job.1.clone(),
+            add_txs_to_mempool_inner,
+        })
+    }
+
+    /// Applies the job policy to the transactions provided for the declared job
+    async fn check_job_policy(
+        self_mutex: Arc<Mutex<Self>>,
+        declared_job: &DeclaredJob,
+    ) -> Result<(), job_policy::JobPolicyError> {
+        let (config, mempool) = self_mutex
+            .safe_lock(|s| (s.job_policy.config(), s.mempool.clone()))
+            .map_err(|e| job_policy::JobPolicyError::Unavailable(e.to_string()))?;
+        let client = mempool
+            .safe_lock(|x| x.get_client())
+            .map_err(|e| job_policy::JobPolicyError::Unavailable(e.to_string()))?;
+        let provided = &declared_job.add_txs_to_mempool_inner.unknown_transactions;
+        job_policy::check_provided_transactions(&config, client, provided).await
+    }
+
+    /// Checks that the block of the declared job, once all its transactions are known, is within
+    /// the consensus limits. The transactions that are only known by txid are retrieved from the
+    /// node and stored in the JDS mempool.
+    async fn validate_declared_job(
+        self_mutex: Arc<Mutex<Self>>,
+        declared_job: &DeclaredJob,
+    ) -> Result<(), BlockValidationError> {
+        let (mempool, mut prevouts_cache) = self_mutex
+            .safe_lock(|s| (s.mempool.clone(), std::mem::take(&mut s.prevouts_cache)))
+            .map_err(|e| BlockValidationError::Unavailable(e.to_string()))?;
+        let transactions_with_state = declared_job.transactions_with_state.clone();
+        let provided = declared_job
+            .add_txs_to_mempool_inner
+            .unknown_transactions
+            .clone();
+        // without a node only the limits that do not depend on the chain are checked
+        let client = mempool
+            .safe_lock(|x| x.get_client())
+            .map_err(|e| BlockValidationError::Unavailable(e.to_string()))?;
+
+        let provided: HashMap<Txid, Transaction> =
+            provided.into_iter().map(|tx| (tx.txid(), tx)).collect();
+        let mut transactions = Vec::with_capacity(transactions_with_state.len());
+        for state in transactions_with_state {
+            let txid = match state {
+                TransactionState::PresentInMempool(txid) => txid,
+                TransactionState::Missing => {
+                    return Err(BlockValidationError::Unavailable(
+                        "Missing transaction".to_string(),
+                    ))
+                }
+            };
+            if let Some(tx) = provided.get(&txid) {
+                transactions.push(tx.clone());
+                continue;
+            }
+            let tx = match mempool
+                .safe_lock(|x| x.mempool.get(&txid).cloned().flatten())
+                .map_err(|e| BlockValidationError::Unavailable(e.to_string()))?
+            {
+                Some(tx) => tx,
+                None => {
+                    let tx = JDsMempool::with_failover(&mempool, |client| async move {
+                        client.get_raw_transaction(&txid.to_string(), None).await
+                    })
+                    .await
+                    .map_err(|e| BlockValidationError::Unavailable(format!("{:?}", e)))?;
+                    let _ = mempool.safe_lock(|x| x.mempool.insert(txid, Some(tx.clone())));
+                    tx
+                }
+            };
+            transactions.push(tx);
+        }
+
+        let block = block_validation::build_block(declared_job.job.clone(), transactions)?;
+        let client = match client {
+            Some(client) => client,
+            None => {
+                debug!("No RPC client, BIP34 height and sigops of the declared job not checked");
+                return block_validation::check_block(&block, None, None);
+            }
+        };
+        // the job must build on the tip of the node of the JDS
+        let chain = client.get_blockchain_info().await?;
+        prevouts_cache.on_tip(&chain.best_block_hash);
+        let prevouts = block_validation::get_prevouts(&client, &block, &mut prevouts_cache).await;
+        let _ = self_mutex.safe_lock(|s| s.prevouts_cache = prevouts_cache);
+        block_validation::check_block(&block, Some(chain.blocks + 1), Some(&prevouts?))
+    }
+
+    /// Checks the job policy and the block of a declared job before signing it off. It runs off
+    /// the receive loop so that the other messages of the downstream are not delayed by the node,
+    /// `declared_job` is the job taken when `success` was built.
+    async fn sign_off_declared_job(
+        self_mutex: Arc<Mutex<Self>>,
+        declared_job: Option<DeclaredJob>,
+        success: DeclareMiningJobSuccess<'static>,
+    ) {
+        let request_id = success.request_id;
+        let checks = async {
+            let declared_job = declared_job
+                .as_ref()
+                .filter(|declared_job| declared_job.job.request_id == request_id)
+                .ok_or_else(|| {
+                    let e = BlockValidationError::Unavailable("No declared job".to_string());
+                    (e.error_code(), e.to_string())
+                })?;
+            Self::check_job_policy(self_mutex.clone(), declared_job)
+                .await
+                .map_err(|e| (e.error_code(), e.to_string()))?;
+            Self::validate_declared_job(self_mutex.clone(), declared_job)
+                .await
+                .map_err(|e| (e.error_code(), e.to_string()))
+        };
+        let result = match tokio::time::timeout(JOB_VALIDATION_TIMEOUT, checks).await {
+            Ok(result) => result,
+            Err(_) => {
+                let e = BlockValidationError::Unavailable(format!(
+                    "no answer after {:?}",
+                    JOB_VALIDATION_TIMEOUT
+                ));
+                Err((e.error_code(), e.to_string()))
+            }
+        };
+        let message = match result {
+            Ok(()) => {
+                debug!("Send message: DMJS. Updating the JDS mempool.");
+                if let Some(declared_job) = declared_job {
+                    Self::send_declared_txs_to_mempool(self_mutex.clone(), declared_job).await;
+                }
+                JobDeclaration::DeclareMiningJobSuccess(success)
+            }
+            Err((error_code, error_details)) => {
+                warn!("Declared job {} rejected: {}", request_id, error_details);
+                Self::reject_declared_job(self_mutex.clone(), request_id, error_code, error_details)
+            }
+        };
+        if Self::send(self_mutex, message).await.is_err() {
+            error!("Failed to answer to the declared job {}", request_id);
+        }
+    }
+
+    /// The declared job is dropped, unless the downstream already declared another job
+    fn reject_declared_job(
+        self_mutex: Arc<Mutex<Self>>,
+        request_id: u32,
+        error_code: &str,
+        error_details: String,
+    ) -> JobDeclaration<'static> {
+        let _ = self_mutex.safe_lock(|s| {
+            if s.declared_mining_job.0.as_ref().map(|job| job.request_id) != Some(request_id) {
+                return;
+            }
+            s.declared_mining_job = (None, Vec::new(), Vec::new());
+        });
+        JobDeclaration::DeclareMiningJobError(declare_mining_job_error(
+            request_id,
+            error_code,
+            error_details,
+        ))
+    }
+
     fn get_block_hex(
         self_mutex: Arc<Mutex<Self>>,
         message: SubmitSolutionJd,
@@ -167,6 +368,22 @@ impl JobDeclaratorDownstream {
         });
     }
 
+    /// Adds the transactions of a signed off job to the JDS mempool
+    async fn send_declared_txs_to_mempool(self_mutex: Arc<Mutex<Self>>, declared_job: DeclaredJob) {
+        let sender_add_txs_to_mempool = match self_mutex
+            .safe_lock(|a| a.add_txs_to_mempool.sender_add_txs_to_mempool.clone())
+        {
+            Ok(sender) => sender,
+            Err(e) => {
+                error!("Can not update the JDS mempool: {}", e);
+                return;
+            }
+        };
+        let _ = sender_add_txs_to_mempool
+            .send(declared_job.add_txs_to_mempool_inner)
+            .await;
+    }
+
     fn get_transactions_in_job(self_mutex: Arc<Mutex<Self>>) -> Vec<Txid> {
         let mut known_transactions: Vec<Txid> = Vec::new();
         let job_transactions = self_mutex
@@ -227,6 +444,21 @@ impl JobDeclaratorDownstream {
                         //    unknown full transactions provided by the downstream are added to the
                         //    JDS mempool
                         match next_message_to_send {
+                            // The job policy and the block of the job are checked before
+                            // signing it off
+                            Ok(SendTo::Respond(JobDeclaration::DeclareMiningJobSuccess(
+                                success,
+                            ))) => {
+                                // the next messages can declare another job
+                                let declared_job = self_mutex
+                                    .safe_lock(|s| s.take_declared_job(success.request_id))
+                                    .unwrap_or(None);
+                                tokio::task::spawn(Self::sign_off_declared_job(
+                                    self_mutex.clone(),
+                                    declared_job,
+                                    success,
+                                ));
+                            }
                             Ok(SendTo::Respond(m)) => {
                                 match m {
                                     JobDeclaration::AllocateMiningJobToken(_) => {
@@ -402,6 +634,18 @@ pub fn signed_token(
     signature.as_ref().to_vec().try_into().unwrap()
 }
 
+pub fn declare_mining_job_error(
+    request_id: u32,
+    error_code: &str,
+    error_details: String,
+) -> DeclareMiningJobError<'static> {
+    DeclareMiningJobError {
+        request_id,
+        error_code: error_code.to_string().into_bytes().try_into().unwrap(),
+        error_details: error_details.into_bytes().try_into().unwrap(),
+    }
+}
+
 fn _get_random_token() -> B0255<'static> {
     let inner: [u8; 32] = rand::random();
     inner.to_vec().try_into().unwrap()
@@ -489,3 +733,161 @@ impl JobDeclarator {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::mempool::backends::RpcBackends;
+    use async_channel::unbounded;
+    use roles_logic_sv2::{job_declaration_sv2::AllocateMiningJobToken, utils::hash_lists_tuple};
+    use stratum_common::bitcoin::{
+        blockdata::constants::genesis_block, consensus::serialize, Network, OutPoint,
+        PackedLockTime, Script, Sequence, TxIn, TxOut, Witness,
+    };
+
+    /// A downstream without node, with the receivers of the messages it sends and of the
+    /// transactions it adds to the JDS mempool
+    pub(crate) fn downstream() -> (
+        JobDeclaratorDownstream,
+        Receiver<EitherFrame>,
+        Receiver<AddTrasactionsToMempoolInner>,
+    ) {
+        let config: Configuration = toml::from_str(include_str!(
+            "../../../config-examples/jds-config-local-example.toml"
+        ))
+        .unwrap();
+        let (_, receiver) = unbounded();
+        let (sender, sent) = unbounded();
+        let (_, new_block_receiver) = unbounded();
+        let (sender_add_txs_to_mempool, added_to_mempool) = unbounded();
+        let mempool = Arc::new(Mutex::new(JDsMempool::new(
+            RpcBackends::new(vec![]),
+            new_block_receiver,
+            None,
+        )));
+        let downstream = JobDeclaratorDownstream::new(
+            receiver,
+            sender,
+            &config,
+            mempool,
+            sender_add_txs_to_mempool,
+        );
+        (downstream, sent, added_to_mempool)
+    }
+
+    fn transaction(value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    // Job with the genesis coinbase and an 8 bytes extranonce
+    fn job(request_id: u32, token: B0255<'static>, txs: Vec<Transaction>) -> DeclareMiningJob {
+        let coinbase = serialize(&genesis_block(Network::Bitcoin).txdata[0]);
+        let script_sig_end = 4 + 1 + 36 + 1 + coinbase[4 + 1 + 36] as usize;
+        let (tx_short_hash_list, tx_hash_list_hash) = hash_lists_tuple(txs, 0);
+        DeclareMiningJob {
+            request_id,
+            mining_job_token: token.to_vec().try_into().unwrap(),
+            version: 0,
+            coinbase_prefix: coinbase[..script_sig_end - 8].to_vec().try_into().unwrap(),
+            coinbase_suffix: coinbase[script_sig_end..].to_vec().try_into().unwrap(),
+            tx_short_hash_nonce: 0,
+            tx_short_hash_list,
+            tx_hash_list_hash,
+            excess_data: Vec::new().try_into().unwrap(),
+        }
+    }
+
+    // Request id of the DeclareMiningJobSuccess or DeclareMiningJobError sent to the downstream
+    fn received(sent: &Receiver<EitherFrame>) -> Result<u32, u32> {
+        let frame: StdFrame = sent.try_recv().unwrap().try_into().unwrap();
+        let mut bytes = vec![0; frame.encoded_length()];
+        frame.serialize(&mut bytes).unwrap();
+        let mut frame = StdFrame::from_bytes_unchecked(bytes.into());
+        let message_type = frame.get_header().unwrap().msg_type();
+        match (message_type, frame.payload()).try_into().unwrap() {
+            JobDeclaration::DeclareMiningJobSuccess(m) => Ok(m.request_id),
+            JobDeclaration::DeclareMiningJobError(m) => Err(m.request_id),
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
+
+    #[tokio::test]
+    async fn declared_jobs_are_signed_off_as_declared() {
+        let (mut downstream, sent, added_to_mempool) = downstream();
+        let known = transaction(1);
+        let unknown = transaction(2);
+        downstream
+            .mempool
+            .safe_lock(|m| m.mempool.insert(known.txid(), Some(known.clone())))
+            .unwrap();
+        let token = match downstream
+            .handle_allocate_mining_job_token(AllocateMiningJobToken {
+                user_identifier: "user".to_string().try_into().unwrap(),
+                request_id: 0,
+            })
+            .unwrap()
+        {
+            SendTo::Respond(JobDeclaration::AllocateMiningJobTokenSuccess(m)) => {
+                m.mining_job_token.into_static()
+            }
+            _ => panic!("expected AllocateMiningJobTokenSuccess"),
+        };
+
+        // the first job is signed off, the second waits for its missing transaction
+        let success = match downstream
+            .handle_declare_mining_job(job(1, token.clone(), vec![known.clone()]))
+            .unwrap()
+        {
+            SendTo::Respond(JobDeclaration::DeclareMiningJobSuccess(m)) => m,
+            _ => panic!("expected DeclareMiningJobSuccess"),
+        };
+        let declared_job = downstream.take_declared_job(success.request_id);
+        assert!(matches!(
+            downstream
+                .handle_declare_mining_job(job(2, token, vec![known.clone(), unknown]))
+                .unwrap(),
+            SendTo::Respond(JobDeclaration::ProvideMissingTransactions(m)) if m.request_id == 2
+        ));
+
+        let downstream = Arc::new(Mutex::new(downstream));
+        JobDeclaratorDownstream::sign_off_declared_job(downstream.clone(), declared_job, success)
+            .await;
+        assert_eq!(received(&sent), Ok(1));
+        assert_eq!(
+            added_to_mempool.try_recv().unwrap().known_transactions,
+            vec![known.txid()]
+        );
+        // the second job is untouched
+        let (job, transactions_with_state, missing) = downstream
+            .safe_lock(|s| s.declared_mining_job.clone())
+            .unwrap();
+        assert_eq!(job.unwrap().request_id, 2);
+        assert_eq!(transactions_with_state.len(), 2);
+        assert_eq!(missing, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn declared_job_of_another_request_is_refused() {
+        let (downstream, sent, _) = downstream();
+        let downstream = Arc::new(Mutex::new(downstream));
+        let success = DeclareMiningJobSuccess {
+            request_id: 1,
+            new_mining_job_token: Vec::new().try_into().unwrap(),
+        };
+        JobDeclaratorDownstream::sign_off_declared_job(downstream, None, success).await;
+        assert_eq!(received(&sent), Err(1));
+    }
+}
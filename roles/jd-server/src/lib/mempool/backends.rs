@@ -0,0 +1,299 @@
+use super::super::RpcBackendConfig;
+use roles_logic_sv2::utils::Mutex;
+use rpc_sv2::mini_rpc_client::{Auth, MiniRpcClient, RpcError};
+use std::{sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+// a node more blocks behind the others is considered not synced
+const MAX_BLOCKS_BEHIND: u64 = 1;
+// JSON-RPC errors of a node that can not serve the requests yet: RPC_IN_WARMUP,
+// RPC_CLIENT_NOT_CONNECTED and RPC_CLIENT_IN_INITIAL_DOWNLOAD
+const NODE_NOT_READY_CODES: [i32; 3] = [-28, -9, -10];
+
+#[derive(Debug, Clone)]
+struct Backend {
+    name: String,
+    priority: u32,
+    client: MiniRpcClient,
+    healthy: bool,
+}
+
+/// Nodes of the JDS, ordered by priority
+#[derive(Debug, Clone)]
+pub struct RpcBackends {
+    backends: Vec<Backend>,
+}
+
+impl RpcBackends {
+    /// The nodes are healthy until the first health check
+    pub fn new(configs: Vec<RpcBackendConfig>) -> Self {
+        let mut backends: Vec<Backend> = configs
+            .into_iter()
+            .map(|config| {
+                let name = format!("{}:{}", config.url, config.port);
+                let auth = match config.cookie_file {
+                    Some(path) => Auth::cookie_file(path),
+                    None => Auth::new(config.user, config.pass),
+                };
+                Backend {
+                    client: MiniRpcClient::new(name.clone(), auth)
+                        .with_options(config.timeout_secs, config.retries),
+                    name,
+                    priority: config.priority,
+                    healthy: true,
+                }
+            })
+            .collect();
+        // stable, nodes with the same priority keep the config order
+        backends.sort_by_key(|backend| backend.priority);
+        Self { backends }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.backends.is_empty()
+    }
+
+    /// Healthy node with the lowest priority, with its index
+    pub fn active(&self) -> Option<(usize, MiniRpcClient)> {
+        self.backends
+            .iter()
+            .enumerate()
+            .find(|(_, backend)| backend.healthy)
+            .map(|(index, backend)| (index, backend.client.clone()))
+    }
+
+    /// Nodes to which a block is submitted: all the healthy ones, or all the nodes if none is
+    /// healthy since a block must not be lost because of a failed health check
+    pub fn submission_targets(&self) -> Vec<(String, MiniRpcClient)> {
+        let healthy = self.backends.iter().any(|backend| backend.healthy);
+        self.backends
+            .iter()
+            .filter(|backend| backend.healthy || !healthy)
+            .map(|backend| (backend.name.clone(), backend.client.clone()))
+            .collect()
+    }
+
+    /// Called when a request to the node fails, the next node serves the requests until the
+    /// next health check
+    pub fn set_unhealthy(&mut self, index: usize, reason: String) {
+        self.set_health(index, Err(reason));
+    }
+
+    fn set_health(&mut self, index: usize, result: Result<(), String>) {
+        let backend = match self.backends.get_mut(index) {
+            Some(backend) => backend,
+            None => return,
+        };
+        match result {
+            Ok(()) => {
+                if !backend.healthy {
+                    info!("Node {} healthy", backend.name);
+                }
+                backend.healthy = true;
+            }
+            Err(reason) => {
+                if backend.healthy {
+                    warn!("Node {} unhealthy: {}", backend.name, reason);
+                }
+                backend.healthy = false;
+            }
+        }
+    }
+
+    /// Checks with `getblockchaininfo` that each node answers and is synced with the others
+    pub async fn health_check(self_: Arc<Mutex<Self>>) {
+        let clients: Vec<MiniRpcClient> = match self_.safe_lock(|s| {
+            s.backends
+                .iter()
+                .map(|backend| {
+                    // a node that does not answer is unhealthy, it is checked again later
+                    backend
+                        .client
+                        .clone()
+                        .with_timeout(HEALTH_CHECK_TIMEOUT)
+                        .with_retries(0, Duration::ZERO)
+                })
+                .collect()
+        }) {
+            Ok(clients) => clients,
+            Err(_) => return,
+        };
+        let mut infos = Vec::with_capacity(clients.len());
+        for client in clients {
+            infos.push(client.get_blockchain_info().await);
+        }
+        let best_height = infos
+            .iter()
+            .filter_map(|info| info.as_ref().ok())
+            .map(|info| info.blocks)
+            .max()
+            .unwrap_or(0);
+        let results: Vec<Result<(), String>> = infos
+            .into_iter()
+            .map(|info| match info {
+                Err(e) => Err(format!("{:?}", e)),
+                Ok(info) if info.initial_block_download => {
+                    Err("initial block download".to_string())
+                }
+                Ok(info) if info.headers > info.blocks + MAX_BLOCKS_BEHIND => Err(format!(
+                    "syncing, {} blocks of {} headers",
+                    info.blocks, info.headers
+                )),
+                Ok(info) if best_height > info.blocks + MAX_BLOCKS_BEHIND => Err(format!(
+                    "at height {}, other nodes at {}",
+                    info.blocks, best_height
+                )),
+                Ok(_) => Ok(()),
+            })
+            .collect();
+        let _ = self_.safe_lock(|s| {
+            for (index, result) in results.into_iter().enumerate() {
+                s.set_health(index, result);
+            }
+        });
+    }
+}
+
+/// True for the errors after which the request is sent to another node
+pub fn is_unreachable(e: &RpcError) -> bool {
+    match e {
+        RpcError::Http(_) | RpcError::Timeout | RpcError::Auth(_) => true,
+        RpcError::JsonRpc(response) => matches!(
+            &response.error,
+            Some(error) if NODE_NOT_READY_CODES.contains(&error.code)
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rpc_sv2::stand_in::{error, result, stand_in};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn config(url: &str, priority: u32) -> RpcBackendConfig {
+        let (url, port) = url.rsplit_once(':').unwrap();
+        RpcBackendConfig {
+            url: url.to_string(),
+            port: port.parse().unwrap(),
+            user: "user".to_string(),
+            pass: "pass".to_string(),
+            cookie_file: None,
+            priority,
+            timeout_secs: 0,
+            retries: 0,
+        }
+    }
+
+    fn names(backends: &RpcBackends) -> Vec<String> {
+        backends
+            .submission_targets()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    // Node answering getblockchaininfo at `height`, with `headers` known headers
+    async fn node(height: Arc<AtomicU64>, headers: u64, initial_block_download: bool) -> String {
+        stand_in(Arc::new(move |request| {
+            let info = json!({
+                "chain": "regtest",
+                "blocks": height.load(Ordering::Relaxed),
+                "headers": headers,
+                "bestblockhash": "00".repeat(32),
+                "difficulty": 1.0,
+                "initialblockdownload": initial_block_download,
+                "verificationprogress": 1.0,
+                "pruned": false,
+            });
+            (200, result(&request.body, info).to_string())
+        }))
+        .await
+    }
+
+    #[test]
+    fn backends_are_ordered_by_priority() {
+        let backends = RpcBackends::new(vec![
+            config("http://a:1", 2),
+            config("http://b:1", 0),
+            config("http://c:1", 0),
+        ]);
+        // same priority, config order
+        assert_eq!(
+            names(&backends),
+            vec!["http://b:1", "http://c:1", "http://a:1"]
+        );
+        assert_eq!(backends.active().unwrap().0, 0);
+        assert!(RpcBackends::new(vec![]).active().is_none());
+    }
+
+    #[test]
+    fn unhealthy_backends_are_skipped() {
+        let mut backends = RpcBackends::new(vec![
+            config("http://a:1", 0),
+            config("http://b:1", 1),
+            config("http://c:1", 2),
+        ]);
+        backends.set_unhealthy(0, "down".to_string());
+        assert_eq!(backends.active().unwrap().0, 1);
+        assert_eq!(names(&backends), vec!["http://b:1", "http://c:1"]);
+        backends.set_unhealthy(1, "down".to_string());
+        backends.set_unhealthy(2, "down".to_string());
+        assert!(backends.active().is_none());
+        // a block is still submitted to every node
+        assert_eq!(names(&backends).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn health_check_scores_the_nodes() {
+        let synced = node(Arc::new(AtomicU64::new(100)), 100, false).await;
+        let behind_height = Arc::new(AtomicU64::new(98));
+        let behind = node(behind_height.clone(), 98, false).await;
+        let syncing = node(Arc::new(AtomicU64::new(100)), 200, false).await;
+        let downloading = node(Arc::new(AtomicU64::new(100)), 100, true).await;
+        let warming_up = stand_in(Arc::new(|request| {
+            let response = error(&request.body, -28, "Loading block index...");
+            (500, response.to_string())
+        }))
+        .await;
+        let backends = Arc::new(Mutex::new(RpcBackends::new(vec![
+            config(&behind, 0),
+            config(&syncing, 1),
+            config(&downloading, 2),
+            config(&warming_up, 3),
+            config(&synced, 4),
+        ])));
+        RpcBackends::health_check(backends.clone()).await;
+        let backends_ = backends.safe_lock(|b| b.clone()).unwrap();
+        assert_eq!(backends_.active().unwrap().0, 4);
+        assert_eq!(names(&backends_), vec![synced]);
+
+        // the node that caught up serves the requests again
+        behind_height.store(100, Ordering::Relaxed);
+        RpcBackends::health_check(backends.clone()).await;
+        assert_eq!(backends.safe_lock(|b| b.active().unwrap().0).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn warming_up_node_is_unreachable() {
+        let code = Arc::new(AtomicU64::new(28));
+        let code_ = code.clone();
+        let url = stand_in(Arc::new(move |request| {
+            let code = -(code_.load(Ordering::Relaxed) as i64);
+            (500, error(&request.body, code, "error").to_string())
+        }))
+        .await;
+        let client = MiniRpcClient::new(url, Auth::new("user".to_string(), "pass".to_string()));
+        assert!(is_unreachable(&client.get_block_count().await.unwrap_err()));
+        // invalid parameter, another node would answer the same
+        code.store(8, Ordering::Relaxed);
+        assert!(!is_unreachable(
+            &client.get_block_count().await.unwrap_err()
+        ));
+        assert!(is_unreachable(&RpcError::Timeout));
+        assert!(!is_unreachable(&RpcError::Other("rejected".to_string())));
+    }
+}
@@ -0,0 +1,14 @@
+//! Journal of the blocks found by the downstreams, one hex encoded block per line, so that they
+//! can be submitted by hand with `bitcoin-cli submitblock`. It is the only sink of the blocks
+//! when the JDS runs without nodes.
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+};
+
+pub fn append(path: &Path, block_hex: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", block_hex)?;
+    file.sync_all()
+}
@@ -1,13 +1,26 @@
+pub mod backends;
 pub mod error;
+pub mod journal;
+pub mod snapshot;
 use super::job_declarator::AddTrasactionsToMempoolInner;
 use crate::mempool::error::JdsMempoolError;
 use async_channel::Receiver;
+use backends::{is_unreachable, RpcBackends};
 use bitcoin::blockdata::transaction::Transaction;
 use hashbrown::HashMap;
 use roles_logic_sv2::utils::Mutex;
 use rpc_sv2::{mini_rpc_client, mini_rpc_client::RpcError};
-use std::{convert::TryInto, str::FromStr, sync::Arc};
+use std::{
+    convert::TryInto,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use stratum_common::{bitcoin, bitcoin::hash_types::Txid};
+use tracing::{error, info, warn};
 
 #[derive(Clone, Debug)]
 pub struct TransactionWithHash {
@@ -15,22 +28,57 @@ pub struct TransactionWithHash {
     pub tx: Option<Transaction>,
 }
 
+// Transactions provided by the JDCs are kept this long when the node does not have them, so that
+// the jobs that use them can still be validated and their blocks assembled
+const PROVIDED_TRANSACTIONS_TTL: Duration = Duration::from_secs(30 * 60);
+
+// Outputs of the snapshot transactions asked to the node in each `gettxout` batch
+const SNAPSHOT_CHECK_BATCH_SIZE: usize = 500;
+
 #[derive(Clone, Debug)]
 pub struct JDsMempool {
     pub mempool: HashMap<Txid, Option<Transaction>>,
-    auth: mini_rpc_client::Auth,
-    url: String,
+    // Transactions provided by the JDCs or loaded from the snapshot, with the time they were added
+    provided: HashMap<Txid, Instant>,
+    backends: Arc<Mutex<RpcBackends>>,
     new_block_receiver: Receiver<String>,
+    blocks_journal: Option<PathBuf>,
 }
 
 impl JDsMempool {
+    /// Client of the healthy node with the lowest priority
     pub fn get_client(&self) -> Option<mini_rpc_client::MiniRpcClient> {
-        let url = self.url.as_str();
-        if url.contains("http") {
-            let client = mini_rpc_client::MiniRpcClient::new(url.to_string(), self.auth.clone());
-            Some(client)
-        } else {
-            None
+        self.backends
+            .safe_lock(|b| b.active().map(|(_, client)| client))
+            .ok()
+            .flatten()
+    }
+
+    pub fn backends(&self) -> Arc<Mutex<RpcBackends>> {
+        self.backends.clone()
+    }
+
+    /// Sends the request to the healthy node with the lowest priority, when the node can not be
+    /// reached it is set unhealthy and the request is sent to the next one
+    pub async fn with_failover<T, F, Fut>(
+        self_: &Arc<Mutex<Self>>,
+        request: F,
+    ) -> Result<T, JdsMempoolError>
+    where
+        F: Fn(mini_rpc_client::MiniRpcClient) -> Fut,
+        Fut: Future<Output = Result<T, RpcError>>,
+    {
+        let backends = self_.safe_lock(|x| x.backends.clone())?;
+        loop {
+            let (index, client) = backends
+                .safe_lock(|b| b.active())?
+                .ok_or(JdsMempoolError::NoClient)?;
+            match request(client).await {
+                Err(e) if is_unreachable(&e) => {
+                    backends.safe_lock(|b| b.set_unhealthy(index, format!("{:?}", e)))?;
+                }
+                result => return result.map_err(JdsMempoolError::Rpc),
+            }
         }
     }
 
@@ -44,18 +92,17 @@ impl JDsMempool {
     }
 
     pub fn new(
-        url: String,
-        username: String,
-        password: String,
+        backends: RpcBackends,
         new_block_receiver: Receiver<String>,
+        blocks_journal: Option<PathBuf>,
     ) -> Self {
-        let auth = mini_rpc_client::Auth::new(username, password);
         let empty_mempool: HashMap<Txid, Option<Transaction>> = HashMap::new();
         JDsMempool {
             mempool: empty_mempool,
-            auth,
-            url,
+            provided: HashMap::new(),
+            backends: Arc::new(Mutex::new(backends)),
             new_block_receiver,
+            blocks_journal,
         }
     }
 
@@ -68,9 +115,6 @@ impl JDsMempool {
     ) -> Result<(), JdsMempoolError> {
         let txids = add_txs_to_mempool_inner.known_transactions;
         let transactions = add_txs_to_mempool_inner.unknown_transactions;
-        let client = self_
-            .safe_lock(|a| a.get_client())?
-            .ok_or(JdsMempoolError::NoClient)?;
         // fill in the mempool the transactions id in the mempool with the full transactions
         // retrieved from the jd client
         for txid in txids {
@@ -78,30 +122,100 @@ impl JDsMempool {
                 .safe_lock(|a| a.mempool.get(&txid).cloned())
                 .map_err(|e| JdsMempoolError::PoisonLock(e.to_string()))?
             {
-                let transaction = client
-                    .get_raw_transaction(&txid.to_string(), None)
-                    .await
-                    .map_err(JdsMempoolError::Rpc)?;
+                let transaction = Self::with_failover(&self_, |client| async move {
+                    client.get_raw_transaction(&txid.to_string(), None).await
+                })
+                .await?;
                 let _ =
                     self_.safe_lock(|a| a.mempool.insert(transaction.txid(), Some(transaction)));
             }
         }
 
         // fill in the mempool the transactions given in input
+        let _ = self_.safe_lock(|a| a.add_provided(transactions));
+        Ok(())
+    }
+
+    // The provided transactions are kept by `update_mempool` even when the node does not have
+    // them
+    fn add_provided(&mut self, transactions: Vec<Transaction>) {
+        let now = Instant::now();
         for transaction in transactions {
-            let _ = self_.safe_lock(|a| a.mempool.insert(transaction.txid(), Some(transaction)));
+            let txid = transaction.txid();
+            self.mempool.insert(txid, Some(transaction));
+            self.provided.insert(txid, now);
         }
-        Ok(())
+    }
+
+    /// Writes the full transactions of the mempool to the snapshot file, returns how many. The
+    /// file is written on a blocking thread.
+    pub async fn save_snapshot(self_: &Arc<Mutex<Self>>, path: &Path) -> io::Result<usize> {
+        let transactions: Vec<Transaction> = self_
+            .safe_lock(|x| x.mempool.values().flatten().cloned().collect())
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || snapshot::save(&path, transactions.iter()))
+            .await
+            .map_err(|e| io::Error::other(e.to_string()))?
+    }
+
+    /// Adds the transactions of the snapshot file to the mempool, returns how many. The ones
+    /// confirmed since the snapshot are dropped when a node can tell it: a transaction is
+    /// confirmed if its first output is in the UTXO set of the node. The others are kept like
+    /// the transactions provided by the JDCs, since the node may not have them.
+    pub async fn load_snapshot(self_: &Arc<Mutex<Self>>, path: &Path) -> io::Result<usize> {
+        let transactions = snapshot::load(path)?;
+        let mut unconfirmed = Vec::with_capacity(transactions.len());
+        // false once the node can not tell, the next transactions are kept unchecked
+        let mut check = true;
+        for chunk in transactions.chunks(SNAPSHOT_CHECK_BATCH_SIZE) {
+            if !check {
+                unconfirmed.extend_from_slice(chunk);
+                continue;
+            }
+            let outpoints: Vec<(String, u32)> =
+                chunk.iter().map(|tx| (tx.txid().to_string(), 0)).collect();
+            let outpoints = &outpoints;
+            match Self::with_failover(self_, |client| async move {
+                client.get_tx_outs(outpoints).await
+            })
+            .await
+            {
+                Ok(outputs) => unconfirmed.extend(
+                    chunk
+                        .iter()
+                        .zip(outputs)
+                        .filter(|(_, output)| output.is_none())
+                        .map(|(tx, _)| tx.clone()),
+                ),
+                Err(e) => {
+                    if !matches!(e, JdsMempoolError::NoClient) {
+                        warn!(
+                            "Can not check which snapshot transactions are confirmed: {:?}",
+                            e
+                        );
+                    }
+                    check = false;
+                    unconfirmed.extend_from_slice(chunk);
+                }
+            }
+        }
+        let loaded = unconfirmed.len();
+        self_
+            .safe_lock(|x| x.add_provided(unconfirmed))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(loaded)
     }
 
     pub async fn update_mempool(self_: Arc<Mutex<Self>>) -> Result<(), JdsMempoolError> {
         let mut mempool_ordered: HashMap<Txid, Option<Transaction>> = HashMap::new();
 
-        let client = self_
-            .safe_lock(|x| x.get_client())?
-            .ok_or(JdsMempoolError::NoClient)?;
-
-        let mempool: Vec<String> = client.get_raw_mempool().await?;
+        let mempool: Vec<String> =
+            Self::with_failover(
+                &self_,
+                |client| async move { client.get_raw_mempool().await },
+            )
+            .await?;
         for id in &mempool {
             let key_id = Txid::from_str(id)
                 .map_err(|err| JdsMempoolError::Rpc(RpcError::Deserialization(err.to_string())))?;
@@ -117,27 +231,103 @@ impl JDsMempool {
         if mempool_ordered.is_empty() {
             Err(JdsMempoolError::EmptyMempool)
         } else {
-            let _ = self_.safe_lock(|x| x.mempool = mempool_ordered);
+            let _ = self_.safe_lock(|x| {
+                x.keep_provided(&mut mempool_ordered);
+                x.mempool = mempool_ordered;
+            });
             Ok(())
         }
     }
 
+    // Adds to the new mempool the provided transactions that are not in the mempool of the node,
+    // until they expire
+    fn keep_provided(&mut self, mempool: &mut HashMap<Txid, Option<Transaction>>) {
+        let now = Instant::now();
+        self.provided
+            .retain(|_, added_at| now.duration_since(*added_at) < PROVIDED_TRANSACTIONS_TTL);
+        for txid in self.provided.keys() {
+            if let Some(Some(transaction)) = self.mempool.get(txid) {
+                mempool
+                    .entry(*txid)
+                    .or_insert_with(|| Some(transaction.clone()));
+            }
+        }
+    }
+
+    /// Submits the blocks found by the downstreams until the channel is closed. Each block is
+    /// first appended to the journal, if any, then submitted to the nodes. A block that can not
+    /// be submitted is logged and the next one is processed, so that the declarators never wait
+    /// on a full channel.
     pub async fn on_submit(self_: Arc<Mutex<Self>>) -> Result<(), JdsMempoolError> {
-        let new_block_receiver: Receiver<String> =
-            self_.safe_lock(|x| x.new_block_receiver.clone())?;
-        let client = self_
-            .safe_lock(|x| x.get_client())?
-            .ok_or(JdsMempoolError::NoClient)?;
+        let (new_block_receiver, backends, blocks_journal) = self_.safe_lock(|x| {
+            (
+                x.new_block_receiver.clone(),
+                x.backends.clone(),
+                x.blocks_journal.clone(),
+            )
+        })?;
 
         while let Ok(block_hex) = new_block_receiver.recv().await {
-            match mini_rpc_client::MiniRpcClient::submit_block(&client, block_hex).await {
-                Ok(_) => return Ok(()),
-                Err(e) => JdsMempoolError::Rpc(e),
+            let journaled = match &blocks_journal {
+                Some(path) => match Self::journal_block(path, &block_hex).await {
+                    Ok(()) => {
+                        info!("Block written to the journal {}", path.display());
+                        true
+                    }
+                    Err(e) => {
+                        error!(
+                            "Can not write the block to the journal {}: {}",
+                            path.display(),
+                            e
+                        );
+                        false
+                    }
+                },
+                None => false,
             };
+            // the block is submitted to all the healthy nodes, so that it propagates even if
+            // one of them is badly connected
+            let targets = backends.safe_lock(|b| b.submission_targets())?;
+            if targets.is_empty() {
+                if !journaled {
+                    error!("Block lost, no node and no journal: {}", block_hex);
+                }
+                continue;
+            }
+            let submissions: Vec<_> = targets
+                .into_iter()
+                .map(|(name, client)| {
+                    let block_hex = block_hex.clone();
+                    tokio::spawn(async move { (name, client.submit_block(block_hex).await) })
+                })
+                .collect();
+            let mut accepted = false;
+            for submission in submissions {
+                match submission.await {
+                    Ok((name, Ok(()))) => {
+                        info!("Block accepted by node {}", name);
+                        accepted = true;
+                    }
+                    Ok((name, Err(e))) => error!("Block not accepted by node {}: {:?}", name, e),
+                    Err(e) => error!("Block submission task failed: {}", e),
+                }
+            }
+            if !accepted && !journaled {
+                error!("Block not accepted by any node: {}", block_hex);
+            }
         }
         Ok(())
     }
 
+    /// Appends the block to the journal on a blocking thread, since the file is synced
+    async fn journal_block(path: &Path, block_hex: &str) -> io::Result<()> {
+        let path = path.to_path_buf();
+        let block_hex = block_hex.to_string();
+        tokio::task::spawn_blocking(move || journal::append(&path, &block_hex))
+            .await
+            .map_err(|e| io::Error::other(e.to_string()))?
+    }
+
     pub fn to_short_ids(&self, nonce: u64) -> Option<HashMap<[u8; 6], TransactionWithHash>> {
         let mut ret = HashMap::new();
         for tx in &self.mempool {
@@ -158,3 +348,210 @@ impl JDsMempool {
         Some(ret)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{super::RpcBackendConfig, *};
+    use rpc_sv2::stand_in::{result, stand_in};
+    use serde_json::json;
+    use stratum_common::bitcoin::{
+        OutPoint, PackedLockTime, Script, Sequence, TxIn, TxOut, Witness,
+    };
+
+    fn transaction(value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(OutPoint::null().txid, 0),
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    fn backends(urls: &[String]) -> RpcBackends {
+        RpcBackends::new(
+            urls.iter()
+                .map(|url| {
+                    let (url, port) = url.rsplit_once(':').unwrap();
+                    RpcBackendConfig {
+                        url: url.to_string(),
+                        port: port.parse().unwrap(),
+                        user: "user".to_string(),
+                        pass: "pass".to_string(),
+                        cookie_file: None,
+                        priority: 0,
+                        timeout_secs: 0,
+                        retries: 0,
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    // Mempool of a node that has only `in_node`
+    async fn mempool(in_node: &Transaction) -> Arc<Mutex<JDsMempool>> {
+        let txid = in_node.txid().to_string();
+        let url = stand_in(Arc::new(move |request| {
+            (200, result(&request.body, json!([txid])).to_string())
+        }))
+        .await;
+        let (_, new_block_receiver) = async_channel::unbounded();
+        Arc::new(Mutex::new(JDsMempool::new(
+            backends(&[url]),
+            new_block_receiver,
+            None,
+        )))
+    }
+
+    fn journal_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("jds-journal-{}-{}", name, std::process::id()))
+    }
+
+    // Runs `on_submit` until all the blocks are processed
+    async fn submit(backends: RpcBackends, blocks_journal: Option<PathBuf>, blocks: &[&str]) {
+        let (new_block_sender, new_block_receiver) = async_channel::unbounded();
+        for block_hex in blocks {
+            new_block_sender.send(block_hex.to_string()).await.unwrap();
+        }
+        drop(new_block_sender);
+        let mempool = JDsMempool::new(backends, new_block_receiver, blocks_journal);
+        JDsMempool::on_submit(Arc::new(Mutex::new(mempool)))
+            .await
+            .unwrap();
+    }
+
+    fn txids(mempool: &Arc<Mutex<JDsMempool>>) -> Vec<Txid> {
+        let mut txids: Vec<Txid> = mempool
+            .safe_lock(|m| m.mempool.keys().cloned().collect())
+            .unwrap();
+        txids.sort();
+        txids
+    }
+
+    #[tokio::test]
+    async fn provided_transactions_are_kept_until_they_expire() {
+        let in_node = transaction(1);
+        let provided = transaction(2);
+        let dropped = transaction(3);
+        let mempool = mempool(&in_node).await;
+        mempool
+            .safe_lock(|m| {
+                m.mempool.insert(dropped.txid(), Some(dropped.clone()));
+                m.add_provided(vec![provided.clone()]);
+            })
+            .unwrap();
+        JDsMempool::update_mempool(mempool.clone()).await.unwrap();
+        let mut expected = vec![in_node.txid(), provided.txid()];
+        expected.sort();
+        assert_eq!(txids(&mempool), expected);
+
+        mempool
+            .safe_lock(|m| {
+                for added_at in m.provided.values_mut() {
+                    *added_at = Instant::now() - PROVIDED_TRANSACTIONS_TTL;
+                }
+            })
+            .unwrap();
+        JDsMempool::update_mempool(mempool.clone()).await.unwrap();
+        assert_eq!(txids(&mempool), vec![in_node.txid()]);
+    }
+
+    #[tokio::test]
+    async fn blocks_are_journaled_without_nodes() {
+        let path = journal_path("no-node");
+        let _ = std::fs::remove_file(&path);
+        submit(backends(&[]), Some(path.clone()), &["00", "01"]).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "00\n01\n");
+
+        // a node that can not be reached does not stop the journal
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        submit(backends(&[unreachable]), Some(path.clone()), &["02"]).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "00\n01\n02\n");
+        std::fs::remove_file(&path).unwrap();
+
+        // without nodes and journal the blocks are lost, the next ones are still processed
+        submit(backends(&[]), None, &["00", "01"]).await;
+    }
+
+    #[tokio::test]
+    async fn blocks_are_submitted_when_the_journal_fails() {
+        let submitted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let submitted_ = submitted.clone();
+        let url = stand_in(Arc::new(move |request| {
+            assert_eq!(request.body["method"], "submitblock");
+            let block_hex = request.body["params"][0].as_str().unwrap().to_string();
+            submitted_.lock().unwrap().push(block_hex);
+            (200, result(&request.body, json!(null)).to_string())
+        }))
+        .await;
+        // the directory of the journal does not exist
+        let path = journal_path("missing-dir").join("blocks");
+        submit(backends(&[url]), Some(path.clone()), &["00", "01"]).await;
+        assert_eq!(*submitted.lock().unwrap(), ["00", "01"]);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn confirmed_snapshot_transactions_are_dropped() {
+        let transactions: Vec<Transaction> = (0..SNAPSHOT_CHECK_BATCH_SIZE as u64 + 2)
+            .map(transaction)
+            .collect();
+        // one confirmed transaction in each batch
+        let confirmed = [
+            transactions[1].txid(),
+            transactions[SNAPSHOT_CHECK_BATCH_SIZE].txid(),
+        ];
+        let path = std::env::temp_dir().join(format!("jds-snapshot-check-{}", std::process::id()));
+        snapshot::save(&path, transactions.iter()).unwrap();
+
+        let batches = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let batches_ = batches.clone();
+        let confirmed_txids: Vec<String> = confirmed.iter().map(|txid| txid.to_string()).collect();
+        let url = stand_in(Arc::new(move |request| {
+            let requests = request.body.as_array().unwrap();
+            batches_.lock().unwrap().push(requests.len());
+            let responses: Vec<serde_json::Value> = requests
+                .iter()
+                .map(|request| {
+                    assert_eq!(request["method"], "gettxout");
+                    let txid = request["params"][0].as_str().unwrap().to_string();
+                    let output = match confirmed_txids.contains(&txid) {
+                        true => json!({ "value": 0.00001, "scriptPubKey": { "hex": "" } }),
+                        false => json!(null),
+                    };
+                    result(request, output)
+                })
+                .collect();
+            (200, json!(responses).to_string())
+        }))
+        .await;
+        let (_, new_block_receiver) = async_channel::unbounded();
+        let mempool = Arc::new(Mutex::new(JDsMempool::new(
+            backends(&[url]),
+            new_block_receiver,
+            None,
+        )));
+        let loaded = JDsMempool::load_snapshot(&mempool, &path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, transactions.len() - 2);
+        assert_eq!(*batches.lock().unwrap(), [SNAPSHOT_CHECK_BATCH_SIZE, 2]);
+        let mut expected: Vec<Txid> = transactions
+            .iter()
+            .map(|tx| tx.txid())
+            .filter(|txid| !confirmed.contains(txid))
+            .collect();
+        expected.sort();
+        assert_eq!(txids(&mempool), expected);
+    }
+}
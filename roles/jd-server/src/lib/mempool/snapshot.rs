@@ -0,0 +1,125 @@
+//! Snapshot of the full transactions of the JDS mempool, so that after a restart they do not have
+//! to be requested again to the node or to the JDCs.
+//!
+//! The file has one transaction per line, hex encoded. It is written to a temporary file that is
+//! then renamed, so a crash during a write leaves the previous snapshot untouched.
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+use stratum_common::bitcoin::{
+    consensus::{deserialize, encode::serialize_hex},
+    Transaction,
+};
+use tracing::warn;
+
+pub fn save<'a>(
+    path: &Path,
+    transactions: impl Iterator<Item = &'a Transaction>,
+) -> io::Result<usize> {
+    let tmp_path = tmp_path(path);
+    let mut writer = BufWriter::new(fs::File::create(&tmp_path)?);
+    let mut saved = 0;
+    for transaction in transactions {
+        writeln!(writer, "{}", serialize_hex(transaction))?;
+        saved += 1;
+    }
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(saved)
+}
+
+/// Transactions of the snapshot, none if the file does not exist. Invalid lines are skipped.
+pub fn load(path: &Path) -> io::Result<Vec<Transaction>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut transactions = vec![];
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let transaction = hex::decode(line.trim())
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| deserialize::<Transaction>(&bytes).map_err(|e| e.to_string()));
+        match transaction {
+            Ok(transaction) => transactions.push(transaction),
+            Err(e) => warn!(
+                "Skipping line {} of the mempool snapshot {}: {}",
+                index + 1,
+                path.display(),
+                e
+            ),
+        }
+    }
+    Ok(transactions)
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use stratum_common::bitcoin::{
+        OutPoint, PackedLockTime, Script, Sequence, TxIn, TxOut, Witness,
+    };
+
+    fn transaction(value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(OutPoint::null().txid, 0),
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_vec(vec![vec![1; 72], vec![2; 33]]),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::from(vec![0; 22]),
+            }],
+        }
+    }
+
+    fn snapshot_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("jds-snapshot-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn saved_transactions_are_loaded() {
+        let path = snapshot_path("round-trip");
+        let transactions = vec![transaction(1), transaction(2)];
+        assert_eq!(save(&path, transactions.iter()).unwrap(), 2);
+        assert!(!tmp_path(&path).exists());
+        assert_eq!(load(&path).unwrap(), transactions);
+        // a new snapshot replaces the previous one
+        assert_eq!(save(&path, transactions[1..].iter()).unwrap(), 1);
+        assert_eq!(load(&path).unwrap(), transactions[1..]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_lines_are_skipped() {
+        let path = snapshot_path("corrupt");
+        let content = format!(
+            "{}\nnot hex\n{}\n\n{}",
+            serialize_hex(&transaction(1)),
+            // truncated transaction
+            &serialize_hex(&transaction(2))[..20],
+            serialize_hex(&transaction(3)),
+        );
+        fs::write(&path, content).unwrap();
+        assert_eq!(load(&path).unwrap(), vec![transaction(1), transaction(3)]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_snapshot_is_empty() {
+        assert!(load(&snapshot_path("missing")).unwrap().is_empty());
+    }
+}
@@ -11,6 +11,7 @@ use roles_logic_sv2::{
 use serde::Deserialize;
 use std::{
     convert::{TryFrom, TryInto},
+    path::PathBuf,
     time::Duration,
 };
 use stratum_common::bitcoin::{Script, TxOut};
@@ -62,12 +63,141 @@ pub struct Configuration {
     pub authority_secret_key: Secp256k1SecretKey,
     pub cert_validity_sec: u64,
     pub coinbase_outputs: Vec<CoinbaseOutput>,
+    /// Node used when `rpc_backends` is empty
+    #[serde(default)]
     pub core_rpc_url: String,
+    #[serde(default)]
     pub core_rpc_port: u16,
+    #[serde(default)]
     pub core_rpc_user: String,
+    #[serde(default)]
     pub core_rpc_pass: String,
+    /// Nodes used for the mempool, the validation of the jobs and the submission of the blocks
+    #[serde(default)]
+    pub rpc_backends: Vec<RpcBackendConfig>,
+    /// Interval between two health checks of the nodes
+    #[serde(
+        default = "default_health_check_interval",
+        deserialize_with = "duration_from_toml"
+    )]
+    pub rpc_health_check_interval: Duration,
+    /// Timeout of the requests to the nodes in seconds, 0 keeps the default of the RPC client
+    #[serde(default)]
+    pub rpc_timeout_secs: u64,
+    /// Retries of a request that failed to reach a node, a found block is never resent after a
+    /// timeout
+    #[serde(default)]
+    pub rpc_retries: u32,
     #[serde(deserialize_with = "duration_from_toml")]
     pub mempool_update_interval: Duration,
+    /// File where the found blocks are appended, needed when there are no nodes
+    #[serde(default)]
+    pub blocks_journal_path: Option<PathBuf>,
+    /// File where the full transactions of the mempool are saved, and reloaded at startup
+    #[serde(default)]
+    pub mempool_snapshot_path: Option<PathBuf>,
+    /// Interval between two snapshots of the mempool
+    #[serde(
+        default = "default_snapshot_interval",
+        deserialize_with = "duration_from_toml"
+    )]
+    pub mempool_snapshot_interval: Duration,
+    #[serde(default)]
+    pub quotas: QuotasConfig,
+    #[serde(default)]
+    pub job_policy: JobPolicyConfig,
+}
+
+impl Configuration {
+    /// The nodes of `rpc_backends`, or the one of `core_rpc_url` when the list is empty, with
+    /// the RPC timeout and retries of the config
+    pub fn rpc_backends(&self) -> Vec<RpcBackendConfig> {
+        let mut backends = self.configured_rpc_backends();
+        for backend in backends.iter_mut() {
+            backend.timeout_secs = self.rpc_timeout_secs;
+            backend.retries = self.rpc_retries;
+        }
+        backends
+    }
+
+    fn configured_rpc_backends(&self) -> Vec<RpcBackendConfig> {
+        if !self.rpc_backends.is_empty() {
+            return self.rpc_backends.clone();
+        }
+        match self.core_rpc_url.contains("http") {
+            true => vec![RpcBackendConfig {
+                url: self.core_rpc_url.clone(),
+                port: self.core_rpc_port,
+                user: self.core_rpc_user.clone(),
+                pass: self.core_rpc_pass.clone(),
+                cookie_file: None,
+                priority: 0,
+                timeout_secs: 0,
+                retries: 0,
+            }],
+            false => vec![],
+        }
+    }
+}
+
+/// A bitcoind used by the JDS.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RpcBackendConfig {
+    pub url: String,
+    pub port: u16,
+    #[serde(default)]
+    pub user: String,
+    #[serde(default)]
+    pub pass: String,
+    /// When set the credentials are read from the cookie file of the node instead of `user` and
+    /// `pass`.
+    #[serde(default)]
+    pub cookie_file: Option<PathBuf>,
+    /// The healthy node with the lowest priority serves the requests, the blocks are submitted
+    /// to all the healthy nodes.
+    #[serde(default)]
+    pub priority: u32,
+    /// Set from `rpc_timeout_secs` of the config
+    #[serde(skip)]
+    pub timeout_secs: u64,
+    /// Set from `rpc_retries` of the config
+    #[serde(skip)]
+    pub retries: u32,
+}
+
+/// Limits applied to each JDC connection, 0 disables a limit.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct QuotasConfig {
+    /// DeclareMiningJob accepted in a sliding window of one minute.
+    #[serde(default)]
+    pub max_declarations_per_minute: u32,
+    /// Size of the transactions of a ProvideMissingTransactionsSuccess.
+    #[serde(default)]
+    pub max_missing_transactions_bytes_per_job: u64,
+    /// Transactions unknown to the node, provided by the connection and still in the JDS mempool.
+    #[serde(default)]
+    pub max_unknown_transactions_cached: u32,
+}
+
+/// Policy applied with `testmempoolaccept` to the transactions provided by a JDC, needs the node
+/// RPC.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct JobPolicyConfig {
+    /// Rejects the jobs with a transaction that the node would not accept in its mempool.
+    #[serde(default)]
+    pub reject_non_standard: bool,
+    /// Minimum fee rate in sat/vB of each package of provided transactions, a package being the
+    /// transactions that spend each other. 0 disables the check.
+    #[serde(default)]
+    pub min_package_fee_rate: f64,
+}
+
+fn default_health_check_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_snapshot_interval() -> Duration {
+    Duration::from_secs(60)
 }
 
 fn duration_from_toml<'de, D>(deserializer: D) -> Result<Duration, D::Error>
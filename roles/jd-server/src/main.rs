@@ -1,9 +1,9 @@
 #![allow(special_module_name)]
 use crate::lib::{
-    mempool::{self, error::JdsMempoolError},
+    mempool::{self, backends::RpcBackends, error::JdsMempoolError},
     status, Configuration,
 };
-use async_channel::{bounded, unbounded, Receiver, Sender};
+use async_channel::{unbounded, Receiver, Sender};
 use error_handling::handle_result;
 use roles_logic_sv2::utils::Mutex;
 use std::{ops::Sub, sync::Arc};
@@ -102,16 +102,19 @@ async fn main() {
         }
     };
 
-    let url = config.core_rpc_url.clone() + ":" + &config.core_rpc_port.clone().to_string();
-    let username = config.core_rpc_user.clone();
-    let password = config.core_rpc_pass.clone();
-    // TODO should we manage what to do when the limit is reaced?
-    let (new_block_sender, new_block_receiver): (Sender<String>, Receiver<String>) = bounded(10);
+    let backends = RpcBackends::new(config.rpc_backends());
+    let has_backends = !backends.is_empty();
+    // without nodes the found blocks would be lost
+    if !has_backends && config.blocks_journal_path.is_none() {
+        error!("No node configured: set core_rpc_url, rpc_backends or blocks_journal_path");
+        return;
+    }
+    // unbounded so that a slow submission never blocks the declarators, blocks are rare
+    let (new_block_sender, new_block_receiver): (Sender<String>, Receiver<String>) = unbounded();
     let mempool = Arc::new(Mutex::new(mempool::JDsMempool::new(
-        url.clone(),
-        username,
-        password,
+        backends,
         new_block_receiver,
+        config.blocks_journal_path.clone(),
     )));
     let mempool_update_interval = config.mempool_update_interval;
     let mempool_cloned_ = mempool.clone();
@@ -120,10 +123,45 @@ async fn main() {
     let mut last_empty_mempool_warning =
         std::time::Instant::now().sub(std::time::Duration::from_secs(60));
 
-    // TODO if the jd-server is launched with core_rpc_url empty, the following flow is never
-    // taken. Consequentally new_block_receiver in JDsMempool::on_submit is never read, possibly
-    // reaching the channel bound. The new_block_sender is given as input to JobDeclarator::start()
-    if url.contains("http") {
+    if let Some(path) = config.mempool_snapshot_path.clone() {
+        match mempool::JDsMempool::load_snapshot(&mempool, &path).await {
+            Ok(loaded) => info!(
+                "Loaded {} transactions from the mempool snapshot {}",
+                loaded,
+                path.display()
+            ),
+            Err(e) => warn!(
+                "Can not load the mempool snapshot {}: {}",
+                path.display(),
+                e
+            ),
+        }
+        let mempool = mempool.clone();
+        let snapshot_interval = config.mempool_snapshot_interval;
+        task::spawn(async move {
+            loop {
+                tokio::time::sleep(snapshot_interval).await;
+                if let Err(e) = mempool::JDsMempool::save_snapshot(&mempool, &path).await {
+                    warn!(
+                        "Can not save the mempool snapshot {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        });
+    }
+
+    if has_backends {
+        let backends = mempool.safe_lock(|m| m.backends()).unwrap();
+        let health_check_interval = config.rpc_health_check_interval;
+        task::spawn(async move {
+            loop {
+                RpcBackends::health_check(backends.clone()).await;
+                tokio::time::sleep(health_check_interval).await;
+            }
+        });
+
         let sender_update_mempool = sender.clone();
         task::spawn(async move {
             loop {
@@ -157,31 +195,23 @@ async fn main() {
                 //let _transactions = mempool::JDsMempool::_get_transaction_list(mempool_cloned_.clone());
             }
         });
+    }
 
-        let mempool_cloned = mempool.clone();
-        let sender_submit_solution = sender.clone();
-        task::spawn(async move {
-            loop {
-                let result = mempool::JDsMempool::on_submit(mempool_cloned.clone()).await;
-                if let Err(err) = result {
-                    match err {
-                        JdsMempoolError::EmptyMempool => {
-                            if last_empty_mempool_warning.elapsed().as_secs() >= 60 {
-                                warn!("{:?}", err);
-                                warn!("Template Provider is running, but its mempool is empty (possible reasons: you're testing in testnet, signet, or regtest)");
-                                last_empty_mempool_warning = std::time::Instant::now();
-                            }
-                        }
-                        _ => {
-                            // TODO here there should be a better error managmenet
-                            mempool::error::handle_error(&err);
-                            handle_result!(sender_submit_solution, Err(err));
-                        }
-                    }
+    // the found blocks go to the nodes and to the journal
+    let mempool_cloned = mempool.clone();
+    let sender_submit_solution = sender.clone();
+    task::spawn(async move {
+        loop {
+            // returns when the declarators are dropped
+            match mempool::JDsMempool::on_submit(mempool_cloned.clone()).await {
+                Ok(()) => break,
+                Err(err) => {
+                    mempool::error::handle_error(&err);
+                    handle_result!(sender_submit_solution, Err(err));
                 }
             }
-        });
-    };
+        }
+    });
 
     info!("Jds INITIALIZING with config: {:?}", &args.config_path);
 
@@ -198,6 +228,7 @@ async fn main() {
         )
         .await
     });
+    let mempool_snapshot = mempool.clone();
     task::spawn(async move {
         loop {
             if let Ok(add_transactions_to_mempool) = receiver_add_txs_to_mempool.recv().await {
@@ -261,4 +292,18 @@ async fn main() {
             }
         }
     }
+    if let Some(path) = &config.mempool_snapshot_path {
+        match mempool::JDsMempool::save_snapshot(&mempool_snapshot, path).await {
+            Ok(saved) => info!(
+                "Saved {} transactions to the mempool snapshot {}",
+                saved,
+                path.display()
+            ),
+            Err(e) => error!(
+                "Can not save the mempool snapshot {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
 }
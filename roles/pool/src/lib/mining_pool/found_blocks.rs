@@ -0,0 +1,154 @@
+//! Blocks found by the pool that are waiting for a confirmation. The hash of a block is computed
+//! from the merkle path of its template, it is confirmed when the TP sends a SetNewPrevHash on it.
+//! Stale solutions and blocks refused by the node are never confirmed.
+use roles_logic_sv2::{
+    template_distribution_sv2::{NewTemplate, SetNewPrevHash, SubmitSolution},
+    utils::{merkle_root_from_path_, u256_to_block_hash},
+};
+use std::collections::VecDeque;
+use stratum_common::bitcoin::{
+    blockdata::block::BlockHeader, consensus::Decodable, hash_types::TxMerkleNode, hashes::Hash,
+    BlockHash, Transaction,
+};
+use tracing::warn;
+
+// How many templates are remembered, a solution always refer to one of the last templates
+const TEMPLATES_CAPACITY: usize = 10;
+// How many found blocks are waiting for a confirmation
+const FOUND_BLOCKS_CAPACITY: usize = 10;
+
+#[derive(Debug)]
+struct Template {
+    template_id: u64,
+    merkle_path: Vec<Vec<u8>>,
+    // (prev hash, nbits) the template is built on, None for a future template not yet activated
+    prev_hash: Option<(BlockHash, u32)>,
+}
+
+#[derive(Debug, Default)]
+pub struct FoundBlocks {
+    templates: VecDeque<Template>,
+    // (prev hash, nbits) of the last SetNewPrevHash
+    prev_hash: Option<(BlockHash, u32)>,
+    pending: VecDeque<BlockHash>,
+}
+
+impl FoundBlocks {
+    pub fn on_new_template(&mut self, m: &NewTemplate) {
+        if self.templates.len() == TEMPLATES_CAPACITY {
+            self.templates.pop_front();
+        }
+        let prev_hash = match m.future_template {
+            true => None,
+            false => self.prev_hash,
+        };
+        self.templates.push_back(Template {
+            template_id: m.template_id,
+            merkle_path: m.merkle_path.to_vec(),
+            prev_hash,
+        });
+    }
+
+    /// Returns true when the new prev hash is a block found by the pool
+    pub fn on_set_new_prev_hash(&mut self, m: &SetNewPrevHash) -> bool {
+        let prev_hash = (
+            u256_to_block_hash(m.prev_hash.clone().into_static()),
+            m.n_bits,
+        );
+        self.prev_hash = Some(prev_hash);
+        for template in self.templates.iter_mut() {
+            if template.template_id == m.template_id {
+                template.prev_hash = Some(prev_hash);
+            }
+        }
+        match self.pending.iter().position(|hash| *hash == prev_hash.0) {
+            Some(index) => {
+                self.pending.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Computes the hash of the block of a solution and waits for its confirmation. Returns None
+    /// if the template of the solution or its prev hash are not known.
+    pub fn on_solution(&mut self, solution: &SubmitSolution) -> Option<BlockHash> {
+        let template = match self
+            .templates
+            .iter()
+            .find(|template| template.template_id == solution.template_id)
+        {
+            Some(template) => template,
+            None => {
+                warn!(
+                    "Template {} unknown, the found block can not be tracked",
+                    solution.template_id
+                );
+                return None;
+            }
+        };
+        let (prev_blockhash, bits) = template.prev_hash?;
+        let coinbase_tx = solution.coinbase_tx.to_vec();
+        let coinbase = Transaction::consensus_decode(&mut &coinbase_tx[..]).ok()?;
+        let merkle_root =
+            merkle_root_from_path_(coinbase.txid().into_inner(), &template.merkle_path);
+        let hash = BlockHeader {
+            version: solution.version as i32,
+            prev_blockhash,
+            merkle_root: TxMerkleNode::from_inner(merkle_root),
+            time: solution.header_timestamp,
+            bits,
+            nonce: solution.header_nonce,
+        }
+        .block_hash();
+        if !self.pending.contains(&hash) {
+            if self.pending.len() == FOUND_BLOCKS_CAPACITY {
+                self.pending.pop_front();
+            }
+            self.pending.push_back(hash);
+        }
+        Some(hash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::super::template_receiver::block_assembly::test::{
+            new_template, set_new_prev_hash, solution, transaction,
+        },
+        *,
+    };
+
+    #[test]
+    fn found_block_is_confirmed_by_the_next_prev_hash() {
+        let transactions: Vec<Transaction> = (0..3).map(transaction).collect();
+        let mut found_blocks = FoundBlocks::default();
+        let mut future = new_template(1, &transactions);
+        future.future_template = true;
+        found_blocks.on_new_template(&future);
+        // the prev hash of a future template is known once it is activated
+        assert!(found_blocks.on_solution(&solution(1)).is_none());
+        assert!(!found_blocks.on_set_new_prev_hash(&set_new_prev_hash(1, [7; 32])));
+        let hash = found_blocks.on_solution(&solution(1)).unwrap();
+
+        // a stale solution or another block do not confirm it
+        assert!(found_blocks.on_solution(&solution(2)).is_none());
+        assert!(!found_blocks.on_set_new_prev_hash(&set_new_prev_hash(2, [8; 32])));
+        assert!(found_blocks.on_set_new_prev_hash(&set_new_prev_hash(3, hash.into_inner())));
+        // confirmed only once
+        assert!(!found_blocks.on_set_new_prev_hash(&set_new_prev_hash(3, hash.into_inner())));
+    }
+
+    #[test]
+    fn template_keeps_the_prev_hash_it_is_built_on() {
+        let transactions: Vec<Transaction> = (0..3).map(transaction).collect();
+        let mut found_blocks = FoundBlocks::default();
+        found_blocks.on_set_new_prev_hash(&set_new_prev_hash(0, [7; 32]));
+        found_blocks.on_new_template(&new_template(1, &transactions));
+        let hash = found_blocks.on_solution(&solution(1)).unwrap();
+        found_blocks.on_set_new_prev_hash(&set_new_prev_hash(2, [8; 32]));
+        // a late solution of the previous tip has the same hash
+        assert_eq!(found_blocks.on_solution(&solution(1)), Some(hash));
+    }
+}
@@ -10,7 +10,7 @@ use roles_logic_sv2::{
     utils::Mutex,
 };
 use std::{convert::TryInto, sync::Arc};
-use tracing::error;
+use tracing::{error, info};
 
 impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting> for Downstream {
     fn get_channel_type(&self) -> SupportedChannelTypes {
@@ -84,15 +84,15 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
 
     fn handle_update_channel(&mut self, m: UpdateChannel) -> Result<SendTo<()>, Error> {
         let maximum_target =
-            roles_logic_sv2::utils::hash_rate_to_target(m.nominal_hash_rate.into(), 10.0)?;
+            Target::from_hashrate(m.nominal_hash_rate.into(), 10.0).map_err(Error::TargetError)?;
         self.channel_factory
-            .safe_lock(|s| s.update_target_for_channel(m.channel_id, maximum_target.clone().into()))
+            .safe_lock(|s| s.update_target_for_channel(m.channel_id, maximum_target.clone()))
             .unwrap_or_else(|_| {
                 std::process::exit(1);
             });
         let set_target = SetTarget {
             channel_id: m.channel_id,
-            maximum_target,
+            maximum_target: maximum_target.into(),
         };
         Ok(SendTo::Respond(Mining::SetTarget(set_target)))
     }
@@ -123,6 +123,10 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
                         };
                         // TODO we can block everything with the below (looks like this will infinite loop??)
                         while self.solution_sender.try_send(solution.clone()).is_err() {};
+                        // the coinbase outputs are rotated once the block is confirmed
+                        if let Some(hash) = self.found_blocks.safe_lock(|f| f.on_solution(&solution)).map_err(|e| Error::PoisonLock(e.to_string()))? {
+                            info!("Found block {}", hash);
+                        }
                     }
                     let success = SubmitSharesSuccess {
                         channel_id: m.channel_id,
@@ -174,6 +178,10 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
                         };
                         // TODO we can block everything with the below (looks like this will infinite loop??)
                         while self.solution_sender.try_send(solution.clone()).is_err() {};
+                        // the coinbase outputs are rotated once the block is confirmed
+                        if let Some(hash) = self.found_blocks.safe_lock(|f| f.on_solution(&solution)).map_err(|e| Error::PoisonLock(e.to_string()))? {
+                            info!("Found block {}", hash);
+                        }
                     }
                     let success = SubmitSharesSuccess {
                         channel_id: m.channel_id,
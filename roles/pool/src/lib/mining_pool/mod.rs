@@ -14,12 +14,12 @@ use roles_logic_sv2::{
     common_properties::{CommonDownstreamData, IsDownstream, IsMiningDownstream},
     errors::Error,
     handlers::mining::{ParseDownstreamMiningMessages, SendTo},
-    job_creator::JobsCreators,
+    job_creator::{CoinbaseCommitment, JobsCreators, OpReturnTag},
     mining_sv2::{ExtendedExtranonce, SetNewPrevHash as SetNPH},
     parsers::{Mining, PoolMessages},
     routing_logic::MiningRoutingLogic,
     template_distribution_sv2::{NewTemplate, SetNewPrevHash, SubmitSolution},
-    utils::{CoinbaseOutput as CoinbaseOutput_, Mutex},
+    utils::{CoinbaseOutput as CoinbaseOutput_, CoinbaseOutputsRotation, Mutex},
 };
 use serde::Deserialize;
 use std::{
@@ -38,6 +38,9 @@ use tracing::{debug, error, info, warn};
 pub mod setup_connection;
 use setup_connection::SetupConnectionHandler;
 
+pub mod found_blocks;
+use found_blocks::FoundBlocks;
+
 pub mod message_handler;
 
 pub type Message = PoolMessages<'static>;
@@ -45,6 +48,9 @@ pub type StdFrame = StandardSv2Frame<Message>;
 pub type EitherFrame = StandardEitherFrame<Message>;
 
 pub fn get_coinbase_output(config: &Configuration) -> Result<Vec<TxOut>, Error> {
+    if let Some(rotation) = get_coinbase_rotation(config)? {
+        return rotation.outputs();
+    }
     let mut result = Vec::new();
     for coinbase_output_pool in &config.coinbase_outputs {
         let coinbase_output: CoinbaseOutput_ = coinbase_output_pool.try_into()?;
@@ -60,10 +66,45 @@ pub fn get_coinbase_output(config: &Configuration) -> Result<Vec<TxOut>, Error>
     }
 }
 
+/// Providers of the extra coinbase data of the jobs
+pub fn get_coinbase_commitments(
+    config: &Configuration,
+) -> Result<Vec<Arc<dyn CoinbaseCommitment>>, Error> {
+    let mut commitments: Vec<Arc<dyn CoinbaseCommitment>> = vec![];
+    if let Some(tag) = &config.coinbase_op_return_tag {
+        commitments.push(Arc::new(OpReturnTag::new(tag.as_bytes())?));
+    }
+    Ok(commitments)
+}
+
+/// Returns None when no coinbase output is derived from an extended public key
+pub fn get_coinbase_rotation(
+    config: &Configuration,
+) -> Result<Option<CoinbaseOutputsRotation>, Error> {
+    let mut outputs = Vec::with_capacity(config.coinbase_outputs.len());
+    for coinbase_output_pool in &config.coinbase_outputs {
+        let coinbase_output: CoinbaseOutput_ = coinbase_output_pool.try_into()?;
+        outputs.push((
+            coinbase_output,
+            coinbase_output_pool.derivation_path.clone(),
+        ));
+    }
+    CoinbaseOutputsRotation::from_config(
+        outputs,
+        config
+            .coinbase_derivation_index_file
+            .clone()
+            .map(Into::into),
+    )
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct CoinbaseOutput {
     output_script_type: String,
     output_script_value: String,
+    /// When set `output_script_value` is an extended public key and a key is derived for each
+    /// found block, eg `m/0/*`
+    derivation_path: Option<String>,
 }
 
 impl TryFrom<&CoinbaseOutput> for CoinbaseOutput_ {
@@ -91,9 +132,44 @@ pub struct Configuration {
     pub authority_secret_key: Secp256k1SecretKey,
     pub cert_validity_sec: u64,
     pub coinbase_outputs: Vec<CoinbaseOutput>,
+    /// File where the derivation index of the coinbase outputs is persisted
+    pub coinbase_derivation_index_file: Option<String>,
     pub pool_signature: String,
+    /// Tag committed in an OP_RETURN output of the coinbase, at most 80 bytes
+    #[serde(default)]
+    pub coinbase_op_return_tag: Option<String>,
     #[cfg(feature = "test_only_allow_unencrypted")]
     pub test_only_listen_adress_plain: String,
+    #[serde(default)]
+    pub block_assembly: BlockAssemblyConfig,
+}
+
+/// Assembly of the found blocks by the pool, from the template transactions asked to the TP.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct BlockAssemblyConfig {
+    /// Sends RequestTransactionData for each template, needed by the other options.
+    #[serde(default)]
+    pub request_transaction_data: bool,
+    /// Directory where each found block is written, hex encoded.
+    #[serde(default)]
+    pub archive_dir: Option<String>,
+    /// Pool's own bitcoind, found blocks are also sent to it with `submitblock`. An empty
+    /// `core_rpc_url` disables it.
+    #[serde(default)]
+    pub core_rpc_url: String,
+    #[serde(default)]
+    pub core_rpc_port: u16,
+    #[serde(default)]
+    pub core_rpc_user: String,
+    #[serde(default)]
+    pub core_rpc_pass: String,
+    /// Timeout of the requests to the node in seconds, 0 keeps the default of the RPC client.
+    #[serde(default)]
+    pub rpc_timeout_secs: u64,
+    /// Retries of a request that failed to reach the node, a block is never resent after a
+    /// timeout.
+    #[serde(default)]
+    pub rpc_retries: u32,
 }
 
 #[derive(Debug)]
@@ -105,6 +181,7 @@ pub struct Downstream {
     downstream_data: CommonDownstreamData,
     solution_sender: Sender<SubmitSolution<'static>>,
     channel_factory: Arc<Mutex<PoolChannelFactory>>,
+    found_blocks: Arc<Mutex<FoundBlocks>>,
 }
 
 /// Accept downstream connection
@@ -115,6 +192,8 @@ pub struct Pool {
     channel_factory: Arc<Mutex<PoolChannelFactory>>,
     last_prev_hash_template_id: u64,
     status_tx: status::Sender,
+    coinbase_rotation: Option<Arc<Mutex<CoinbaseOutputsRotation>>>,
+    found_blocks: Arc<Mutex<FoundBlocks>>,
 }
 
 impl Downstream {
@@ -125,6 +204,7 @@ impl Downstream {
         solution_sender: Sender<SubmitSolution<'static>>,
         pool: Arc<Mutex<Pool>>,
         channel_factory: Arc<Mutex<PoolChannelFactory>>,
+        found_blocks: Arc<Mutex<FoundBlocks>>,
         status_tx: status::Sender,
         address: SocketAddr,
     ) -> PoolResult<Arc<Mutex<Self>>> {
@@ -145,6 +225,7 @@ impl Downstream {
             downstream_data,
             solution_sender,
             channel_factory,
+            found_blocks,
         }));
 
         let cloned = self_.clone();
@@ -392,6 +473,7 @@ impl Pool {
         let solution_sender = self_.safe_lock(|p| p.solution_sender.clone())?;
         let status_tx = self_.safe_lock(|s| s.status_tx.clone())?;
         let channel_factory = self_.safe_lock(|s| s.channel_factory.clone())?;
+        let found_blocks = self_.safe_lock(|s| s.found_blocks.clone())?;
 
         let downstream = Downstream::new(
             receiver,
@@ -399,6 +481,7 @@ impl Pool {
             solution_sender,
             self_.clone(),
             channel_factory,
+            found_blocks,
             // convert Listener variant to Downstream variant
             status_tx.listener_to_connection(),
             address,
@@ -426,9 +509,16 @@ impl Pool {
             let res = self_
                 .safe_lock(|s| {
                     s.last_prev_hash_template_id = new_prev_hash.template_id;
+                    s.found_blocks
+                        .safe_lock(|f| f.on_set_new_prev_hash(&new_prev_hash))
+                        .map_err(|e| PoolError::PoisonLock(e.to_string()))
                 })
                 .map_err(|e| PoolError::PoisonLock(e.to_string()));
-            handle_result!(status_tx, res);
+            let confirmed = handle_result!(status_tx, handle_result!(status_tx, res));
+            if confirmed {
+                info!("Block found by the pool confirmed by the TP");
+                handle_result!(status_tx, Self::rotate_coinbase_outputs(&self_).await);
+            }
 
             let job_id_res = self_
                 .safe_lock(|s| {
@@ -476,11 +566,16 @@ impl Pool {
     ) -> PoolResult<()> {
         let status_tx = self_.safe_lock(|s| s.status_tx.clone())?;
         let channel_factory = self_.safe_lock(|s| s.channel_factory.clone())?;
+        let found_blocks = self_.safe_lock(|s| s.found_blocks.clone())?;
         while let Ok(mut new_template) = rx.recv().await {
             debug!(
                 "New template received, creating a new mining job(s): {:?}",
                 new_template
             );
+            let res = found_blocks
+                .safe_lock(|f| f.on_new_template(&new_template))
+                .map_err(|e| PoolError::PoisonLock(e.to_string()));
+            handle_result!(status_tx, res);
 
             let messages = channel_factory
                 .safe_lock(|cf| cf.on_new_template(&mut new_template))
@@ -513,6 +608,35 @@ impl Pool {
         Ok(())
     }
 
+    /// Called when a block found by the pool is confirmed, the jobs of the next templates pay to a
+    /// fresh key
+    async fn rotate_coinbase_outputs(self_: &Arc<Mutex<Self>>) -> PoolResult<()> {
+        let (rotation, channel_factory) =
+            self_.safe_lock(|s| (s.coinbase_rotation.clone(), s.channel_factory.clone()))?;
+        let rotation = match rotation {
+            Some(rotation) => rotation,
+            None => return Ok(()),
+        };
+        // the next index is written to disk before being used
+        let rotated = task::spawn_blocking(move || {
+            rotation
+                .safe_lock(|r| r.rotate())
+                .map_err(|e| e.to_string())
+        })
+        .await;
+        match rotated {
+            Ok(Ok(Ok(outputs))) => {
+                info!("New coinbase outputs: {:?}", outputs);
+                channel_factory.safe_lock(|cf| cf.update_pool_outputs(outputs))?;
+            }
+            // The block reward is safe, the next block would pay to the same key
+            Ok(Ok(Err(e))) => error!("Impossible to rotate the coinbase outputs: {}", e),
+            Ok(Err(e)) => error!("Impossible to rotate the coinbase outputs: {}", e),
+            Err(e) => error!("Impossible to rotate the coinbase outputs: {}", e),
+        }
+        Ok(())
+    }
+
     pub fn start(
         config: Configuration,
         new_template_rx: Receiver<NewTemplate<'static>>,
@@ -531,8 +655,15 @@ impl Pool {
         let ids = Arc::new(Mutex::new(roles_logic_sv2::utils::GroupId::new()));
         let pool_coinbase_outputs = get_coinbase_output(&config);
         info!("PUB KEY: {:?}", pool_coinbase_outputs);
+        let coinbase_rotation = get_coinbase_rotation(&config)
+            .expect("Invalid coinbase output in config")
+            .map(|rotation| Arc::new(Mutex::new(rotation)));
         let extranonces = ExtendedExtranonce::new(range_0, range_1, range_2);
-        let creator = JobsCreators::new(extranonce_len as u8);
+        let mut creator = JobsCreators::new(extranonce_len as u8);
+        for commitment in get_coinbase_commitments(&config).expect("Invalid coinbase tag in config")
+        {
+            creator.add_coinbase_commitment(commitment);
+        }
         let share_per_min = 1.0;
         let kind = roles_logic_sv2::channel_logic::channel_factory::ExtendedChannelKind::Pool;
         let channel_factory = Arc::new(Mutex::new(PoolChannelFactory::new(
@@ -551,6 +682,8 @@ impl Pool {
             channel_factory,
             last_prev_hash_template_id: 0,
             status_tx: status_tx.clone(),
+            coinbase_rotation,
+            found_blocks: Arc::new(Mutex::new(FoundBlocks::default())),
         }));
 
         let cloned = pool.clone();
@@ -0,0 +1,377 @@
+//! Assembly of the blocks found by the pool. The transactions of each template are asked to the
+//! TP with RequestTransactionData and checked against the merkle path of the NewTemplate, so that
+//! on a solution the pool can build the block itself. The block is archived and sent with
+//! `submitblock` to the pool's own node, when they are configured, in parallel with the
+//! SubmitSolution sent to the TP.
+use super::super::mining_pool::BlockAssemblyConfig;
+use roles_logic_sv2::{
+    template_distribution_sv2::{
+        NewTemplate, RequestTransactionDataSuccess, SetNewPrevHash, SubmitSolution,
+    },
+    utils::{merkle_path_from_txids, u256_to_block_hash},
+};
+use rpc_sv2::mini_rpc_client::{Auth, MiniRpcClient};
+use std::{
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+};
+use stratum_common::bitcoin::{
+    blockdata::block::BlockHeader,
+    consensus::{encode::serialize_hex, Decodable},
+    hash_types::TxMerkleNode,
+    hashes::Hash,
+    Block, BlockHash, Transaction, Txid,
+};
+
+// How many templates are remembered, a solution always refer to one of the last templates
+const TEMPLATES_CAPACITY: usize = 10;
+
+#[derive(Debug)]
+struct Template {
+    template_id: u64,
+    merkle_path: Vec<Vec<u8>>,
+    // (prev hash, nbits) the template is built on, None for a future template not yet activated
+    prev_hash: Option<(BlockHash, u32)>,
+    // None until the transactions of the template are received
+    transactions: Option<Vec<Transaction>>,
+}
+
+#[derive(Debug)]
+pub struct BlockAssembler {
+    client: Option<MiniRpcClient>,
+    archive_dir: Option<PathBuf>,
+    templates: VecDeque<Template>,
+    // (prev hash, nbits) of the last SetNewPrevHash
+    prev_hash: Option<(BlockHash, u32)>,
+}
+
+impl BlockAssembler {
+    /// Returns None when the transactions of the templates are not requested
+    pub fn new(config: &BlockAssemblyConfig) -> Option<Self> {
+        if !config.request_transaction_data {
+            return None;
+        }
+        let client = match config.core_rpc_url.is_empty() {
+            true => None,
+            false => {
+                let url = config.core_rpc_url.clone() + ":" + &config.core_rpc_port.to_string();
+                let auth = Auth::new(config.core_rpc_user.clone(), config.core_rpc_pass.clone());
+                Some(
+                    MiniRpcClient::new(url, auth)
+                        .with_options(config.rpc_timeout_secs, config.rpc_retries),
+                )
+            }
+        };
+        Some(Self {
+            client,
+            archive_dir: config.archive_dir.clone().map(PathBuf::from),
+            templates: VecDeque::with_capacity(TEMPLATES_CAPACITY),
+            prev_hash: None,
+        })
+    }
+
+    pub fn client(&self) -> Option<MiniRpcClient> {
+        self.client.clone()
+    }
+
+    pub fn archive_dir(&self) -> Option<PathBuf> {
+        self.archive_dir.clone()
+    }
+
+    /// True when the transactions of the template have been asked and not received yet
+    pub fn is_waiting_for(&self, template_id: u64) -> bool {
+        self.templates
+            .iter()
+            .any(|template| template.template_id == template_id && template.transactions.is_none())
+    }
+
+    pub fn on_new_template(&mut self, m: &NewTemplate) {
+        if self.templates.len() == TEMPLATES_CAPACITY {
+            self.templates.pop_front();
+        }
+        let prev_hash = match m.future_template {
+            true => None,
+            false => self.prev_hash,
+        };
+        self.templates.push_back(Template {
+            template_id: m.template_id,
+            merkle_path: m.merkle_path.to_vec(),
+            prev_hash,
+            transactions: None,
+        });
+    }
+
+    /// The prev hash is the one of the templates received from now on and of the template it
+    /// activates
+    pub fn on_set_new_prev_hash(&mut self, m: &SetNewPrevHash) {
+        let prev_hash = (
+            u256_to_block_hash(m.prev_hash.clone().into_static()),
+            m.n_bits,
+        );
+        self.prev_hash = Some(prev_hash);
+        for template in self.templates.iter_mut() {
+            if template.template_id == m.template_id {
+                template.prev_hash = Some(prev_hash);
+            }
+        }
+    }
+
+    /// Stores the transactions of a template if they match its merkle path
+    pub fn on_transaction_data(&mut self, m: &RequestTransactionDataSuccess) -> Result<(), String> {
+        let template_id = m.template_id;
+        let position = self
+            .templates
+            .iter()
+            .position(|template| {
+                template.template_id == template_id && template.transactions.is_none()
+            })
+            .ok_or_else(|| format!("Transactions of unknown template {}", template_id))?;
+        let mut transactions = Vec::new();
+        for raw_tx in m.transaction_list.to_vec() {
+            let transaction = match Transaction::consensus_decode(&mut &raw_tx[..]) {
+                Ok(transaction) => transaction,
+                Err(e) => {
+                    // the template is forgotten after a failed check
+                    self.templates.remove(position);
+                    return Err(format!(
+                        "Invalid transaction in template {}: {:?}",
+                        template_id, e
+                    ));
+                }
+            };
+            transactions.push(transaction);
+        }
+        let txids: Vec<Txid> = transactions.iter().map(|tx| tx.txid()).collect();
+        let expected: Vec<Vec<u8>> = merkle_path_from_txids(&txids)
+            .iter()
+            .map(|node| node.to_vec())
+            .collect();
+        if expected != self.templates[position].merkle_path {
+            self.templates.remove(position);
+            return Err(format!(
+                "Transactions of template {} do not match its merkle path",
+                template_id
+            ));
+        }
+        self.templates[position].transactions = Some(transactions);
+        Ok(())
+    }
+
+    /// Assembles the block of a solution, the template must be built on a known prev hash
+    pub fn build_block(&self, solution: &SubmitSolution<'static>) -> Result<Block, String> {
+        let template = self
+            .templates
+            .iter()
+            .find(|template| template.template_id == solution.template_id)
+            .ok_or_else(|| format!("Template {} unknown", solution.template_id))?;
+        let transactions = template
+            .transactions
+            .clone()
+            .ok_or_else(|| format!("Transactions of template {} unknown", solution.template_id))?;
+        let (prev_blockhash, bits) = template.prev_hash.ok_or_else(|| {
+            format!(
+                "Template {} not built on a known prev hash",
+                solution.template_id
+            )
+        })?;
+        let coinbase_tx = solution.coinbase_tx.to_vec();
+        let coinbase = Transaction::consensus_decode(&mut &coinbase_tx[..])
+            .map_err(|e| format!("Invalid coinbase in solution: {:?}", e))?;
+        let mut txdata = Vec::with_capacity(transactions.len() + 1);
+        txdata.push(coinbase);
+        txdata.extend(transactions);
+        let mut block = Block {
+            header: BlockHeader {
+                version: solution.version as i32,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: solution.header_timestamp,
+                bits,
+                nonce: solution.header_nonce,
+            },
+            txdata,
+        };
+        // txdata contains at least the coinbase
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        Ok(block)
+    }
+}
+
+/// Writes the block to `<archive_dir>/<block hash>.hex`, returns the path of the file
+pub fn archive(archive_dir: &Path, block: &Block) -> Result<PathBuf, String> {
+    fs::create_dir_all(archive_dir).map_err(|e| e.to_string())?;
+    let path = archive_dir.join(format!("{}.hex", block.block_hash()));
+    fs::write(&path, serialize_hex(block)).map_err(|e| e.to_string())?;
+    Ok(path)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use binary_sv2::{Seq0255, Seq064K, B016M, U256};
+    use std::convert::TryInto;
+    use stratum_common::bitcoin::{
+        consensus::serialize, OutPoint, PackedLockTime, Script, Sequence, TxIn, TxOut, Witness,
+    };
+
+    pub(crate) fn transaction(index: u32) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(index),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), index),
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    pub(crate) fn new_template(
+        template_id: u64,
+        transactions: &[Transaction],
+    ) -> NewTemplate<'static> {
+        let txids: Vec<Txid> = transactions.iter().map(|tx| tx.txid()).collect();
+        let merkle_path: Vec<U256<'static>> = merkle_path_from_txids(&txids)
+            .into_iter()
+            .map(|node| node.into())
+            .collect();
+        NewTemplate {
+            template_id,
+            future_template: false,
+            version: 0x2000_0000,
+            coinbase_tx_version: 2,
+            coinbase_prefix: vec![3, 200, 0, 0].try_into().unwrap(),
+            coinbase_tx_input_sequence: u32::MAX,
+            coinbase_tx_value_remaining: 5_000_000_000,
+            coinbase_tx_outputs_count: 0,
+            coinbase_tx_outputs: vec![].try_into().unwrap(),
+            coinbase_tx_locktime: 0,
+            merkle_path: Seq0255::new(merkle_path).unwrap(),
+        }
+    }
+
+    pub(crate) fn transaction_data(
+        template_id: u64,
+        transactions: &[Transaction],
+    ) -> RequestTransactionDataSuccess<'static> {
+        let transaction_list: Vec<B016M<'static>> = transactions
+            .iter()
+            .map(|tx| serialize(tx).try_into().unwrap())
+            .collect();
+        RequestTransactionDataSuccess {
+            template_id,
+            excess_data: vec![].try_into().unwrap(),
+            transaction_list: Seq064K::new(transaction_list).unwrap(),
+        }
+    }
+
+    pub(crate) fn set_new_prev_hash(
+        template_id: u64,
+        prev_hash: [u8; 32],
+    ) -> SetNewPrevHash<'static> {
+        SetNewPrevHash {
+            template_id,
+            prev_hash: prev_hash.into(),
+            header_timestamp: 1,
+            n_bits: 0x207f_ffff,
+            target: [0xff_u8; 32].into(),
+        }
+    }
+
+    pub(crate) fn solution(template_id: u64) -> SubmitSolution<'static> {
+        SubmitSolution {
+            template_id,
+            version: 0x2000_0000,
+            header_timestamp: 2,
+            header_nonce: 3,
+            coinbase_tx: serialize(&transaction(10)).try_into().unwrap(),
+        }
+    }
+
+    fn assembler() -> BlockAssembler {
+        let config = BlockAssemblyConfig {
+            request_transaction_data: true,
+            ..Default::default()
+        };
+        BlockAssembler::new(&config).unwrap()
+    }
+
+    #[test]
+    fn assembles_the_block_of_a_solution() {
+        let transactions: Vec<Transaction> = (0..3).map(transaction).collect();
+        let mut assembler = assembler();
+        assembler.on_new_template(&new_template(1, &transactions));
+        assembler.on_set_new_prev_hash(&set_new_prev_hash(1, [7; 32]));
+        assembler
+            .on_transaction_data(&transaction_data(1, &transactions))
+            .unwrap();
+
+        let coinbase = transaction(10);
+        let block = assembler.build_block(&solution(1)).unwrap();
+        assert_eq!(block.txdata[0], coinbase);
+        assert_eq!(&block.txdata[1..], &transactions[..]);
+        assert!(block.check_merkle_root());
+        assert_eq!(block.header.bits, 0x207f_ffff);
+        assert_eq!(block.header.nonce, 3);
+
+        assert!(assembler.build_block(&solution(2)).is_err());
+    }
+
+    #[test]
+    fn transactions_must_match_the_merkle_path() {
+        let transactions: Vec<Transaction> = (0..3).map(transaction).collect();
+        let mut assembler = assembler();
+        assembler.on_new_template(&new_template(1, &transactions));
+        assert!(assembler.is_waiting_for(1));
+        assert!(assembler
+            .on_transaction_data(&transaction_data(1, &transactions[..2]))
+            .is_err());
+        assert!(!assembler.is_waiting_for(1));
+        // the template is forgotten after a failed check
+        assert!(assembler
+            .on_transaction_data(&transaction_data(1, &transactions))
+            .is_err());
+        assert!(assembler
+            .on_transaction_data(&transaction_data(2, &transactions))
+            .is_err());
+    }
+
+    #[test]
+    fn blocks_are_built_on_the_prev_hash_of_their_template() {
+        let transactions: Vec<Transaction> = (0..3).map(transaction).collect();
+        let mut assembler = assembler();
+        // received before any SetNewPrevHash
+        assembler.on_new_template(&new_template(1, &transactions));
+        let mut future = new_template(2, &transactions);
+        future.future_template = true;
+        assembler.on_new_template(&future);
+        for template_id in [1, 2] {
+            assembler
+                .on_transaction_data(&transaction_data(template_id, &transactions))
+                .unwrap();
+        }
+        assert!(assembler.build_block(&solution(1)).is_err());
+        assert!(assembler.build_block(&solution(2)).is_err());
+
+        // activates the future template, the other one stays on an unknown prev hash
+        assembler.on_set_new_prev_hash(&set_new_prev_hash(2, [7; 32]));
+        assert!(assembler.build_block(&solution(1)).is_err());
+        let block = assembler.build_block(&solution(2)).unwrap();
+        assert_eq!(block.header.prev_blockhash.into_inner(), [7; 32]);
+
+        // a new tip does not change the prev hash of the templates built on the previous one
+        assembler.on_new_template(&new_template(3, &transactions));
+        assembler
+            .on_transaction_data(&transaction_data(3, &transactions))
+            .unwrap();
+        assembler.on_set_new_prev_hash(&set_new_prev_hash(4, [8; 32]));
+        let block = assembler.build_block(&solution(3)).unwrap();
+        assert_eq!(block.header.prev_blockhash.into_inner(), [7; 32]);
+    }
+}
@@ -7,9 +7,13 @@ use roles_logic_sv2::{
     utils::Mutex,
 };
 use std::sync::Arc;
+use tracing::{debug, error, warn};
 
 impl ParseServerTemplateDistributionMessages for TemplateRx {
     fn handle_new_template(&mut self, m: NewTemplate) -> Result<SendTo, Error> {
+        if let Some(assembler) = self.block_assembler.as_mut() {
+            assembler.on_new_template(&m);
+        }
         let new_template = TemplateDistribution::NewTemplate(m.into_static());
         Ok(SendTo::RelayNewMessageToRemote(
             Arc::new(Mutex::new(())),
@@ -18,6 +22,9 @@ impl ParseServerTemplateDistributionMessages for TemplateRx {
     }
 
     fn handle_set_new_prev_hash(&mut self, m: SetNewPrevHash) -> Result<SendTo, Error> {
+        if let Some(assembler) = self.block_assembler.as_mut() {
+            assembler.on_set_new_prev_hash(&m);
+        }
         let new_prev_hash = TemplateDistribution::SetNewPrevHash(m.into_static());
         Ok(SendTo::RelayNewMessageToRemote(
             Arc::new(Mutex::new(())),
@@ -27,17 +34,27 @@ impl ParseServerTemplateDistributionMessages for TemplateRx {
 
     fn handle_request_tx_data_success(
         &mut self,
-        _m: RequestTransactionDataSuccess,
+        m: RequestTransactionDataSuccess,
     ) -> Result<SendTo, Error> {
-        // Just ignore tx data messages this are meant for the declaretors
+        // Only requested when the pool assembles the blocks
+        if let Some(assembler) = self.block_assembler.as_mut() {
+            match assembler.on_transaction_data(&m) {
+                Ok(()) => debug!("Transactions of template {} stored", m.template_id),
+                Err(e) => error!("{}", e),
+            }
+        }
         Ok(SendTo::None(None))
     }
 
     fn handle_request_tx_data_error(
         &mut self,
-        _m: RequestTransactionDataError,
+        m: RequestTransactionDataError,
     ) -> Result<SendTo, Error> {
-        // Just ignore tx data messages this are meant for the declaretors
+        warn!(
+            "TP refused the transactions of template {}: {}",
+            m.template_id,
+            String::from_utf8_lossy(&m.error_code.to_vec())
+        );
         Ok(SendTo::None(None))
     }
 }
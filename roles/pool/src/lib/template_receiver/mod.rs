@@ -1,6 +1,6 @@
 use super::{
     error::{PoolError, PoolResult},
-    mining_pool::{EitherFrame, StdFrame},
+    mining_pool::{BlockAssemblyConfig, EitherFrame, StdFrame},
     status,
 };
 use async_channel::{Receiver, Sender};
@@ -12,18 +12,30 @@ use roles_logic_sv2::{
     handlers::template_distribution::ParseServerTemplateDistributionMessages,
     parsers::{PoolMessages, TemplateDistribution},
     template_distribution_sv2::{
-        CoinbaseOutputDataSize, NewTemplate, SetNewPrevHash, SubmitSolution,
+        CoinbaseOutputDataSize, NewTemplate, RequestTransactionData, SetNewPrevHash, SubmitSolution,
     },
     utils::Mutex,
 };
-use std::{convert::TryInto, net::SocketAddr, sync::Arc};
+use std::{
+    convert::TryInto,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use stratum_common::bitcoin::consensus::encode::serialize_hex;
 use tokio::{net::TcpStream, task};
-use tracing::info;
+use tracing::{error, info, warn};
 
+pub mod block_assembly;
 mod message_handler;
 mod setup_connection;
+use block_assembly::BlockAssembler;
 use setup_connection::SetupConnectionHandler;
 
+// A solution can arrive before the transactions of its template, they are waited for this long
+const TRANSACTION_DATA_TIMEOUT: Duration = Duration::from_secs(5);
+const TRANSACTION_DATA_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
 pub struct TemplateRx {
     receiver: Receiver<EitherFrame>,
     sender: Sender<EitherFrame>,
@@ -31,6 +43,7 @@ pub struct TemplateRx {
     new_template_sender: Sender<NewTemplate<'static>>,
     new_prev_hash_sender: Sender<SetNewPrevHash<'static>>,
     status_tx: status::Sender,
+    block_assembler: Option<BlockAssembler>,
 }
 
 impl TemplateRx {
@@ -44,6 +57,7 @@ impl TemplateRx {
         status_tx: status::Sender,
         coinbase_out_len: u32,
         expected_tp_authority_public_key: Option<Secp256k1PublicKey>,
+        block_assembly: BlockAssemblyConfig,
     ) -> PoolResult<()> {
         let stream = TcpStream::connect(address).await?;
         info!("Connected to template distribution server at {}", address);
@@ -68,6 +82,7 @@ impl TemplateRx {
             new_prev_hash_sender: prev_h_sender,
             message_received_signal,
             status_tx,
+            block_assembler: BlockAssembler::new(&block_assembly),
         }));
         let cloned = self_.clone();
 
@@ -125,9 +140,14 @@ impl TemplateRx {
                 roles_logic_sv2::handlers::SendTo_::RelayNewMessageToRemote(_, m) => match m {
                     TemplateDistribution::CoinbaseOutputDataSize(_) => todo!(),
                     TemplateDistribution::NewTemplate(m) => {
+                        let template_id = m.template_id;
                         let res = new_template_sender.send(m).await;
                         handle_result!(status_tx, res);
                         handle_result!(status_tx, recv_msg_signal.recv().await);
+                        handle_result!(
+                            status_tx,
+                            Self::request_transaction_data(self_.clone(), template_id).await
+                        );
                     }
                     TemplateDistribution::RequestTransactionData(_) => todo!(),
                     TemplateDistribution::RequestTransactionDataError(_) => todo!(),
@@ -161,12 +181,14 @@ impl TemplateRx {
         let status_tx = self_.safe_lock(|s| s.status_tx.clone()).unwrap();
         while let Ok(solution) = rx.recv().await {
             info!("Sending Solution to TP: {:?}", &solution);
+            let solution_ = solution.clone();
             let sv2_frame_res: Result<StdFrame, _> =
                 PoolMessages::TemplateDistribution(TemplateDistribution::SubmitSolution(solution))
                     .try_into();
             match sv2_frame_res {
                 Ok(frame) => {
                     handle_result!(status_tx, Self::send(self_.clone(), frame).await);
+                    Self::on_found_block(&self_, &solution_);
                 }
                 Err(_e) => {
                     // return submit error
@@ -175,4 +197,136 @@ impl TemplateRx {
             };
         }
     }
+
+    /// Asks the transactions of the template to the TP, when the pool assembles the blocks
+    async fn request_transaction_data(self_: Arc<Mutex<Self>>, template_id: u64) -> PoolResult<()> {
+        if self_.safe_lock(|s| s.block_assembler.is_none())? {
+            return Ok(());
+        }
+        let frame = PoolMessages::TemplateDistribution(
+            TemplateDistribution::RequestTransactionData(RequestTransactionData { template_id }),
+        )
+        .try_into()?;
+        Self::send(self_, frame).await
+    }
+
+    /// Assembles the block of the solution, sends it to the pool's node and archives it
+    fn on_found_block(self_: &Arc<Mutex<Self>>, solution: &SubmitSolution<'static>) {
+        if self_
+            .safe_lock(|s| s.block_assembler.is_none())
+            .unwrap_or(true)
+        {
+            return;
+        }
+        task::spawn(Self::assemble_found_block(self_.clone(), solution.clone()));
+    }
+
+    async fn assemble_found_block(self_: Arc<Mutex<Self>>, solution: SubmitSolution<'static>) {
+        let timeout = Instant::now() + TRANSACTION_DATA_TIMEOUT;
+        let (block, client, archive_dir) = loop {
+            // the lock error holds the guard, it can not be kept across the sleep
+            let assembled = self_
+                .safe_lock(|s| {
+                    s.block_assembler.as_ref().map(|assembler| {
+                        (
+                            assembler.build_block(&solution),
+                            assembler.is_waiting_for(solution.template_id),
+                            assembler.client(),
+                            assembler.archive_dir(),
+                        )
+                    })
+                })
+                .map_err(|e| e.to_string());
+            match assembled {
+                Ok(Some((Ok(block), _, client, archive_dir))) => {
+                    break (block, client, archive_dir)
+                }
+                Ok(Some((Err(_), true, _, _))) if Instant::now() < timeout => {
+                    tokio::time::sleep(TRANSACTION_DATA_POLL_INTERVAL).await;
+                }
+                Ok(Some((Err(e), _, _, _))) => {
+                    error!("Can not assemble the block of the solution: {}", e);
+                    return;
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            }
+        };
+        let hash = block.block_hash();
+        if let Some(client) = client {
+            info!("Submitting block {} to the pool node", hash);
+            let block_hex = serialize_hex(&block);
+            task::spawn(async move {
+                match client.submit_block(block_hex).await {
+                    Ok(()) => info!("Block {} accepted by the pool node", hash),
+                    Err(e) => warn!("Pool node refused block {}: {:?}", hash, e),
+                }
+            });
+        }
+        if let Some(archive_dir) = archive_dir {
+            match task::spawn_blocking(move || block_assembly::archive(&archive_dir, &block)).await
+            {
+                Ok(Ok(path)) => info!("Block {} archived in {}", hash, path.display()),
+                Ok(Err(e)) => error!("Can not archive block {}: {}", hash, e),
+                Err(e) => error!("Can not archive block {}: {}", hash, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use block_assembly::test::{
+        new_template, set_new_prev_hash, solution, transaction, transaction_data,
+    };
+    use stratum_common::bitcoin::Transaction;
+
+    #[tokio::test]
+    async fn found_block_waits_for_the_transaction_data() {
+        let archive_dir =
+            std::env::temp_dir().join(format!("pool-found-block-wait-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&archive_dir);
+        let config = BlockAssemblyConfig {
+            request_transaction_data: true,
+            archive_dir: Some(archive_dir.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        let (sender, receiver) = async_channel::unbounded();
+        let (new_template_sender, _) = async_channel::unbounded();
+        let (new_prev_hash_sender, _) = async_channel::unbounded();
+        let (_, message_received_signal) = async_channel::unbounded();
+        let (status_tx, _) = async_channel::unbounded();
+        let mut block_assembler = BlockAssembler::new(&config).unwrap();
+        let transactions: Vec<Transaction> = (0..3).map(transaction).collect();
+        block_assembler.on_new_template(&new_template(1, &transactions));
+        block_assembler.on_set_new_prev_hash(&set_new_prev_hash(1, [7; 32]));
+        let self_ = Arc::new(Mutex::new(TemplateRx {
+            receiver,
+            sender,
+            message_received_signal,
+            new_template_sender,
+            new_prev_hash_sender,
+            status_tx: status::Sender::Upstream(status_tx),
+            block_assembler: Some(block_assembler),
+        }));
+        let found = task::spawn(TemplateRx::assemble_found_block(self_.clone(), solution(1)));
+        tokio::time::sleep(TRANSACTION_DATA_POLL_INTERVAL * 4).await;
+        self_
+            .safe_lock(|s| {
+                s.block_assembler
+                    .as_mut()
+                    .unwrap()
+                    .on_transaction_data(&transaction_data(1, &transactions))
+            })
+            .unwrap()
+            .unwrap();
+        found.await.unwrap();
+        let archived = std::fs::read_dir(&archive_dir).unwrap().count();
+        assert_eq!(archived, 1);
+        let _ = std::fs::remove_dir_all(&archive_dir);
+    }
 }
@@ -4,10 +4,11 @@ use async_channel::{bounded, unbounded};
 use tracing::{error, info, warn};
 mod lib;
 use lib::{
-    mining_pool::{get_coinbase_output, Configuration, Pool},
+    mining_pool::{get_coinbase_commitments, get_coinbase_output, Configuration, Pool},
     status,
     template_receiver::TemplateRx,
 };
+use roles_logic_sv2::job_creator::coinbase_output_max_additional_size;
 
 use tokio::select;
 
@@ -108,13 +109,22 @@ async fn main() {
     let (s_message_recv_signal, r_message_recv_signal) = bounded(10);
     info!("Pool INITIALIZING with config: {:?}", &args.config_path);
     let coinbase_output_result = get_coinbase_output(&config);
-    let coinbase_output_len = match coinbase_output_result {
-        Ok(coinbase_output) => coinbase_output.len() as u32,
+    let coinbase_output = match coinbase_output_result {
+        Ok(coinbase_output) => coinbase_output,
         Err(err) => {
             error!("Failed to get coinbase output: {:?}", err);
             return;
         }
     };
+    let coinbase_commitments = match get_coinbase_commitments(&config) {
+        Ok(coinbase_commitments) => coinbase_commitments,
+        Err(err) => {
+            error!("Invalid coinbase tag: {}", err);
+            return;
+        }
+    };
+    let coinbase_output_len =
+        coinbase_output_max_additional_size(&coinbase_output, &coinbase_commitments);
     let tp_authority_public_key = config.tp_authority_public_key;
     let template_rx_res = TemplateRx::connect(
         config.tp_address.parse().unwrap(),
@@ -125,6 +135,7 @@ async fn main() {
         status::Sender::Upstream(status_tx.clone()),
         coinbase_output_len,
         tp_authority_public_key,
+        config.block_assembly.clone(),
     )
     .await;
 
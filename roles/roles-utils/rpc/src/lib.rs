@@ -1,4 +1,6 @@
 pub mod mini_rpc_client;
+#[cfg(any(test, feature = "stand_in"))]
+pub mod stand_in;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
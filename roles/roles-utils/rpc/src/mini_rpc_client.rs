@@ -1,34 +1,90 @@
-// TODO
-//  - manage id in RpcResult messages
+//! JSON-RPC client of bitcoind, shared by the roles that talk with a node.
 use base64::Engine;
 use hex::decode;
 use http_body_util::{BodyExt, Full};
 use hyper::{
     body::Bytes,
     header::{AUTHORIZATION, CONTENT_TYPE},
-    Request,
+    Request, StatusCode,
 };
 use hyper_util::{
     client::legacy::{connect::HttpConnector, Client},
     rt::TokioExecutor,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
-use stratum_common::bitcoin::{consensus::encode::deserialize as consensus_decode, Transaction};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use stratum_common::bitcoin::{
+    self, consensus::encode::deserialize as consensus_decode, Transaction, TxOut,
+};
 
 use super::BlockHash;
 
+const SATS_PER_BTC: f64 = 100_000_000.0;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+// User of the cookie file written by bitcoind
+const COOKIE_USER: &str = "__cookie__";
+// JSON-RPC error of getmempoolentry for a transaction that is not in the mempool
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
 #[derive(Clone, Debug)]
 pub struct MiniRpcClient {
     client: Client<HttpConnector, Full<Bytes>>,
     url: String,
     auth: Auth,
+    // Shared by the clones of the client so that the request ids are unique
+    next_id: Arc<AtomicU64>,
+    timeout: Duration,
+    retries: u32,
+    retry_interval: Duration,
 }
 
 impl MiniRpcClient {
     pub fn new(url: String, auth: Auth) -> MiniRpcClient {
         let client: Client<_, Full<Bytes>> = Client::builder(TokioExecutor::new()).build_http();
-        MiniRpcClient { client, url, auth }
+        MiniRpcClient {
+            client,
+            url,
+            auth,
+            next_id: Arc::new(AtomicU64::new(1)),
+            timeout: DEFAULT_TIMEOUT,
+            retries: 0,
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+        }
+    }
+
+    /// Timeout of each attempt of a request, long polling `getblocktemplate` is not limited
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// A request that can not reach the node, or that times out, is sent again up to `retries`
+    /// times, waiting `interval` between two attempts
+    pub fn with_retries(mut self, retries: u32, interval: Duration) -> Self {
+        self.retries = retries;
+        self.retry_interval = interval;
+        self
+    }
+
+    /// Applies the `rpc_timeout_secs` and `rpc_retries` options of the role configs, a timeout
+    /// of 0 keeps the default one
+    pub fn with_options(self, timeout_secs: u64, retries: u32) -> Self {
+        let client = match timeout_secs {
+            0 => self,
+            secs => self.with_timeout(Duration::from_secs(secs)),
+        };
+        client.with_retries(retries, DEFAULT_RETRY_INTERVAL)
     }
 
     pub async fn get_raw_transaction(
@@ -36,76 +92,250 @@ impl MiniRpcClient {
         txid: &String,
         block_hash: Option<&BlockHash>,
     ) -> Result<Transaction, RpcError> {
-        let response = match block_hash {
-            Some(hash) => {
-                self.send_json_rpc_request("getrawtransaction", json!([txid, false, hash]))
+        let params = match block_hash {
+            Some(hash) => json!([txid, false, hash]),
+            None => json!([txid, false]),
+        };
+        let transaction_hex: String = self.call_non_null("getrawtransaction", params).await?;
+        let transaction_bytes =
+            decode(transaction_hex).map_err(|e| RpcError::Deserialization(e.to_string()))?;
+        consensus_decode(&transaction_bytes).map_err(|e| RpcError::Deserialization(e.to_string()))
+    }
+
+    pub async fn get_raw_mempool(&self) -> Result<Vec<String>, RpcError> {
+        self.call_non_null("getrawmempool", json!([])).await
+    }
+
+    /// Returns the mempool entries by txid (`getrawmempool` in verbose mode)
+    pub async fn get_raw_mempool_verbose(
+        &self,
+    ) -> Result<BTreeMap<String, MempoolEntry>, RpcError> {
+        self.call_non_null("getrawmempool", json!([true])).await
+    }
+
+    pub async fn get_mempool_entry(&self, txid: &String) -> Result<MempoolEntry, RpcError> {
+        self.call_non_null("getmempoolentry", json!([txid])).await
+    }
+
+    /// Mempool entries of the transactions, asked in one batch. The entry is None for the
+    /// transactions that are not in the mempool.
+    pub async fn get_mempool_entries(
+        &self,
+        txids: &[String],
+    ) -> Result<Vec<Option<MempoolEntry>>, RpcError> {
+        let requests = txids
+            .iter()
+            .map(|txid| ("getmempoolentry", json!([txid])))
+            .collect();
+        let mut entries = Vec::with_capacity(txids.len());
+        for result in self.batch::<MempoolEntry>(requests).await? {
+            match result {
+                Ok(entry) => entries.push(entry),
+                Err(RpcError::JsonRpc(response))
+                    if matches!(
+                        &response.error,
+                        Some(error) if error.code == RPC_INVALID_ADDRESS_OR_KEY
+                    ) =>
+                {
+                    entries.push(None)
+                }
+                Err(e) => return Err(e),
             }
-            None => self.send_json_rpc_request("getrawtransaction", json!([txid, false])),
         }
-        .await;
-        match response {
-            Ok(result_hex) => {
-                let result_deserialized: JsonRpcResult<String> = serde_json::from_str(&result_hex)
-                    .map_err(|e| {
-                        RpcError::Deserialization(e.to_string()) // TODO manage message ids
-                    })?;
-                let transaction_hex: String = result_deserialized
-                    .result
-                    .ok_or_else(|| RpcError::Other("Result not found".to_string()))?;
-                let transaction_bytes = decode(transaction_hex).expect("Decoding failed");
-                Ok(consensus_decode(&transaction_bytes).expect("Deserialization failed"))
+        Ok(entries)
+    }
+
+    /// Returns the height of the node's best chain
+    pub async fn get_block_count(&self) -> Result<u64, RpcError> {
+        self.call_non_null("getblockcount", json!([])).await
+    }
+
+    pub async fn get_best_block_hash(&self) -> Result<bitcoin::BlockHash, RpcError> {
+        let hash: String = self.call_non_null("getbestblockhash", json!([])).await?;
+        bitcoin::BlockHash::from_str(&hash).map_err(|e| RpcError::Deserialization(e.to_string()))
+    }
+
+    pub async fn get_blockchain_info(&self) -> Result<BlockchainInfo, RpcError> {
+        self.call_non_null("getblockchaininfo", json!([])).await
+    }
+
+    /// With `long_poll_id` the node answers when a better template is available, see BIP22
+    pub async fn get_block_template(
+        &self,
+        long_poll_id: Option<&str>,
+    ) -> Result<BlockTemplate, RpcError> {
+        match long_poll_id {
+            Some(long_poll_id) => {
+                let params = json!([{ "rules": ["segwit"], "longpollid": long_poll_id }]);
+                let id = self.next_id();
+                let body = Self::request_body(id, "getblocktemplate", params)?;
+                let (status, response) = self.post(body, None, true).await?;
+                parse_response(status, &response, id)?
+                    .ok_or_else(|| RpcError::Other("Result not found".to_string()))
+            }
+            None => {
+                self.call_non_null("getblocktemplate", json!([{ "rules": ["segwit"] }]))
+                    .await
             }
-            Err(error) => Err(error),
         }
     }
 
-    pub async fn get_raw_mempool(&self) -> Result<Vec<String>, RpcError> {
-        let response = self.send_json_rpc_request("getrawmempool", json!([])).await;
-        match response {
-            Ok(result_hex) => {
-                let result_deserialized: JsonRpcResult<Vec<String>> =
-                    serde_json::from_str(&result_hex).map_err(|e| {
-                        RpcError::Deserialization(e.to_string()) // TODO manage message ids
-                    })?;
-                let mempool: Vec<String> = result_deserialized
-                    .result
-                    .ok_or_else(|| RpcError::Other("Result not found".to_string()))?;
-                Ok(mempool)
-            }
-            Err(error) => Err(error),
+    /// Returns the unspent output `txid:vout` of the best chain, or None if it does not exist or
+    /// it is spent. Mempool transactions are not considered.
+    pub async fn get_tx_out(&self, txid: &String, vout: u32) -> Result<Option<TxOut>, RpcError> {
+        let entry: Option<TxOutEntry> = self.call("gettxout", json!([txid, vout, false])).await?;
+        entry.map(TxOut::try_from).transpose()
+    }
+
+    /// Same as `get_tx_out` for several outputs, sent in one batch
+    pub async fn get_tx_outs(
+        &self,
+        outpoints: &[(String, u32)],
+    ) -> Result<Vec<Option<TxOut>>, RpcError> {
+        let requests = outpoints
+            .iter()
+            .map(|(txid, vout)| ("gettxout", json!([txid, vout, false])))
+            .collect();
+        let mut outputs = Vec::with_capacity(outpoints.len());
+        for result in self.batch::<TxOutEntry>(requests).await? {
+            outputs.push(result?.map(TxOut::try_from).transpose()?);
         }
+        Ok(outputs)
     }
 
-    pub async fn submit_block(&self, block_hex: String) -> Result<(), RpcError> {
-        let response = self
-            .send_json_rpc_request("submitblock", json!([block_hex]))
-            .await;
+    /// Checks if the node would accept the transactions in its mempool (`testmempoolaccept`),
+    /// they are tested as a package when there are more than one
+    pub async fn test_mempool_accept(
+        &self,
+        raw_txs: Vec<String>,
+    ) -> Result<Vec<MempoolAcceptResult>, RpcError> {
+        self.call_non_null("testmempoolaccept", json!([raw_txs]))
+            .await
+    }
 
-        match response {
-            Ok(_) => Ok(()),
-            Err(error) => Err(error),
+    /// Returns an error with the reason of the rejection if the node do not accept the block. A
+    /// request that times out is not retried, the node may still be validating the block.
+    pub async fn submit_block(&self, block_hex: String) -> Result<(), RpcError> {
+        let id = self.next_id();
+        let body = Self::request_body(id, "submitblock", json!([block_hex]))?;
+        let (status, response) = self.post(body, Some(self.timeout), false).await?;
+        // submitblock returns null when the block is accepted
+        match parse_response::<String>(status, &response, id)? {
+            None => Ok(()),
+            Some(reason) => Err(RpcError::Other(reason)),
         }
     }
 
-    async fn send_json_rpc_request(
+    /// Sends a request, the result is None when the node returns null
+    pub async fn call<T: DeserializeOwned>(
         &self,
         method: &str,
         params: serde_json::Value,
-    ) -> Result<String, RpcError> {
-        let client = &self.client;
-        let (username, password) = self.auth.clone().get_user_pass();
+    ) -> Result<Option<T>, RpcError> {
+        let id = self.next_id();
+        let body = Self::request_body(id, method, params)?;
+        let (status, response) = self.post(body, Some(self.timeout), true).await?;
+        parse_response(status, &response, id)
+    }
+
+    async fn call_non_null<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<T, RpcError> {
+        self.call(method, params)
+            .await?
+            .ok_or_else(|| RpcError::Other("Result not found".to_string()))
+    }
+
+    /// Sends the requests in one JSON-RPC batch. The results are in the order of the requests,
+    /// the outer error is returned when the whole batch fails.
+    pub async fn batch<T: DeserializeOwned>(
+        &self,
+        requests: Vec<(&str, serde_json::Value)>,
+    ) -> Result<Vec<Result<Option<T>, RpcError>>, RpcError> {
+        if requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        let ids: Vec<u64> = requests.iter().map(|_| self.next_id()).collect();
+        let batch: Vec<JsonRpcRequest> = requests
+            .into_iter()
+            .zip(ids.iter())
+            .map(|((method, params), id)| JsonRpcRequest {
+                jsonrpc: "2.0".to_string(),
+                method: method.to_string(),
+                params,
+                id: *id,
+            })
+            .collect();
+        let body =
+            serde_json::to_string(&batch).map_err(|e| RpcError::Serialization(e.to_string()))?;
+        let (status, response) = self.post(body, Some(self.timeout), true).await?;
+        let responses: Vec<JsonRpcResult<T>> = match serde_json::from_slice(&response) {
+            Ok(responses) => responses,
+            // a batch can fail as a whole, eg with an authentication error
+            Err(e) if status.is_success() => return Err(RpcError::Deserialization(e.to_string())),
+            Err(_) => return Err(RpcError::Http(status.to_string())),
+        };
+        // the node may answer in any order
+        let mut by_id: HashMap<u64, JsonRpcResult<T>> = responses
+            .into_iter()
+            .filter_map(|response| response.id.map(|id| (id, response)))
+            .collect();
+        Ok(ids
+            .iter()
+            .map(|id| match by_id.remove(id) {
+                Some(response) => response.into_result(),
+                None => Err(RpcError::Other(format!("No response for request {}", id))),
+            })
+            .collect())
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn request_body(id: u64, method: &str, params: serde_json::Value) -> Result<String, RpcError> {
         let request = JsonRpcRequest {
             jsonrpc: "2.0".to_string(),
             method: method.to_string(),
             params,
-            id: 1, //TODO manage message ids
+            id,
         };
+        serde_json::to_string(&request).map_err(|e| RpcError::Serialization(e.to_string()))
+    }
 
-        let request_body = match serde_json::to_string(&request) {
-            Ok(body) => body,
-            Err(e) => return Err(RpcError::Serialization(e.to_string())),
-        };
+    // Sends the body with the configured retries, only errors reaching the node are retried and
+    // the timeouts only when `retry_timeouts` is set
+    async fn post(
+        &self,
+        body: String,
+        timeout: Option<Duration>,
+        retry_timeouts: bool,
+    ) -> Result<(StatusCode, Vec<u8>), RpcError> {
+        let mut attempt = 0;
+        loop {
+            let result = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, self.post_once(body.clone()))
+                    .await
+                    .unwrap_or(Err(RpcError::Timeout)),
+                None => self.post_once(body.clone()).await,
+            };
+            let retry = match result {
+                Err(RpcError::Http(_)) => true,
+                Err(RpcError::Timeout) => retry_timeouts,
+                _ => false,
+            };
+            if !retry || attempt >= self.retries {
+                return result;
+            }
+            attempt += 1;
+            tokio::time::sleep(self.retry_interval).await;
+        }
+    }
 
+    async fn post_once(&self, body: String) -> Result<(StatusCode, Vec<u8>), RpcError> {
+        let (username, password) = self.auth.clone().get_user_pass()?;
         let req = Request::builder()
             .method("POST")
             .uri(self.url.as_str())
@@ -118,14 +348,14 @@ impl MiniRpcClient {
                         .encode(format!("{}:{}", username, password))
                 ),
             )
-            .body(Full::<Bytes>::from(request_body))
+            .body(Full::<Bytes>::from(body))
             .map_err(|e| RpcError::Http(e.to_string()))?;
 
-        let response = client
+        let response = self
+            .client
             .request(req)
             .await
             .map_err(|e| RpcError::Http(e.to_string()))?;
-
         let status = response.status();
         let body = response
             .into_body()
@@ -134,33 +364,63 @@ impl MiniRpcClient {
             .map_err(|e| RpcError::Http(e.to_string()))?
             .to_bytes()
             .to_vec();
+        Ok((status, body))
+    }
+}
 
-        if status.is_success() {
-            String::from_utf8(body).map_err(|e| {
-                RpcError::Deserialization(e.to_string()) // TODO manage message ids
-            })
-        } else {
-            let error_result: Result<JsonRpcResult<_>, _> = serde_json::from_slice(&body);
-            match error_result {
-                Ok(error_response) => Err(error_response.into()),
-                Err(e) => Err(RpcError::Deserialization(e.to_string())),
-            }
-        }
+// bitcoind answers JSON-RPC errors with an error status (legacy JSON-RPC) or with 200 (JSON-RPC
+// 2.0), other errors like a failed authentication have an empty body
+fn parse_response<T: DeserializeOwned>(
+    status: StatusCode,
+    body: &[u8],
+    id: u64,
+) -> Result<Option<T>, RpcError> {
+    let response: JsonRpcResult<T> = match serde_json::from_slice(body) {
+        Ok(response) => response,
+        Err(e) if status.is_success() => return Err(RpcError::Deserialization(e.to_string())),
+        Err(_) => return Err(RpcError::Http(status.to_string())),
+    };
+    if response.error.is_none() && response.id != Some(id) {
+        return Err(RpcError::Other(format!(
+            "Response id {:?} do not match request id {}",
+            response.id, id
+        )));
     }
+    response.into_result()
 }
 
 #[derive(Clone, Debug)]
-pub struct Auth {
-    username: String,
-    password: String,
+pub enum Auth {
+    UserPass(String, String),
+    /// Path of the `.cookie` file written by bitcoind when no rpcpassword is set
+    CookieFile(PathBuf),
 }
 
 impl Auth {
-    pub fn get_user_pass(self) -> (String, String) {
-        (self.username, self.password)
+    /// The cookie file is read at each request since bitcoind rewrites it when it restarts
+    pub fn get_user_pass(self) -> Result<(String, String), RpcError> {
+        match self {
+            Auth::UserPass(username, password) => Ok((username, password)),
+            Auth::CookieFile(path) => {
+                let cookie = std::fs::read_to_string(&path).map_err(|e| {
+                    RpcError::Auth(format!("Cannot read cookie file {:?}: {}", path, e))
+                })?;
+                match cookie.trim().split_once(':') {
+                    Some((COOKIE_USER, password)) => {
+                        Ok((COOKIE_USER.to_string(), password.to_string()))
+                    }
+                    _ => Err(RpcError::Auth(format!("Invalid cookie file {:?}", path))),
+                }
+            }
+        }
     }
+
     pub fn new(username: String, password: String) -> Auth {
-        Auth { username, password }
+        Auth::UserPass(username, password)
+    }
+
+    pub fn cookie_file(path: PathBuf) -> Auth {
+        Auth::CookieFile(path)
     }
 }
 
@@ -172,11 +432,148 @@ struct JsonRpcRequest {
     id: u64,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct MempoolEntry {
+    pub vsize: u64,
+    pub weight: u64,
+    /// vsize of the transaction and of its unconfirmed ancestors
+    #[serde(default, rename = "ancestorsize")]
+    pub ancestor_size: Option<u64>,
+    pub fees: MempoolEntryFees,
+    /// Unconfirmed parents of the transaction
+    #[serde(default)]
+    pub depends: Vec<String>,
+}
+
+/// Result of `testmempoolaccept` for a transaction, `vsize` and `fees` are set when it is
+/// allowed
+#[derive(Clone, Debug, Deserialize)]
+pub struct MempoolAcceptResult {
+    pub txid: String,
+    pub allowed: Option<bool>,
+    pub vsize: Option<u64>,
+    pub fees: Option<MempoolEntryFees>,
+    #[serde(rename = "reject-reason")]
+    pub reject_reason: Option<String>,
+    #[serde(rename = "package-error")]
+    pub package_error: Option<String>,
+}
+
+/// Output returned by `gettxout`, the value is in BTC
+#[derive(Clone, Debug, Deserialize)]
+struct TxOutEntry {
+    value: f64,
+    #[serde(rename = "scriptPubKey")]
+    script_pub_key: ScriptPubKeyEntry,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ScriptPubKeyEntry {
+    hex: String,
+}
+
+impl TryFrom<TxOutEntry> for TxOut {
+    type Error = RpcError;
+
+    fn try_from(entry: TxOutEntry) -> Result<Self, Self::Error> {
+        let script_pubkey = decode(entry.script_pub_key.hex)
+            .map_err(|e| RpcError::Deserialization(e.to_string()))?;
+        Ok(TxOut {
+            value: (entry.value * SATS_PER_BTC).round() as u64,
+            script_pubkey: script_pubkey.into(),
+        })
+    }
+}
+
+/// Fees in BTC
+#[derive(Clone, Debug, Deserialize)]
+pub struct MempoolEntryFees {
+    pub base: f64,
+    /// Fees of the transaction and of its unconfirmed ancestors, only in the mempool entries
+    #[serde(default)]
+    pub ancestor: Option<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlockchainInfo {
+    pub chain: String,
+    pub blocks: u64,
+    pub headers: u64,
+    #[serde(rename = "bestblockhash")]
+    pub best_block_hash: String,
+    pub difficulty: f64,
+    #[serde(rename = "initialblockdownload")]
+    pub initial_block_download: bool,
+    #[serde(rename = "verificationprogress")]
+    pub verification_progress: f64,
+    pub pruned: bool,
+}
+
+/// Result of `getblocktemplate`, see BIP22 and BIP23
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlockTemplate {
+    pub version: i32,
+    #[serde(default)]
+    pub rules: Vec<String>,
+    #[serde(rename = "previousblockhash")]
+    pub previous_block_hash: String,
+    pub transactions: Vec<BlockTemplateTransaction>,
+    /// Sats
+    #[serde(rename = "coinbasevalue")]
+    pub coinbase_value: u64,
+    #[serde(rename = "longpollid")]
+    pub long_poll_id: Option<String>,
+    pub target: String,
+    #[serde(rename = "mintime")]
+    pub min_time: u32,
+    #[serde(rename = "curtime")]
+    pub cur_time: u32,
+    /// Compact target in hex
+    pub bits: String,
+    pub height: u64,
+    /// Script of the witness commitment output in hex, absent when there are no segwit
+    /// transactions
+    pub default_witness_commitment: Option<String>,
+    #[serde(rename = "sigoplimit")]
+    pub sigop_limit: Option<u64>,
+    #[serde(rename = "weightlimit")]
+    pub weight_limit: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlockTemplateTransaction {
+    /// Serialized transaction in hex
+    pub data: String,
+    pub txid: String,
+    /// wtxid
+    pub hash: String,
+    /// 1-based indexes of the template transactions this one depends on
+    #[serde(default)]
+    pub depends: Vec<u32>,
+    /// Sats
+    pub fee: Option<u64>,
+    pub sigops: Option<u64>,
+    pub weight: Option<u64>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct JsonRpcResult<T> {
     result: Option<T>,
     pub error: Option<JsonRpcError>,
-    pub id: u64,
+    pub id: Option<u64>,
+}
+
+impl<T> JsonRpcResult<T> {
+    fn into_result(self) -> Result<Option<T>, RpcError> {
+        match self.error {
+            Some(error) => Err(RpcError::JsonRpc(JsonRpcResult {
+                result: None,
+                error: Some(error),
+                id: self.id,
+            })),
+            None => Ok(self.result),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -193,6 +590,8 @@ pub enum RpcError {
     Deserialization(String),
     Serialization(String),
     Http(String),
+    Timeout,
+    Auth(String),
     Other(String),
 }
 
@@ -201,3 +600,251 @@ impl From<JsonRpcResult<JsonRpcError>> for RpcError {
         Self::JsonRpc(error)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stand_in::{result, stand_in};
+    use serde_json::Value;
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    fn client(url: String) -> MiniRpcClient {
+        MiniRpcClient::new(url, Auth::new("user".to_string(), "pass".to_string()))
+    }
+
+    #[tokio::test]
+    async fn request_ids_are_unique() {
+        let ids = Arc::new(Mutex::new(Vec::new()));
+        let ids_ = ids.clone();
+        let url = stand_in(Arc::new(move |request| {
+            ids_.lock()
+                .unwrap()
+                .push(request.body["id"].as_u64().unwrap());
+            (200, result(&request.body, json!(800_000)).to_string())
+        }))
+        .await;
+        let client = client(url);
+        let clone = client.clone();
+        assert_eq!(client.get_block_count().await.unwrap(), 800_000);
+        assert_eq!(clone.get_block_count().await.unwrap(), 800_000);
+        assert_eq!(client.get_block_count().await.unwrap(), 800_000);
+        let ids = ids.lock().unwrap();
+        assert_eq!(ids.len(), 3);
+        assert!(ids[0] != ids[1] && ids[1] != ids[2] && ids[0] != ids[2]);
+    }
+
+    #[tokio::test]
+    async fn mismatched_response_id_is_an_error() {
+        let url = stand_in(Arc::new(|_| {
+            (
+                200,
+                json!({ "result": 1, "error": null, "id": 0 }).to_string(),
+            )
+        }))
+        .await;
+        assert!(matches!(
+            client(url).get_block_count().await,
+            Err(RpcError::Other(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn cookie_file_auth() {
+        let path = std::env::temp_dir().join(format!("rpc_sv2_cookie_{}", std::process::id()));
+        std::fs::write(&path, "__cookie__:secret\n").unwrap();
+        let expected = format!(
+            "basic {}",
+            base64::engine::general_purpose::STANDARD.encode("__cookie__:secret")
+        )
+        .to_lowercase();
+        let url = stand_in(Arc::new(move |request| {
+            if request.authorization == expected {
+                (
+                    200,
+                    result(&request.body, json!("00".repeat(32))).to_string(),
+                )
+            } else {
+                (401, String::new())
+            }
+        }))
+        .await;
+        let client = MiniRpcClient::new(url, Auth::cookie_file(path.clone()));
+        assert!(client.get_best_block_hash().await.is_ok());
+
+        // bitcoind rewrites the cookie when it restarts
+        std::fs::write(&path, "__cookie__:other\n").unwrap();
+        assert!(matches!(
+            client.get_best_block_hash().await,
+            Err(RpcError::Http(_))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            client.get_best_block_hash().await,
+            Err(RpcError::Auth(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn batch_results_follow_the_requests_order() {
+        let url = stand_in(Arc::new(|request| {
+            let mut responses: Vec<Value> = request
+                .body
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|request| {
+                    let vout = request["params"][1].as_u64().unwrap();
+                    match vout {
+                        0 => result(
+                            request,
+                            json!({ "value": 0.5, "scriptPubKey": { "hex": "51" } }),
+                        ),
+                        1 => result(request, Value::Null),
+                        _ => json!({
+                            "result": null,
+                            "error": { "code": -8, "message": "Invalid parameter" },
+                            "id": request["id"],
+                        }),
+                    }
+                })
+                .collect();
+            responses.reverse();
+            (200, Value::Array(responses).to_string())
+        }))
+        .await;
+        let txid = "00".repeat(32);
+        let requests = (0..3)
+            .map(|vout| ("gettxout", json!([txid, vout, false])))
+            .collect();
+        let results = client(url.clone())
+            .batch::<TxOutEntry>(requests)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().as_ref().unwrap().value, 0.5);
+        assert!(results[1].as_ref().unwrap().is_none());
+        assert!(matches!(results[2], Err(RpcError::JsonRpc(_))));
+
+        let outputs = client(url)
+            .get_tx_outs(&[(txid.clone(), 0), (txid, 1)])
+            .await
+            .unwrap();
+        assert_eq!(outputs[0].as_ref().unwrap().value, 50_000_000);
+        assert!(outputs[1].is_none());
+    }
+
+    #[tokio::test]
+    async fn timeouts_are_retried() {
+        let attempts = Arc::new(AtomicU64::new(0));
+        let attempts_ = attempts.clone();
+        // never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                attempts_.fetch_add(1, Ordering::Relaxed);
+                streams.push(stream);
+            }
+        });
+        let client = client(url)
+            .with_timeout(Duration::from_millis(100))
+            .with_retries(2, Duration::from_millis(10));
+        assert!(matches!(
+            client.get_block_count().await,
+            Err(RpcError::Timeout)
+        ));
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+
+        // a block is sent once, the node may still be validating it
+        attempts.store(0, Ordering::Relaxed);
+        assert!(matches!(
+            client.submit_block("00".to_string()).await,
+            Err(RpcError::Timeout)
+        ));
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn json_rpc_errors_are_returned() {
+        let url = stand_in(Arc::new(|request| {
+            let error = json!({
+                "result": null,
+                "error": { "code": -5, "message": "No such mempool transaction" },
+                "id": request.body["id"],
+            });
+            (500, error.to_string())
+        }))
+        .await;
+        match client(url).get_mempool_entry(&"00".repeat(32)).await {
+            Err(RpcError::JsonRpc(response)) => {
+                assert_eq!(response.error.unwrap().code, -5)
+            }
+            e => panic!("Unexpected result {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn submit_block_rejection() {
+        let url = stand_in(Arc::new(|request| {
+            (200, result(&request.body, json!("duplicate")).to_string())
+        }))
+        .await;
+        match client(url).submit_block("00".to_string()).await {
+            Err(RpcError::Other(reason)) => assert_eq!(reason, "duplicate"),
+            e => panic!("Unexpected result {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn get_block_template() {
+        let long_poll_ids = Arc::new(Mutex::new(Vec::new()));
+        let long_poll_ids_ = long_poll_ids.clone();
+        let url = stand_in(Arc::new(move |request| {
+            assert_eq!(request.body["method"], "getblocktemplate");
+            long_poll_ids_
+                .lock()
+                .unwrap()
+                .push(request.body["params"][0]["longpollid"].clone());
+            let template = json!({
+                "version": 536870912,
+                "rules": ["csv", "!segwit", "taproot"],
+                "previousblockhash": "00".repeat(32),
+                "transactions": [{
+                    "data": "0100",
+                    "txid": "11".repeat(32),
+                    "hash": "22".repeat(32),
+                    "depends": [],
+                    "fee": 1000,
+                    "sigops": 4,
+                    "weight": 800,
+                }],
+                "coinbasevalue": 625001000u64,
+                "longpollid": "abc1",
+                "target": "7fffff0000000000000000000000000000000000000000000000000000000000",
+                "mintime": 1700000000,
+                "curtime": 1700000100,
+                "bits": "207fffff",
+                "height": 101,
+                "default_witness_commitment": "6a24aa21a9ed",
+                "sigoplimit": 80000,
+                "weightlimit": 4000000,
+            });
+            (200, result(&request.body, template).to_string())
+        }))
+        .await;
+        let client = client(url);
+        let template = client.get_block_template(None).await.unwrap();
+        assert_eq!(template.height, 101);
+        assert_eq!(template.coinbase_value, 625_001_000);
+        assert_eq!(template.transactions[0].fee, Some(1000));
+        assert_eq!(template.long_poll_id.as_deref(), Some("abc1"));
+        client.get_block_template(Some("abc1")).await.unwrap();
+        assert_eq!(
+            *long_poll_ids.lock().unwrap(),
+            vec![Value::Null, json!("abc1")]
+        );
+    }
+}
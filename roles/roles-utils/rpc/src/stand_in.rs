@@ -0,0 +1,85 @@
+//! Minimal HTTP server standing in for bitcoind in the tests of the roles that use the RPC
+//! client. Enabled by the `stand_in` feature.
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+pub struct StandInRequest {
+    pub authorization: String,
+    pub body: Value,
+}
+
+/// Returns the HTTP status and the body of the response to a request
+pub type Handler = Arc<dyn Fn(StandInRequest) -> (u16, String) + Send + Sync>;
+
+/// Starts a server that answers each request with the handler and closes the connection,
+/// returns its url
+pub async fn stand_in(handler: Handler) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(stream, handler.clone()));
+        }
+    });
+    format!("http://{}", address)
+}
+
+async fn serve(mut stream: TcpStream, handler: Handler) {
+    let mut request = Vec::new();
+    let mut buffer = [0; 4096];
+    let header_end = loop {
+        let read = stream.read(&mut buffer).await.unwrap();
+        if read == 0 {
+            return;
+        }
+        request.extend_from_slice(&buffer[..read]);
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+    };
+    let headers = String::from_utf8_lossy(&request[..header_end]).to_lowercase();
+    let header = |name: &str| {
+        headers
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{}: ", name)))
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    };
+    let content_length: usize = header("content-length").parse().unwrap();
+    let authorization = header("authorization");
+    while request.len() < header_end + content_length {
+        let read = stream.read(&mut buffer).await.unwrap();
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let body = serde_json::from_slice(&request[header_end..]).unwrap();
+    let (status, response) = handler(StandInRequest {
+        authorization,
+        body,
+    });
+    let response = format!(
+        "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        response.len(),
+        response
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+/// Successful JSON-RPC response to `request`
+pub fn result(request: &Value, result: Value) -> Value {
+    json!({ "result": result, "error": null, "id": request["id"] })
+}
+
+/// JSON-RPC error response to `request`
+pub fn error(request: &Value, code: i64, message: &str) -> Value {
+    json!({
+        "result": null,
+        "error": { "code": code, "message": message },
+        "id": request["id"],
+    })
+}
@@ -0,0 +1,77 @@
+use super::Downstream;
+use roles_logic_sv2::{
+    errors::Error,
+    handlers::template_distribution::{ParseClientTemplateDistributionMessages, SendTo},
+    parsers::TemplateDistribution,
+    template_distribution_sv2::{
+        CoinbaseOutputDataSize, RequestTransactionData, RequestTransactionDataError, SubmitSolution,
+    },
+};
+use std::convert::TryInto;
+use tracing::{debug, info, warn};
+
+impl ParseClientTemplateDistributionMessages for Downstream {
+    fn handle_coinbase_out_data_size(
+        &mut self,
+        m: CoinbaseOutputDataSize,
+    ) -> Result<SendTo, Error> {
+        info!(
+            "Downstream {} coinbase outputs take up to {} bytes",
+            self.id, m.coinbase_output_max_additional_size
+        );
+        self.coinbase_output_max_additional_size = Some(m.coinbase_output_max_additional_size);
+        // the next templates are selected with the new size
+        Ok(SendTo::None(Some(
+            TemplateDistribution::CoinbaseOutputDataSize(m),
+        )))
+    }
+
+    fn handle_request_tx_data(&mut self, m: RequestTransactionData) -> Result<SendTo, Error> {
+        debug!("Downstream {} asks transactions of {:?}", self.id, m);
+        let message = match self.templates.get(&m.template_id) {
+            Some(template) => {
+                let success = template
+                    .request_transaction_data_success(m.template_id)
+                    .map_err(|e| Error::NoValidTemplate(e.to_string()))?;
+                TemplateDistribution::RequestTransactionDataSuccess(success)
+            }
+            None => {
+                warn!(
+                    "Downstream {} asks transactions of unknown template {}",
+                    self.id, m.template_id
+                );
+                let error_code = match m.template_id <= self.last_template_id {
+                    true => "stale-template-id",
+                    false => "template-id-not-found",
+                };
+                TemplateDistribution::RequestTransactionDataError(RequestTransactionDataError {
+                    template_id: m.template_id,
+                    error_code: error_code
+                        .to_string()
+                        .into_bytes()
+                        .try_into()
+                        .map_err(Error::BinarySv2Error)?,
+                })
+            }
+        };
+        Ok(SendTo::Respond(message))
+    }
+
+    fn handle_request_submit_solution(&mut self, m: SubmitSolution) -> Result<SendTo, Error> {
+        info!(
+            "Downstream {} found a solution for template {}",
+            self.id, m.template_id
+        );
+        // the block is submitted outside of the handler since the RPC is async
+        let solution = SubmitSolution {
+            template_id: m.template_id,
+            version: m.version,
+            header_timestamp: m.header_timestamp,
+            header_nonce: m.header_nonce,
+            coinbase_tx: m.coinbase_tx.to_vec().try_into()?,
+        };
+        Ok(SendTo::None(Some(TemplateDistribution::SubmitSolution(
+            solution,
+        ))))
+    }
+}
@@ -0,0 +1,391 @@
+pub mod message_handler;
+pub mod setup_connection;
+
+use super::{
+    error::TpError,
+    status,
+    template::{SelectedTemplate, Template},
+    template_provider::TemplateProvider,
+    EitherFrame, StdFrame,
+};
+use async_channel::{Receiver, Sender};
+use codec_sv2::Frame;
+use error_handling::handle_result;
+use nohash_hasher::BuildNoHashHasher;
+use roles_logic_sv2::{
+    handlers::template_distribution::{ParseClientTemplateDistributionMessages, SendTo},
+    parsers::{PoolMessages, TemplateDistribution},
+    template_distribution_sv2::SubmitSolution,
+    utils::Mutex,
+};
+use rpc_sv2::mini_rpc_client::{MiniRpcClient, RpcError};
+use std::{collections::HashMap, convert::TryInto, sync::Arc};
+use stratum_common::bitcoin::consensus::encode::serialize_hex;
+use tracing::{error, info, warn};
+
+// Templates of a downstream kept for its solutions, the oldest ones are dropped first. A template
+// is sent at each poll interval and when the mempool changes, there are few between two blocks.
+const MAX_TEMPLATES: u64 = 100;
+
+/// Connection of a role that asks templates, like a pool or a JDC
+#[derive(Debug)]
+pub struct Downstream {
+    id: u32,
+    sender: Sender<EitherFrame>,
+    receiver: Receiver<EitherFrame>,
+    // None until the downstream sends CoinbaseOutputDataSize, no template is sent before
+    coinbase_output_max_additional_size: Option<u32>,
+    last_template_id: u64,
+    // Templates sent since the last SetNewPrevHash, at most MAX_TEMPLATES
+    templates: HashMap<u64, SelectedTemplate, BuildNoHashHasher<u64>>,
+}
+
+impl Downstream {
+    pub fn new(id: u32, receiver: Receiver<EitherFrame>, sender: Sender<EitherFrame>) -> Self {
+        Self {
+            id,
+            sender,
+            receiver,
+            coinbase_output_max_additional_size: None,
+            last_template_id: 0,
+            templates: HashMap::with_hasher(BuildNoHashHasher::default()),
+        }
+    }
+
+    /// Messages that give `template` to the downstream. On a new prev hash, and for the first
+    /// template of the connection, the template is sent as a future template followed by its
+    /// SetNewPrevHash.
+    fn on_template(
+        &mut self,
+        template: &Arc<Template>,
+        new_prev_hash: bool,
+    ) -> Result<Vec<TemplateDistribution<'static>>, TpError> {
+        let coinbase_output_max_additional_size = match self.coinbase_output_max_additional_size {
+            Some(size) => size,
+            None => return Ok(vec![]),
+        };
+        let new_prev_hash = new_prev_hash || self.templates.is_empty();
+        let selected = template.select(coinbase_output_max_additional_size);
+        self.last_template_id += 1;
+        let template_id = self.last_template_id;
+        let mut messages = vec![TemplateDistribution::NewTemplate(
+            selected.new_template(template_id, new_prev_hash)?,
+        )];
+        if new_prev_hash {
+            messages.push(TemplateDistribution::SetNewPrevHash(
+                selected.set_new_prev_hash(template_id)?,
+            ));
+            self.templates.clear();
+        }
+        self.templates.insert(template_id, selected);
+        // the template ids of a downstream are consecutive
+        if let Some(oldest) = template_id.checked_sub(MAX_TEMPLATES) {
+            self.templates.remove(&oldest);
+        }
+        Ok(messages)
+    }
+
+    pub async fn send_template(
+        self_mutex: Arc<Mutex<Self>>,
+        template: &Arc<Template>,
+        new_prev_hash: bool,
+    ) -> Result<(), TpError> {
+        let messages = self_mutex.safe_lock(|s| s.on_template(template, new_prev_hash))??;
+        for message in messages {
+            Self::send(self_mutex.clone(), message).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn send(
+        self_mutex: Arc<Mutex<Self>>,
+        message: TemplateDistribution<'static>,
+    ) -> Result<(), TpError> {
+        let sv2_frame: StdFrame = PoolMessages::TemplateDistribution(message).try_into()?;
+        let sender = self_mutex.safe_lock(|s| s.sender.clone())?;
+        sender.send(sv2_frame.into()).await?;
+        Ok(())
+    }
+
+    pub fn start(
+        self_mutex: Arc<Mutex<Self>>,
+        template_provider: Arc<Mutex<TemplateProvider>>,
+        client: MiniRpcClient,
+        tx_status: status::Sender,
+    ) {
+        let (id, recv) = self_mutex
+            .safe_lock(|s| (s.id, s.receiver.clone()))
+            .unwrap();
+        tokio::spawn(async move {
+            loop {
+                let message = match recv.recv().await {
+                    Ok(message) => message,
+                    Err(_) => {
+                        info!("Downstream {} disconnected", id);
+                        break;
+                    }
+                };
+                let mut frame: StdFrame = handle_result!(tx_status, message.try_into());
+                let header = frame
+                    .get_header()
+                    .ok_or_else(|| TpError::Custom(String::from("No header set")));
+                let header = handle_result!(tx_status, header);
+                let message_type = header.msg_type();
+                let payload = frame.payload();
+                let next_message_to_send =
+                    ParseClientTemplateDistributionMessages::handle_message_template_distribution(
+                        self_mutex.clone(),
+                        message_type,
+                        payload,
+                    );
+                match next_message_to_send {
+                    Ok(SendTo::Respond(message)) => {
+                        handle_result!(tx_status, Self::send(self_mutex.clone(), message).await);
+                    }
+                    Ok(SendTo::None(Some(TemplateDistribution::CoinbaseOutputDataSize(_)))) => {
+                        // the downstream gets the current template as soon as its coinbase
+                        // outputs are known
+                        let template = handle_result!(
+                            tx_status,
+                            Self::first_template(&self_mutex, &template_provider)
+                        );
+                        if let Some(template) = template {
+                            handle_result!(
+                                tx_status,
+                                Self::send_template(self_mutex.clone(), &template, true).await
+                            );
+                        }
+                    }
+                    Ok(SendTo::None(Some(TemplateDistribution::SubmitSolution(solution)))) => {
+                        Self::on_submit_solution(self_mutex.clone(), client.clone(), solution);
+                    }
+                    Ok(_) => (),
+                    Err(e) => {
+                        handle_result!(tx_status, Err::<(), _>(TpError::RolesLogic(e)));
+                    }
+                }
+            }
+            let _ = template_provider.safe_lock(|tp| tp.remove_downstream(id));
+        });
+    }
+
+    // Last template of the node if none has been sent to the downstream yet
+    fn first_template(
+        self_mutex: &Arc<Mutex<Self>>,
+        template_provider: &Arc<Mutex<TemplateProvider>>,
+    ) -> Result<Option<Arc<Template>>, TpError> {
+        if self_mutex.safe_lock(|s| s.last_template_id)? != 0 {
+            return Ok(None);
+        }
+        Ok(template_provider.safe_lock(|tp| tp.last_template())?)
+    }
+
+    fn on_submit_solution(
+        self_mutex: Arc<Mutex<Self>>,
+        client: MiniRpcClient,
+        solution: SubmitSolution<'static>,
+    ) {
+        let block = self_mutex.safe_lock(|s| {
+            s.templates
+                .get(&solution.template_id)
+                .map(|template| template.block(&solution))
+        });
+        let block = match block {
+            Ok(Some(Ok(block))) => block,
+            Ok(Some(Err(e))) => {
+                error!("Impossible to build the block of the solution: {}", e);
+                return;
+            }
+            Ok(None) => {
+                warn!(
+                    "Solution for unknown or stale template {}",
+                    solution.template_id
+                );
+                return;
+            }
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        let hash = block.block_hash();
+        info!("Submitting block {}", hash);
+        tokio::spawn(async move {
+            match client.submit_block(serialize_hex(&block)).await {
+                Ok(()) => info!("Block {} accepted by the node", hash),
+                Err(RpcError::Other(reason)) => {
+                    error!("Block {} rejected by the node: {}", hash, reason)
+                }
+                Err(e) => error!("Impossible to submit block {}: {:?}", hash, e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{
+        super::template::tests::{block_template, transaction},
+        *,
+    };
+    use roles_logic_sv2::template_distribution_sv2::RequestTransactionData;
+    use rpc_sv2::{
+        mini_rpc_client::Auth,
+        stand_in::{result, stand_in},
+    };
+    use serde_json::json;
+    use std::{convert::TryFrom, time::Duration};
+    use stratum_common::bitcoin::{consensus::serialize, OutPoint};
+
+    /// Message sent to a downstream, without the fields that the tests do not check
+    #[derive(Debug, PartialEq)]
+    pub(crate) enum Sent {
+        NewTemplate(u64, bool),
+        SetNewPrevHash(u64, Vec<u8>),
+        TransactionData(u64, usize),
+        TransactionDataError(u64, String),
+    }
+
+    /// Downstream that already sent CoinbaseOutputDataSize, with the receiving side of the
+    /// messages sent to it and the sending side of the messages it receives
+    pub(crate) fn downstream() -> (Downstream, Receiver<EitherFrame>, Sender<EitherFrame>) {
+        let (to_downstream, sent) = async_channel::unbounded();
+        let (received, from_downstream) = async_channel::unbounded();
+        let mut downstream = Downstream::new(1, from_downstream, to_downstream);
+        downstream.coinbase_output_max_additional_size = Some(0);
+        (downstream, sent, received)
+    }
+
+    /// Frame sent by a downstream
+    pub(crate) fn frame(message: TemplateDistribution<'static>) -> EitherFrame {
+        let frame: StdFrame = PoolMessages::TemplateDistribution(message)
+            .try_into()
+            .unwrap();
+        let mut bytes = vec![0; frame.encoded_length()];
+        frame.serialize(&mut bytes).unwrap();
+        StdFrame::from_bytes_unchecked(bytes.into()).into()
+    }
+
+    /// Next message sent to the downstream
+    pub(crate) async fn next_sent(sent: &Receiver<EitherFrame>) -> Sent {
+        let frame = tokio::time::timeout(Duration::from_secs(5), sent.recv())
+            .await
+            .expect("No message sent to the downstream")
+            .unwrap();
+        let frame: StdFrame = frame.try_into().unwrap();
+        let mut bytes = vec![0; frame.encoded_length()];
+        frame.serialize(&mut bytes).unwrap();
+        let mut frame = StdFrame::from_bytes_unchecked(bytes.into());
+        let message_type = frame.get_header().unwrap().msg_type();
+        match (message_type, frame.payload()).try_into().unwrap() {
+            TemplateDistribution::NewTemplate(m) => {
+                Sent::NewTemplate(m.template_id, m.future_template)
+            }
+            TemplateDistribution::SetNewPrevHash(m) => {
+                Sent::SetNewPrevHash(m.template_id, m.prev_hash.to_vec())
+            }
+            TemplateDistribution::RequestTransactionDataSuccess(m) => {
+                Sent::TransactionData(m.template_id, m.transaction_list.to_vec().len())
+            }
+            TemplateDistribution::RequestTransactionDataError(m) => Sent::TransactionDataError(
+                m.template_id,
+                String::from_utf8(m.error_code.to_vec()).unwrap(),
+            ),
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    pub(crate) fn solution(template_id: u64) -> SubmitSolution<'static> {
+        let coinbase = transaction(OutPoint::null(), 312_500_600);
+        SubmitSolution {
+            template_id,
+            version: 0x2000_0000,
+            header_timestamp: 1_700_000_200,
+            header_nonce: 42,
+            coinbase_tx: serialize(&coinbase).try_into().unwrap(),
+        }
+    }
+
+    fn request_transaction_data(downstream: &mut Downstream, template_id: u64) -> Sent {
+        match downstream.handle_request_tx_data(RequestTransactionData { template_id }) {
+            Ok(SendTo::Respond(TemplateDistribution::RequestTransactionDataSuccess(m))) => {
+                Sent::TransactionData(m.template_id, m.transaction_list.to_vec().len())
+            }
+            Ok(SendTo::Respond(TemplateDistribution::RequestTransactionDataError(m))) => {
+                Sent::TransactionDataError(
+                    m.template_id,
+                    String::from_utf8(m.error_code.to_vec()).unwrap(),
+                )
+            }
+            other => panic!("Unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn templates_are_bounded() {
+        let (mut downstream, _, _) = downstream();
+        let template = Arc::new(Template::try_from(block_template()).unwrap());
+        for _ in 0..MAX_TEMPLATES + 10 {
+            downstream.on_template(&template, false).unwrap();
+        }
+        assert_eq!(downstream.templates.len(), MAX_TEMPLATES as usize);
+        assert!(!downstream.templates.contains_key(&10));
+        assert!(downstream.templates.contains_key(&11));
+        assert!(downstream.templates.contains_key(&(MAX_TEMPLATES + 10)));
+        // a new prev hash drops the templates of the previous block
+        downstream.on_template(&template, true).unwrap();
+        assert_eq!(downstream.templates.len(), 1);
+    }
+
+    #[test]
+    fn transaction_data_of_stale_and_unknown_templates() {
+        let (mut downstream, _, _) = downstream();
+        let template = Arc::new(Template::try_from(block_template()).unwrap());
+        downstream.on_template(&template, true).unwrap();
+        downstream.on_template(&template, false).unwrap();
+        assert_eq!(
+            request_transaction_data(&mut downstream, 2),
+            Sent::TransactionData(2, 3)
+        );
+        downstream.on_template(&template, true).unwrap();
+        assert_eq!(
+            request_transaction_data(&mut downstream, 2),
+            Sent::TransactionDataError(2, "stale-template-id".to_string())
+        );
+        assert_eq!(
+            request_transaction_data(&mut downstream, 4),
+            Sent::TransactionDataError(4, "template-id-not-found".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn solutions_of_known_templates_are_submitted() {
+        let submitted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let submitted_ = submitted.clone();
+        let url = stand_in(Arc::new(move |request| {
+            assert_eq!(request.body["method"], "submitblock");
+            let block_hex = request.body["params"][0].as_str().unwrap().to_string();
+            submitted_.lock().unwrap().push(block_hex);
+            (200, result(&request.body, json!(null)).to_string())
+        }))
+        .await;
+        let client = MiniRpcClient::new(url, Auth::new("user".to_string(), "pass".to_string()));
+        let (mut downstream, _, _) = downstream();
+        let template = Arc::new(Template::try_from(block_template()).unwrap());
+        downstream.on_template(&template, true).unwrap();
+        let block = downstream.templates[&1].block(&solution(1)).unwrap();
+        let downstream = Arc::new(Mutex::new(downstream));
+
+        // unknown template, nothing is submitted
+        Downstream::on_submit_solution(downstream.clone(), client.clone(), solution(2));
+        Downstream::on_submit_solution(downstream, client, solution(1));
+        for _ in 0..50 {
+            if !submitted.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*submitted.lock().unwrap(), [serialize_hex(&block)]);
+    }
+}
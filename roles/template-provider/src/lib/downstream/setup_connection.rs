@@ -0,0 +1,68 @@
+use super::super::{error::TpError, EitherFrame, StdFrame};
+use async_channel::{Receiver, Sender};
+use codec_sv2::Frame;
+use roles_logic_sv2::{
+    common_messages_sv2::{
+        Protocol, SetupConnection, SetupConnectionError, SetupConnectionSuccess,
+    },
+    parsers::{CommonMessages, PoolMessages},
+};
+use std::convert::TryInto;
+use tracing::{debug, warn};
+
+const PROTOCOL_VERSION: u16 = 2;
+
+/// Answers the SetupConnection of a new downstream, only the Template Distribution Protocol is
+/// accepted
+pub async fn setup_connection(
+    receiver: &Receiver<EitherFrame>,
+    sender: &Sender<EitherFrame>,
+) -> Result<(), TpError> {
+    let mut incoming: StdFrame = receiver.recv().await?.try_into()?;
+    let message_type = incoming
+        .get_header()
+        .ok_or_else(|| TpError::Custom(String::from("No header set")))?
+        .msg_type();
+    let payload = incoming.payload();
+    let setup_connection: SetupConnection = match (message_type, payload).try_into()? {
+        CommonMessages::SetupConnection(m) => m,
+        m => {
+            return Err(TpError::Custom(format!(
+                "Expected SetupConnection, got {:?}",
+                m
+            )))
+        }
+    };
+    debug!("Setup connection message: {:?}", setup_connection);
+
+    let error_code = if setup_connection.protocol != Protocol::TemplateDistributionProtocol {
+        Some("unsupported-protocol")
+    } else if setup_connection.min_version > PROTOCOL_VERSION
+        || setup_connection.max_version < PROTOCOL_VERSION
+    {
+        Some("protocol-version-mismatch")
+    } else {
+        None
+    };
+    let response: CommonMessages<'static> = match error_code {
+        None => SetupConnectionSuccess {
+            used_version: PROTOCOL_VERSION,
+            flags: 0,
+        }
+        .into(),
+        Some(error_code) => SetupConnectionError {
+            flags: 0,
+            error_code: error_code.to_string().into_bytes().try_into()?,
+        }
+        .into(),
+    };
+    let sv2_frame: StdFrame = PoolMessages::Common(response).try_into()?;
+    sender.send(sv2_frame.into()).await?;
+    match error_code {
+        None => Ok(()),
+        Some(error_code) => {
+            warn!("Refused connection: {}", error_code);
+            Err(TpError::Custom(error_code.to_string()))
+        }
+    }
+}
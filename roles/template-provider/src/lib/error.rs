@@ -0,0 +1,110 @@
+use std::{
+    convert::From,
+    fmt::Debug,
+    sync::{MutexGuard, PoisonError},
+};
+
+use rpc_sv2::mini_rpc_client::RpcError;
+
+#[derive(std::fmt::Debug)]
+pub enum TpError {
+    Io(std::io::Error),
+    ChannelSend(Box<dyn std::marker::Send + Debug>),
+    ChannelRecv(async_channel::RecvError),
+    BinarySv2(binary_sv2::Error),
+    Codec(codec_sv2::Error),
+    Noise(noise_sv2::Error),
+    RolesLogic(roles_logic_sv2::Error),
+    Framing(codec_sv2::framing_sv2::Error),
+    PoisonLock(String),
+    Custom(String),
+    Rpc(RpcError),
+    /// The template returned by the node can not be turned in SV2 messages
+    InvalidTemplate(String),
+}
+
+impl std::fmt::Display for TpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use TpError::*;
+        match self {
+            Io(ref e) => write!(f, "I/O error: `{:?}", e),
+            ChannelSend(ref e) => write!(f, "Channel send failed: `{:?}`", e),
+            ChannelRecv(ref e) => write!(f, "Channel recv failed: `{:?}`", e),
+            BinarySv2(ref e) => write!(f, "Binary SV2 error: `{:?}`", e),
+            Codec(ref e) => write!(f, "Codec SV2 error: `{:?}", e),
+            Framing(ref e) => write!(f, "Framing SV2 error: `{:?}`", e),
+            Noise(ref e) => write!(f, "Noise SV2 error: `{:?}", e),
+            RolesLogic(ref e) => write!(f, "Roles Logic SV2 error: `{:?}`", e),
+            PoisonLock(ref e) => write!(f, "Poison lock: {:?}", e),
+            Custom(ref e) => write!(f, "Custom SV2 error: `{:?}`", e),
+            Rpc(ref e) => write!(f, "RPC error: `{:?}`", e),
+            InvalidTemplate(ref e) => write!(f, "Invalid block template: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for TpError {
+    fn from(e: std::io::Error) -> TpError {
+        TpError::Io(e)
+    }
+}
+
+impl From<async_channel::RecvError> for TpError {
+    fn from(e: async_channel::RecvError) -> TpError {
+        TpError::ChannelRecv(e)
+    }
+}
+
+impl From<binary_sv2::Error> for TpError {
+    fn from(e: binary_sv2::Error) -> TpError {
+        TpError::BinarySv2(e)
+    }
+}
+
+impl From<codec_sv2::Error> for TpError {
+    fn from(e: codec_sv2::Error) -> TpError {
+        TpError::Codec(e)
+    }
+}
+
+impl From<noise_sv2::Error> for TpError {
+    fn from(e: noise_sv2::Error) -> TpError {
+        TpError::Noise(e)
+    }
+}
+
+impl From<roles_logic_sv2::Error> for TpError {
+    fn from(e: roles_logic_sv2::Error) -> TpError {
+        TpError::RolesLogic(e)
+    }
+}
+
+impl<T: 'static + std::marker::Send + Debug> From<async_channel::SendError<T>> for TpError {
+    fn from(e: async_channel::SendError<T>) -> TpError {
+        TpError::ChannelSend(Box::new(e))
+    }
+}
+
+impl From<String> for TpError {
+    fn from(e: String) -> TpError {
+        TpError::Custom(e)
+    }
+}
+
+impl From<codec_sv2::framing_sv2::Error> for TpError {
+    fn from(e: codec_sv2::framing_sv2::Error) -> TpError {
+        TpError::Framing(e)
+    }
+}
+
+impl<T> From<PoisonError<MutexGuard<'_, T>>> for TpError {
+    fn from(e: PoisonError<MutexGuard<T>>) -> TpError {
+        TpError::PoisonLock(e.to_string())
+    }
+}
+
+impl From<RpcError> for TpError {
+    fn from(e: RpcError) -> TpError {
+        TpError::Rpc(e)
+    }
+}
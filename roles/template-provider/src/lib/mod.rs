@@ -0,0 +1,84 @@
+pub mod downstream;
+pub mod error;
+pub mod status;
+pub mod template;
+pub mod template_provider;
+
+use codec_sv2::{StandardEitherFrame, StandardSv2Frame};
+use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
+use roles_logic_sv2::parsers::PoolMessages as TpMessages;
+use rpc_sv2::mini_rpc_client::{Auth, MiniRpcClient};
+use serde::Deserialize;
+use std::{path::PathBuf, time::Duration};
+
+pub type Message = TpMessages<'static>;
+pub type StdFrame = StandardSv2Frame<Message>;
+pub type EitherFrame = StandardEitherFrame<Message>;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Configuration {
+    pub listen_address: String,
+    pub authority_public_key: Secp256k1PublicKey,
+    pub authority_secret_key: Secp256k1SecretKey,
+    pub cert_validity_sec: u64,
+    pub core_rpc_url: String,
+    pub core_rpc_port: u16,
+    #[serde(default)]
+    pub core_rpc_user: String,
+    #[serde(default)]
+    pub core_rpc_pass: String,
+    /// When set the credentials are read from the bitcoind cookie file instead of
+    /// `core_rpc_user` and `core_rpc_pass`
+    #[serde(default)]
+    pub core_rpc_cookie_file: Option<PathBuf>,
+    /// Timeout of the requests to the node in seconds, 0 keeps the default of the RPC client
+    #[serde(default)]
+    pub rpc_timeout_secs: u64,
+    /// Retries of a request that failed to reach the node, a block is never resent after a
+    /// timeout
+    #[serde(default)]
+    pub rpc_retries: u32,
+    /// Interval between two `getblocktemplate`, a new template is sent only when it differs from
+    /// the last one
+    #[serde(deserialize_with = "duration_from_toml")]
+    pub poll_interval: Duration,
+    /// Wait for new blocks with the `getblocktemplate` long polling, so that they are not
+    /// noticed only at the next poll
+    #[serde(default = "default_long_poll")]
+    pub long_poll: bool,
+}
+
+fn default_long_poll() -> bool {
+    true
+}
+
+pub fn get_rpc_client(config: &Configuration) -> MiniRpcClient {
+    let url = config.core_rpc_url.clone() + ":" + &config.core_rpc_port.to_string();
+    let auth = match &config.core_rpc_cookie_file {
+        Some(path) => Auth::cookie_file(path.clone()),
+        None => Auth::new(config.core_rpc_user.clone(), config.core_rpc_pass.clone()),
+    };
+    MiniRpcClient::new(url, auth).with_options(config.rpc_timeout_secs, config.rpc_retries)
+}
+
+fn duration_from_toml<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Helper {
+        unit: String,
+        value: u64,
+    }
+
+    let helper = Helper::deserialize(deserializer)?;
+    match helper.unit.as_str() {
+        "seconds" => Ok(Duration::from_secs(helper.value)),
+        "secs" => Ok(Duration::from_secs(helper.value)),
+        "s" => Ok(Duration::from_secs(helper.value)),
+        "milliseconds" => Ok(Duration::from_millis(helper.value)),
+        "millis" => Ok(Duration::from_millis(helper.value)),
+        "ms" => Ok(Duration::from_millis(helper.value)),
+        _ => Err(serde::de::Error::custom("Unsupported duration unit")),
+    }
+}
@@ -0,0 +1,103 @@
+use super::error::TpError;
+
+/// Each sending side of the status channel
+/// should be wrapped with this enum to allow
+/// the main thread to know which component sent the message
+#[derive(Debug)]
+pub enum Sender {
+    Downstream(async_channel::Sender<Status>),
+    DownstreamListener(async_channel::Sender<Status>),
+    Node(async_channel::Sender<Status>),
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Downstream(inner) => Self::Downstream(inner.clone()),
+            Self::DownstreamListener(inner) => Self::DownstreamListener(inner.clone()),
+            Self::Node(inner) => Self::Node(inner.clone()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum State {
+    DownstreamShutdown(TpError),
+    NodeShutdown(TpError),
+    Healthy(String),
+}
+
+/// message to be sent to the status loop on the main thread
+#[derive(Debug)]
+pub struct Status {
+    pub state: State,
+}
+
+/// this function is used to discern which componnent experienced the event.
+/// With this knowledge we can wrap the status message with information (`State` variants) so
+/// the main status loop can decide what should happen
+async fn send_status(
+    sender: &Sender,
+    e: TpError,
+    outcome: error_handling::ErrorBranch,
+) -> error_handling::ErrorBranch {
+    match sender {
+        Sender::Downstream(tx) => {
+            let string_err = e.to_string();
+            tx.send(Status {
+                state: State::Healthy(string_err),
+            })
+            .await
+            .unwrap_or(());
+        }
+        Sender::DownstreamListener(tx) => {
+            tx.send(Status {
+                state: State::DownstreamShutdown(e),
+            })
+            .await
+            .unwrap_or(());
+        }
+        Sender::Node(tx) => match e {
+            // the node may be restarting or syncing, the poller tries again
+            TpError::Rpc(_) | TpError::InvalidTemplate(_) => {
+                let string_err = e.to_string();
+                tx.send(Status {
+                    state: State::Healthy(string_err),
+                })
+                .await
+                .unwrap_or(());
+            }
+            _ => {
+                tx.send(Status {
+                    state: State::NodeShutdown(e),
+                })
+                .await
+                .unwrap_or(());
+            }
+        },
+    }
+    outcome
+}
+
+// this is called by `error_handling::handle_result!`
+pub async fn handle_error(sender: &Sender, e: TpError) -> error_handling::ErrorBranch {
+    tracing::debug!("Error: {:?}", &e);
+    match e {
+        TpError::Io(_) => send_status(sender, e, error_handling::ErrorBranch::Break).await,
+        TpError::ChannelSend(_) => {
+            send_status(sender, e, error_handling::ErrorBranch::Continue).await
+        }
+        TpError::ChannelRecv(_) => send_status(sender, e, error_handling::ErrorBranch::Break).await,
+        TpError::BinarySv2(_) => send_status(sender, e, error_handling::ErrorBranch::Break).await,
+        TpError::Codec(_) => send_status(sender, e, error_handling::ErrorBranch::Break).await,
+        TpError::Noise(_) => send_status(sender, e, error_handling::ErrorBranch::Continue).await,
+        TpError::RolesLogic(_) => send_status(sender, e, error_handling::ErrorBranch::Break).await,
+        TpError::Custom(_) => send_status(sender, e, error_handling::ErrorBranch::Break).await,
+        TpError::Framing(_) => send_status(sender, e, error_handling::ErrorBranch::Break).await,
+        TpError::PoisonLock(_) => send_status(sender, e, error_handling::ErrorBranch::Break).await,
+        TpError::Rpc(_) => send_status(sender, e, error_handling::ErrorBranch::Continue).await,
+        TpError::InvalidTemplate(_) => {
+            send_status(sender, e, error_handling::ErrorBranch::Continue).await
+        }
+    }
+}
@@ -0,0 +1,436 @@
+//! Turns the `getblocktemplate` result of the node in Template Distribution messages
+use super::error::TpError;
+use binary_sv2::{Seq0255, Seq064K, B016M, B064K, U256};
+use roles_logic_sv2::{
+    template_distribution_sv2::{
+        NewTemplate, RequestTransactionDataSuccess, SetNewPrevHash, SubmitSolution,
+    },
+    utils::{merkle_path_from_txids, witness_commitment_script},
+};
+use rpc_sv2::mini_rpc_client::BlockTemplate;
+use std::{
+    collections::HashSet,
+    convert::{TryFrom, TryInto},
+    str::FromStr,
+    sync::Arc,
+};
+use stratum_common::bitcoin::{
+    blockdata::{block::BlockHeader, script::Builder},
+    consensus::{deserialize, encode::serialize},
+    hashes::Hash,
+    Block, BlockHash, Transaction, TxMerkleNode, TxOut, Txid,
+};
+
+// bitcoind keeps this weight free for the block header and the coinbase when it builds the
+// template, the coinbase outputs of the downstream are accounted on top of it
+const COINBASE_RESERVED_WEIGHT: u64 = 4000;
+const MAX_BLOCK_WEIGHT: u64 = 4_000_000;
+const COINBASE_TX_VERSION: u32 = 2;
+const COINBASE_TX_INPUT_SEQUENCE: u32 = u32::MAX;
+
+#[derive(Debug, Clone)]
+pub struct TemplateTransaction {
+    pub transaction: Transaction,
+    pub txid: Txid,
+    /// Sats
+    pub fee: u64,
+    pub weight: u64,
+    /// Indexes in the template of the transactions spent by this one
+    pub depends: Vec<usize>,
+}
+
+/// Block template of the node
+#[derive(Debug, Clone)]
+pub struct Template {
+    pub prev_hash: BlockHash,
+    pub version: u32,
+    pub height: u64,
+    pub bits: u32,
+    /// Little endian
+    pub target: [u8; 32],
+    pub cur_time: u32,
+    /// Subsidy plus the fees of all the transactions
+    pub coinbase_value: u64,
+    pub transactions: Vec<TemplateTransaction>,
+    /// The node adds a witness commitment output to the coinbase
+    pub witness_commitment: bool,
+    pub weight_limit: u64,
+}
+
+impl TryFrom<BlockTemplate> for Template {
+    type Error = TpError;
+
+    fn try_from(template: BlockTemplate) -> Result<Self, Self::Error> {
+        let invalid = |e: String| TpError::InvalidTemplate(e);
+        let prev_hash = BlockHash::from_str(&template.previous_block_hash)
+            .map_err(|e| invalid(e.to_string()))?;
+        let bits = u32::from_str_radix(&template.bits, 16).map_err(|e| invalid(e.to_string()))?;
+        let mut target: [u8; 32] = hex::decode(&template.target)
+            .map_err(|e| invalid(e.to_string()))?
+            .try_into()
+            .map_err(|_| invalid(format!("Invalid target {}", template.target)))?;
+        // getblocktemplate returns the target in big endian
+        target.reverse();
+        let mut transactions = Vec::with_capacity(template.transactions.len());
+        for (index, tx) in template.transactions.into_iter().enumerate() {
+            let bytes = hex::decode(&tx.data).map_err(|e| invalid(e.to_string()))?;
+            let transaction: Transaction =
+                deserialize(&bytes).map_err(|e| invalid(e.to_string()))?;
+            let fee = tx
+                .fee
+                .ok_or_else(|| invalid(format!("No fee for transaction {}", tx.txid)))?;
+            let mut depends = Vec::with_capacity(tx.depends.len());
+            for depend in tx.depends {
+                // depends are 1-based and refer to previous transactions
+                match (depend as usize).checked_sub(1) {
+                    Some(depend) if depend < index => depends.push(depend),
+                    _ => return Err(invalid(format!("Invalid depend {}", depend))),
+                }
+            }
+            transactions.push(TemplateTransaction {
+                txid: transaction.txid(),
+                weight: tx.weight.unwrap_or_else(|| transaction.weight() as u64),
+                transaction,
+                fee,
+                depends,
+            });
+        }
+        Ok(Self {
+            prev_hash,
+            version: template.version as u32,
+            height: template.height,
+            bits,
+            target,
+            cur_time: template.cur_time,
+            coinbase_value: template.coinbase_value,
+            transactions,
+            witness_commitment: template.default_witness_commitment.is_some(),
+            weight_limit: template.weight_limit.unwrap_or(MAX_BLOCK_WEIGHT),
+        })
+    }
+}
+
+impl Template {
+    /// True if the templates would give the same block, apart from the header time
+    pub fn same_block(&self, other: &Template) -> bool {
+        self.prev_hash == other.prev_hash
+            && self.coinbase_value == other.coinbase_value
+            && self.transactions.len() == other.transactions.len()
+            && self
+                .transactions
+                .iter()
+                .zip(other.transactions.iter())
+                .all(|(a, b)| a.txid == b.txid)
+    }
+
+    /// Selects the transactions that still fit in the block when the coinbase outputs of the
+    /// downstream take `coinbase_output_max_additional_size` bytes. The transactions are kept in
+    /// the node order, that is by decreasing fee rate, a transaction is dropped with the ones
+    /// that spend it.
+    pub fn select(self: &Arc<Self>, coinbase_output_max_additional_size: u32) -> SelectedTemplate {
+        let max_weight = self
+            .weight_limit
+            .saturating_sub(COINBASE_RESERVED_WEIGHT)
+            .saturating_sub(coinbase_output_max_additional_size as u64 * 4);
+        let mut weight = 0;
+        let mut coinbase_value = self.coinbase_value;
+        let mut selected = HashSet::new();
+        let mut transactions = Vec::with_capacity(self.transactions.len());
+        for (index, tx) in self.transactions.iter().enumerate() {
+            if weight + tx.weight <= max_weight
+                && tx.depends.iter().all(|depend| selected.contains(depend))
+            {
+                weight += tx.weight;
+                selected.insert(index);
+                transactions.push(tx.transaction.clone());
+            } else {
+                coinbase_value = coinbase_value.saturating_sub(tx.fee);
+            }
+        }
+        SelectedTemplate {
+            template: self.clone(),
+            transactions,
+            coinbase_value,
+        }
+    }
+}
+
+/// Template sent to a downstream
+#[derive(Debug, Clone)]
+pub struct SelectedTemplate {
+    pub template: Arc<Template>,
+    pub transactions: Vec<Transaction>,
+    /// Coinbase value without the fees of the transactions that do not fit
+    pub coinbase_value: u64,
+}
+
+impl SelectedTemplate {
+    fn coinbase_outputs(&self) -> Vec<TxOut> {
+        match self.template.witness_commitment {
+            true => vec![TxOut {
+                value: 0,
+                script_pubkey: witness_commitment_script(&self.transactions),
+            }],
+            false => vec![],
+        }
+    }
+
+    pub fn new_template(
+        &self,
+        template_id: u64,
+        future_template: bool,
+    ) -> Result<NewTemplate<'static>, TpError> {
+        // BIP34 height followed by OP_0, like the coinbase of bitcoind
+        let coinbase_prefix = Builder::new()
+            .push_int(self.template.height as i64)
+            .push_int(0)
+            .into_script()
+            .to_bytes();
+        let outputs = self.coinbase_outputs();
+        let coinbase_tx_outputs: Vec<u8> = outputs.iter().flat_map(serialize).collect();
+        let txids: Vec<Txid> = self.transactions.iter().map(|tx| tx.txid()).collect();
+        let mut merkle_path: Vec<U256<'static>> = Vec::new();
+        for node in merkle_path_from_txids(&txids) {
+            merkle_path.push(node.to_vec().try_into()?);
+        }
+        Ok(NewTemplate {
+            template_id,
+            future_template,
+            version: self.template.version,
+            coinbase_tx_version: COINBASE_TX_VERSION,
+            coinbase_prefix: coinbase_prefix.try_into()?,
+            coinbase_tx_input_sequence: COINBASE_TX_INPUT_SEQUENCE,
+            coinbase_tx_value_remaining: self.coinbase_value,
+            coinbase_tx_outputs_count: outputs.len() as u32,
+            coinbase_tx_outputs: coinbase_tx_outputs.try_into()?,
+            coinbase_tx_locktime: 0,
+            merkle_path: Seq0255::new(merkle_path)?,
+        })
+    }
+
+    pub fn set_new_prev_hash(&self, template_id: u64) -> Result<SetNewPrevHash<'static>, TpError> {
+        Ok(SetNewPrevHash {
+            template_id,
+            prev_hash: self.template.prev_hash.into_inner().to_vec().try_into()?,
+            header_timestamp: self.template.cur_time,
+            n_bits: self.template.bits,
+            target: self.template.target.to_vec().try_into()?,
+        })
+    }
+
+    pub fn request_transaction_data_success(
+        &self,
+        template_id: u64,
+    ) -> Result<RequestTransactionDataSuccess<'static>, TpError> {
+        let mut transaction_list: Vec<B016M<'static>> = Vec::new();
+        for tx in &self.transactions {
+            transaction_list.push(serialize(tx).try_into()?);
+        }
+        let excess_data: B064K<'static> = Vec::new().try_into()?;
+        Ok(RequestTransactionDataSuccess {
+            template_id,
+            excess_data,
+            transaction_list: Seq064K::new(transaction_list)?,
+        })
+    }
+
+    /// Block of a solution found on this template
+    pub fn block(&self, solution: &SubmitSolution) -> Result<Block, TpError> {
+        let coinbase: Transaction = deserialize(solution.coinbase_tx.inner_as_ref())
+            .map_err(|e| TpError::Custom(format!("Invalid coinbase: {}", e)))?;
+        let mut txdata = Vec::with_capacity(self.transactions.len() + 1);
+        txdata.push(coinbase);
+        txdata.extend(self.transactions.iter().cloned());
+        let mut block = Block {
+            header: BlockHeader {
+                version: solution.version as i32,
+                prev_blockhash: self.template.prev_hash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: solution.header_timestamp,
+                bits: self.template.bits,
+                nonce: solution.header_nonce,
+            },
+            txdata,
+        };
+        block.header.merkle_root = block
+            .compute_merkle_root()
+            .ok_or_else(|| TpError::Custom("Empty block".to_string()))?;
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use rpc_sv2::mini_rpc_client::BlockTemplateTransaction;
+    use stratum_common::bitcoin::{
+        consensus::encode::serialize_hex, OutPoint, PackedLockTime, Script, Sequence, TxIn, Witness,
+    };
+
+    pub(crate) fn transaction(previous_output: OutPoint, value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output,
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_vec(vec![vec![1; 72], vec![2; 33]]),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::from(vec![0; 22]),
+            }],
+        }
+    }
+
+    // Three transactions: the second one spends the first one
+    pub(crate) fn block_template() -> BlockTemplate {
+        let first = transaction(OutPoint::default(), 1000);
+        let second = transaction(
+            OutPoint {
+                txid: first.txid(),
+                vout: 0,
+            },
+            900,
+        );
+        let third = transaction(
+            OutPoint {
+                txid: Txid::from_inner([7; 32]),
+                vout: 1,
+            },
+            500,
+        );
+        let transactions = vec![
+            (first, vec![], 300),
+            (second, vec![1], 200),
+            (third, vec![], 100),
+        ]
+        .into_iter()
+        .map(|(tx, depends, fee)| BlockTemplateTransaction {
+            data: serialize_hex(&tx),
+            txid: tx.txid().to_string(),
+            hash: tx.wtxid().to_string(),
+            depends,
+            fee: Some(fee),
+            sigops: Some(4),
+            weight: Some(tx.weight() as u64),
+        })
+        .collect();
+        BlockTemplate {
+            version: 0x2000_0000,
+            rules: vec!["segwit".to_string()],
+            previous_block_hash: "000000000000000000028c4a1d4d1d8b4b7c7e0d2e1f8e54c4a2c1b0a9f8e7d6"
+                .to_string(),
+            transactions,
+            coinbase_value: 312_500_600,
+            long_poll_id: None,
+            target: "00000000000000000003c1f30000000000000000000000000000000000000000".to_string(),
+            min_time: 1_700_000_000,
+            cur_time: 1_700_000_100,
+            bits: "17034219".to_string(),
+            height: 840_000,
+            default_witness_commitment: Some("6a24aa21a9ed".to_string()),
+            sigop_limit: Some(80_000),
+            weight_limit: Some(4_000_000),
+        }
+    }
+
+    #[test]
+    fn new_template_from_block_template() {
+        let template = Arc::new(Template::try_from(block_template()).unwrap());
+        let selected = template.select(0);
+        assert_eq!(selected.transactions.len(), 3);
+        assert_eq!(selected.coinbase_value, 312_500_600);
+
+        let new_template = selected.new_template(1, true).unwrap();
+        // push 3 bytes of height 840000 then OP_0
+        assert_eq!(
+            new_template.coinbase_prefix.to_vec(),
+            vec![0x03, 0x40, 0xd1, 0x0c, 0x00]
+        );
+        assert_eq!(new_template.coinbase_tx_value_remaining, 312_500_600);
+        assert_eq!(new_template.coinbase_tx_outputs_count, 1);
+        let outputs: TxOut = deserialize(&new_template.coinbase_tx_outputs.to_vec()).unwrap();
+        assert_eq!(
+            outputs.script_pubkey,
+            witness_commitment_script(&selected.transactions)
+        );
+        let txids: Vec<Txid> = template.transactions.iter().map(|tx| tx.txid).collect();
+        let merkle_path: Vec<Vec<u8>> = new_template
+            .merkle_path
+            .to_vec()
+            .into_iter()
+            .map(|node| node.to_vec())
+            .collect();
+        let expected: Vec<Vec<u8>> = merkle_path_from_txids(&txids)
+            .into_iter()
+            .map(|node| node.to_vec())
+            .collect();
+        assert_eq!(merkle_path, expected);
+
+        let prev_hash = selected.set_new_prev_hash(1).unwrap();
+        assert_eq!(prev_hash.n_bits, 0x1703_4219);
+        // little endian target
+        let mut target = [0; 32];
+        target[20..23].copy_from_slice(&[0xf3, 0xc1, 0x03]);
+        assert_eq!(prev_hash.target.to_vec(), target.to_vec());
+        assert_eq!(
+            u256_to_hash(prev_hash.prev_hash.to_vec()),
+            template.prev_hash
+        );
+    }
+
+    fn u256_to_hash(bytes: Vec<u8>) -> BlockHash {
+        BlockHash::from_inner(bytes.try_into().unwrap())
+    }
+
+    #[test]
+    fn coinbase_output_data_size_is_honoured() {
+        let mut block_template = block_template();
+        for (tx, weight) in block_template
+            .transactions
+            .iter_mut()
+            .zip(vec![10_000, 1000, 1000])
+        {
+            tx.weight = Some(weight);
+        }
+        block_template.weight_limit = Some(COINBASE_RESERVED_WEIGHT + 12_000);
+        let template = Arc::new(Template::try_from(block_template).unwrap());
+        assert_eq!(template.select(0).transactions.len(), 3);
+
+        // the first transaction do not fit anymore, the second one spends it
+        let selected = template.select(2500);
+        assert_eq!(selected.transactions.len(), 1);
+        assert_eq!(
+            selected.transactions[0].txid(),
+            template.transactions[2].txid
+        );
+        assert_eq!(selected.coinbase_value, 312_500_600 - 300 - 200);
+        let new_template = selected.new_template(2, false).unwrap();
+        assert_eq!(new_template.merkle_path.to_vec().len(), 1);
+    }
+
+    #[test]
+    fn block_from_solution() {
+        let template = Arc::new(Template::try_from(block_template()).unwrap());
+        let selected = template.select(0);
+        let coinbase = transaction(OutPoint::null(), 312_500_600);
+        let solution = SubmitSolution {
+            template_id: 1,
+            version: 0x2000_0000,
+            header_timestamp: 1_700_000_200,
+            header_nonce: 42,
+            coinbase_tx: serialize(&coinbase).try_into().unwrap(),
+        };
+        let block = selected.block(&solution).unwrap();
+        assert_eq!(block.txdata.len(), 4);
+        assert_eq!(block.txdata[0], coinbase);
+        assert_eq!(block.header.prev_blockhash, template.prev_hash);
+        assert_eq!(block.header.nonce, 42);
+        assert!(block.check_merkle_root());
+
+        let data = selected.request_transaction_data_success(1).unwrap();
+        assert_eq!(data.transaction_list.to_vec().len(), 3);
+    }
+}
@@ -0,0 +1,446 @@
+use super::{
+    downstream::{setup_connection::setup_connection, Downstream},
+    error::TpError,
+    get_rpc_client, status,
+    template::Template,
+    Configuration, EitherFrame,
+};
+use async_channel::{Receiver, Sender};
+use codec_sv2::{HandshakeRole, Responder};
+use network_helpers_sv2::noise_connection_tokio::Connection;
+use nohash_hasher::BuildNoHashHasher;
+use roles_logic_sv2::utils::{Id, Mutex};
+use rpc_sv2::mini_rpc_client::MiniRpcClient;
+use std::{collections::HashMap, convert::TryFrom, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task,
+};
+use tracing::{info, warn};
+
+// Time given to a new connection for the noise handshake and SetupConnection
+const SETUP_CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TemplateProvider {
+    downstreams: HashMap<u32, Arc<Mutex<Downstream>>, BuildNoHashHasher<u32>>,
+    last_template: Option<Arc<Template>>,
+    ids: Id,
+}
+
+impl TemplateProvider {
+    pub async fn start(config: Configuration, status_tx: async_channel::Sender<status::Status>) {
+        let self_ = Arc::new(Mutex::new(Self {
+            downstreams: HashMap::with_hasher(BuildNoHashHasher::default()),
+            last_template: None,
+            ids: Id::new(),
+        }));
+        let client = get_rpc_client(&config);
+        info!("TP INITIALIZED");
+
+        let cloned = self_.clone();
+        let client_ = client.clone();
+        let poll_config = config.clone();
+        let sender = status::Sender::Node(status_tx.clone());
+        task::spawn(
+            async move { Self::poll_templates(cloned, client_, poll_config, sender).await },
+        );
+
+        let sender = status::Sender::DownstreamListener(status_tx.clone());
+        if let Err(e) = Self::accept_incoming_connection(self_, client, config, status_tx).await {
+            let _ = status::handle_error(&sender, e).await;
+        }
+    }
+
+    pub fn last_template(&self) -> Option<Arc<Template>> {
+        self.last_template.clone()
+    }
+
+    pub fn remove_downstream(&mut self, id: u32) {
+        self.downstreams.remove(&id);
+    }
+
+    async fn accept_incoming_connection(
+        self_: Arc<Mutex<Self>>,
+        client: MiniRpcClient,
+        config: Configuration,
+        status_tx: async_channel::Sender<status::Status>,
+    ) -> Result<(), TpError> {
+        let listener = TcpListener::bind(&config.listen_address).await?;
+        info!("Listening for downstreams on {}", config.listen_address);
+        while let Ok((stream, addr)) = listener.accept().await {
+            let responder = Responder::from_authority_kp(
+                &config.authority_public_key.into_bytes(),
+                &config.authority_secret_key.into_bytes(),
+                Duration::from_secs(config.cert_validity_sec),
+            )?;
+            // a slow or silent connection must not hold back the next ones
+            let self_ = self_.clone();
+            let client = client.clone();
+            let status_tx = status_tx.clone();
+            task::spawn(async move {
+                let (receiver, sender) = match tokio::time::timeout(
+                    SETUP_CONNECTION_TIMEOUT,
+                    Self::connect(stream, responder),
+                )
+                .await
+                {
+                    Ok(Ok(connection)) => connection,
+                    Ok(Err(e)) => {
+                        warn!("Setup connection failed for {}: {}", addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        warn!(
+                            "Setup connection of {} not done after {:?}",
+                            addr, SETUP_CONNECTION_TIMEOUT
+                        );
+                        return;
+                    }
+                };
+                match Self::add_downstream(self_, client, receiver, sender, status_tx.clone()) {
+                    Ok(id) => info!("Downstream {} connected from {}", id, addr),
+                    Err(e) => {
+                        let sender = status::Sender::DownstreamListener(status_tx);
+                        let _ = status::handle_error(&sender, e).await;
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    // Noise handshake and SetupConnection of a new downstream
+    async fn connect(
+        stream: TcpStream,
+        responder: Box<Responder>,
+    ) -> Result<(Receiver<EitherFrame>, Sender<EitherFrame>), TpError> {
+        let (receiver, sender, _, _) = Connection::new(stream, HandshakeRole::Responder(responder))
+            .await
+            .map_err(|e| TpError::Custom(format!("Noise handshake failed: {:?}", e)))?;
+        setup_connection(&receiver, &sender).await?;
+        Ok((receiver, sender))
+    }
+
+    fn add_downstream(
+        self_: Arc<Mutex<Self>>,
+        client: MiniRpcClient,
+        receiver: Receiver<EitherFrame>,
+        sender: Sender<EitherFrame>,
+        status_tx: async_channel::Sender<status::Status>,
+    ) -> Result<u32, TpError> {
+        let id = self_.safe_lock(|s| s.ids.next())?;
+        let downstream = Arc::new(Mutex::new(Downstream::new(id, receiver, sender)));
+        self_.safe_lock(|s| s.downstreams.insert(id, downstream.clone()))?;
+        Downstream::start(
+            downstream,
+            self_,
+            client,
+            status::Sender::Downstream(status_tx),
+        );
+        Ok(id)
+    }
+
+    // Polls getblocktemplate and sends the new templates to the downstreams. When long polling
+    // the node answers as soon as there is a new block, otherwise the template is refreshed at
+    // each interval.
+    async fn poll_templates(
+        self_: Arc<Mutex<Self>>,
+        client: MiniRpcClient,
+        config: Configuration,
+        tx_status: status::Sender,
+    ) {
+        let mut long_poll_id: Option<String> = None;
+        loop {
+            let result = match (config.long_poll, &long_poll_id) {
+                (true, Some(id)) => {
+                    match tokio::time::timeout(
+                        config.poll_interval,
+                        client.get_block_template(Some(id)),
+                    )
+                    .await
+                    {
+                        Ok(result) => result,
+                        // no new block in the interval, the mempool may have changed
+                        Err(_) => client.get_block_template(None).await,
+                    }
+                }
+                (false, Some(_)) => {
+                    tokio::time::sleep(config.poll_interval).await;
+                    client.get_block_template(None).await
+                }
+                // first template or after an error
+                (_, None) => client.get_block_template(None).await,
+            };
+            let template = result.map_err(TpError::from).and_then(|block_template| {
+                long_poll_id = block_template.long_poll_id.clone();
+                Template::try_from(block_template)
+            });
+            match template {
+                Ok(template) => {
+                    if let Err(e) = Self::on_template(self_.clone(), template).await {
+                        let _ = status::handle_error(&tx_status, e).await;
+                    }
+                }
+                Err(e) => {
+                    long_poll_id = None;
+                    let _ = status::handle_error(&tx_status, e).await;
+                    tokio::time::sleep(config.poll_interval).await;
+                }
+            }
+        }
+    }
+
+    async fn on_template(self_: Arc<Mutex<Self>>, template: Template) -> Result<(), TpError> {
+        let update = self_.safe_lock(|s| {
+            let new_prev_hash = match &s.last_template {
+                Some(last) if last.same_block(&template) => return None,
+                Some(last) => last.prev_hash != template.prev_hash,
+                None => true,
+            };
+            let template = Arc::new(template);
+            s.last_template = Some(template.clone());
+            let downstreams: Vec<Arc<Mutex<Downstream>>> =
+                s.downstreams.values().cloned().collect();
+            Some((template, new_prev_hash, downstreams))
+        })?;
+        let (template, new_prev_hash, downstreams) = match update {
+            Some(update) => update,
+            None => return Ok(()),
+        };
+        if new_prev_hash {
+            info!(
+                "New prev hash {} at height {}",
+                template.prev_hash, template.height
+            );
+        }
+        for downstream in downstreams {
+            if let Err(e) = Downstream::send_template(downstream, &template, new_prev_hash).await {
+                warn!("Impossible to send the template to a downstream: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{
+            downstream::tests::{downstream, frame, next_sent, Sent},
+            template::tests::block_template,
+        },
+        *,
+    };
+    use roles_logic_sv2::{
+        parsers::TemplateDistribution, template_distribution_sv2::RequestTransactionData,
+    };
+    use rpc_sv2::{
+        mini_rpc_client::BlockTemplate,
+        stand_in::{error, result, stand_in},
+    };
+    use serde_json::{json, Value};
+    use std::str::FromStr;
+    use stratum_common::bitcoin::{hashes::Hash, BlockHash};
+
+    fn template_provider() -> Arc<Mutex<TemplateProvider>> {
+        Arc::new(Mutex::new(TemplateProvider {
+            downstreams: HashMap::with_hasher(BuildNoHashHasher::default()),
+            last_template: None,
+            ids: Id::new(),
+        }))
+    }
+
+    fn config(url: &str) -> Configuration {
+        let (url, port) = url.rsplit_once(':').unwrap();
+        Configuration {
+            listen_address: "127.0.0.1:0".to_string(),
+            authority_public_key: FromStr::from_str(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72",
+            )
+            .unwrap(),
+            authority_secret_key: FromStr::from_str(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n",
+            )
+            .unwrap(),
+            cert_validity_sec: 3600,
+            core_rpc_url: url.to_string(),
+            core_rpc_port: port.parse().unwrap(),
+            core_rpc_user: "user".to_string(),
+            core_rpc_pass: "pass".to_string(),
+            core_rpc_cookie_file: None,
+            rpc_timeout_secs: 0,
+            rpc_retries: 0,
+            poll_interval: Duration::from_millis(50),
+            long_poll: true,
+        }
+    }
+
+    // Template of the next block, on top of the one of `block_template`
+    fn next_block_template() -> BlockTemplate {
+        let mut block_template = block_template();
+        block_template.previous_block_hash = "11".repeat(32);
+        block_template.height += 1;
+        block_template
+    }
+
+    fn prev_hash(block_template: &BlockTemplate) -> Vec<u8> {
+        BlockHash::from_str(&block_template.previous_block_hash)
+            .unwrap()
+            .into_inner()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn templates_are_sent_to_the_downstreams() {
+        let template_provider = template_provider();
+        let (downstream, sent, _) = downstream();
+        template_provider
+            .safe_lock(|tp| tp.downstreams.insert(1, Arc::new(Mutex::new(downstream))))
+            .unwrap();
+        let on_template = |block_template: BlockTemplate| {
+            let template = Template::try_from(block_template).unwrap();
+            TemplateProvider::on_template(template_provider.clone(), template)
+        };
+
+        on_template(block_template()).await.unwrap();
+        assert_eq!(next_sent(&sent).await, Sent::NewTemplate(1, true));
+        assert_eq!(
+            next_sent(&sent).await,
+            Sent::SetNewPrevHash(1, prev_hash(&block_template()))
+        );
+        // same block, nothing is sent
+        on_template(block_template()).await.unwrap();
+        assert!(sent.is_empty());
+
+        // refreshed template on the same prev hash
+        let mut refreshed = block_template();
+        refreshed.transactions.pop();
+        on_template(refreshed).await.unwrap();
+        assert_eq!(next_sent(&sent).await, Sent::NewTemplate(2, false));
+        assert!(sent.is_empty());
+
+        on_template(next_block_template()).await.unwrap();
+        assert_eq!(next_sent(&sent).await, Sent::NewTemplate(3, true));
+        assert_eq!(
+            next_sent(&sent).await,
+            Sent::SetNewPrevHash(3, prev_hash(&next_block_template()))
+        );
+    }
+
+    // `getblocktemplate` result of the node
+    fn block_template_json(block_template: &BlockTemplate, long_poll_id: &str) -> Value {
+        let transactions: Vec<Value> = block_template
+            .transactions
+            .iter()
+            .map(|tx| {
+                json!({
+                    "data": tx.data,
+                    "txid": tx.txid,
+                    "hash": tx.hash,
+                    "depends": tx.depends,
+                    "fee": tx.fee,
+                    "sigops": tx.sigops,
+                    "weight": tx.weight,
+                })
+            })
+            .collect();
+        json!({
+            "version": block_template.version,
+            "rules": block_template.rules,
+            "previousblockhash": block_template.previous_block_hash,
+            "transactions": transactions,
+            "coinbasevalue": block_template.coinbase_value,
+            "longpollid": long_poll_id,
+            "target": block_template.target,
+            "mintime": block_template.min_time,
+            "curtime": block_template.cur_time,
+            "bits": block_template.bits,
+            "height": block_template.height,
+            "default_witness_commitment": block_template.default_witness_commitment,
+            "sigoplimit": block_template.sigop_limit,
+            "weightlimit": block_template.weight_limit,
+        })
+    }
+
+    #[tokio::test]
+    async fn node_templates_are_polled_and_sent() {
+        // The first long poll returns the template of the next block, the second one fails and
+        // the poller asks a template without long polling
+        let long_poll_ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let long_poll_ids_ = long_poll_ids.clone();
+        let url = stand_in(Arc::new(move |request| {
+            assert_eq!(request.body["method"], "getblocktemplate");
+            let long_poll_id = request.body["params"][0]["longpollid"].clone();
+            let mut long_poll_ids = long_poll_ids_.lock().unwrap();
+            long_poll_ids.push(long_poll_id.clone());
+            let block_template = match (long_poll_id.as_str(), long_poll_ids.len()) {
+                (None, 1) => block_template_json(&block_template(), "1"),
+                (Some("1"), _) => block_template_json(&next_block_template(), "2"),
+                (Some("2"), _) => return (500, error(&request.body, -1, "failure").to_string()),
+                (None, _) => block_template_json(&next_block_template(), "3"),
+                _ => return (500, error(&request.body, -1, "failure").to_string()),
+            };
+            (200, result(&request.body, block_template).to_string())
+        }))
+        .await;
+        let config = config(&url);
+        let client = get_rpc_client(&config);
+        let template_provider = template_provider();
+        let (downstream, sent, received) = downstream();
+        let downstream = Arc::new(Mutex::new(downstream));
+        template_provider
+            .safe_lock(|tp| tp.downstreams.insert(1, downstream.clone()))
+            .unwrap();
+        let (status_tx, _status_rx) = async_channel::unbounded();
+        Downstream::start(
+            downstream,
+            template_provider.clone(),
+            client.clone(),
+            status::Sender::Downstream(status_tx.clone()),
+        );
+        let poll = task::spawn(TemplateProvider::poll_templates(
+            template_provider,
+            client,
+            config,
+            status::Sender::Node(status_tx),
+        ));
+
+        assert_eq!(next_sent(&sent).await, Sent::NewTemplate(1, true));
+        assert_eq!(
+            next_sent(&sent).await,
+            Sent::SetNewPrevHash(1, prev_hash(&block_template()))
+        );
+        assert_eq!(next_sent(&sent).await, Sent::NewTemplate(2, true));
+        assert_eq!(
+            next_sent(&sent).await,
+            Sent::SetNewPrevHash(2, prev_hash(&next_block_template()))
+        );
+
+        for template_id in [2, 1] {
+            let request = RequestTransactionData { template_id };
+            received
+                .send(frame(TemplateDistribution::RequestTransactionData(request)))
+                .await
+                .unwrap();
+        }
+        assert_eq!(next_sent(&sent).await, Sent::TransactionData(2, 3));
+        assert_eq!(
+            next_sent(&sent).await,
+            Sent::TransactionDataError(1, "stale-template-id".to_string())
+        );
+
+        // the template asked after the failure is the same, nothing is sent
+        for _ in 0..100 {
+            if long_poll_ids.lock().unwrap().len() >= 5 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        poll.abort();
+        assert_eq!(
+            long_poll_ids.lock().unwrap()[..5],
+            [json!(null), json!("1"), json!("2"), json!(null), json!("3")]
+        );
+        assert!(sent.is_empty());
+    }
+}
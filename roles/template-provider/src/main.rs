@@ -0,0 +1,139 @@
+#![allow(special_module_name)]
+use crate::lib::{status, template_provider::TemplateProvider, Configuration};
+use async_channel::unbounded;
+use tokio::{select, task};
+use tracing::{error, info};
+mod lib;
+
+mod args {
+    use std::path::PathBuf;
+
+    #[derive(Debug)]
+    pub struct Args {
+        pub config_path: PathBuf,
+    }
+
+    enum ArgsState {
+        Next,
+        ExpectPath,
+        Done,
+    }
+
+    enum ArgsResult {
+        Config(PathBuf),
+        None,
+        Help(String),
+    }
+
+    impl Args {
+        const DEFAULT_CONFIG_PATH: &'static str = "tp-config.toml";
+        const HELP_MSG: &'static str =
+            "Usage: -h/--help, -c/--config <path|default tp-config.toml>";
+
+        pub fn from_args() -> Result<Self, String> {
+            let cli_args = std::env::args();
+
+            if cli_args.len() == 1 {
+                println!("Using default config path: {}", Self::DEFAULT_CONFIG_PATH);
+                println!("{}\n", Self::HELP_MSG);
+            }
+
+            let config_path = cli_args
+                .scan(ArgsState::Next, |state, item| {
+                    match std::mem::replace(state, ArgsState::Done) {
+                        ArgsState::Next => match item.as_str() {
+                            "-c" | "--config" => {
+                                *state = ArgsState::ExpectPath;
+                                Some(ArgsResult::None)
+                            }
+                            "-h" | "--help" => Some(ArgsResult::Help(Self::HELP_MSG.to_string())),
+                            _ => {
+                                *state = ArgsState::Next;
+
+                                Some(ArgsResult::None)
+                            }
+                        },
+                        ArgsState::ExpectPath => Some(ArgsResult::Config(PathBuf::from(item))),
+                        ArgsState::Done => None,
+                    }
+                })
+                .last();
+            let config_path = match config_path {
+                Some(ArgsResult::Config(p)) => p,
+                Some(ArgsResult::Help(h)) => return Err(h),
+                _ => PathBuf::from(Self::DEFAULT_CONFIG_PATH),
+            };
+            Ok(Self { config_path })
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    let args = match args::Args::from_args() {
+        Ok(cfg) => cfg,
+        Err(help) => {
+            error!("{}", help);
+            return;
+        }
+    };
+
+    // Load config
+    let config: Configuration = match std::fs::read_to_string(&args.config_path) {
+        Ok(c) => match toml::from_str(&c) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to parse config: {}", e);
+                return;
+            }
+        },
+        Err(e) => {
+            error!("Failed to read config: {}", e);
+            return;
+        }
+    };
+
+    info!("Tp INITIALIZING with config: {:?}", &args.config_path);
+
+    let (status_tx, status_rx) = unbounded();
+    task::spawn(async move { TemplateProvider::start(config, status_tx).await });
+
+    // Start the error handling loop
+    // See `./status.rs` and `utils/error_handling` for information on how this operates
+    loop {
+        let task_status = select! {
+            task_status = status_rx.recv() => task_status,
+            interrupt_signal = tokio::signal::ctrl_c() => {
+                match interrupt_signal {
+                    Ok(()) => {
+                        info!("Interrupt received");
+                    },
+                    Err(err) => {
+                        error!("Unable to listen for interrupt signal: {}", err);
+                        // we also shut down in case of error
+                    },
+                }
+                break;
+            }
+        };
+        let task_status: status::Status = match task_status {
+            Ok(task_status) => task_status,
+            Err(_) => break,
+        };
+
+        match task_status.state {
+            status::State::DownstreamShutdown(err) => {
+                error!("SHUTDOWN from the downstream listener: {}", err);
+                break;
+            }
+            status::State::NodeShutdown(err) => {
+                error!("SHUTDOWN from the node poller: {}", err);
+                break;
+            }
+            status::State::Healthy(msg) => {
+                info!("HEALTHY message: {}", msg);
+            }
+        }
+    }
+}
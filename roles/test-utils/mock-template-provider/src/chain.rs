@@ -0,0 +1,419 @@
+//! Fake chain on which the templates of the mock are built. Nothing is persisted, blocks are
+//! connected when a downstream submits a valid solution or when a block is mined by the test.
+use roles_logic_sv2::{
+    template_distribution_sv2::SubmitSolution, utils::witness_commitment_script,
+};
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use stratum_common::bitcoin::{
+    blockdata::{block::BlockHeader, opcodes::all::OP_PUSHNUM_1, script::Builder},
+    hashes::{sha256d, Hash},
+    Block, BlockHash, OutPoint, PackedLockTime, Sequence, Transaction, TxIn, TxMerkleNode, TxOut,
+    Witness,
+};
+use template_provider_sv2::template::{SelectedTemplate, Template, TemplateTransaction};
+use tracing::debug;
+
+const BLOCK_VERSION: u32 = 0x2000_0000;
+const MAX_BLOCK_WEIGHT: u64 = 4_000_000;
+const INITIAL_SUBSIDY: u64 = 50 * 100_000_000;
+// regtest value, so that the subsidy changes in tests that mine a few hundred blocks
+const HALVING_INTERVAL: u64 = 150;
+// a block can not be more than two hours in the future
+const MAX_FUTURE_BLOCK_TIME: u32 = 2 * 60 * 60;
+// the block time must be above the median time of the last blocks
+const MEDIAN_TIME_SPAN: usize = 11;
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+fn subsidy(height: u64) -> u64 {
+    match height / HALVING_INTERVAL {
+        halvings if halvings >= 64 => 0,
+        halvings => INITIAL_SUBSIDY >> halvings,
+    }
+}
+
+/// Little endian target of `bits`
+fn target(bits: u32) -> [u8; 32] {
+    let mut target = BlockHeader::u256_from_compact_target(bits).to_be_bytes();
+    target.reverse();
+    target
+}
+
+/// Coinbase of a block on `template` that pays `value` to an anyone can spend output
+fn coinbase(template: &Template, value: u64) -> Transaction {
+    let mut coinbase = Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Builder::new()
+                .push_int(template.height as i64)
+                .push_int(0)
+                .into_script(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey: Builder::new().push_opcode(OP_PUSHNUM_1).into_script(),
+        }],
+    };
+    // like bitcoind the commitment is always added, with an all zeros reserved value
+    if template.witness_commitment {
+        let transactions: Vec<Transaction> = template
+            .transactions
+            .iter()
+            .map(|tx| tx.transaction.clone())
+            .collect();
+        coinbase.output.push(TxOut {
+            value: 0,
+            script_pubkey: witness_commitment_script(&transactions),
+        });
+        coinbase.input[0].witness = Witness::from_vec(vec![vec![0; 32]]);
+    }
+    coinbase
+}
+
+#[derive(Debug)]
+pub struct FakeChain {
+    tip: BlockHash,
+    // height of the tip
+    height: u64,
+    // times of the last blocks, oldest first
+    last_times: Vec<u32>,
+    bits: u32,
+    witness_commitment: bool,
+    mempool: Vec<TemplateTransaction>,
+    template: Arc<Template>,
+    found_blocks: Vec<Block>,
+    rejected_solutions: Vec<String>,
+}
+
+impl FakeChain {
+    pub fn new(height: u64, bits: u32, witness_commitment: bool) -> Self {
+        let tip = BlockHash::from_hash(sha256d::Hash::hash(&height.to_le_bytes()));
+        let mut chain = Self {
+            tip,
+            height,
+            // blocks every ten minutes until an hour ago
+            last_times: (0..MEDIAN_TIME_SPAN as u32)
+                .rev()
+                .map(|i| now().saturating_sub(60 * 60 + i * 600))
+                .collect(),
+            bits,
+            witness_commitment,
+            mempool: Vec::new(),
+            // replaced right below
+            template: Arc::new(Template {
+                prev_hash: tip,
+                version: BLOCK_VERSION,
+                height: height + 1,
+                bits,
+                target: target(bits),
+                cur_time: 0,
+                coinbase_value: 0,
+                transactions: Vec::new(),
+                witness_commitment,
+                weight_limit: MAX_BLOCK_WEIGHT,
+            }),
+            found_blocks: Vec::new(),
+            rejected_solutions: Vec::new(),
+        };
+        chain.update_template();
+        chain
+    }
+
+    pub fn tip(&self) -> (BlockHash, u64) {
+        (self.tip, self.height)
+    }
+
+    pub fn template(&self) -> Arc<Template> {
+        self.template.clone()
+    }
+
+    pub fn found_blocks(&self) -> &[Block] {
+        &self.found_blocks
+    }
+
+    pub fn rejected_solutions(&self) -> &[String] {
+        &self.rejected_solutions
+    }
+
+    fn median_time_past(&self) -> u32 {
+        let mut times = self.last_times.clone();
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+
+    fn update_template(&mut self) {
+        let fees: u64 = self.mempool.iter().map(|tx| tx.fee).sum();
+        self.template = Arc::new(Template {
+            prev_hash: self.tip,
+            version: BLOCK_VERSION,
+            height: self.height + 1,
+            bits: self.bits,
+            target: target(self.bits),
+            cur_time: now().max(self.median_time_past() + 1),
+            coinbase_value: subsidy(self.height + 1) + fees,
+            transactions: self.mempool.clone(),
+            witness_commitment: self.witness_commitment,
+            weight_limit: MAX_BLOCK_WEIGHT,
+        });
+    }
+
+    /// Adds the transactions to the next templates. Scripts are not checked, the transactions
+    /// only need to be valid by themselves.
+    pub fn add_transactions(&mut self, transactions: Vec<(Transaction, u64)>) {
+        for (transaction, fee) in transactions {
+            let depends = self
+                .mempool
+                .iter()
+                .enumerate()
+                .filter(|(_, tx)| {
+                    transaction
+                        .input
+                        .iter()
+                        .any(|input| input.previous_output.txid == tx.txid)
+                })
+                .map(|(index, _)| index)
+                .collect();
+            self.mempool.push(TemplateTransaction {
+                txid: transaction.txid(),
+                weight: transaction.weight() as u64,
+                transaction,
+                fee,
+                depends,
+            });
+        }
+        self.update_template();
+    }
+
+    /// Checks the solution with the consensus rules that can be checked on a fake chain, the
+    /// error is the reject reason that bitcoind would give
+    pub fn check_solution(
+        &self,
+        selected: &SelectedTemplate,
+        solution: &SubmitSolution,
+    ) -> Result<Block, String> {
+        if selected.template.prev_hash != self.tip {
+            return Err("inconclusive-not-best-prevblk".to_string());
+        }
+        let block = selected.block(solution).map_err(|e| e.to_string())?;
+        let coinbase = &block.txdata[0];
+        if !coinbase.is_coin_base() {
+            return Err("bad-cb-missing".to_string());
+        }
+        if block.bip34_block_height().ok() != Some(self.height + 1) {
+            return Err("bad-cb-height".to_string());
+        }
+        let coinbase_value: u64 = coinbase.output.iter().map(|output| output.value).sum();
+        if coinbase_value > selected.coinbase_value {
+            return Err("bad-cb-amount".to_string());
+        }
+        if block.header.time <= self.median_time_past() {
+            return Err("time-too-old".to_string());
+        }
+        if block.header.time > now() + MAX_FUTURE_BLOCK_TIME {
+            return Err("time-too-new".to_string());
+        }
+        if block.header.validate_pow(&block.header.target()).is_err() {
+            return Err("high-hash".to_string());
+        }
+        if !block.check_witness_commitment() {
+            return Err("bad-witness-merkle-match".to_string());
+        }
+        if block.weight() as u64 > selected.template.weight_limit {
+            return Err("bad-blk-weight".to_string());
+        }
+        Ok(block)
+    }
+
+    /// Records the result of a solution, a valid block becomes the new tip
+    pub fn on_solution(&mut self, result: Result<Block, String>) -> bool {
+        match result {
+            Ok(block) => {
+                self.connect_block(&block);
+                self.found_blocks.push(block);
+                true
+            }
+            Err(reason) => {
+                self.rejected_solutions.push(reason);
+                false
+            }
+        }
+    }
+
+    /// Mines a block on the current template, like a miner that does not use the mock would
+    pub fn mine_block(&mut self) -> Block {
+        let template = self.template.clone();
+        let coinbase = coinbase(&template, template.coinbase_value);
+        let transactions = template
+            .transactions
+            .iter()
+            .map(|tx| tx.transaction.clone());
+        let mut txdata = vec![coinbase];
+        txdata.extend(transactions);
+        let mut block = Block {
+            header: BlockHeader {
+                version: template.version as i32,
+                prev_blockhash: template.prev_hash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: template.cur_time,
+                bits: template.bits,
+                nonce: 0,
+            },
+            txdata,
+        };
+        block.header.merkle_root = block
+            .compute_merkle_root()
+            .expect("the block has a coinbase");
+        while block.header.validate_pow(&block.header.target()).is_err() {
+            block.header.nonce = block
+                .header
+                .nonce
+                .checked_add(1)
+                .expect("mock chain difficulty too high to mine a block");
+        }
+        self.connect_block(&block);
+        block
+    }
+
+    fn connect_block(&mut self, block: &Block) {
+        self.tip = block.block_hash();
+        self.height += 1;
+        self.last_times.push(block.header.time);
+        if self.last_times.len() > MEDIAN_TIME_SPAN {
+            self.last_times.remove(0);
+        }
+        let included: Vec<_> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        self.mempool.retain(|tx| !included.contains(&tx.txid));
+        // the indexes of the remaining transactions changed
+        let mempool: Vec<(Transaction, u64)> = self
+            .mempool
+            .drain(..)
+            .map(|tx| (tx.transaction, tx.fee))
+            .collect();
+        self.add_transactions(mempool);
+        debug!("Fake chain tip {} at height {}", self.tip, self.height);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use stratum_common::bitcoin::{consensus::encode::serialize, Script, Txid};
+
+    const REGTEST_BITS: u32 = 0x207f_ffff;
+
+    fn transaction() -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Txid::from_inner([7; 32]),
+                    vout: 0,
+                },
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_vec(vec![vec![1; 72], vec![2; 33]]),
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::from(vec![0; 22]),
+            }],
+        }
+    }
+
+    // Grinds the nonce until the solution is not rejected for its proof of work
+    fn submit(
+        chain: &FakeChain,
+        selected: &SelectedTemplate,
+        coinbase: &Transaction,
+    ) -> Result<Block, String> {
+        let mut nonce = 0;
+        loop {
+            let solution = SubmitSolution {
+                template_id: 1,
+                version: selected.template.version,
+                header_timestamp: selected.template.cur_time,
+                header_nonce: nonce,
+                coinbase_tx: serialize(coinbase).try_into().unwrap(),
+            };
+            match chain.check_solution(selected, &solution) {
+                Err(reason) if reason == "high-hash" => nonce += 1,
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn subsidy_halves() {
+        assert_eq!(subsidy(149), INITIAL_SUBSIDY);
+        assert_eq!(subsidy(150), INITIAL_SUBSIDY / 2);
+        assert_eq!(subsidy(150 * 64), 0);
+    }
+
+    #[test]
+    fn mined_block_is_valid() {
+        let mut chain = FakeChain::new(200, REGTEST_BITS, true);
+        chain.add_transactions(vec![(transaction(), 300)]);
+        assert_eq!(chain.template().coinbase_value, subsidy(201) + 300);
+        let block = chain.mine_block();
+        assert!(block.check_merkle_root());
+        assert!(block.check_witness_commitment());
+        assert_eq!(block.bip34_block_height().unwrap(), 201);
+        assert_eq!(chain.tip(), (block.block_hash(), 201));
+        // the transaction has been mined
+        assert!(chain.template().transactions.is_empty());
+        assert_eq!(chain.template().prev_hash, block.block_hash());
+    }
+
+    #[test]
+    fn check_solution() {
+        let mut chain = FakeChain::new(200, REGTEST_BITS, true);
+        chain.add_transactions(vec![(transaction(), 300)]);
+        let template = chain.template();
+        let selected = template.select(0);
+
+        let too_much = coinbase(&template, template.coinbase_value + 1);
+        assert_eq!(
+            submit(&chain, &selected, &too_much).unwrap_err(),
+            "bad-cb-amount"
+        );
+
+        let mut wrong_height = coinbase(&template, template.coinbase_value);
+        wrong_height.input[0].script_sig = Builder::new().push_int(300).push_int(0).into_script();
+        assert_eq!(
+            submit(&chain, &selected, &wrong_height).unwrap_err(),
+            "bad-cb-height"
+        );
+
+        let mut no_reserved_value = coinbase(&template, template.coinbase_value);
+        no_reserved_value.input[0].witness = Witness::new();
+        assert_eq!(
+            submit(&chain, &selected, &no_reserved_value).unwrap_err(),
+            "bad-witness-merkle-match"
+        );
+
+        let valid = coinbase(&template, template.coinbase_value);
+        let block = submit(&chain, &selected, &valid).unwrap();
+        assert!(chain.on_solution(Ok(block.clone())));
+        assert_eq!(chain.found_blocks(), &[block]);
+
+        // the template is not on the tip anymore
+        assert_eq!(
+            submit(&chain, &selected, &valid).unwrap_err(),
+            "inconclusive-not-best-prevblk"
+        );
+    }
+}
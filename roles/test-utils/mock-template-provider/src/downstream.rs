@@ -0,0 +1,229 @@
+use super::MockTemplateProvider;
+use async_channel::{Receiver, Sender};
+use codec_sv2::Frame;
+use nohash_hasher::BuildNoHashHasher;
+use roles_logic_sv2::{
+    errors::Error,
+    handlers::template_distribution::{ParseClientTemplateDistributionMessages, SendTo},
+    parsers::{PoolMessages, TemplateDistribution},
+    template_distribution_sv2::{
+        CoinbaseOutputDataSize, RequestTransactionData, RequestTransactionDataError, SubmitSolution,
+    },
+    utils::Mutex,
+};
+use std::{collections::HashMap, convert::TryInto, sync::Arc};
+use template_provider_sv2::{
+    error::TpError,
+    template::{SelectedTemplate, Template},
+    EitherFrame, StdFrame,
+};
+use tracing::{debug, info, warn};
+
+/// Connection of a role that asks templates to the mock
+#[derive(Debug)]
+pub struct Downstream {
+    id: u32,
+    sender: Sender<EitherFrame>,
+    // None until the downstream sends CoinbaseOutputDataSize, no template is sent before
+    coinbase_output_max_additional_size: Option<u32>,
+    last_template_id: u64,
+    // Templates sent since the last SetNewPrevHash
+    templates: HashMap<u64, SelectedTemplate, BuildNoHashHasher<u64>>,
+}
+
+impl Downstream {
+    pub fn new(id: u32, sender: Sender<EitherFrame>) -> Self {
+        Self {
+            id,
+            sender,
+            coinbase_output_max_additional_size: None,
+            last_template_id: 0,
+            templates: HashMap::with_hasher(BuildNoHashHasher::default()),
+        }
+    }
+
+    fn on_template(
+        &mut self,
+        template: &Arc<Template>,
+        new_prev_hash: bool,
+    ) -> Result<Vec<TemplateDistribution<'static>>, TpError> {
+        let coinbase_output_max_additional_size = match self.coinbase_output_max_additional_size {
+            Some(size) => size,
+            None => return Ok(vec![]),
+        };
+        let new_prev_hash = new_prev_hash || self.templates.is_empty();
+        let selected = template.select(coinbase_output_max_additional_size);
+        self.last_template_id += 1;
+        let template_id = self.last_template_id;
+        let mut messages = vec![TemplateDistribution::NewTemplate(
+            selected.new_template(template_id, new_prev_hash)?,
+        )];
+        if new_prev_hash {
+            messages.push(TemplateDistribution::SetNewPrevHash(
+                selected.set_new_prev_hash(template_id)?,
+            ));
+            self.templates.clear();
+        }
+        self.templates.insert(template_id, selected);
+        Ok(messages)
+    }
+
+    pub async fn send_template(
+        self_mutex: Arc<Mutex<Self>>,
+        template: &Arc<Template>,
+        new_prev_hash: bool,
+    ) -> Result<(), TpError> {
+        let messages = self_mutex.safe_lock(|s| s.on_template(template, new_prev_hash))??;
+        let sender = self_mutex.safe_lock(|s| s.sender.clone())?;
+        for message in messages {
+            let sv2_frame: StdFrame = PoolMessages::TemplateDistribution(message).try_into()?;
+            sender.send(sv2_frame.into()).await?;
+        }
+        Ok(())
+    }
+
+    pub fn start(
+        self_mutex: Arc<Mutex<Self>>,
+        receiver: Receiver<EitherFrame>,
+        template_provider: MockTemplateProvider,
+    ) {
+        tokio::spawn(async move {
+            while let Ok(message) = receiver.recv().await {
+                if let Err(e) =
+                    Self::on_message(self_mutex.clone(), &template_provider, message).await
+                {
+                    warn!("Closing the connection of a downstream: {}", e);
+                    break;
+                }
+            }
+            let _ = template_provider
+                .downstreams
+                .safe_lock(|d| d.retain(|downstream| !Arc::ptr_eq(downstream, &self_mutex)));
+        });
+    }
+
+    async fn on_message(
+        self_mutex: Arc<Mutex<Self>>,
+        template_provider: &MockTemplateProvider,
+        message: EitherFrame,
+    ) -> Result<(), TpError> {
+        let mut frame: StdFrame = message.try_into()?;
+        let message_type = frame
+            .get_header()
+            .ok_or_else(|| TpError::Custom(String::from("No header set")))?
+            .msg_type();
+        let payload = frame.payload();
+        let next_message_to_send =
+            ParseClientTemplateDistributionMessages::handle_message_template_distribution(
+                self_mutex.clone(),
+                message_type,
+                payload,
+            )?;
+        match next_message_to_send {
+            SendTo::Respond(message) => {
+                let sv2_frame: StdFrame = PoolMessages::TemplateDistribution(message).try_into()?;
+                let sender = self_mutex.safe_lock(|s| s.sender.clone())?;
+                sender.send(sv2_frame.into()).await?;
+            }
+            SendTo::None(Some(TemplateDistribution::CoinbaseOutputDataSize(_))) => {
+                // the downstream gets the current template as soon as its coinbase outputs are
+                // known
+                let template = Self::first_template(&self_mutex, template_provider)?;
+                if let Some(template) = template {
+                    Self::send_template(self_mutex, &template, true).await?;
+                }
+            }
+            SendTo::None(Some(TemplateDistribution::SubmitSolution(solution))) => {
+                let (selected, last_template_id) = self_mutex.safe_lock(|s| {
+                    (
+                        s.templates.get(&solution.template_id).cloned(),
+                        s.last_template_id,
+                    )
+                })?;
+                let result = match selected {
+                    Some(selected) => template_provider
+                        .chain
+                        .safe_lock(|c| c.check_solution(&selected, &solution))?,
+                    // a share found before the downstream got the new prev hash
+                    None if solution.template_id <= last_template_id => {
+                        Err("stale-template-id".to_string())
+                    }
+                    None => Err("template-id-not-found".to_string()),
+                };
+                template_provider.on_solution(result).await?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Current template of the chain if none has been sent to the downstream yet
+    fn first_template(
+        self_mutex: &Arc<Mutex<Self>>,
+        template_provider: &MockTemplateProvider,
+    ) -> Result<Option<Arc<Template>>, TpError> {
+        if self_mutex.safe_lock(|s| s.last_template_id)? != 0 {
+            return Ok(None);
+        }
+        Ok(Some(template_provider.chain.safe_lock(|c| c.template())?))
+    }
+}
+
+impl ParseClientTemplateDistributionMessages for Downstream {
+    fn handle_coinbase_out_data_size(
+        &mut self,
+        m: CoinbaseOutputDataSize,
+    ) -> Result<SendTo, Error> {
+        debug!(
+            "Downstream {} coinbase outputs take up to {} bytes",
+            self.id, m.coinbase_output_max_additional_size
+        );
+        self.coinbase_output_max_additional_size = Some(m.coinbase_output_max_additional_size);
+        Ok(SendTo::None(Some(
+            TemplateDistribution::CoinbaseOutputDataSize(m),
+        )))
+    }
+
+    fn handle_request_tx_data(&mut self, m: RequestTransactionData) -> Result<SendTo, Error> {
+        let message = match self.templates.get(&m.template_id) {
+            Some(template) => {
+                let success = template
+                    .request_transaction_data_success(m.template_id)
+                    .map_err(|e| Error::NoValidTemplate(e.to_string()))?;
+                TemplateDistribution::RequestTransactionDataSuccess(success)
+            }
+            None => {
+                let error_code = match m.template_id <= self.last_template_id {
+                    true => "stale-template-id",
+                    false => "template-id-not-found",
+                };
+                TemplateDistribution::RequestTransactionDataError(RequestTransactionDataError {
+                    template_id: m.template_id,
+                    error_code: error_code
+                        .to_string()
+                        .into_bytes()
+                        .try_into()
+                        .map_err(Error::BinarySv2Error)?,
+                })
+            }
+        };
+        Ok(SendTo::Respond(message))
+    }
+
+    fn handle_request_submit_solution(&mut self, m: SubmitSolution) -> Result<SendTo, Error> {
+        info!(
+            "Downstream {} submitted a solution for template {}",
+            self.id, m.template_id
+        );
+        let solution = SubmitSolution {
+            template_id: m.template_id,
+            version: m.version,
+            header_timestamp: m.header_timestamp,
+            header_nonce: m.header_nonce,
+            coinbase_tx: m.coinbase_tx.to_vec().try_into()?,
+        };
+        Ok(SendTo::None(Some(TemplateDistribution::SubmitSolution(
+            solution,
+        ))))
+    }
+}
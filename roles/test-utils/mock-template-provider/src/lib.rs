@@ -0,0 +1,221 @@
+//! Template Provider that runs in the process of the test and builds its templates on a fake
+//! chain, so that the pool or the JDC can be tested without bitcoind.
+//!
+//! The templates are consensus valid on the fake chain: the coinbase prefix has the BIP34
+//! height, the coinbase value is the regtest subsidy plus the fees and the witness commitment
+//! matches the transactions. The solutions submitted by the downstreams are checked like
+//! bitcoind would, the valid ones are connected to the chain and a new template is sent.
+//!
+//! ```ignore
+//! let tp = MockTemplateProvider::start(MockTpConfig::default()).await?;
+//! // start the pool and the mining devices against `tp.address()`
+//! assert!(tp.wait_for_blocks(1, Duration::from_secs(60)).await);
+//! ```
+mod chain;
+mod downstream;
+
+use chain::FakeChain;
+use codec_sv2::{HandshakeRole, Responder};
+use downstream::Downstream;
+use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
+use network_helpers_sv2::noise_connection_tokio::Connection;
+use roles_logic_sv2::utils::{Id, Mutex};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use stratum_common::bitcoin::{Block, BlockHash, Transaction};
+use template_provider_sv2::{
+    downstream::setup_connection::setup_connection, error::TpError, template::Template,
+};
+use tokio::{net::TcpListener, time::Instant};
+use tracing::{error, info, warn};
+
+// same keys as the config examples of the roles
+const AUTHORITY_PUBLIC_KEY: &str = "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72";
+const AUTHORITY_SECRET_KEY: &str = "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n";
+
+#[derive(Debug, Clone)]
+pub struct MockTpConfig {
+    /// Use port 0 to let the system pick a free port, see `MockTemplateProvider::address`
+    pub listen_address: String,
+    pub authority_public_key: Secp256k1PublicKey,
+    pub authority_secret_key: Secp256k1SecretKey,
+    pub cert_validity_sec: u64,
+    /// Height of the tip of the fake chain, the first template is for the next block
+    pub start_height: u64,
+    /// Difficulty of the fake chain, the default is the regtest one so that every share is a
+    /// block
+    pub bits: u32,
+    /// Add a witness commitment output to the coinbase of the templates
+    pub witness_commitment: bool,
+}
+
+impl Default for MockTpConfig {
+    fn default() -> Self {
+        Self {
+            listen_address: "127.0.0.1:0".to_string(),
+            authority_public_key: AUTHORITY_PUBLIC_KEY.parse().expect("valid public key"),
+            authority_secret_key: AUTHORITY_SECRET_KEY.parse().expect("valid secret key"),
+            cert_validity_sec: 3600,
+            // heights up to 16 are pushed with a single opcode, that the roles do not expect
+            start_height: 200,
+            bits: 0x207f_ffff,
+            witness_commitment: true,
+        }
+    }
+}
+
+/// Handle of a running mock, the clones share the same chain
+#[derive(Debug, Clone)]
+pub struct MockTemplateProvider {
+    address: SocketAddr,
+    authority_public_key: Secp256k1PublicKey,
+    chain: Arc<Mutex<FakeChain>>,
+    downstreams: Arc<Mutex<Vec<Arc<Mutex<Downstream>>>>>,
+}
+
+impl MockTemplateProvider {
+    /// Listens for downstreams in a background task, the task stops with the tokio runtime
+    pub async fn start(config: MockTpConfig) -> Result<Self, TpError> {
+        let listener = TcpListener::bind(&config.listen_address).await?;
+        let self_ = Self {
+            address: listener.local_addr()?,
+            authority_public_key: config.authority_public_key,
+            chain: Arc::new(Mutex::new(FakeChain::new(
+                config.start_height,
+                config.bits,
+                config.witness_commitment,
+            ))),
+            downstreams: Arc::new(Mutex::new(Vec::new())),
+        };
+        info!("Mock Template Provider listening on {}", self_.address);
+        let cloned = self_.clone();
+        tokio::spawn(async move {
+            if let Err(e) = cloned.accept_incoming_connection(listener, config).await {
+                error!("Mock Template Provider stopped: {}", e);
+            }
+        });
+        Ok(self_)
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn authority_public_key(&self) -> Secp256k1PublicKey {
+        self.authority_public_key
+    }
+
+    /// Hash and height of the tip of the fake chain
+    pub fn tip(&self) -> (BlockHash, u64) {
+        self.chain.safe_lock(|c| c.tip()).unwrap()
+    }
+
+    /// Template on which the next block is built
+    pub fn template(&self) -> Arc<Template> {
+        self.chain.safe_lock(|c| c.template()).unwrap()
+    }
+
+    /// Blocks of the valid solutions submitted by the downstreams
+    pub fn found_blocks(&self) -> Vec<Block> {
+        self.chain.safe_lock(|c| c.found_blocks().to_vec()).unwrap()
+    }
+
+    /// Reject reasons of the invalid solutions, like "high-hash" or "bad-cb-amount"
+    pub fn rejected_solutions(&self) -> Vec<String> {
+        self.chain
+            .safe_lock(|c| c.rejected_solutions().to_vec())
+            .unwrap()
+    }
+
+    /// Waits until the downstreams found `count` blocks, false on timeout
+    pub async fn wait_for_blocks(&self, count: usize, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.chain.safe_lock(|c| c.found_blocks().len()).unwrap() >= count {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Adds the transactions, with their fee, to the mempool and sends the new template to the
+    /// downstreams
+    pub async fn add_transactions(&self, transactions: Vec<(Transaction, u64)>) {
+        let template = self
+            .chain
+            .safe_lock(|c| {
+                c.add_transactions(transactions);
+                c.template()
+            })
+            .unwrap();
+        self.broadcast(template, false).await;
+    }
+
+    /// Mines a block that does not come from the downstreams, as if another pool found it, and
+    /// sends the new prev hash
+    pub async fn mine_block(&self) -> Block {
+        let (block, template) = self
+            .chain
+            .safe_lock(|c| (c.mine_block(), c.template()))
+            .unwrap();
+        self.broadcast(template, true).await;
+        block
+    }
+
+    async fn on_solution(&self, result: Result<Block, String>) -> Result<(), TpError> {
+        match &result {
+            Ok(block) => info!("Valid solution, block {}", block.block_hash()),
+            Err(reason) => warn!("Invalid solution: {}", reason),
+        }
+        let template = self.chain.safe_lock(|c| match c.on_solution(result) {
+            true => Some(c.template()),
+            false => None,
+        })?;
+        if let Some(template) = template {
+            self.broadcast(template, true).await;
+        }
+        Ok(())
+    }
+
+    async fn broadcast(&self, template: Arc<Template>, new_prev_hash: bool) {
+        let downstreams = self.downstreams.safe_lock(|d| d.clone()).unwrap();
+        for downstream in downstreams {
+            if let Err(e) = Downstream::send_template(downstream, &template, new_prev_hash).await {
+                warn!("Impossible to send the template to a downstream: {}", e);
+            }
+        }
+    }
+
+    async fn accept_incoming_connection(
+        &self,
+        listener: TcpListener,
+        config: MockTpConfig,
+    ) -> Result<(), TpError> {
+        let mut ids = Id::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            let responder = Responder::from_authority_kp(
+                &config.authority_public_key.into_bytes(),
+                &config.authority_secret_key.into_bytes(),
+                Duration::from_secs(config.cert_validity_sec),
+            )?;
+            let (receiver, sender) =
+                match Connection::new(stream, HandshakeRole::Responder(responder)).await {
+                    Ok((receiver, sender, _, _)) => (receiver, sender),
+                    Err(e) => {
+                        warn!("Can not connect a downstream: {:?}", e);
+                        continue;
+                    }
+                };
+            if let Err(e) = setup_connection(&receiver, &sender).await {
+                warn!("Setup connection failed: {}", e);
+                continue;
+            }
+            let downstream = Arc::new(Mutex::new(Downstream::new(ids.next(), sender)));
+            self.downstreams.safe_lock(|d| d.push(downstream.clone()))?;
+            Downstream::start(downstream, receiver, self.clone());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use async_channel::{bounded, unbounded};
+use mock_template_provider::{MockTemplateProvider, MockTpConfig};
+use pool_sv2::{
+    mining_pool::{
+        get_coinbase_commitments, get_coinbase_output, BlockAssemblyConfig, Configuration, Pool,
+    },
+    status,
+    template_receiver::TemplateRx,
+};
+use roles_logic_sv2::job_creator::coinbase_output_max_additional_size;
+use std::time::Duration;
+use stratum_common::bitcoin::{
+    consensus::deserialize, hashes::Hash, Block, OutPoint, PackedLockTime, Script, Sequence,
+    Transaction, TxIn, TxOut, Txid, Witness,
+};
+
+fn pool_config(tp: &MockTemplateProvider) -> Configuration {
+    // the port is free again when the pool binds it
+    let listen_address = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = format!(
+        r#"
+        authority_public_key = "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72"
+        authority_secret_key = "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n"
+        cert_validity_sec = 3600
+        test_only_listen_adress_plain = "127.0.0.1:0"
+        listen_address = "{}"
+        coinbase_outputs = [
+            {{ output_script_type = "P2WPKH", output_script_value = "036adc3bdf21e6f9a0f0fb0066bf517e5b7909ed1563d6958a10993849a7554075" }},
+        ]
+        pool_signature = "Stratum v2 SRI Pool"
+        tp_address = "{}"
+        tp_authority_public_key = "{}"
+        "#,
+        listen_address,
+        tp.address(),
+        String::from(tp.authority_public_key()),
+    );
+    toml::from_str(&config).unwrap()
+}
+
+// Same wiring as the main of the pool
+async fn start_pool(config: Configuration) -> async_channel::Receiver<status::Status> {
+    let (status_tx, status_rx) = unbounded();
+    let (s_new_t, r_new_t) = bounded(10);
+    let (s_prev_hash, r_prev_hash) = bounded(10);
+    let (s_solution, r_solution) = bounded(10);
+    let (s_message_recv_signal, r_message_recv_signal) = bounded(10);
+    let coinbase_output_len = coinbase_output_max_additional_size(
+        &get_coinbase_output(&config).unwrap(),
+        &get_coinbase_commitments(&config).unwrap(),
+    );
+    TemplateRx::connect(
+        config.tp_address.parse().unwrap(),
+        s_new_t,
+        s_prev_hash,
+        r_solution,
+        r_message_recv_signal,
+        status::Sender::Upstream(status_tx.clone()),
+        coinbase_output_len,
+        config.tp_authority_public_key,
+        config.block_assembly.clone(),
+    )
+    .await
+    .unwrap();
+    Pool::start(
+        config,
+        r_new_t,
+        r_prev_hash,
+        s_solution,
+        s_message_recv_signal,
+        status::Sender::DownstreamListener(status_tx),
+    );
+    status_rx
+}
+
+// The device measures its hashrate with a blocking loop, a thread of its own keeps it from
+// stalling the devices of the other tests on the async-std executor
+fn start_mining_device(pool_address: String) {
+    std::thread::spawn(move || {
+        // a low nominal hashrate gives an easy share target, with the regtest difficulty every
+        // share is a block
+        async_std::task::block_on(mining_device::connect(
+            pool_address,
+            None,
+            None,
+            None,
+            0,
+            Some(0.01),
+        ))
+    });
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pool_and_mining_device_find_blocks() {
+    let _ = tracing_subscriber::fmt::try_init();
+    let tp = MockTemplateProvider::start(MockTpConfig::default())
+        .await
+        .unwrap();
+    let (_, start_height) = tp.tip();
+    let config = pool_config(&tp);
+    let pool_address = config.listen_address.clone();
+    let _status_rx = start_pool(config).await;
+
+    start_mining_device(pool_address);
+
+    let found = tp.wait_for_blocks(2, Duration::from_secs(120)).await;
+    assert!(
+        found,
+        "no blocks found, rejected solutions: {:?}",
+        tp.rejected_solutions()
+    );
+    // shares of the previous block can still be in flight when a block is found
+    assert!(tp
+        .rejected_solutions()
+        .iter()
+        .all(|reason| reason == "stale-template-id"));
+    let blocks = tp.found_blocks();
+    // the second block is built on the first one, so the pool got the new prev hash
+    assert_eq!(blocks[1].header.prev_blockhash, blocks[0].block_hash());
+    assert_eq!(blocks[0].bip34_block_height().unwrap(), start_height + 1);
+    assert!(tp.tip().1 >= start_height + 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blocks_from_other_miners_move_the_pool() {
+    let _ = tracing_subscriber::fmt::try_init();
+    let tp = MockTemplateProvider::start(MockTpConfig::default())
+        .await
+        .unwrap();
+    let config = pool_config(&tp);
+    let pool_address = config.listen_address.clone();
+    let _status_rx = start_pool(config).await;
+    let other = tp.mine_block().await;
+    let (tip, _) = tp.tip();
+    assert_eq!(tip, other.block_hash());
+
+    start_mining_device(pool_address);
+
+    assert!(tp.wait_for_blocks(1, Duration::from_secs(120)).await);
+    assert_eq!(tp.found_blocks()[0].header.prev_blockhash, tip);
+}
+
+fn transaction(index: u8) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: Txid::from_inner([index; 32]),
+                vout: 0,
+            },
+            script_sig: Script::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::from_vec(vec![vec![1; 72], vec![2; 33]]),
+        }],
+        output: vec![TxOut {
+            value: 1000,
+            script_pubkey: Script::from(vec![0; 22]),
+        }],
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pool_assembles_the_found_blocks() {
+    let _ = tracing_subscriber::fmt::try_init();
+    let tp = MockTemplateProvider::start(MockTpConfig::default())
+        .await
+        .unwrap();
+    tp.add_transactions(vec![(transaction(1), 300), (transaction(2), 200)])
+        .await;
+    let archive_dir =
+        std::env::temp_dir().join(format!("pool-found-blocks-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&archive_dir);
+    let mut config = pool_config(&tp);
+    config.block_assembly = BlockAssemblyConfig {
+        request_transaction_data: true,
+        archive_dir: Some(archive_dir.to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+    let pool_address = config.listen_address.clone();
+    let _status_rx = start_pool(config).await;
+
+    start_mining_device(pool_address);
+
+    assert!(tp.wait_for_blocks(1, Duration::from_secs(120)).await);
+    let found = tp.found_blocks()[0].clone();
+    assert_eq!(found.txdata.len(), 3);
+    // the pool archives the block after sending the solution to the TP
+    let path = archive_dir.join(format!("{}.hex", found.block_hash()));
+    let mut archived = None;
+    for _ in 0..100 {
+        if let Ok(hex) = std::fs::read_to_string(&path) {
+            archived = Some(hex);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let archived: Block = deserialize(&hex::decode(archived.unwrap()).unwrap()).unwrap();
+    assert_eq!(archived, found);
+    let _ = std::fs::remove_dir_all(&archive_dir);
+}
@@ -0,0 +1,185 @@
+use super::{kill, Downstream};
+
+use crate::stats::now_secs;
+use async_channel::{Receiver, Sender};
+use async_std::{net::TcpStream, task};
+use futures::{select, FutureExt};
+use roles_logic_sv2::utils::Mutex;
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{debug, warn};
+
+/// Interval in seconds between two checks of the idle timeout of a SV1 connection.
+const IDLE_CHECK_INTERVAL_SECS: u64 = 5;
+
+impl Downstream {
+    /// Records that the Downstream sent a `mining.subscribe` or a `mining.submit`, the messages
+    /// that keep the connection alive with respect to the idle timeout.
+    pub(super) fn on_activity(&mut self) {
+        self.last_activity = now_secs();
+    }
+
+    /// Seconds elapsed since the last `mining.subscribe` or `mining.submit`, or since the
+    /// connection if none was received yet.
+    pub(super) fn secs_since_last_activity(&self, now: u64) -> u64 {
+        now.saturating_sub(self.last_activity)
+    }
+
+    /// Spawns a task that closes the connection when the Downstream stays idle for more than
+    /// `idle_timeout_secs`. The task sends the shutdown message to the other Downstream tasks,
+    /// the job notifier then removes the miner hashrate from the channel.
+    pub(super) fn start_idle_timeout(
+        self_: Arc<Mutex<Self>>,
+        idle_timeout_secs: u64,
+        host: String,
+        tx_shutdown: Sender<bool>,
+        rx_shutdown: Receiver<bool>,
+    ) {
+        task::spawn(async move {
+            loop {
+                select! {
+                    _ = task::sleep(Duration::from_secs(IDLE_CHECK_INTERVAL_SECS)).fuse() => {
+                        let idle = match self_.safe_lock(|d| d.secs_since_last_activity(now_secs())) {
+                            Ok(idle) => idle,
+                            Err(_e) => {
+                                debug!("\nDownstream: Poison Lock - last_activity\n");
+                                break;
+                            }
+                        };
+                        if idle >= idle_timeout_secs {
+                            warn!("Downstream: {} idle for {}s, closing the connection", &host, idle);
+                            break;
+                        }
+                    },
+                    _ = rx_shutdown.recv().fuse() => {
+                        break;
+                    }
+                };
+            }
+            kill(&tx_shutdown).await;
+        });
+    }
+}
+
+/// Counts the open SV1 connections, plaintext and TLS together, to enforce `max_connections`.
+/// A slot is reserved as soon as a connection is accepted, before the TLS handshake, so that
+/// concurrent handshakes can not exceed the cap.
+#[derive(Debug, Clone)]
+pub struct ConnectionSlots {
+    open: Arc<AtomicUsize>,
+    /// 0 means no limit
+    max_connections: usize,
+}
+
+impl ConnectionSlots {
+    pub fn new(max_connections: usize) -> Self {
+        Self {
+            open: Arc::new(AtomicUsize::new(0)),
+            max_connections,
+        }
+    }
+
+    /// Reserves a slot for a new connection, returns None if `max_connections` connections are
+    /// already open. The slot is released when the returned `ConnectionSlot` is dropped.
+    pub fn try_reserve(&self) -> Option<ConnectionSlot> {
+        let max_connections = self.max_connections;
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                if max_connections > 0 && open >= max_connections {
+                    None
+                } else {
+                    Some(open + 1)
+                }
+            })
+            .ok()
+            .map(|_| ConnectionSlot {
+                open: self.open.clone(),
+            })
+    }
+
+    /// Number of reserved slots.
+    pub fn open_connections(&self) -> usize {
+        self.open.load(Ordering::Acquire)
+    }
+}
+
+/// A slot reserved in `ConnectionSlots`, held for the lifetime of the connection.
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Enables TCP keepalive on a SV1 connection so that half-open sockets (miner powered off,
+/// NAT entry dropped, ...) are detected by the OS and closed.
+pub fn set_tcp_keepalive(stream: &TcpStream, keepalive_secs: u64) -> std::io::Result<()> {
+    let keepalive = TcpKeepalive::new()
+        .with_time(Duration::from_secs(keepalive_secs))
+        .with_interval(Duration::from_secs(keepalive_secs));
+    // async_std does not implement `AsFd`/`AsSocket`, the socket is borrowed for the duration of
+    // this call only, while `stream` keeps it open
+    #[cfg(unix)]
+    let socket =
+        unsafe { std::os::fd::BorrowedFd::borrow_raw(std::os::fd::AsRawFd::as_raw_fd(stream)) };
+    #[cfg(windows)]
+    let socket = unsafe {
+        std::os::windows::io::BorrowedSocket::borrow_raw(
+            std::os::windows::io::AsRawSocket::as_raw_socket(stream),
+        )
+    };
+    SockRef::from(&socket).set_tcp_keepalive(&keepalive)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tracks_activity() {
+        let (mut downstream, _, _) = Downstream::for_test();
+        downstream.last_activity = 100;
+        assert_eq!(downstream.secs_since_last_activity(160), 60);
+        // the clock going backwards does not reap the connection
+        assert_eq!(downstream.secs_since_last_activity(50), 0);
+        downstream.on_activity();
+        assert!(downstream.secs_since_last_activity(now_secs()) <= 1);
+    }
+
+    #[test]
+    fn reserves_up_to_max_connections() {
+        let slots = ConnectionSlots::new(2);
+        let first = slots.try_reserve().unwrap();
+        let second = slots.try_reserve().unwrap();
+        assert!(slots.try_reserve().is_none());
+        assert_eq!(slots.open_connections(), 2);
+
+        drop(first);
+        assert_eq!(slots.open_connections(), 1);
+        let third = slots.try_reserve().unwrap();
+        assert!(slots.try_reserve().is_none());
+
+        drop(second);
+        drop(third);
+        assert_eq!(slots.open_connections(), 0);
+    }
+
+    #[test]
+    fn no_limit_when_max_connections_is_zero() {
+        let slots = ConnectionSlots::new(0);
+        let reserved: Vec<_> = (0..100).map(|_| slots.try_reserve().unwrap()).collect();
+        assert_eq!(slots.open_connections(), 100);
+        drop(reserved);
+        assert_eq!(slots.open_connections(), 0);
+    }
+}
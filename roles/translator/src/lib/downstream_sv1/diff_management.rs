@@ -1,12 +1,10 @@
 use super::{Downstream, DownstreamMessages, SetDownstreamTarget};
 
 use super::super::error::{Error, ProxyResult};
-use roles_logic_sv2::utils::Mutex;
-use std::{ops::Div, sync::Arc};
+use roles_logic_sv2::{mining_sv2::Target, utils::Mutex};
+use std::sync::Arc;
 use v1::json_rpc;
 
-use stratum_common::bitcoin::util::uint::Uint256;
-
 impl Downstream {
     /// initializes the timestamp and resets the number of submits for a connection.
     /// Should only be called once for the lifetime of a connection since `try_update_difficulty_settings()`
@@ -75,9 +73,9 @@ impl Downstream {
     pub async fn try_update_difficulty_settings(
         self_: Arc<Mutex<Self>>,
     ) -> ProxyResult<'static, ()> {
-        let (diff_mgmt, channel_id) = self_
+        let (diff_mgmt, channel_id, last_target) = self_
             .clone()
-            .safe_lock(|d| (d.difficulty_mgmt.clone(), d.connection_id))
+            .safe_lock(|d| (d.difficulty_mgmt.clone(), d.connection_id, d.target.clone()))
             .map_err(|_e| Error::PoisonLock)?;
         tracing::debug!(
             "Time of last diff update: {:?}",
@@ -87,30 +85,40 @@ impl Downstream {
             "Number of shares submitted: {:?}",
             diff_mgmt.submits_since_last_update
         );
-        let prev_target = match roles_logic_sv2::utils::hash_rate_to_target(
-            diff_mgmt.min_individual_miner_hashrate.into(),
-            diff_mgmt.shares_per_minute.into(),
-        ) {
-            Ok(target) => target.to_vec(),
-            Err(v) => return Err(Error::TargetError(v)),
+        // the shares have been mined on the last target sent to the miner, that can be harder
+        // than the one of the estimated hashrate if the miner asked for a minimum difficulty
+        let prev_target = if last_target.is_empty() {
+            match Target::from_hashrate(
+                diff_mgmt.min_individual_miner_hashrate.into(),
+                diff_mgmt.shares_per_minute.into(),
+            ) {
+                Ok(target) => binary_sv2::U256::from(target).to_vec(),
+                Err(e) => return Err(Error::TargetError(roles_logic_sv2::Error::TargetError(e))),
+            }
+        } else {
+            last_target
         };
         if let Some(new_hash_rate) =
             Self::update_miner_hashrate(self_.clone(), prev_target.clone())?
         {
-            let new_target = match roles_logic_sv2::utils::hash_rate_to_target(
+            let new_target: binary_sv2::U256 = match Target::from_hashrate(
                 new_hash_rate.into(),
                 diff_mgmt.shares_per_minute.into(),
             ) {
-                Ok(target) => target,
-                Err(v) => return Err(Error::TargetError(v)),
+                Ok(target) => target.into(),
+                Err(e) => return Err(Error::TargetError(roles_logic_sv2::Error::TargetError(e))),
             };
             tracing::debug!("New target from hashrate: {:?}", new_target.inner_as_ref());
-            let message = Self::get_set_difficulty(new_target.to_vec())?;
+            let new_target = self_
+                .safe_lock(|d| d.apply_minimum_difficulty(new_target.to_vec()))
+                .map_err(|_e| Error::PoisonLock)??;
+            let message = Self::get_set_difficulty(new_target.clone())?;
             // send mining.set_difficulty to miner
             Downstream::send_message_downstream(self_.clone(), message).await?;
+            Self::on_new_target(self_.clone(), new_target.clone())?;
             let update_target_msg = SetDownstreamTarget {
                 channel_id,
-                new_target: new_target.into(),
+                new_target: binary_sv2::U256::try_from(new_target)?.into(),
             };
             // notify bridge of target update
             Downstream::send_message_upstream(
@@ -127,17 +135,70 @@ impl Downstream {
     pub fn hash_rate_to_target(self_: Arc<Mutex<Self>>) -> ProxyResult<'static, Vec<u8>> {
         self_
             .safe_lock(|d| {
-                match roles_logic_sv2::utils::hash_rate_to_target(
+                match Target::from_hashrate(
                     d.difficulty_mgmt.min_individual_miner_hashrate.into(),
                     d.difficulty_mgmt.shares_per_minute.into(),
                 ) {
-                    Ok(target) => Ok(target.to_vec()),
-                    Err(e) => Err(Error::TargetError(e)),
+                    Ok(target) => {
+                        d.apply_minimum_difficulty(binary_sv2::U256::from(target).to_vec())
+                    }
+                    Err(e) => Err(Error::TargetError(roles_logic_sv2::Error::TargetError(e))),
                 }
             })
             .map_err(|_e| Error::PoisonLock)?
     }
 
+    /// returns the target with the `minimum-difficulty` requested by the miner in
+    /// `mining.configure` if `target` (little endian) is easier than that
+    #[allow(clippy::result_large_err)]
+    pub(super) fn apply_minimum_difficulty(
+        &self,
+        target: Vec<u8>,
+    ) -> ProxyResult<'static, Vec<u8>> {
+        match self.minimum_difficulty {
+            Some(min_diff)
+                if min_diff > 0
+                    && Self::difficulty_from_target(target.clone())? < min_diff as f64 =>
+            {
+                tracing::debug!("Raising difficulty to the miner minimum {}", min_diff);
+                Ok(Self::target_from_difficulty(min_diff))
+            }
+            _ => Ok(target),
+        }
+    }
+
+    /// Inverse of `difficulty_from_target`, returns the little endian target of a difficulty
+    pub(super) fn target_from_difficulty(difficulty: u64) -> Vec<u8> {
+        // difficulty is never 0 here so the conversion can not fail
+        let target = Target::from_pool_difficulty(difficulty.max(1) as f64).unwrap_or(Target::MAX);
+        binary_sv2::U256::from(target).to_vec()
+    }
+
+    /// saves the target of the `mining.set_difficulty` sent to the miner, so that shares can be
+    /// checked against it, and records the difficulty and the current estimated hashrate in the
+    /// worker stats
+    #[allow(clippy::result_large_err)]
+    pub(super) fn on_new_target(
+        self_: Arc<Mutex<Self>>,
+        target: Vec<u8>,
+    ) -> ProxyResult<'static, ()> {
+        let difficulty = Self::difficulty_from_target(target.clone())?;
+        let (connection_id, hashrate, worker_stats) = self_
+            .safe_lock(|d| {
+                d.target = target;
+                (
+                    d.connection_id,
+                    d.difficulty_mgmt.min_individual_miner_hashrate,
+                    d.worker_stats.clone(),
+                )
+            })
+            .map_err(|_e| Error::PoisonLock)?;
+        worker_stats
+            .safe_lock(|s| s.on_difficulty_update(connection_id, difficulty, hashrate))
+            .map_err(|_e| Error::PoisonLock)?;
+        Ok(())
+    }
+
     /// increments the number of shares since the last difficulty update
     #[allow(clippy::result_large_err)]
     pub(super) fn save_share(self_: Arc<Mutex<Self>>) -> ProxyResult<'static, ()> {
@@ -164,37 +225,20 @@ impl Downstream {
     /// Converts target received by the `SetTarget` SV2 message from the Upstream role into the
     /// difficulty for the Downstream role sent via the SV1 `mining.set_difficulty` message.
     #[allow(clippy::result_large_err)]
-    pub(super) fn difficulty_from_target(mut target: Vec<u8>) -> ProxyResult<'static, f64> {
-        // reverse because target is LE and this function relies on BE
-        target.reverse();
-        let target = target.as_slice();
+    pub(super) fn difficulty_from_target(target: Vec<u8>) -> ProxyResult<'static, f64> {
         tracing::debug!("Target: {:?}", target);
+        let target = Target::from(binary_sv2::U256::try_from(target)?);
 
         // If received target is 0, return 0
-        if Downstream::is_zero(target) {
+        if target.is_zero() {
             return Ok(0.0);
         }
-        let target = Uint256::from_be_slice(target)?;
-        let pdiff: [u8; 32] = [
-            0, 0, 0, 0, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
-            255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
-        ];
-        let pdiff = Uint256::from_be_bytes(pdiff);
-
-        if pdiff > target {
-            let diff = pdiff.div(target);
-            Ok(diff.low_u64() as f64)
-        } else {
-            let diff = target.div(pdiff);
-            let diff = diff.low_u64() as f64;
-            // TODO still results in a difficulty that is too low
-            Ok(1.0 / diff)
-        }
+        Ok(target.pool_difficulty())
     }
 
     /// This function updates the miner hashrate and resets difficulty management params. To calculate hashrate it calculates the realized shares per minute from the number of shares submitted
     /// and the delta time since last update. It then uses the realized shares per minute and the target those shares where mined on to calculate an estimated hashrate during that period with the
-    /// function [`roles_logic_sv2::utils::hash_rate_from_target`]. Lastly, it adjusts the `channel_nominal_hashrate` according to the change in estimated miner hashrate
+    /// method [`Target::hashrate`]. Lastly, it adjusts the `channel_nominal_hashrate` according to the change in estimated miner hashrate
     #[allow(clippy::result_large_err)]
     pub fn update_miner_hashrate(
         self_: Arc<Mutex<Self>>,
@@ -227,10 +271,8 @@ impl Downstream {
                 let realized_share_per_min =
                     d.difficulty_mgmt.submits_since_last_update as f64 / (delta_time as f64 / 60.0);
                 tracing::debug!("\nREALIZED SHARES PER MINUTE {:?}", realized_share_per_min);
-                let mut new_miner_hashrate = match roles_logic_sv2::utils::hash_rate_from_target(
-                    miner_target.clone().try_into()?,
-                    realized_share_per_min,
-                ) {
+                let miner_target = Target::from(binary_sv2::U256::try_from(miner_target.clone())?);
+                let mut new_miner_hashrate = match miner_target.hashrate(realized_share_per_min) {
                     Ok(hashrate) => hashrate as f32,
                     Err(e) => {
                         tracing::debug!("{:?} -> Probably min_individual_miner_hashrate parameter was not set properly in config file. New hashrate will be automatically adjusted to match the real one.", e);
@@ -289,23 +331,11 @@ impl Downstream {
             })
             .map_err(|_e| Error::PoisonLock)?
     }
-
-    /// Helper function to check if target is set to zero for some reason (typically happens when
-    /// Downstream role first connects).
-    /// https://stackoverflow.com/questions/65367552/checking-a-vecu8-to-see-if-its-all-zero
-    fn is_zero(buf: &[u8]) -> bool {
-        let (prefix, aligned, suffix) = unsafe { buf.align_to::<u128>() };
-
-        prefix.iter().all(|&x| x == 0)
-            && suffix.iter().all(|&x| x == 0)
-            && aligned.iter().all(|&x| x == 0)
-    }
 }
 
 #[cfg(test)]
 mod test {
     use crate::proxy_config::{DownstreamDifficultyConfig, UpstreamDifficultyConfig};
-    use async_channel::unbounded;
     use binary_sv2::U256;
     use rand::{thread_rng, Rng};
     use roles_logic_sv2::{mining_sv2::Target, utils::Mutex};
@@ -322,11 +352,11 @@ mod test {
         let expected_shares_per_minute = 1000.0;
         let total_run_time = std::time::Duration::from_secs(11);
         let initial_nominal_hashrate = measure_hashrate(5);
-        let target = match roles_logic_sv2::utils::hash_rate_to_target(
+        let target = match Target::from_hashrate(
             initial_nominal_hashrate,
             expected_shares_per_minute.into(),
         ) {
-            Ok(target) => target,
+            Ok(target) => U256::from(target),
             Err(_) => panic!(),
         };
 
@@ -401,6 +431,39 @@ mod test {
         arr
     }
 
+    #[test]
+    fn test_minimum_difficulty() {
+        let (mut downstream, _, _) = Downstream::for_test();
+        downstream.difficulty_mgmt.min_individual_miner_hashrate = 1.0;
+        downstream.difficulty_mgmt.shares_per_minute = 10.0;
+        let easy_target = Downstream::target_from_difficulty(1);
+        assert_eq!(
+            Downstream::difficulty_from_target(easy_target.clone()).unwrap(),
+            1.0
+        );
+        assert_eq!(
+            downstream
+                .apply_minimum_difficulty(easy_target.clone())
+                .unwrap(),
+            easy_target
+        );
+
+        downstream.minimum_difficulty = Some(2048);
+        let target = downstream.apply_minimum_difficulty(easy_target).unwrap();
+        assert_eq!(
+            Downstream::difficulty_from_target(target.clone()).unwrap(),
+            2048.0
+        );
+        // harder targets are left untouched
+        let hard_target = Downstream::target_from_difficulty(4096);
+        assert_eq!(
+            downstream
+                .apply_minimum_difficulty(hard_target.clone())
+                .unwrap(),
+            hard_target
+        );
+    }
+
     #[tokio::test]
     async fn test_converge_to_spm_from_low() {
         test_converge_to_spm(1.0).await
@@ -424,21 +487,9 @@ mod test {
             timestamp_of_last_update: 0,
             should_aggregate: false,
         };
-        let (tx_sv1_submit, _rx_sv1_submit) = unbounded();
-        let (tx_outgoing, _rx_outgoing) = unbounded();
-        let mut downstream = Downstream::new(
-            1,
-            vec![],
-            vec![],
-            None,
-            None,
-            tx_sv1_submit,
-            tx_outgoing,
-            false,
-            0,
-            downstream_conf.clone(),
-            Arc::new(Mutex::new(upstream_config)),
-        );
+        let (mut downstream, _rx_sv1_submit, _rx_outgoing) = Downstream::for_test();
+        downstream.difficulty_mgmt = downstream_conf.clone();
+        downstream.upstream_difficulty_config = Arc::new(Mutex::new(upstream_config));
         downstream.difficulty_mgmt.min_individual_miner_hashrate = start_hashrate as f32;
 
         let total_run_time = std::time::Duration::from_secs(10);
@@ -447,20 +498,19 @@ mod test {
         let mut elapsed = std::time::Duration::from_secs(0);
 
         let expected_nominal_hashrate = measure_hashrate(5);
-        let expected_target = match roles_logic_sv2::utils::hash_rate_to_target(
-            expected_nominal_hashrate,
-            config_shares_per_minute.into(),
-        ) {
-            Ok(target) => target,
-            Err(_) => panic!(),
-        };
+        let expected_target =
+            match Target::from_hashrate(expected_nominal_hashrate, config_shares_per_minute.into())
+            {
+                Ok(target) => U256::from(target),
+                Err(_) => panic!(),
+            };
 
         let initial_nominal_hashrate = start_hashrate;
-        let mut initial_target = match roles_logic_sv2::utils::hash_rate_to_target(
+        let mut initial_target = match Target::from_hashrate(
             initial_nominal_hashrate,
             config_shares_per_minute.into(),
         ) {
-            Ok(target) => target,
+            Ok(target) => U256::from(target),
             Err(_) => panic!(),
         };
         let downstream = Arc::new(Mutex::new(downstream));
@@ -476,11 +526,11 @@ mod test {
                 .unwrap();
             initial_target = downstream
                 .safe_lock(|d| {
-                    match roles_logic_sv2::utils::hash_rate_to_target(
+                    match Target::from_hashrate(
                         d.difficulty_mgmt.min_individual_miner_hashrate.into(),
                         config_shares_per_minute.into(),
                     ) {
-                        Ok(target) => target,
+                        Ok(target) => U256::from(target),
                         Err(_) => panic!(),
                     }
                 })
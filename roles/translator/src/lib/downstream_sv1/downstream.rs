@@ -1,21 +1,26 @@
 use crate::{
     downstream_sv1,
     error::ProxyResult,
-    proxy_config::{DownstreamDifficultyConfig, UpstreamDifficultyConfig},
+    proxy_config::{
+        DownstreamConnectionConfig, DownstreamDifficultyConfig, UpstreamDifficultyConfig,
+    },
+    stats::{now_secs, ShareOutcome, WorkerStatsRegistry},
     status,
 };
 use async_channel::{bounded, Receiver, Sender};
-use async_std::{
-    io::BufReader,
-    net::{TcpListener, TcpStream},
-    prelude::*,
-    task,
-};
+use async_std::{io::BufReader, net::TcpListener, prelude::*, task};
 use error_handling::handle_result;
-use futures::FutureExt;
+use futures::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    FutureExt,
+};
+use futures_rustls::TlsAcceptor;
 use tokio::sync::broadcast;
 
-use super::{kill, DownstreamMessages, SubmitShareWithChannelId, SUBSCRIBE_TIMEOUT_SECS};
+use super::{
+    connection_management::{set_tcp_keepalive, ConnectionSlot, ConnectionSlots},
+    kill, DownstreamMessages, SubmitShareWithChannelId, SUBSCRIBE_TIMEOUT_SECS,
+};
 
 use roles_logic_sv2::{
     common_properties::{IsDownstream, IsMiningDownstream},
@@ -44,14 +49,21 @@ pub struct Downstream {
     /// List of authorized Downstream Mining Devices.
     pub(super) connection_id: u32,
     authorized_names: Vec<String>,
-    extranonce1: Vec<u8>,
+    pub(super) extranonce1: Vec<u8>,
     /// `extranonce1` to be sent to the Downstream in the SV1 `mining.subscribe` message response.
     //extranonce1: Vec<u8>,
     //extranonce2_size: usize,
     /// Version rolling mask bits
-    version_rolling_mask: Option<HexU32Be>,
+    pub(super) version_rolling_mask: Option<HexU32Be>,
     /// Minimum version rolling mask bits size
-    version_rolling_min_bit: Option<HexU32Be>,
+    pub(super) version_rolling_min_bit: Option<HexU32Be>,
+    /// Version rolling mask requested by the Downstream in `mining.configure`, the negotiated
+    /// `version_rolling_mask` is this mask intersected with what the Upstream allows.
+    pub(super) requested_version_rolling_mask: Option<HexU32Be>,
+    /// Whether the jobs of the Upstream channel allow version rolling, shared with the `Bridge`.
+    pub(super) version_rolling_allowed: Arc<Mutex<bool>>,
+    /// Minimum difficulty requested by the Downstream with the `minimum-difficulty` extension.
+    pub(super) minimum_difficulty: Option<u64>,
     /// Sends a SV1 `mining.submit` message received from the Downstream role to the `Bridge` for
     /// translation into a SV2 `SubmitSharesExtended`.
     tx_sv1_bridge: Sender<DownstreamMessages>,
@@ -60,8 +72,17 @@ pub struct Downstream {
     /// True if this is the first job received from `Upstream`.
     first_job_received: bool,
     extranonce2_len: usize,
+    /// Last `mining.notify` sent to the Downstream, the only job shares are accepted for.
+    pub(super) last_job: Option<server_to_client::Notify<'static>>,
+    /// Target of the last `mining.set_difficulty` sent to the Downstream (little endian).
+    pub(super) target: Vec<u8>,
     pub(super) difficulty_mgmt: DownstreamDifficultyConfig,
     pub(super) upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+    /// Per-worker statistics shared with the `Bridge`.
+    pub(super) worker_stats: Arc<Mutex<WorkerStatsRegistry>>,
+    /// Unix timestamp (secs) of the last `mining.subscribe` or `mining.submit`, used to reap idle
+    /// connections.
+    pub(super) last_activity: u64,
 }
 
 impl Downstream {
@@ -78,25 +99,74 @@ impl Downstream {
         extranonce2_len: usize,
         difficulty_mgmt: DownstreamDifficultyConfig,
         upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+        worker_stats: Arc<Mutex<WorkerStatsRegistry>>,
     ) -> Self {
         Downstream {
             connection_id,
             authorized_names,
             extranonce1,
+            requested_version_rolling_mask: version_rolling_mask.clone(),
             version_rolling_mask,
             version_rolling_min_bit,
+            version_rolling_allowed: Arc::new(Mutex::new(true)),
+            minimum_difficulty: None,
             tx_sv1_bridge,
             tx_outgoing,
             first_job_received,
             extranonce2_len,
+            last_job: None,
+            target: vec![],
             difficulty_mgmt,
             upstream_difficulty_config,
+            worker_stats,
+            last_activity: now_secs(),
         }
     }
-    /// Instantiate a new `Downstream`.
+
+    /// Authorized `Downstream` with an 8 bytes extranonce1 and extranonce2, used by the unit
+    /// tests of the `downstream_sv1` modules which set the fields they exercise. The receivers
+    /// of the messages sent to the `Bridge` and to the miner are returned along with it.
+    #[cfg(test)]
+    pub(super) fn for_test() -> (
+        Self,
+        Receiver<DownstreamMessages>,
+        Receiver<json_rpc::Message>,
+    ) {
+        let (tx_sv1_bridge, rx_sv1_bridge) = async_channel::unbounded();
+        let (tx_outgoing, rx_outgoing) = async_channel::unbounded();
+        let downstream = Self::new(
+            1,
+            vec!["user".to_string()],
+            vec![0; 8],
+            None,
+            None,
+            tx_sv1_bridge,
+            tx_outgoing,
+            true,
+            8,
+            DownstreamDifficultyConfig {
+                min_individual_miner_hashrate: 0.0,
+                shares_per_minute: 1.0,
+                submits_since_last_update: 0,
+                timestamp_of_last_update: 0,
+            },
+            Arc::new(Mutex::new(UpstreamDifficultyConfig {
+                channel_diff_update_interval: 60,
+                channel_nominal_hashrate: 0.0,
+                timestamp_of_last_update: 0,
+                should_aggregate: false,
+            })),
+            Arc::new(Mutex::new(WorkerStatsRegistry::new())),
+        );
+        (downstream, rx_sv1_bridge, rx_outgoing)
+    }
+
+    /// Instantiate a new `Downstream`. The SV1 messages are read from `socket_reader` and
+    /// written to `socket_writer`, the two halves of either a plain TCP or a TLS connection.
     #[allow(clippy::too_many_arguments)]
-    pub async fn new_downstream(
-        stream: TcpStream,
+    pub async fn new_downstream<R, W>(
+        socket_reader: R,
+        mut socket_writer: W,
         connection_id: u32,
         tx_sv1_bridge: Sender<DownstreamMessages>,
         mut rx_sv1_notify: broadcast::Receiver<server_to_client::Notify<'static>>,
@@ -107,17 +177,18 @@ impl Downstream {
         host: String,
         difficulty_config: DownstreamDifficultyConfig,
         upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
-    ) {
-        let stream = std::sync::Arc::new(stream);
+        worker_stats: Arc<Mutex<WorkerStatsRegistry>>,
+        version_rolling_allowed: Arc<Mutex<bool>>,
+        idle_timeout_secs: u64,
+        connection_slot: ConnectionSlot,
+    ) where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        worker_stats.super_safe_lock(|s| s.add_worker(connection_id, host.clone()));
 
-        // Reads and writes from Downstream SV1 Mining Device Client
-        let (socket_reader, socket_writer) = (stream.clone(), stream);
         let (tx_outgoing, receiver_outgoing) = bounded(10);
 
-        let socket_writer_clone = socket_writer.clone();
-        // Used to send SV1 `mining.notify` messages to the Downstreams
-        let _socket_writer_notify = socket_writer;
-
         let downstream = Arc::new(Mutex::new(Downstream {
             connection_id,
             authorized_names: vec![],
@@ -125,12 +196,19 @@ impl Downstream {
             //extranonce1: extranonce1.to_vec(),
             version_rolling_mask: None,
             version_rolling_min_bit: None,
+            requested_version_rolling_mask: None,
+            version_rolling_allowed,
+            minimum_difficulty: None,
             tx_sv1_bridge,
             tx_outgoing,
             first_job_received: false,
             extranonce2_len,
+            last_job: None,
+            target: vec![],
             difficulty_mgmt: difficulty_config,
             upstream_difficulty_config,
+            worker_stats: worker_stats.clone(),
+            last_activity: now_secs(),
         }));
         let self_ = downstream.clone();
 
@@ -141,7 +219,17 @@ impl Downstream {
         // a task down, we should `break` out of the loop, so that the `kill` function can send the shutdown broadcast.
         // EXTRA: The since all downstream tasks rely on receiving messages with a future (either TCP recv or Receiver<_>)
         // we use the futures::select! macro to merge the receiving end of a task channels into a single loop within the task
-        let (tx_shutdown, rx_shutdown): (Sender<bool>, Receiver<bool>) = async_channel::bounded(3);
+        let (tx_shutdown, rx_shutdown): (Sender<bool>, Receiver<bool>) = async_channel::bounded(4);
+
+        if idle_timeout_secs > 0 {
+            Self::start_idle_timeout(
+                downstream.clone(),
+                idle_timeout_secs,
+                host.clone(),
+                tx_shutdown.clone(),
+                rx_shutdown.clone(),
+            );
+        }
 
         let rx_shutdown_clone = rx_shutdown.clone();
         let tx_shutdown_clone = tx_shutdown.clone();
@@ -151,7 +239,7 @@ impl Downstream {
         // role, or the message is sent upwards to the Bridge for translation into a SV2 message
         // and then sent to the SV2 Upstream role.
         let _socket_reader_task = task::spawn(async move {
-            let reader = BufReader::new(&*socket_reader);
+            let reader = BufReader::new(socket_reader);
             let mut messages = FramedRead::new(
                 async_compat::Compat::new(reader),
                 LinesCodec::new_with_max_length(MAX_LINE_LENGTH),
@@ -172,6 +260,9 @@ impl Downstream {
 
                                 // if message is Submit Shares update difficulty management
                                 if let v1::Message::StandardRequest(standard_req) = incoming.clone() {
+                                    if standard_req.method == "mining.subscribe" || standard_req.method == "mining.submit" {
+                                        handle_result!(tx_status_reader, self_.safe_lock(|d| d.on_activity()).map_err(|_e| Error::PoisonLock));
+                                    }
                                     if let Ok(Submit{..}) = standard_req.try_into() {
                                         handle_result!(tx_status_reader, Self::save_share(self_.clone()));
                                     }
@@ -222,7 +313,7 @@ impl Downstream {
                             }
                         };
                         debug!("Sending to Mining Device: {} - {:?}", &host_, &to_send);
-                        let res = (&*socket_writer_clone)
+                        let res = socket_writer
                                     .write_all(to_send.as_bytes())
                                     .await;
                         handle_result!(tx_status_writer, res);
@@ -264,20 +355,35 @@ impl Downstream {
                         Self::init_difficulty_management(downstream.clone(), &target).await
                     );
                     let message =
-                        handle_result!(tx_status_notify, Self::get_set_difficulty(target));
+                        handle_result!(tx_status_notify, Self::get_set_difficulty(target.clone()));
+                    handle_result!(
+                        tx_status_notify,
+                        Self::on_new_target(downstream.clone(), target)
+                    );
                     handle_result!(
                         tx_status_notify,
                         Downstream::send_message_downstream(downstream.clone(), message).await
                     );
 
+                    if let Some(message) = handle_result!(
+                        tx_status_notify,
+                        Self::update_version_rolling(downstream.clone())
+                    ) {
+                        handle_result!(
+                            tx_status_notify,
+                            Downstream::send_message_downstream(downstream.clone(), message).await
+                        );
+                    }
+
                     let sv1_mining_notify_msg = last_notify.clone().unwrap();
-                    let message: json_rpc::Message = sv1_mining_notify_msg.into();
+                    let message: json_rpc::Message = sv1_mining_notify_msg.clone().into();
                     handle_result!(
                         tx_status_notify,
                         Downstream::send_message_downstream(downstream.clone(), message).await
                     );
                     if let Err(_e) = downstream.clone().safe_lock(|s| {
                         s.first_job_received = true;
+                        s.last_job = Some(sv1_mining_notify_msg);
                     }) {
                         debug!("\nDownstream: Poison Lock - first_job_received\n");
                         break;
@@ -292,6 +398,14 @@ impl Downstream {
 
 
                             let sv1_mining_notify_msg = handle_result!(tx_status_notify, res);
+                            // the new job may come with a different version rolling permission
+                            if let Some(message) = handle_result!(tx_status_notify, Self::update_version_rolling(downstream.clone())) {
+                                handle_result!(tx_status_notify, Downstream::send_message_downstream(downstream.clone(), message).await);
+                            }
+                            if let Err(_e) = downstream.safe_lock(|d| d.last_job = Some(sv1_mining_notify_msg.clone())) {
+                                debug!("\nDownstream: Poison Lock - last_job\n");
+                                break;
+                            }
                             let message: json_rpc::Message = sv1_mining_notify_msg.into();
                             handle_result!(tx_status_notify, Downstream::send_message_downstream(downstream.clone(), message).await);
                         },
@@ -312,6 +426,8 @@ impl Downstream {
                 }
             }
             let _ = Self::remove_miner_hashrate_from_channel(self_);
+            let _ = worker_stats.safe_lock(|s| s.remove_worker(connection_id));
+            drop(connection_slot);
             kill(&tx_shutdown).await;
             warn!(
                 "Downstream: Shutting down sv1 downstream job notifier for {}",
@@ -321,9 +437,14 @@ impl Downstream {
     }
 
     /// Accept connections from one or more SV1 Downstream roles (SV1 Mining Devices) and create a
-    /// new `Downstream` for each connection.
+    /// new `Downstream` for each connection. If `tls_acceptor` is set, the connections are
+    /// expected to be SV1 over TLS (stratum+ssl), otherwise plain SV1 over TCP.
+    #[allow(clippy::too_many_arguments)]
     pub fn accept_connections(
         downstream_addr: SocketAddr,
+        tls_acceptor: Option<TlsAcceptor>,
+        connection_config: DownstreamConnectionConfig,
+        connection_slots: ConnectionSlots,
         tx_sv1_submit: Sender<DownstreamMessages>,
         tx_mining_notify: broadcast::Sender<server_to_client::Notify<'static>>,
         tx_status: status::Sender,
@@ -337,38 +458,147 @@ impl Downstream {
 
             while let Some(stream) = downstream_incoming.next().await {
                 let stream = stream.expect("Err on SV1 Downstream connection stream");
-                let expected_hash_rate = downstream_difficulty_config.min_individual_miner_hashrate;
-                let open_sv1_downstream = bridge
-                    .safe_lock(|s| s.on_new_sv1_connection(expected_hash_rate))
-                    .unwrap();
-
                 let host = stream.peer_addr().unwrap().to_string();
-                match open_sv1_downstream {
-                    Ok(opened) => {
-                        info!("PROXY SERVER - ACCEPTING FROM DOWNSTREAM: {}", host);
-                        Downstream::new_downstream(
+                let connection_slot = match connection_slots.try_reserve() {
+                    Some(slot) => slot,
+                    None => {
+                        warn!(
+                            "Rejecting connection from {}: {} SV1 connections already open",
+                            host,
+                            connection_slots.open_connections()
+                        );
+                        // dropping the stream closes the connection
+                        continue;
+                    }
+                };
+                if connection_config.tcp_keepalive_secs > 0 {
+                    if let Err(e) = set_tcp_keepalive(&stream, connection_config.tcp_keepalive_secs)
+                    {
+                        warn!("Failed to set TCP keepalive for {}: {}", host, e);
+                    }
+                }
+                match &tls_acceptor {
+                    None => {
+                        Self::open_downstream(
+                            stream.clone(),
                             stream,
-                            opened.channel_id,
+                            host,
+                            connection_slot,
                             tx_sv1_submit.clone(),
-                            tx_mining_notify.subscribe(),
+                            tx_mining_notify.clone(),
                             tx_status.listener_to_connection(),
-                            opened.extranonce,
-                            opened.last_notify,
-                            opened.extranonce2_len as usize,
-                            host,
+                            bridge.clone(),
                             downstream_difficulty_config.clone(),
                             upstream_difficulty_config.clone(),
+                            connection_config.clone(),
                         )
-                        .await;
+                        .await
                     }
-                    Err(e) => {
-                        tracing::error!("Failed to create a new downstream connection: {:?}", e);
+                    Some(acceptor) => {
+                        // the handshake is done in its own task so that a slow or malicious
+                        // client can not block the listener
+                        let acceptor = acceptor.clone();
+                        let tx_sv1_submit = tx_sv1_submit.clone();
+                        let tx_mining_notify = tx_mining_notify.clone();
+                        let tx_status = tx_status.listener_to_connection();
+                        let bridge = bridge.clone();
+                        let downstream_difficulty_config = downstream_difficulty_config.clone();
+                        let upstream_difficulty_config = upstream_difficulty_config.clone();
+                        let connection_config = connection_config.clone();
+                        task::spawn(async move {
+                            let handshake = async_std::future::timeout(
+                                std::time::Duration::from_secs(SUBSCRIBE_TIMEOUT_SECS),
+                                acceptor.accept(stream),
+                            )
+                            .await;
+                            let stream = match handshake {
+                                Ok(Ok(stream)) => stream,
+                                Ok(Err(e)) => {
+                                    warn!("TLS handshake with {} failed: {}", host, e);
+                                    return;
+                                }
+                                Err(_) => {
+                                    warn!("TLS handshake with {} timed out", host);
+                                    return;
+                                }
+                            };
+                            let (socket_reader, socket_writer) = stream.split();
+                            Self::open_downstream(
+                                socket_reader,
+                                socket_writer,
+                                host,
+                                connection_slot,
+                                tx_sv1_submit,
+                                tx_mining_notify,
+                                tx_status,
+                                bridge,
+                                downstream_difficulty_config,
+                                upstream_difficulty_config,
+                                connection_config,
+                            )
+                            .await
+                        });
                     }
                 }
             }
         });
     }
 
+    /// Opens a new channel with the `Bridge` for an accepted SV1 connection and starts the
+    /// `Downstream` handling it, `connection_slot` is released when the connection is closed.
+    #[allow(clippy::too_many_arguments)]
+    async fn open_downstream<R, W>(
+        socket_reader: R,
+        socket_writer: W,
+        host: String,
+        connection_slot: ConnectionSlot,
+        tx_sv1_submit: Sender<DownstreamMessages>,
+        tx_mining_notify: broadcast::Sender<server_to_client::Notify<'static>>,
+        tx_status: status::Sender,
+        bridge: Arc<Mutex<crate::proxy::Bridge>>,
+        downstream_difficulty_config: DownstreamDifficultyConfig,
+        upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+        connection_config: DownstreamConnectionConfig,
+    ) where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let worker_stats = bridge.safe_lock(|s| s.worker_stats()).unwrap();
+
+        let expected_hash_rate = downstream_difficulty_config.min_individual_miner_hashrate;
+        let open_sv1_downstream = bridge
+            .safe_lock(|s| s.on_new_sv1_connection(expected_hash_rate))
+            .unwrap();
+
+        match open_sv1_downstream {
+            Ok(opened) => {
+                info!("PROXY SERVER - ACCEPTING FROM DOWNSTREAM: {}", host);
+                Downstream::new_downstream(
+                    socket_reader,
+                    socket_writer,
+                    opened.channel_id,
+                    tx_sv1_submit,
+                    tx_mining_notify.subscribe(),
+                    tx_status,
+                    opened.extranonce,
+                    opened.last_notify,
+                    opened.extranonce2_len as usize,
+                    host,
+                    downstream_difficulty_config,
+                    upstream_difficulty_config,
+                    worker_stats,
+                    opened.version_rolling_allowed,
+                    connection_config.idle_timeout_secs,
+                    connection_slot,
+                )
+                .await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to create a new downstream connection: {:?}", e);
+            }
+        }
+    }
+
     /// As SV1 messages come in, determines if the message response needs to be translated to SV2
     /// and sent to the `Upstream`, or if a direct response can be sent back by the `Translator`
     /// (SV1 and SV2 protocol messages are NOT 1-to-1).
@@ -428,7 +658,8 @@ impl Downstream {
 /// Implements `IsServer` for `Downstream` to handle the SV1 messages.
 impl IsServer<'static> for Downstream {
     /// Handle the incoming `mining.configure` message which is received after a Downstream role is
-    /// subscribed and authorized. Contains the version rolling mask parameters.
+    /// subscribed and authorized. Contains the version rolling mask parameters and the minimum
+    /// difficulty requested by the Downstream.
     fn handle_configure(
         &mut self,
         request: &client_to_server::Configure,
@@ -436,28 +667,18 @@ impl IsServer<'static> for Downstream {
         info!("Down: Configuring");
         debug!("Down: Handling mining.configure: {:?}", &request);
 
-        // TODO 0x1FFFE000 should be configured
-        // = 11111111111111110000000000000
-        // this is a reasonable default as it allows all 16 version bits to be used
-        // If the tproxy/pool needs to use some version bits this needs to be configurable
-        // so upstreams can negotiate with downstreams. When that happens this should consider
-        // the min_bit_count in the mining.configure message
-        self.version_rolling_mask = request
-            .version_rolling_mask()
-            .map(|mask| HexU32Be(mask & 0x1FFFE000));
+        self.requested_version_rolling_mask = request.version_rolling_mask();
         self.version_rolling_min_bit = request.version_rolling_min_bit_count();
-
+        let version_rolling = self.negotiate_version_rolling();
         debug!(
             "Negotiated version_rolling_mask is {:?}",
             self.version_rolling_mask
         );
-        (
-            Some(server_to_client::VersionRollingParams::new(
-                self.version_rolling_mask.clone().unwrap_or(HexU32Be(0)),
-                self.version_rolling_min_bit.clone().unwrap_or(HexU32Be(0)),
-            ).expect("Version mask invalid, automatic version mask selection not supported, please change it in carte::downstream_sv1::mod.rs")),
-            Some(false),
-        )
+
+        self.minimum_difficulty = request.minimum_difficulty();
+        let minimum_difficulty = self.minimum_difficulty.map(|_| true);
+
+        (version_rolling, minimum_difficulty)
     }
 
     /// Handle the response to a `mining.subscribe` message received from the client.
@@ -494,8 +715,6 @@ impl IsServer<'static> for Downstream {
         info!("Down: Submitting Share");
         debug!("Down: Handling mining.submit: {:?}", &request);
 
-        // TODO: Check if receiving valid shares by adding diff field to Downstream
-
         if self.first_job_received {
             let to_send = SubmitShareWithChannelId {
                 channel_id: self.connection_id,
@@ -511,7 +730,33 @@ impl IsServer<'static> for Downstream {
         true
     }
 
-    /// Indicates to the server that the client supports the mining.set_extranonce method.
+    /// Checks the share against the last job and target sent to the miner, so that shares that
+    /// would be refused upstream are answered with the right error and never forwarded.
+    fn validate_submit(
+        &self,
+        request: &client_to_server::Submit<'static>,
+    ) -> Result<(), server_to_client::SubmitError> {
+        let res = self.check_share(request);
+        if let Err(e) = &res {
+            info!("Down: Share rejected: {}", e.message());
+            let outcome = match e {
+                server_to_client::SubmitError::JobNotFound => ShareOutcome::Stale,
+                _ => ShareOutcome::Rejected,
+            };
+            let connection_id = self.connection_id;
+            if self
+                .worker_stats
+                .safe_lock(|s| s.on_share(connection_id, outcome))
+                .is_err()
+            {
+                debug!("\nDownstream: Poison Lock - worker_stats\n");
+            }
+        }
+        res
+    }
+
+    /// Indicates to the server that the client supports the mining.set_extranonce method. The
+    /// extranonce of a Downstream never changes during the connection so nothing has to be done.
     fn handle_extranonce_subscribe(&self) {}
 
     /// Checks if a Downstream role is authorized.
@@ -522,6 +767,14 @@ impl IsServer<'static> for Downstream {
     /// Authorizes a Downstream role.
     fn authorize(&mut self, name: &str) {
         self.authorized_names.push(name.to_string());
+        let connection_id = self.connection_id;
+        if self
+            .worker_stats
+            .safe_lock(|s| s.on_authorize(connection_id, name))
+            .is_err()
+        {
+            debug!("\nDownstream: Poison Lock - worker_stats\n");
+        }
     }
 
     /// Sets the `extranonce1` field sent in the SV1 `mining.notify` message to the value specified
@@ -590,7 +843,8 @@ mod tests {
             0, 0, 0, 0, 0,
         ];
         let actual = Downstream::difficulty_from_target(target).unwrap();
-        let expect = 512.0;
+        // 0x00000000ffff.. / 0x00000000007fff80.. is not an integer
+        let expect = 512.0 + 65536.0 / 8388480.0;
         assert_eq!(actual, expect);
     }
 }
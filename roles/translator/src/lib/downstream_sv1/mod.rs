@@ -1,7 +1,11 @@
 use roles_logic_sv2::mining_sv2::Target;
 use v1::{client_to_server::Submit, utils::HexU32Be};
+pub mod connection_management;
 pub mod diff_management;
 pub mod downstream;
+pub mod share_validation;
+pub mod tls;
+pub mod version_rolling;
 pub use downstream::Downstream;
 
 /// This constant is used as a check to ensure clients
@@ -11,6 +15,11 @@ pub use downstream::Downstream;
 /// `mining.subscribe` messages that init connections and take up compute
 const SUBSCRIBE_TIMEOUT_SECS: u64 = 10;
 
+/// Version bits that can be rolled on a SV2 channel whose jobs have `version_rolling_allowed`
+/// set, as defined by BIP320.
+/// = 11111111111111110000000000000
+const UPSTREAM_VERSION_ROLLING_MASK: u32 = 0x1FFFE000;
+
 /// enum of messages sent to the Bridge
 #[derive(Debug)]
 pub enum DownstreamMessages {
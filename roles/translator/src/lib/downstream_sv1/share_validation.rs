@@ -0,0 +1,156 @@
+use super::Downstream;
+
+use roles_logic_sv2::{
+    mining_sv2::Target,
+    utils::{merkle_root_from_path, u256_to_block_hash},
+};
+use stratum_common::bitcoin::{blockdata::block::BlockHeader, hashes::Hash};
+use v1::{client_to_server::Submit, server_to_client::SubmitError};
+
+/// Maximum number of seconds a share ntime can be ahead of the local clock, same as the limit
+/// used by bitcoin to accept a block.
+const MAX_NTIME_OFFSET_SECS: u32 = 2 * 60 * 60;
+
+impl Downstream {
+    /// Checks a `mining.submit` against the last job and the last target sent to the miner. The
+    /// proxy channel only accepts shares for the last job received from the Upstream, so any
+    /// other job is considered stale.
+    pub(super) fn check_share(&self, share: &Submit<'static>) -> Result<(), SubmitError> {
+        let job = match &self.last_job {
+            Some(job) if job.job_id == share.job_id => job,
+            _ => return Err(SubmitError::JobNotFound),
+        };
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_secs() as u32;
+        if share.time.0 < job.time.0 || share.time.0 > now.saturating_add(MAX_NTIME_OFFSET_SECS) {
+            return Err(SubmitError::InvalidNTime);
+        }
+
+        // regarding version masking see https://github.com/slushpool/stratumprotocol/blob/master/stratum-extensions.mediawiki#changes-in-request-miningsubmit
+        let version = match (&share.version_bits, &self.version_rolling_mask) {
+            (Some(vb), Some(mask)) if mask.check_mask(vb) => {
+                (job.version.0 & !mask.0) | (vb.0 & mask.0)
+            }
+            (None, _) => job.version.0,
+            _ => return Err(SubmitError::InvalidVersionBits),
+        };
+
+        // the target is set before the first job is sent, if for any reason it is not there is
+        // nothing to check the share against and it is left to the Bridge
+        let target: [u8; 32] = match self.target.clone().try_into() {
+            Ok(target) => target,
+            Err(_) => return Ok(()),
+        };
+        let target: Target = target.into();
+
+        let extranonce = [&self.extranonce1[..], share.extra_nonce2.as_ref()].concat();
+        let merkle_root: [u8; 32] = merkle_root_from_path(
+            job.coin_base1.as_ref(),
+            job.coin_base2.as_ref(),
+            &extranonce,
+            &job.merkle_branch,
+        )
+        .and_then(|root| root.try_into().ok())
+        .ok_or_else(|| SubmitError::Other("Invalid coinbase".to_string()))?;
+        let header = BlockHeader {
+            version: version as i32,
+            prev_blockhash: u256_to_block_hash(job.prev_hash.0.clone()),
+            merkle_root: Hash::from_inner(merkle_root),
+            time: share.time.0,
+            bits: job.bits.0,
+            nonce: share.nonce.0,
+        };
+        let hash: Target = header.block_hash().as_hash().into_inner().into();
+        if hash > target {
+            return Err(SubmitError::LowDifficulty);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use stratum_common::bitcoin::{
+        self, blockdata::witness::Witness, util::psbt::serialize::Serialize,
+    };
+    use v1::{
+        server_to_client::Notify,
+        utils::{Extranonce, HexU32Be, PrevHash},
+    };
+
+    fn downstream_with_job(target: [u8; 32]) -> Downstream {
+        let in_ = bitcoin::TxIn {
+            previous_output: bitcoin::OutPoint::null(),
+            script_sig: vec![89_u8; 16].into(),
+            sequence: bitcoin::Sequence(0),
+            witness: Witness::from_vec(vec![]),
+        };
+        let tx = bitcoin::Transaction {
+            version: 1,
+            lock_time: bitcoin::PackedLockTime(0),
+            input: vec![in_],
+            output: vec![],
+        }
+        .serialize();
+        let (mut downstream, _, _) = Downstream::for_test();
+        downstream.last_job = Some(Notify {
+            job_id: "1".to_string(),
+            prev_hash: PrevHash([3_u8; 32].into()),
+            coin_base1: tx[0..42].to_vec().into(),
+            coin_base2: tx[58..].to_vec().into(),
+            merkle_branch: vec![],
+            version: HexU32Be(0x2000_0000),
+            bits: HexU32Be(0x1d00_ffff),
+            time: HexU32Be(1_700_000_000),
+            clean_jobs: true,
+        });
+        downstream.target = target.to_vec();
+        downstream
+    }
+
+    fn share(job_id: &str, time: u32) -> Submit<'static> {
+        Submit {
+            user_name: "user".to_string(),
+            job_id: job_id.to_string(),
+            extra_nonce2: Extranonce::try_from(vec![0; 8]).unwrap(),
+            time: HexU32Be(time),
+            nonce: HexU32Be(1),
+            version_bits: None,
+            id: 0,
+        }
+    }
+
+    #[test]
+    fn classifies_invalid_shares() {
+        let downstream = downstream_with_job([255; 32]);
+        assert_eq!(downstream.check_share(&share("1", 1_700_000_000)), Ok(()));
+        assert_eq!(
+            downstream.check_share(&share("2", 1_700_000_000)),
+            Err(SubmitError::JobNotFound)
+        );
+        assert_eq!(
+            downstream.check_share(&share("1", 1_699_999_999)),
+            Err(SubmitError::InvalidNTime)
+        );
+        assert_eq!(
+            downstream.check_share(&share("1", u32::MAX)),
+            Err(SubmitError::InvalidNTime)
+        );
+        let mut with_version_bits = share("1", 1_700_000_000);
+        with_version_bits.version_bits = Some(HexU32Be(0x0000_2000));
+        assert_eq!(
+            downstream.check_share(&with_version_bits),
+            Err(SubmitError::InvalidVersionBits)
+        );
+
+        let downstream = downstream_with_job([0; 32]);
+        assert_eq!(
+            downstream.check_share(&share("1", 1_700_000_000)),
+            Err(SubmitError::LowDifficulty)
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use super::{Downstream, UPSTREAM_VERSION_ROLLING_MASK};
+
+use crate::error::{Error, ProxyResult};
+use roles_logic_sv2::utils::Mutex;
+use std::sync::Arc;
+use tracing::{info, warn};
+use v1::{json_rpc, server_to_client::VersionRollingParams, utils::HexU32Be, IsServer};
+
+impl Downstream {
+    /// Mask that the Downstream can roll: the mask requested in `mining.configure` restricted to
+    /// the bits the Upstream channel allows. `None` if the Downstream did not ask for version
+    /// rolling.
+    pub(super) fn negotiated_version_rolling_mask(&self) -> Option<HexU32Be> {
+        let requested = self.requested_version_rolling_mask.as_ref()?;
+        // if the lock is poisoned no version bits are allowed rather than rolling unchecked bits
+        let allowed = self
+            .version_rolling_allowed
+            .safe_lock(|a| *a)
+            .unwrap_or(false);
+        let upstream_mask = if allowed {
+            UPSTREAM_VERSION_ROLLING_MASK
+        } else {
+            0
+        };
+        Some(HexU32Be(requested.0 & upstream_mask))
+    }
+
+    /// Negotiates the version rolling mask as described in BIP310 and returns the
+    /// `version-rolling` part of the `mining.configure` response. If the negotiated mask has less
+    /// bits than the `min-bit-count` requested by the Downstream, version rolling is refused.
+    pub(super) fn negotiate_version_rolling(&mut self) -> Option<VersionRollingParams> {
+        let mask = self.negotiated_version_rolling_mask()?;
+        let min_bit_count = self.version_rolling_min_bit.clone().unwrap_or(HexU32Be(0));
+        if mask.0.count_ones() < min_bit_count.0 {
+            warn!(
+                "Down: version rolling refused, {:?} has less than {} bits",
+                mask, min_bit_count.0
+            );
+            self.requested_version_rolling_mask = None;
+            self.version_rolling_mask = None;
+            return Some(VersionRollingParams {
+                version_rolling: false,
+                version_rolling_mask: HexU32Be(0),
+                version_rolling_min_bit_count: min_bit_count,
+            });
+        }
+        self.version_rolling_mask = Some(mask.clone());
+        Some(VersionRollingParams {
+            version_rolling: true,
+            version_rolling_mask: mask,
+            version_rolling_min_bit_count: min_bit_count,
+        })
+    }
+
+    /// Called before sending a new job to the Downstream. If the version rolling permission of
+    /// the Upstream channel changed since the last job, updates the negotiated mask and returns
+    /// the `mining.set_version_mask` to be sent before the job.
+    #[allow(clippy::result_large_err)]
+    pub(super) fn update_version_rolling(
+        self_: Arc<Mutex<Self>>,
+    ) -> ProxyResult<'static, Option<json_rpc::Message>> {
+        self_
+            .safe_lock(|d| match d.negotiated_version_rolling_mask() {
+                Some(mask) if Some(&mask) != d.version_rolling_mask.as_ref() => {
+                    info!(
+                        "Down: version rolling mask of {} changed to {:?}",
+                        d.connection_id, mask
+                    );
+                    Ok(Some(d.update_version_rolling_mask(mask)?))
+                }
+                _ => Ok(None),
+            })
+            .map_err(|_e| Error::PoisonLock)?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn downstream(requested_mask: u32, min_bit_count: u32) -> Downstream {
+        let (mut downstream, _, _) = Downstream::for_test();
+        downstream.requested_version_rolling_mask = Some(HexU32Be(requested_mask));
+        downstream.version_rolling_mask = Some(HexU32Be(requested_mask));
+        downstream.version_rolling_min_bit = Some(HexU32Be(min_bit_count));
+        downstream
+    }
+
+    #[test]
+    fn intersects_requested_mask_with_upstream() {
+        let mut d = downstream(0xffff_ffff, 2);
+        let params = d.negotiate_version_rolling().unwrap();
+        assert!(params.version_rolling);
+        assert_eq!(params.version_rolling_mask, HexU32Be(0x1FFF_E000));
+        assert_eq!(d.version_rolling_mask, Some(HexU32Be(0x1FFF_E000)));
+
+        let mut d = downstream(0x0000_6000, 2);
+        let params = d.negotiate_version_rolling().unwrap();
+        assert_eq!(params.version_rolling_mask, HexU32Be(0x0000_6000));
+
+        // not enough bits for the miner
+        let mut d = downstream(0x0000_6000, 3);
+        let params = d.negotiate_version_rolling().unwrap();
+        assert!(!params.version_rolling);
+        assert_eq!(d.version_rolling_mask, None);
+    }
+
+    #[test]
+    fn pushes_set_version_mask_when_permission_changes() {
+        let mut d = downstream(0x1FFF_E000, 0);
+        d.negotiate_version_rolling();
+        let allowed = d.version_rolling_allowed.clone();
+        let d = Arc::new(Mutex::new(d));
+        assert!(Downstream::update_version_rolling(d.clone())
+            .unwrap()
+            .is_none());
+
+        allowed.safe_lock(|a| *a = false).unwrap();
+        let message = Downstream::update_version_rolling(d.clone())
+            .unwrap()
+            .unwrap();
+        let expected: json_rpc::Message = v1::server_to_client::SetVersionMask {
+            version_mask: HexU32Be(0),
+        }
+        .into();
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            serde_json::to_string(&expected).unwrap()
+        );
+        assert_eq!(
+            d.safe_lock(|d| d.version_rolling_mask.clone()).unwrap(),
+            Some(HexU32Be(0))
+        );
+        assert!(Downstream::update_version_rolling(d).unwrap().is_none());
+    }
+}
@@ -50,6 +50,8 @@ pub enum Error<'a> {
     Io(std::io::Error),
     /// Errors due to invalid extranonce from upstream
     InvalidExtranonce(String),
+    /// Errors on bad certificate or private key for the SV1 TLS listener.
+    InvalidTlsConfig(String),
     /// Errors on bad `String` to `int` conversion.
     ParseInt(std::num::ParseIntError),
     /// Errors from `roles_logic_sv2` crate.
@@ -88,6 +90,7 @@ impl<'a> fmt::Display for Error<'a> {
             CodecNoise(ref e) => write!(f, "Noise error: `{:?}", e),
             FramingSv2(ref e) => write!(f, "Framing SV2 error: `{:?}`", e),
             InvalidExtranonce(ref e) => write!(f, "Invalid Extranonce error: `{:?}", e),
+            InvalidTlsConfig(ref e) => write!(f, "Invalid TLS configuration: `{:?}`", e),
             Io(ref e) => write!(f, "I/O error: `{:?}", e),
             ParseInt(ref e) => write!(f, "Bad convert from `String` to `int`: `{:?}`", e),
             RolesSv2Logic(ref e) => write!(f, "Roles SV2 Logic Error: `{:?}`", e),
@@ -2,6 +2,7 @@ pub mod downstream_sv1;
 pub mod error;
 pub mod proxy;
 pub mod proxy_config;
+pub mod stats;
 pub mod status;
 pub mod upstream_sv2;
 pub mod utils;
@@ -3,7 +3,8 @@ use async_std::task;
 use roles_logic_sv2::{
     channel_logic::channel_factory::{ExtendedChannelKind, ProxyExtendedChannelFactory, Share},
     mining_sv2::{
-        ExtendedExtranonce, NewExtendedMiningJob, SetNewPrevHash, SubmitSharesExtended, Target,
+        ExtendedExtranonce, NewExtendedMiningJob, SetNewPrevHash, SubmitSharesError,
+        SubmitSharesExtended, Target,
     },
     parsers::Mining,
     utils::{GroupId, Mutex},
@@ -18,6 +19,7 @@ use super::super::{
         Error::{self, PoisonLock},
         ProxyResult,
     },
+    stats::{ShareOutcome, WorkerStatsRegistry},
     status,
 };
 use error_handling::handle_result;
@@ -64,6 +66,11 @@ pub struct Bridge {
     last_p_hash: Option<SetNewPrevHash<'static>>,
     target: Arc<Mutex<Vec<u8>>>,
     last_job_id: u32,
+    /// Per-worker statistics, shared with every `Downstream`.
+    worker_stats: Arc<Mutex<WorkerStatsRegistry>>,
+    /// `version_rolling_allowed` of the last job sent to the Downstreams, shared with every
+    /// `Downstream` so that the negotiated version rolling masks follow the Upstream permission.
+    version_rolling_allowed: Arc<Mutex<bool>>,
 }
 
 impl Bridge {
@@ -107,9 +114,16 @@ impl Bridge {
             last_p_hash: None,
             target,
             last_job_id: 0,
+            worker_stats: Arc::new(Mutex::new(WorkerStatsRegistry::new())),
+            version_rolling_allowed: Arc::new(Mutex::new(true)),
         }))
     }
 
+    /// Returns the per-worker statistics of the SV1 connections handled by this `Bridge`.
+    pub fn worker_stats(&self) -> Arc<Mutex<WorkerStatsRegistry>> {
+        self.worker_stats.clone()
+    }
+
     #[allow(clippy::result_large_err)]
     pub fn on_new_sv1_connection(
         &mut self,
@@ -131,6 +145,7 @@ impl Bridge {
                                 extranonce,
                                 target: self.target.clone(),
                                 extranonce2_len,
+                                version_rolling_allowed: self.version_rolling_allowed.clone(),
                             });
                         }
                         Mining::OpenMiningChannelError(_) => todo!(),
@@ -206,15 +221,17 @@ impl Bridge {
         self_: Arc<Mutex<Self>>,
         share: SubmitShareWithChannelId,
     ) -> ProxyResult<'static, ()> {
-        let (tx_sv2_submit_shares_ext, target_mutex, tx_status) = self_
+        let (tx_sv2_submit_shares_ext, target_mutex, tx_status, worker_stats) = self_
             .safe_lock(|s| {
                 (
                     s.tx_sv2_submit_shares_ext.clone(),
                     s.target.clone(),
                     s.tx_status.clone(),
+                    s.worker_stats.clone(),
                 )
             })
             .map_err(|_| PoisonLock)?;
+        let connection_id = share.channel_id;
         let upstream_target: [u8; 32] = target_mutex
             .safe_lock(|t| t.clone())
             .map_err(|_| PoisonLock)?
@@ -235,13 +252,27 @@ impl Bridge {
 
         match res {
             Ok(Ok(OnNewShare::SendErrorDownstream(e))) => {
+                let error_code = e.error_code.to_vec();
                 error!(
                     "Submit share error {:?}",
-                    std::str::from_utf8(&e.error_code.to_vec()[..])
+                    std::str::from_utf8(&error_code[..])
                 );
+                // the proxy only keeps the last valid job, so a share for any other job is stale
+                let outcome =
+                    if error_code == SubmitSharesError::invalid_job_id_error_code().as_bytes() {
+                        ShareOutcome::Stale
+                    } else {
+                        ShareOutcome::Rejected
+                    };
+                worker_stats
+                    .safe_lock(|s| s.on_share(connection_id, outcome))
+                    .map_err(|_| PoisonLock)?;
             }
             Ok(Ok(OnNewShare::SendSubmitShareUpstream((share, _)))) => {
                 info!("SHARE MEETS UPSTREAM TARGET");
+                worker_stats
+                    .safe_lock(|s| s.on_share(connection_id, ShareOutcome::Accepted))
+                    .map_err(|_| PoisonLock)?;
                 match share {
                     Share::Extended(share) => {
                         tx_sv2_submit_shares_ext.send(share).await?;
@@ -254,6 +285,9 @@ impl Bridge {
             Ok(Ok(OnNewShare::RelaySubmitShareUpstream)) => unreachable!(),
             Ok(Ok(OnNewShare::ShareMeetDownstreamTarget)) => {
                 debug!("SHARE MEETS DOWNSTREAM TARGET");
+                worker_stats
+                    .safe_lock(|s| s.on_share(connection_id, ShareOutcome::Accepted))
+                    .map_err(|_| PoisonLock)?;
             }
             // Proxy do not have JD capabilities
             Ok(Ok(OnNewShare::ShareMeetBitcoinTarget(..))) => unreachable!(),
@@ -301,6 +335,27 @@ impl Bridge {
         })
     }
 
+    /// Records the version rolling permission of the job about to be sent to the Downstreams,
+    /// each `Downstream` checks it before forwarding the job to the miner.
+    #[allow(clippy::result_large_err)]
+    fn set_version_rolling_allowed(
+        self_: &Arc<Mutex<Self>>,
+        allowed: bool,
+    ) -> ProxyResult<'static, ()> {
+        let version_rolling_allowed = self_
+            .safe_lock(|s| s.version_rolling_allowed.clone())
+            .map_err(|_| PoisonLock)?;
+        version_rolling_allowed
+            .safe_lock(|a| {
+                if *a != allowed {
+                    info!("Upstream version rolling allowed: {}", allowed);
+                }
+                *a = allowed;
+            })
+            .map_err(|_| PoisonLock)?;
+        Ok(())
+    }
+
     async fn handle_new_prev_hash_(
         self_: Arc<Mutex<Self>>,
         sv2_set_new_prev_hash: SetNewPrevHash<'static>,
@@ -335,12 +390,14 @@ impl Bridge {
         while let Some(job) = future_jobs.pop() {
             if job.job_id == sv2_set_new_prev_hash.job_id {
                 let j_id = job.job_id;
+                let version_rolling_allowed = job.version_rolling_allowed;
                 // Create the mining.notify to be sent to the Downstream.
                 let notify = crate::proxy::next_mining_notify::create_notify(
                     sv2_set_new_prev_hash.clone(),
                     job,
                     true,
                 );
+                Self::set_version_rolling_allowed(&self_, version_rolling_allowed)?;
 
                 // Get the sender to send the mining.notify to the Downstream
                 tx_sv1_notify.send(notify.clone())?;
@@ -432,6 +489,10 @@ impl Bridge {
             ))?;
 
             let j_id = sv2_new_extended_mining_job.job_id;
+            Self::set_version_rolling_allowed(
+                &self_,
+                sv2_new_extended_mining_job.version_rolling_allowed,
+            )?;
             // Create the mining.notify to be sent to the Downstream.
             // clean_jobs must be false because it's not a NewPrevHash template
             let notify = crate::proxy::next_mining_notify::create_notify(
@@ -502,6 +563,7 @@ pub struct OpenSv1Downstream {
     pub extranonce: Vec<u8>,
     pub target: Arc<Mutex<Vec<u8>>>,
     pub extranonce2_len: u16,
+    pub version_rolling_allowed: Arc<Mutex<bool>>,
 }
 
 #[cfg(test)]
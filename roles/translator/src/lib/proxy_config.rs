@@ -13,6 +13,10 @@ pub struct ProxyConfig {
     pub min_extranonce2_size: u16,
     pub downstream_difficulty_config: DownstreamDifficultyConfig,
     pub upstream_difficulty_config: UpstreamDifficultyConfig,
+    pub stats_config: Option<StatsConfig>,
+    pub downstream_tls_config: Option<DownstreamTlsConfig>,
+    #[serde(default)]
+    pub downstream_connection_config: DownstreamConnectionConfig,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -41,3 +45,38 @@ pub struct UpstreamDifficultyConfig {
     #[serde(default = "bool::default")]
     pub should_aggregate: bool,
 }
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StatsConfig {
+    pub api_address: String,
+    pub api_port: u16,
+    /// Interval in seconds between worker summaries in the logs, 0 disables them
+    #[serde(default = "u64::default")]
+    pub log_interval_secs: u64,
+}
+
+/// Optional SV1 over TLS (stratum+ssl) listener, running alongside the plaintext one.
+#[derive(Debug, Deserialize, Clone)]
+pub struct DownstreamTlsConfig {
+    pub tls_address: String,
+    pub tls_port: u16,
+    /// PEM file with the certificate chain presented to the miners.
+    pub cert_path: String,
+    /// PEM file with the private key of the certificate (PKCS#8, PKCS#1 or SEC1).
+    pub key_path: String,
+}
+
+/// Limits applied to the SV1 connections, every limit is disabled when set to 0.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct DownstreamConnectionConfig {
+    /// Seconds without `mining.subscribe` or `mining.submit` after which a SV1 connection is
+    /// closed.
+    #[serde(default = "u64::default")]
+    pub idle_timeout_secs: u64,
+    /// Maximum number of SV1 connections, plaintext and TLS together.
+    #[serde(default = "u32::default")]
+    pub max_connections: u32,
+    /// Seconds of silence on a SV1 connection before TCP keepalive probes are sent.
+    #[serde(default = "u64::default")]
+    pub tcp_keepalive_secs: u64,
+}
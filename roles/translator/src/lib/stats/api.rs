@@ -0,0 +1,131 @@
+use super::WorkerStatsRegistry;
+use async_std::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    prelude::*,
+    task,
+};
+use roles_logic_sv2::utils::Mutex;
+use std::{net::SocketAddr, sync::Arc};
+use tracing::{debug, error, info};
+
+/// Maximum size of the request line accepted by the stats endpoint.
+const MAX_REQUEST_LINE_LENGTH: usize = 1024;
+
+/// Starts a minimal HTTP server exposing the worker statistics as JSON:
+/// - `GET /workers` returns the list of all the connected workers
+/// - `GET /workers/<connection_id>` returns a single worker
+///
+/// The endpoint is meant to be reachable only from the local network of the farm, it does not
+/// support TLS nor authentication.
+pub fn start_api_server(address: SocketAddr, stats: Arc<Mutex<WorkerStatsRegistry>>) {
+    task::spawn(async move {
+        let listener = match TcpListener::bind(address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind worker stats API to {}: {}", address, e);
+                return;
+            }
+        };
+        info!("Worker stats API listening on http://{}/workers", address);
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    let stats = stats.clone();
+                    task::spawn(async move {
+                        if let Err(e) = handle_connection(stream, stats).await {
+                            debug!("Worker stats API: error handling request: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Worker stats API: failed to accept connection: {}", e),
+            }
+        }
+    });
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    stats: Arc<Mutex<WorkerStatsRegistry>>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(&stream).take(MAX_REQUEST_LINE_LENGTH as u64);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+
+    let (status, body) = route(&request_line, &stats);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    (&stream).write_all(response.as_bytes()).await?;
+    (&stream).flush().await
+}
+
+/// Returns the HTTP status line and the JSON body for a request line.
+fn route(request_line: &str, stats: &Arc<Mutex<WorkerStatsRegistry>>) -> (&'static str, String) {
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method, path),
+        _ => return ("400 Bad Request", error_body("bad request")),
+    };
+    if method != "GET" {
+        return ("405 Method Not Allowed", error_body("method not allowed"));
+    }
+    let path = path.trim_end_matches('/');
+    let body = if path == "/workers" {
+        stats
+            .safe_lock(|s| serde_json::to_string(&s.snapshot()))
+            .map(|r| r.ok())
+    } else if let Some(id) = path.strip_prefix("/workers/") {
+        let id = match id.parse::<u32>() {
+            Ok(id) => id,
+            Err(_) => return ("400 Bad Request", error_body("invalid connection id")),
+        };
+        match stats.safe_lock(|s| s.get(id).map(serde_json::to_string)) {
+            Ok(Some(r)) => Ok(r.ok()),
+            Ok(None) => return ("404 Not Found", error_body("worker not found")),
+            Err(e) => Err(e),
+        }
+    } else {
+        return ("404 Not Found", error_body("not found"));
+    };
+    match body {
+        Ok(Some(body)) => ("200 OK", body),
+        _ => ("500 Internal Server Error", error_body("internal error")),
+    }
+}
+
+fn error_body(message: &str) -> String {
+    format!("{{\"error\":\"{}\"}}", message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn routes_requests() {
+        let stats = Arc::new(Mutex::new(WorkerStatsRegistry::new()));
+        stats
+            .safe_lock(|s| s.add_worker(7, "127.0.0.1:1000".to_string()))
+            .unwrap();
+
+        let (status, body) = route("GET /workers HTTP/1.1\r\n", &stats);
+        assert_eq!(status, "200 OK");
+        assert!(body.starts_with("[{\"connection_id\":7,"));
+
+        let (status, body) = route("GET /workers/7 HTTP/1.1\r\n", &stats);
+        assert_eq!(status, "200 OK");
+        assert!(body.starts_with("{\"connection_id\":7,"));
+
+        let (status, _) = route("GET /workers/8 HTTP/1.1\r\n", &stats);
+        assert_eq!(status, "404 Not Found");
+        let (status, _) = route("GET /workers/abc HTTP/1.1\r\n", &stats);
+        assert_eq!(status, "400 Bad Request");
+        let (status, _) = route("POST /workers HTTP/1.1\r\n", &stats);
+        assert_eq!(status, "405 Method Not Allowed");
+    }
+}
@@ -0,0 +1,226 @@
+pub mod api;
+
+use roles_logic_sv2::utils::Mutex;
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+/// Number of seconds without a valid share after which a worker is reported as possibly dead in
+/// the periodic log summaries.
+const IDLE_WORKER_WARNING_SECS: u64 = 600;
+
+/// Outcome of a share submitted by a SV1 worker, as classified by the `Bridge`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShareOutcome {
+    /// The share met the downstream target (and possibly the upstream one).
+    Accepted,
+    /// The share was refused for any reason other than being stale.
+    Rejected,
+    /// The share referenced a job that is no longer valid.
+    Stale,
+}
+
+/// Statistics collected for a single SV1 connection.
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkerStats {
+    /// Id of the connection, also used as channel id by the `Bridge`.
+    pub connection_id: u32,
+    /// Address of the SV1 Mining Device.
+    pub peer_address: String,
+    /// Names authorized via `mining.authorize` on this connection.
+    pub authorized_names: Vec<String>,
+    pub accepted_shares: u64,
+    pub rejected_shares: u64,
+    pub stale_shares: u64,
+    /// Difficulty last sent to the worker with `mining.set_difficulty`.
+    pub difficulty: f64,
+    /// Hashrate estimated by the downstream difficulty management, in hashes/s.
+    pub hashrate: f32,
+    /// Unix timestamp (secs) of the connection.
+    pub connected_at: u64,
+    /// Unix timestamp (secs) of the last accepted share, if any.
+    pub last_share_time: Option<u64>,
+}
+
+impl WorkerStats {
+    fn new(connection_id: u32, peer_address: String) -> Self {
+        Self {
+            connection_id,
+            peer_address,
+            authorized_names: vec![],
+            accepted_shares: 0,
+            rejected_shares: 0,
+            stale_shares: 0,
+            difficulty: 0.0,
+            hashrate: 0.0,
+            connected_at: now_secs(),
+            last_share_time: None,
+        }
+    }
+
+    /// Seconds elapsed since the last accepted share, or since the connection if the worker never
+    /// submitted a valid share.
+    pub fn secs_since_last_share(&self, now: u64) -> u64 {
+        now.saturating_sub(self.last_share_time.unwrap_or(self.connected_at))
+    }
+}
+
+/// Per-worker statistics of every SV1 connection currently open on the translator.
+#[derive(Debug, Default)]
+pub struct WorkerStatsRegistry {
+    workers: HashMap<u32, WorkerStats>,
+}
+
+impl WorkerStatsRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts tracking a new SV1 connection.
+    pub fn add_worker(&mut self, connection_id: u32, peer_address: String) {
+        self.workers
+            .insert(connection_id, WorkerStats::new(connection_id, peer_address));
+    }
+
+    /// Stops tracking a SV1 connection, called when the connection is closed.
+    pub fn remove_worker(&mut self, connection_id: u32) {
+        self.workers.remove(&connection_id);
+    }
+
+    pub fn on_authorize(&mut self, connection_id: u32, name: &str) {
+        if let Some(worker) = self.workers.get_mut(&connection_id) {
+            if !worker.authorized_names.iter().any(|n| n == name) {
+                worker.authorized_names.push(name.to_string());
+            }
+        }
+    }
+
+    pub fn on_share(&mut self, connection_id: u32, outcome: ShareOutcome) {
+        if let Some(worker) = self.workers.get_mut(&connection_id) {
+            match outcome {
+                ShareOutcome::Accepted => {
+                    worker.accepted_shares += 1;
+                    worker.last_share_time = Some(now_secs());
+                }
+                ShareOutcome::Rejected => worker.rejected_shares += 1,
+                ShareOutcome::Stale => worker.stale_shares += 1,
+            }
+        }
+    }
+
+    /// Updates the difficulty and the estimated hashrate of a worker.
+    pub fn on_difficulty_update(&mut self, connection_id: u32, difficulty: f64, hashrate: f32) {
+        if let Some(worker) = self.workers.get_mut(&connection_id) {
+            worker.difficulty = difficulty;
+            worker.hashrate = hashrate;
+        }
+    }
+
+    pub fn get(&self, connection_id: u32) -> Option<&WorkerStats> {
+        self.workers.get(&connection_id)
+    }
+
+    /// Returns the stats of all the workers ordered by connection id.
+    pub fn snapshot(&self) -> Vec<WorkerStats> {
+        let mut workers: Vec<WorkerStats> = self.workers.values().cloned().collect();
+        workers.sort_by_key(|w| w.connection_id);
+        workers
+    }
+}
+
+/// Periodically logs a summary line for every connected worker. Workers that did not submit a
+/// valid share for `IDLE_WORKER_WARNING_SECS` are logged as warnings.
+pub fn start_log_summaries(stats: Arc<Mutex<WorkerStatsRegistry>>, interval_secs: u64) {
+    async_std::task::spawn(async move {
+        loop {
+            async_std::task::sleep(Duration::from_secs(interval_secs)).await;
+            let workers = match stats.safe_lock(|s| s.snapshot()) {
+                Ok(workers) => workers,
+                Err(_) => {
+                    warn!("Worker stats: Poison Lock, stopping log summaries");
+                    break;
+                }
+            };
+            let now = now_secs();
+            let total_hashrate: f32 = workers.iter().map(|w| w.hashrate).sum();
+            info!(
+                "Worker stats: {} workers connected, total estimated hashrate {} h/s",
+                workers.len(),
+                total_hashrate
+            );
+            for w in workers {
+                let idle = w.secs_since_last_share(now);
+                if idle >= IDLE_WORKER_WARNING_SECS {
+                    warn!(
+                        "Worker {} ({}) {:?}: no valid share in the last {}s",
+                        w.connection_id, w.peer_address, w.authorized_names, idle
+                    );
+                } else {
+                    info!(
+                        "Worker {} ({}) {:?}: accepted {} rejected {} stale {} difficulty {} hashrate {} h/s last share {}s ago",
+                        w.connection_id,
+                        w.peer_address,
+                        w.authorized_names,
+                        w.accepted_shares,
+                        w.rejected_shares,
+                        w.stale_shares,
+                        w.difficulty,
+                        w.hashrate,
+                        idle
+                    );
+                }
+            }
+        }
+    });
+}
+
+pub(crate) fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_shares_per_worker() {
+        let mut registry = WorkerStatsRegistry::new();
+        registry.add_worker(1, "127.0.0.1:1000".to_string());
+        registry.add_worker(2, "127.0.0.1:1001".to_string());
+
+        registry.on_share(1, ShareOutcome::Accepted);
+        registry.on_share(1, ShareOutcome::Accepted);
+        registry.on_share(1, ShareOutcome::Stale);
+        registry.on_share(2, ShareOutcome::Rejected);
+        // unknown connections are ignored
+        registry.on_share(3, ShareOutcome::Accepted);
+
+        let worker_1 = registry.get(1).unwrap();
+        assert_eq!(worker_1.accepted_shares, 2);
+        assert_eq!(worker_1.stale_shares, 1);
+        assert!(worker_1.last_share_time.is_some());
+        let worker_2 = registry.get(2).unwrap();
+        assert_eq!(worker_2.rejected_shares, 1);
+        assert!(worker_2.last_share_time.is_none());
+        assert!(registry.get(3).is_none());
+
+        registry.remove_worker(1);
+        assert_eq!(registry.snapshot().len(), 1);
+    }
+
+    #[test]
+    fn authorized_names_are_not_duplicated() {
+        let mut registry = WorkerStatsRegistry::new();
+        registry.add_worker(1, "127.0.0.1:1000".to_string());
+        registry.on_authorize(1, "farm.worker1");
+        registry.on_authorize(1, "farm.worker1");
+        registry.on_authorize(1, "farm.worker2");
+        assert_eq!(
+            registry.get(1).unwrap().authorized_names,
+            vec!["farm.worker1".to_string(), "farm.worker2".to_string()]
+        );
+    }
+}
@@ -127,6 +127,10 @@ pub async fn handle_error(
         Error::InvalidExtranonce(_) => {
             send_status(sender, e, error_handling::ErrorBranch::Break).await
         }
+        // Errors on bad certificate or key for the SV1 TLS listener
+        Error::InvalidTlsConfig(_) => {
+            send_status(sender, e, error_handling::ErrorBranch::Break).await
+        }
         // Errors on bad `TcpStream` connection.
         Error::Io(_) => send_status(sender, e, error_handling::ErrorBranch::Break).await,
         // Errors on bad `String` to `int` conversion.
@@ -39,7 +39,7 @@ use std::{
     thread::sleep,
     time::Duration,
 };
-use tracing::{error, info, warn};
+use tracing::{debug, error, info};
 
 use stratum_common::bitcoin::BlockHash;
 
@@ -744,8 +744,7 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         } else {
             IS_NEW_JOB_HANDLED.store(false, std::sync::atomic::Ordering::SeqCst);
             if !m.version_rolling_allowed {
-                warn!("VERSION ROLLING NOT ALLOWED IS A TODO");
-                // todo!()
+                debug!("Version rolling not allowed for job {}", m.job_id);
             }
 
             let message = Mining::NewExtendedMiningJob(m.into_static());
@@ -4,7 +4,7 @@ mod lib;
 
 use args::Args;
 use error::{Error, ProxyResult};
-use lib::{downstream_sv1, error, proxy, proxy_config, status, upstream_sv2};
+use lib::{downstream_sv1, error, proxy, proxy_config, stats, status, upstream_sv2};
 use proxy_config::ProxyConfig;
 use roles_logic_sv2::utils::Mutex;
 
@@ -166,15 +166,68 @@ async fn main() {
         );
         proxy::Bridge::start(b.clone());
 
+        // Expose the per-worker statistics if configured
+        if let Some(stats_config) = proxy_config.stats_config {
+            let worker_stats = b.safe_lock(|b| b.worker_stats()).unwrap();
+            let api_addr = SocketAddr::new(
+                IpAddr::from_str(&stats_config.api_address)
+                    .expect("Failed to parse stats api address!"),
+                stats_config.api_port,
+            );
+            stats::api::start_api_server(api_addr, worker_stats.clone());
+            if stats_config.log_interval_secs > 0 {
+                stats::start_log_summaries(worker_stats, stats_config.log_interval_secs);
+            }
+        }
+
         // Format `Downstream` connection address
         let downstream_addr = SocketAddr::new(
             IpAddr::from_str(&proxy_config.downstream_address).unwrap(),
             proxy_config.downstream_port,
         );
 
+        // Accept SV1 over TLS connections alongside the plaintext ones if configured
+        // shared by the plaintext and the TLS listeners, `max_connections` caps both together
+        let connection_slots = downstream_sv1::connection_management::ConnectionSlots::new(
+            proxy_config.downstream_connection_config.max_connections as usize,
+        );
+        if let Some(tls_config) = proxy_config.downstream_tls_config {
+            let tls_acceptor = match downstream_sv1::tls::tls_acceptor(
+                &tls_config.cert_path,
+                &tls_config.key_path,
+            ) {
+                Ok(acceptor) => acceptor,
+                Err(e) => {
+                    error!("Failed to load TLS certificate EXITING! : {}", e);
+                    return;
+                }
+            };
+            let tls_addr = SocketAddr::new(
+                IpAddr::from_str(&tls_config.tls_address)
+                    .expect("Failed to parse downstream tls address!"),
+                tls_config.tls_port,
+            );
+            info!("Accepting SV1 over TLS connections on {}", tls_addr);
+            downstream_sv1::Downstream::accept_connections(
+                tls_addr,
+                Some(tls_acceptor),
+                proxy_config.downstream_connection_config.clone(),
+                connection_slots.clone(),
+                tx_sv1_bridge.clone(),
+                tx_sv1_notify.clone(),
+                status::Sender::DownstreamListener(tx_status.clone()),
+                b.clone(),
+                proxy_config.downstream_difficulty_config.clone(),
+                diff_config.clone(),
+            );
+        }
+
         // Accept connections from one or more SV1 Downstream roles (SV1 Mining Devices)
         downstream_sv1::Downstream::accept_connections(
             downstream_addr,
+            None,
+            proxy_config.downstream_connection_config,
+            connection_slots,
             tx_sv1_bridge,
             tx_sv1_notify,
             status::Sender::DownstreamListener(tx_status.clone()),